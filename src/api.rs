@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Error, Result};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE, HOST};
+use reqwest::{Client, Method, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::UnixStream;
+use url::form_urlencoded;
+
+/// Proxy or proxy group, as listed by `GET /proxies`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Proxy {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub proxy_type: String,
+    /// Selected member of a group
+    pub now: Option<String>,
+    /// Members of a group
+    pub all: Option<Vec<String>>,
+    #[serde(default)]
+    pub history: Vec<DelayHistory>,
+}
+
+impl Proxy {
+    /// Delay of the latest test in milliseconds, `Some(0)` if it timed out.
+    pub fn last_delay(&self) -> Option<u64> {
+        self.history.last().map(|history| history.delay)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DelayHistory {
+    pub delay: u64,
+}
+
+#[derive(Deserialize)]
+struct Proxies {
+    proxies: HashMap<String, Proxy>,
+}
+
+#[derive(Deserialize)]
+struct Delay {
+    delay: u64,
+}
+
+#[derive(Deserialize)]
+struct Version {
+    version: String,
+}
+
+/// Active connection, as listed by `GET /connections`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Connection {
+    pub id: String,
+    /// Bytes uploaded since the connection started
+    pub upload: u64,
+    /// Bytes downloaded since the connection started
+    pub download: u64,
+    /// Start time in RFC 3339
+    pub start: String,
+    /// Proxies the connection goes through, from the outbound node up to the matched policy
+    #[serde(default)]
+    pub chains: Vec<String>,
+    #[serde(default)]
+    pub rule: String,
+    pub metadata: ConnectionMetadata,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ConnectionMetadata {
+    #[serde(default)]
+    pub network: String,
+    #[serde(default)]
+    pub host: String,
+    #[serde(default, rename = "destinationIP")]
+    pub destination_ip: String,
+    #[serde(default, rename = "destinationPort")]
+    pub destination_port: String,
+    /// Name of the source process, only found if `find-process-mode` is enabled
+    #[serde(default)]
+    pub process: String,
+}
+
+impl ConnectionMetadata {
+    /// Destination host, or ip if the connection was made without a domain name.
+    pub fn destination(&self) -> &str {
+        if self.host.is_empty() {
+            &self.destination_ip
+        } else {
+            &self.host
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Connections {
+    #[serde(default)]
+    connections: Option<Vec<Connection>>,
+    upload_total: u64,
+    download_total: u64,
+    #[serde(default)]
+    memory: u64,
+}
+
+/// Url of mihomo's external controller at a TCP `external-controller` address, through loopback
+/// if it listens on all interfaces.
+pub fn controller_url(external_controller: &str) -> String {
+    let (host, port) = external_controller
+        .rsplit_once(':')
+        .unwrap_or((external_controller, "9090"));
+    let host = match host {
+        "" | "0.0.0.0" | "[::]" | "::" => "127.0.0.1",
+        host => host,
+    };
+    format!("http://{}:{}", host, port)
+}
+
+/// Connection to mihomo's external controller.
+enum Transport {
+    Tcp {
+        client: Client,
+        base_url: String,
+    },
+    /// `external-controller-unix`, spoken to with hyper directly as reqwest has no unix sockets
+    Unix {
+        socket_path: PathBuf,
+    },
+}
+
+/// Client of mihomo's RESTful API, served by the `external-controller` or on the
+/// `external-controller-unix` socket.
+///
+/// Reference: https://wiki.metacubex.one/api
+pub struct MihomoApi {
+    transport: Transport,
+    secret: Option<String>,
+}
+
+impl MihomoApi {
+    /// Create an API client from mihomo's `external-controller` address, connecting through
+    /// loopback if it listens on all interfaces.
+    pub fn new(client: Client, external_controller: &str, secret: Option<&str>) -> MihomoApi {
+        MihomoApi {
+            transport: Transport::Tcp {
+                client,
+                base_url: controller_url(external_controller),
+            },
+            secret: secret.map(String::from),
+        }
+    }
+
+    /// Create an API client of mihomo's `external-controller-unix` socket.
+    pub fn new_unix(socket_path: &Path, secret: Option<&str>) -> MihomoApi {
+        MihomoApi {
+            transport: Transport::Unix {
+                socket_path: socket_path.to_path_buf(),
+            },
+            secret: secret.map(String::from),
+        }
+    }
+
+    fn unreachable(&self) -> Error {
+        let address = match &self.transport {
+            Transport::Tcp { base_url, .. } => base_url.clone(),
+            Transport::Unix { socket_path } => socket_path.display().to_string(),
+        };
+        anyhow!(
+            "failed to reach mihomo's external controller at {}, is mihomo running?",
+            address
+        )
+    }
+
+    /// Send a request with an optional JSON body, returning the body of a successful response.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<String> {
+        let (status, body) = match &self.transport {
+            Transport::Tcp { client, base_url } => {
+                let mut request = client.request(method, format!("{}{}", base_url, path));
+                if let Some(secret) = &self.secret {
+                    request = request.bearer_auth(secret);
+                }
+                if let Some(body) = body {
+                    request = request.json(&body);
+                }
+                let response = request.send().await.map_err(|_| self.unreachable())?;
+                (response.status(), response.text().await?)
+            }
+            Transport::Unix { socket_path } => {
+                send_unix(socket_path, method, path, self.secret.as_deref(), body)
+                    .await
+                    .map_err(|_| self.unreachable())?
+            }
+        };
+        if !status.is_success() {
+            // Errors are reported as `{"message": "..."}`
+            let message = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|body| body.get("message")?.as_str().map(String::from))
+                .unwrap_or(body);
+            bail!("mihomo API responded with {}: {}", status, message.trim());
+        }
+        Ok(body)
+    }
+
+    /// Send a request to any endpoint, e.g. one not wrapped here, returning the raw body.
+    /// Streaming endpoints such as `/traffic` never complete.
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<String> {
+        self.send(method, path, body).await
+    }
+
+    /// Version of the running mihomo, doubling as a health check of its external controller.
+    pub async fn version(&self) -> Result<String> {
+        let body = self.send(Method::GET, "/version", None).await?;
+        Ok(serde_json::from_str::<Version>(&body)?.version)
+    }
+
+    /// All proxies and proxy groups, by name.
+    pub async fn proxies(&self) -> Result<HashMap<String, Proxy>> {
+        let body = self.send(Method::GET, "/proxies", None).await?;
+        Ok(serde_json::from_str::<Proxies>(&body)?.proxies)
+    }
+
+    /// Active connections.
+    pub async fn connections(&self) -> Result<Vec<Connection>> {
+        let body = self.send(Method::GET, "/connections", None).await?;
+        Ok(serde_json::from_str::<Connections>(&body)?
+            .connections
+            .unwrap_or_default())
+    }
+
+    /// Traffic counters of mihomo in bytes since it started, as (upload, download).
+    pub async fn traffic_totals(&self) -> Result<(u64, u64)> {
+        let body = self.send(Method::GET, "/connections", None).await?;
+        let connections = serde_json::from_str::<Connections>(&body)?;
+        Ok((connections.upload_total, connections.download_total))
+    }
+
+    /// Memory in use by mihomo in bytes. `GET /memory` only streams samples, the first of them 0,
+    /// so the figure is read from the connections snapshot instead.
+    pub async fn memory(&self) -> Result<u64> {
+        let body = self.send(Method::GET, "/connections", None).await?;
+        Ok(serde_json::from_str::<Connections>(&body)?.memory)
+    }
+
+    /// Close an active connection.
+    pub async fn close_connection(&self, id: &str) -> Result<()> {
+        let path = format!("/connections/{}", utf8_percent_encode(id, NON_ALPHANUMERIC));
+        self.send(Method::DELETE, &path, None).await?;
+        Ok(())
+    }
+
+    /// Test delay of a proxy in milliseconds by requesting `url` through it, failing if it times
+    /// out after `timeout` milliseconds or is unreachable.
+    pub async fn proxy_delay(&self, name: &str, url: &str, timeout: u64) -> Result<u64> {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("url", url)
+            .append_pair("timeout", &timeout.to_string())
+            .finish();
+        let path = format!(
+            "/proxies/{}/delay?{}",
+            utf8_percent_encode(name, NON_ALPHANUMERIC),
+            query
+        );
+        let body = self.send(Method::GET, &path, None).await?;
+        Ok(serde_json::from_str::<Delay>(&body)?.delay)
+    }
+
+    /// Switch a selector group to one of its members.
+    pub async fn select_proxy(&self, group: &str, name: &str) -> Result<()> {
+        let path = format!("/proxies/{}", utf8_percent_encode(group, NON_ALPHANUMERIC));
+        self.send(Method::PUT, &path, Some(json!({ "name": name })))
+            .await?;
+        Ok(())
+    }
+
+    /// Drop the mappings of mihomo's fake-ip pool, including those stored in its cache.
+    pub async fn flush_fake_ip(&self) -> Result<()> {
+        self.send(Method::POST, "/cache/fakeip/flush", None).await?;
+        Ok(())
+    }
+
+    /// Reload mihomo with the given config content, without writing it to disk.
+    pub async fn reload_config(&self, payload: &str) -> Result<()> {
+        let body = json!({ "path": "", "payload": payload });
+        self.send(Method::PUT, "/configs?force=true", Some(body))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Send an HTTP/1.1 request over a unix socket, returning the status and body of the response.
+async fn send_unix(
+    socket_path: &Path,
+    method: Method,
+    path: &str,
+    secret: Option<&str>,
+    body: Option<serde_json::Value>,
+) -> Result<(StatusCode, String)> {
+    let stream = UnixStream::connect(socket_path).await?;
+    let (mut sender, connection) = hyper::client::conn::handshake(stream).await?;
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    let mut request = hyper::Request::builder()
+        .method(method)
+        .uri(path)
+        .header(HOST, "localhost");
+    if let Some(secret) = secret {
+        request = request.header(AUTHORIZATION, format!("Bearer {}", secret));
+    }
+    let body = match body {
+        Some(body) => {
+            request = request.header(CONTENT_TYPE, "application/json");
+            hyper::Body::from(body.to_string())
+        }
+        None => hyper::Body::empty(),
+    };
+    let response = sender.send_request(request.body(body)?).await?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Ok((status, String::from_utf8_lossy(&body).into_owned()))
+}
@@ -0,0 +1,110 @@
+use crate::backup::{backup_file, BACKUPS_DIR};
+use crate::config::Config;
+use crate::migrate::migrate_config;
+use crate::utils::{create_parent_dir, expand_path};
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::prelude::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use tar::{Archive, Builder};
+
+/// Name of mihoro's config in a setup archive, always its first entry.
+pub const ARCHIVE_CONFIG_FILE: &str = "mihoro.toml";
+
+/// Directory of files under mihomo's config root in a setup archive.
+const ARCHIVE_CONFIG_ROOT: &str = "config";
+
+/// Geodata downloaded next to mihomo's `config.yaml`, included in setup archives on request.
+pub const GEODATA_FILES: &[&str] = &["geoip.dat", "geosite.dat", "country.mmdb"];
+
+/// Write a zstd compressed tarball of mihoro's config and the `files` (or directories) under
+/// mihomo's config root that exist, returning their paths. The archive holds inline secrets of the
+/// config, so it is only readable by the owner.
+pub fn export_setup(
+    archive_path: &str,
+    config_path: &str,
+    config_root: &Path,
+    files: &[&str],
+) -> Result<Vec<PathBuf>> {
+    create_parent_dir(archive_path)?;
+    let archive = File::create(archive_path)?;
+    archive.set_permissions(fs::Permissions::from_mode(0o600))?;
+    let mut builder = Builder::new(zstd::Encoder::new(archive, 0)?);
+
+    builder.append_path_with_name(config_path, ARCHIVE_CONFIG_FILE)?;
+    let mut exported = vec![PathBuf::from(config_path)];
+    for file in files {
+        let path = config_root.join(file);
+        let name = Path::new(ARCHIVE_CONFIG_ROOT).join(file);
+        if path.is_dir() {
+            builder.append_dir_all(name, &path)?;
+        } else if path.is_file() {
+            builder.append_path_with_name(&path, name)?;
+        } else {
+            continue;
+        }
+        exported.push(path);
+    }
+    builder.into_inner()?.finish()?;
+    Ok(exported)
+}
+
+/// Unpack a setup archive written by `export_setup`: mihoro's config to `config_path`, then the
+/// remaining files under mihomo's config root of the imported config. Replaced files are backed up
+/// to `backups/` under that root first. Returns paths of the unpacked files.
+pub fn import_setup(archive_path: &str, config_path: &str) -> Result<Vec<PathBuf>> {
+    let mut archive = Archive::new(zstd::Decoder::new(File::open(archive_path)?)?);
+    let mut entries = archive.entries()?;
+
+    let mut config_entry = match entries.next() {
+        Some(entry) => entry.with_context(|| format!("failed to read {}", archive_path))?,
+        None => bail!("{} is empty", archive_path),
+    };
+    if config_entry.path()? != Path::new(ARCHIVE_CONFIG_FILE) {
+        bail!("{} is not a mihoro setup archive", archive_path);
+    }
+    let mut raw_config = String::new();
+    config_entry.read_to_string(&mut raw_config)?;
+    let raw_config = migrate_config(&raw_config)?.unwrap_or(raw_config);
+    let mut config: Config = toml::from_str(&raw_config)?;
+    config.apply_install_scope();
+    let config_root = PathBuf::from(expand_path(&config.mihomo_config_root));
+    let backups_dir = config_root.join(BACKUPS_DIR);
+
+    backup_file(config_path, &backups_dir, config.backup_retention())?;
+    create_parent_dir(config_path)?;
+    fs::write(config_path, raw_config)?;
+    let mut imported = vec![PathBuf::from(config_path)];
+    for entry in entries {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        // Refuse absolute paths and `..` that would unpack outside of mihomo's config root
+        let relative_path = match path.strip_prefix(ARCHIVE_CONFIG_ROOT) {
+            Ok(relative_path)
+                if relative_path
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_))) =>
+            {
+                relative_path
+            }
+            _ => bail!("unexpected entry {} in setup archive", path.display()),
+        };
+        let target_path = config_root.join(relative_path);
+        if entry.header().entry_type().is_dir() {
+            fs::create_dir_all(&target_path)?;
+            continue;
+        }
+        backup_file(
+            &target_path.to_string_lossy(),
+            &backups_dir,
+            config.backup_retention(),
+        )?;
+        create_parent_dir(&target_path.to_string_lossy())?;
+        entry.unpack(&target_path)?;
+        imported.push(target_path);
+    }
+    Ok(imported)
+}
@@ -0,0 +1,68 @@
+use crate::utils::create_parent_dir;
+
+use std::{
+    cmp::Reverse,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+
+/// Directory of backups of files replaced by mihoro, kept next to mihomo's `config.yaml`.
+pub const BACKUPS_DIR: &str = "backups";
+
+/// Number of backups kept of each file if `backup_retention` is undefined.
+pub const DEFAULT_BACKUP_RETENTION: usize = 3;
+
+/// Copy a file about to be replaced into `backups_dir` as `{file_name}.{unix_timestamp}`, unless
+/// the latest backup is identical, then remove its backups beyond the `retention` newest.
+pub fn backup_file(path: &str, backups_dir: &Path, retention: usize) -> Result<()> {
+    let path = Path::new(path);
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(());
+    };
+    if !path.is_file() {
+        return Ok(());
+    }
+    if let Some(latest) = list_backups(backups_dir, file_name)?.first() {
+        if fs::read(latest)? == fs::read(path)? {
+            return Ok(());
+        }
+    }
+
+    // Copying keeps permissions, so that binary backups stay executable
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let backup_path = backups_dir.join(format!("{}.{}", file_name, timestamp));
+    create_parent_dir(&backup_path.to_string_lossy())?;
+    fs::copy(path, backup_path)?;
+    for stale_backup in list_backups(backups_dir, file_name)?
+        .into_iter()
+        .skip(retention)
+    {
+        fs::remove_file(stale_backup)?;
+    }
+    Ok(())
+}
+
+/// Backups of a file in `backups_dir`, newest first.
+pub fn list_backups(backups_dir: &Path, file_name: &str) -> Result<Vec<PathBuf>> {
+    let mut backups: Vec<(u64, PathBuf)> = Vec::new();
+    if !backups_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    for entry in fs::read_dir(backups_dir)? {
+        let path = entry?.path();
+        let timestamp = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix(file_name))
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|timestamp| timestamp.parse().ok());
+        if let Some(timestamp) = timestamp {
+            backups.push((timestamp, path));
+        }
+    }
+    backups.sort_by_key(|(timestamp, _)| Reverse(*timestamp));
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
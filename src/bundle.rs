@@ -0,0 +1,197 @@
+use crate::utils::create_parent_dir;
+
+use std::fs::{self, File};
+use std::os::unix::prelude::PermissionsExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tar::{Builder, Header};
+
+/// Lines of mihomo's most recent logs included in debug bundles.
+pub const BUNDLE_LOG_LINES: usize = 200;
+
+/// Replacement of redacted values.
+const REDACTED: &str = "<redacted>";
+
+/// Keys whose values are redacted wherever they appear, compared with `_` read as `-`: secrets of
+/// the controller and the subscription, and credentials and addresses of nodes.
+const SECRET_KEYS: &[&str] = &[
+    "secret",
+    "authentication",
+    "password",
+    "obfs-password",
+    "username",
+    "uuid",
+    "private-key",
+    "public-key",
+    "pre-shared-key",
+    "psk",
+    "auth",
+    "auth-str",
+    "token",
+    "headers",
+    "server",
+    "servername",
+    "sni",
+    "short-id",
+    "minisign-public-key",
+    "webdav-password",
+    "access-key",
+    "secret-key",
+];
+
+/// Schemes of share links, which carry credentials and server addresses throughout.
+const SHARE_LINK_SCHEMES: &[&str] = &[
+    "ss",
+    "ssr",
+    "vmess",
+    "vless",
+    "trojan",
+    "hysteria",
+    "hysteria2",
+    "hy2",
+    "tuic",
+];
+
+/// Redaction of secrets in configs and logs for debug bundles. Secrets found in configs are
+/// collected, to also scrub them from free text such as logs.
+#[derive(Debug, Default)]
+pub struct Redactor {
+    secrets: Vec<String>,
+}
+
+impl Redactor {
+    fn is_secret_key(key: &str) -> bool {
+        SECRET_KEYS.contains(&key.replace('_', "-").to_lowercase().as_str())
+    }
+
+    fn collect(&mut self, secret: &str) {
+        // Short values such as ports or `true` would scrub unrelated text
+        if secret.len() >= 6 && !self.secrets.iter().any(|known| known == secret) {
+            self.secrets.push(secret.to_string());
+        }
+    }
+
+    /// Redact values of secret keys and the paths of urls in mihomo's config.
+    pub fn yaml(&mut self, value: &mut serde_yaml::Value) {
+        match value {
+            serde_yaml::Value::Mapping(mapping) => {
+                for (key, value) in mapping.iter_mut() {
+                    if key.as_str().is_some_and(Self::is_secret_key) {
+                        self.collect_yaml(value);
+                        *value = serde_yaml::Value::from(REDACTED);
+                    } else {
+                        self.yaml(value);
+                    }
+                }
+            }
+            serde_yaml::Value::Sequence(items) => items.iter_mut().for_each(|item| self.yaml(item)),
+            serde_yaml::Value::String(text) => *text = self.url(text),
+            _ => {}
+        }
+    }
+
+    fn collect_yaml(&mut self, value: &serde_yaml::Value) {
+        match value {
+            serde_yaml::Value::String(text) => self.collect(text),
+            serde_yaml::Value::Mapping(mapping) => {
+                mapping.values().for_each(|value| self.collect_yaml(value))
+            }
+            serde_yaml::Value::Sequence(items) => {
+                items.iter().for_each(|item| self.collect_yaml(item))
+            }
+            _ => {}
+        }
+    }
+
+    /// Redact values of secret keys and the paths of urls in mihoro's config.
+    pub fn toml(&mut self, value: &mut toml::Value) {
+        match value {
+            toml::Value::Table(table) => {
+                for (key, value) in table.iter_mut() {
+                    if Self::is_secret_key(key) {
+                        self.collect_toml(value);
+                        *value = toml::Value::from(REDACTED);
+                    } else {
+                        self.toml(value);
+                    }
+                }
+            }
+            toml::Value::Array(items) => items.iter_mut().for_each(|item| self.toml(item)),
+            toml::Value::String(text) => *text = self.url(text),
+            _ => {}
+        }
+    }
+
+    fn collect_toml(&mut self, value: &toml::Value) {
+        match value {
+            toml::Value::String(text) => self.collect(text),
+            toml::Value::Table(table) => table.values().for_each(|value| self.collect_toml(value)),
+            toml::Value::Array(items) => items.iter().for_each(|item| self.collect_toml(item)),
+            _ => {}
+        }
+    }
+
+    /// A url reduced to its scheme and host, as paths and queries of subscriptions and providers
+    /// carry tokens, or to its scheme alone for share links. Other text is kept as is.
+    fn url(&mut self, text: &str) -> String {
+        let Some((scheme, rest)) = text.split_once("://") else {
+            return text.to_string();
+        };
+        if SHARE_LINK_SCHEMES.contains(&scheme.to_lowercase().as_str()) {
+            self.collect(text);
+            return format!("{}://{}", scheme, REDACTED);
+        }
+        if !matches!(scheme, "http" | "https") {
+            return text.to_string();
+        }
+        let (authority, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+        let host = authority.rsplit('@').next().unwrap_or(authority);
+        if path.trim_start_matches('/').is_empty() && host == authority {
+            return text.to_string();
+        }
+        self.collect(text);
+        format!("{}://{}/{}", scheme, host, REDACTED)
+    }
+
+    /// Scrub secrets collected from configs out of free text, longest first.
+    pub fn text(&self, text: &str) -> String {
+        let mut secrets: Vec<&String> = self.secrets.iter().collect();
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.len()));
+        secrets.into_iter().fold(text.to_string(), |text, secret| {
+            text.replace(secret.as_str(), REDACTED)
+        })
+    }
+}
+
+/// Write a gzip compressed tarball of `files`, given by name and contents, under a directory named
+/// after the archive. Only readable by the owner, in case redaction missed anything.
+pub fn write_bundle(path: &str, files: &[(&str, String)]) -> Result<()> {
+    create_parent_dir(path)?;
+    let archive = File::create(path)?;
+    archive.set_permissions(fs::Permissions::from_mode(0o600))?;
+    let mut builder = Builder::new(GzEncoder::new(archive, Compression::default()));
+    let root = path
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+        .trim_end_matches(".tar.gz");
+    let modified = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    for (name, contents) in files {
+        let mut header = Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_mtime(modified);
+        builder.append_data(
+            &mut header,
+            format!("{}/{}", root, name),
+            contents.as_bytes(),
+        )?;
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use crate::utils::{create_parent_dir, expand_path, root};
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Root of the download cache, `$XDG_CACHE_HOME/mihoro` or `~/.cache/mihoro`, always the latter
+/// under `--root`.
+pub fn cache_root() -> PathBuf {
+    let cache_home = env::var("XDG_CACHE_HOME")
+        .ok()
+        .filter(|cache_home| !cache_home.is_empty() && root().is_none())
+        .unwrap_or_else(|| expand_path("~/.cache"));
+    Path::new(&cache_home).join("mihoro")
+}
+
+/// Cached download of a url, with validators of the response it came from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheEntry {
+    pub sha256: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// Content-addressed cache of downloaded artifacts (binaries, geodata):
+///
+/// * `blobs/{sha256}` - downloaded content, shared by urls serving the same content.
+/// * `index.toml` - cache entry of each url.
+///
+/// Cached urls are revalidated with conditional requests, so unchanged artifacts are copied from
+/// the cache instead of being downloaded again.
+#[derive(Debug, Clone)]
+pub struct DownloadCache {
+    root: PathBuf,
+}
+
+impl DownloadCache {
+    pub fn new(root: PathBuf) -> DownloadCache {
+        DownloadCache { root }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.root.join("index.toml")
+    }
+
+    fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.root.join("blobs").join(sha256)
+    }
+
+    fn load_index(&self) -> Result<BTreeMap<String, CacheEntry>> {
+        let index_path = self.index_path();
+        if !index_path.exists() {
+            return Ok(BTreeMap::new());
+        }
+        Ok(toml::from_str(&fs::read_to_string(index_path)?)?)
+    }
+
+    /// Cache entry of a url, if its content is still cached intact.
+    pub fn lookup(&self, url: &str) -> Option<CacheEntry> {
+        let entry = self.load_index().ok()?.remove(url)?;
+        let blob_sha256 = sha256_file(&self.blob_path(&entry.sha256)).ok()?;
+        (blob_sha256 == entry.sha256).then_some(entry)
+    }
+
+    /// Copy the cached content of an entry to path.
+    pub fn restore(&self, entry: &CacheEntry, path: &str) -> Result<()> {
+        create_parent_dir(path)?;
+        fs::copy(self.blob_path(&entry.sha256), path)?;
+        Ok(())
+    }
+
+    /// Add a file downloaded from url to the cache, along with validators of its response.
+    pub fn store(
+        &self,
+        url: &str,
+        path: &str,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    ) -> Result<()> {
+        let sha256 = sha256_file(Path::new(path))?;
+        let blob_path = self.blob_path(&sha256);
+        if !blob_path.exists() {
+            create_parent_dir(&blob_path.to_string_lossy())?;
+            fs::copy(path, blob_path)?;
+        }
+
+        let mut index = self.load_index()?;
+        let entry = CacheEntry {
+            sha256,
+            etag,
+            last_modified,
+        };
+        index.insert(url.to_string(), entry);
+        fs::write(self.index_path(), toml::to_string(&index)?)?;
+        Ok(())
+    }
+
+    /// Remove the whole cache, returning the number of bytes reclaimed.
+    pub fn clear(&self) -> Result<u64> {
+        if !self.root.exists() {
+            return Ok(0);
+        }
+        let mut size = 0;
+        for dir in [self.root.clone(), self.root.join("blobs")] {
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(dir)? {
+                let metadata = entry?.metadata()?;
+                if metadata.is_file() {
+                    size += metadata.len();
+                }
+            }
+        }
+        fs::remove_dir_all(&self.root)?;
+        Ok(size)
+    }
+}
+
+/// Hex-encoded SHA-256 digest of a file.
+pub fn sha256_file(path: &Path) -> Result<String> {
+    Ok(format!("{:x}", Sha256::digest(fs::read(path)?)))
+}
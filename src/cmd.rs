@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(author, about, version, arg_required_else_help(true))]
@@ -6,6 +6,15 @@ pub struct Args {
     /// Path to mihoro config file
     #[clap(short, long, default_value = "~/.config/mihoro.toml")]
     pub mihoro_config: String,
+    /// Keep mihoro.toml, mihomo, its config and systemd units under this directory
+    #[clap(long, global = true, env = "MIHORO_ROOT")]
+    pub root: Option<String>,
+    /// Keep them next to the mihoro executable instead, implied by a `mihoro-portable` file there
+    #[clap(long, global = true, conflicts_with = "root")]
+    pub portable: bool,
+    /// When to color the output
+    #[clap(long, value_enum, global = true, default_value_t = ColorMode::Auto)]
+    pub color: ColorMode,
     #[command(subcommand)]
     pub command: Option<Commands>,
 }
@@ -16,33 +25,338 @@ pub enum Commands {
     Setup,
     /// Update mihomo remote config and restart mihomo.service
     Update,
+    /// Download remote config to staging without applying it, the first step of update
+    Fetch,
+    /// Build mihomo config with overrides in staging from the staged (or current) remote config
+    Build,
+    /// Test the staged config with mihomo, swap it in and restart mihomo.service
+    Activate,
+    /// Print the config `apply` generates, with each value annotated by where it comes from
+    Inspect {
+        /// Only print this top-level field, e.g. `dns` or `rules`
+        field: Option<String>,
+    },
+    /// Query the generated config with a jq-like filter, e.g. `.proxy-groups[] | .name`
+    Query {
+        /// Paths such as `.dns.nameserver[0]` or `.proxies[].name`, piped into paths, `length`
+        /// or `keys`
+        filter: String,
+    },
     /// Update mihomo geodata
     UpdateGeodata,
     /// Apply mihomo config overrides and restart mihomo.service
     Apply,
     /// Start mihomo.service with systemctl
     Start,
-    /// Check mihomo.service status with systemctl
+    /// Check mihomo.service status and selected node of each group
     Status,
     /// Stop mihomo.service with systemctl
     Stop,
     /// Restart mihomo.service with systemctl
     Restart,
-    /// Check mihomo.service logs with journalctl
+    /// Check mihomo.service logs with journalctl, or mihomo.log of `mihoro run`
     #[clap(visible_alias("logs"))]
     Log,
+    /// Run and supervise mihomo in the foreground without systemd, e.g. in containers or WSL
+    Run {
+        /// Rotate mihomo.log once it exceeds this size in MiB
+        #[arg(long, default_value_t = 10)]
+        max_log_size: u64,
+        /// Also rotate mihomo.log once it is this many hours old
+        #[arg(long)]
+        max_log_age: Option<u64>,
+        /// Number of rotated logs kept
+        #[arg(long, default_value_t = 3)]
+        log_files: usize,
+    },
     /// Output proxy export commands
     Proxy {
         #[clap(subcommand)]
         proxy: Option<ProxyCommands>,
     },
+    /// Run a command with proxy environment variables set, e.g. `mihoro exec -- cargo build`
+    Exec {
+        /// Command and its arguments
+        #[arg(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Set or restore the system proxy of macOS or GNOME/KDE desktops
+    Sysproxy {
+        #[clap(subcommand)]
+        sysproxy: Option<SysproxyCommands>,
+    },
+    /// Generate and serve proxy auto-config (PAC) file
+    Pac {
+        #[clap(subcommand)]
+        pac: Option<PacCommands>,
+    },
+    /// Output managed policy JSON configuring a browser's proxy
+    BrowserPolicy {
+        #[arg(value_enum)]
+        browser: Browser,
+        /// Use a PAC file url instead of mihomo's proxy ports
+        #[arg(long)]
+        pac: Option<String>,
+        /// Use LAN ip address as proxy host, for other devices
+        #[arg(long)]
+        lan: bool,
+        /// Write the policy to path instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Share proxy details or config with LAN devices over http with a one-time token
+    Share {
+        #[arg(short, long, default_value_t = 7898)]
+        port: u16,
+        /// Share the generated mihomo config (without controller settings) instead
+        #[arg(long)]
+        config: bool,
+    },
+    /// Test delay of nodes in a proxy group concurrently, all nodes if no group is given
+    Ping {
+        /// Proxy group name
+        #[arg(short, long)]
+        group: Option<String>,
+        /// Number of nodes tested at once [default: `concurrency` of `[latency]`, or 16]
+        #[arg(short, long)]
+        concurrency: Option<usize>,
+        /// Url requested through each node [default: `test_url` of `[latency]`, or
+        /// https://www.gstatic.com/generate_204]
+        #[arg(short, long)]
+        url: Option<String>,
+        /// Timeout of each test in milliseconds [default: `timeout_ms` of `[latency]`, or 5000]
+        #[arg(short, long)]
+        timeout: Option<u64>,
+    },
+    /// Switch a selector group to a node
+    Select {
+        /// Proxy group name
+        #[arg(required_unless_present = "interactive")]
+        group: Option<String>,
+        /// Node (or group) name
+        #[arg(required_unless_present = "interactive")]
+        node: Option<String>,
+        /// Pick the group and node not given from a fuzzy-searchable list
+        #[arg(short, long)]
+        interactive: bool,
+    },
+    /// Switch a selector group to its fastest node after testing all of them
+    Autoselect {
+        /// Proxy group name
+        group: String,
+        /// Skip nodes whose name matches this regex
+        #[arg(short, long)]
+        exclude: Option<String>,
+        /// Number of nodes tested at once [default: `concurrency` of `[latency]`, or 16]
+        #[arg(short, long)]
+        concurrency: Option<usize>,
+        /// Url requested through each node [default: `test_url` of `[latency]`, or
+        /// https://www.gstatic.com/generate_204]
+        #[arg(short, long)]
+        url: Option<String>,
+        /// Timeout of each test in milliseconds [default: `timeout_ms` of `[latency]`, or 5000]
+        #[arg(short, long)]
+        timeout: Option<u64>,
+    },
+    /// Show hosts and processes with the most proxy traffic right now
+    Top {
+        /// Seconds to sample connections for
+        #[arg(short, long, default_value_t = 5)]
+        window: u64,
+        /// Number of hosts and processes shown
+        #[arg(short = 'n', long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// List active connections
+    Connections {
+        /// Show a continuously refreshing table to sort, filter and close connections
+        #[arg(short, long)]
+        live: bool,
+        /// Seconds between refreshes of the live table
+        #[arg(short, long, default_value_t = 1)]
+        refresh: u64,
+    },
+    /// Send a request to mihomo's external controller, authorized with the configured secret
+    Api {
+        /// Request method, e.g. GET, PUT or PATCH
+        method: String,
+        /// Path of the endpoint, e.g. /connections or /configs
+        path: String,
+        /// JSON body of the request
+        #[arg(short, long)]
+        data: Option<String>,
+    },
+    /// Show daily traffic through mihomo
+    Usage {
+        /// Show each day of the current month instead of the last 7 days
+        #[arg(long)]
+        month: bool,
+        /// Only record traffic of the running mihomo, run periodically by mihoro-usage.timer
+        #[arg(long)]
+        record: bool,
+    },
+    /// Test whether nodes actually relay UDP, all nodes if none is given
+    TestUdp {
+        /// Node name in the generated mihomo config
+        node: Option<String>,
+    },
+    /// Render QR code of a node share link or the subscription url
+    Qr {
+        #[clap(subcommand)]
+        qr: Option<QrCommands>,
+    },
+    /// Manage domain patterns excluded from fake-ip resolution
+    FakeIpFilter {
+        #[clap(subcommand)]
+        filter: Option<FakeIpFilterCommands>,
+    },
+    /// Manage nameservers resolving specific domains, e.g. corporate ones
+    NameserverPolicy {
+        #[clap(subcommand)]
+        policy: Option<NameserverPolicyCommands>,
+    },
+    /// Switch modes of mihomo's DNS
+    Dns {
+        #[clap(subcommand)]
+        dns: Option<DnsCommands>,
+    },
+    /// Manage local nodes imported from share links
+    Node {
+        #[clap(subcommand)]
+        node: Option<NodeCommands>,
+    },
+    /// Manage rulesets merged ahead of remote config rules
+    Rules {
+        #[clap(subcommand)]
+        rules: Option<RulesCommands>,
+    },
+    /// Look up domains in the local GeoSite database
+    Geosite {
+        #[clap(subcommand)]
+        geosite: Option<GeositeCommands>,
+    },
+    /// Manage persistent routing rules for domains and ips
+    Route {
+        #[clap(subcommand)]
+        route: Option<RouteCommands>,
+    },
+    /// Toggle the ad-blocking REJECT ruleset
+    Adblock {
+        #[clap(subcommand)]
+        adblock: Option<AdblockCommands>,
+    },
+    /// Set up or revert TPROXY transparent proxying with nftables
+    Transparent {
+        #[clap(subcommand)]
+        transparent: Option<TransparentCommands>,
+    },
+    /// Run a command with its traffic routed to a proxy group or node of `app_routes`
+    AppRoute {
+        /// Proxy group or node of an `app_routes` entry
+        #[arg(required_unless_present = "clear")]
+        policy: Option<String>,
+        /// Command and its arguments
+        #[arg(
+            required_unless_present = "clear",
+            trailing_var_arg = true,
+            allow_hyphen_values = true
+        )]
+        command: Vec<String>,
+        /// Revert the routing rules instead, with sudo unless running as root
+        #[arg(long, conflicts_with_all = ["policy", "command"])]
+        clear: bool,
+    },
+    /// Check for VPNs, other proxies and broken IPv6 clashing with mihomo, suggesting fixes
+    Doctor {
+        /// Apply fixes to `mihoro.toml` without asking
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Toggle TUN mode, handling capabilities and the system resolver
+    Tun {
+        #[clap(subcommand)]
+        tun: Option<TunCommands>,
+    },
+    /// Manage the secret of mihomo's external controller
+    Secret {
+        #[clap(subcommand)]
+        secret: Option<SecretCommands>,
+    },
+    /// Restart mihomo.service on network changes with a NetworkManager dispatcher script
+    NmDispatcher {
+        #[clap(subcommand)]
+        dispatcher: Option<NmDispatcherCommands>,
+    },
+    /// Run mihomo and notify systemd's watchdog while it is healthy, used by mihomo.service
+    Watchdog,
+    /// Switch to a backup profile while all nodes of the subscription are down, and back after
+    Failover,
+    /// Sync mihoro.toml, local fragments and patches across machines with a git repository
+    Sync {
+        #[clap(subcommand)]
+        sync: Option<SyncCommands>,
+    },
+    /// Share selected nodes and usage history with other machines over WebDAV or S3
+    StateSync {
+        #[clap(subcommand)]
+        state_sync: Option<StateSyncCommands>,
+    },
+    /// Export mihoro.toml, local fragments and patches as a tar.zst archive for another machine
+    Export {
+        /// Path of the archive, e.g. setup.tar.zst
+        path: String,
+        /// Include downloaded geodata
+        #[arg(long)]
+        geodata: bool,
+    },
+    /// Write a tarball for bug reports with versions, configs, logs and doctor output, redacted
+    DebugBundle {
+        /// Path of the tarball
+        #[arg(default_value = "mihoro-debug.tar.gz")]
+        path: String,
+    },
+    /// Import a setup exported by `mihoro export`, backing up replaced files
+    Import {
+        /// Path of the archive
+        path: String,
+    },
+    /// Remove download cache, old backups, quarantined and partial downloads
+    Clean,
     /// Uninstall and remove mihoro and config
-    Uninstall,
+    Uninstall {
+        /// Remove files without asking for confirmation
+        #[arg(short, long)]
+        yes: bool,
+        /// Also remove the local rules and proxies fragments
+        #[arg(long)]
+        purge: bool,
+    },
+    /// Print group or node names of the running mihomo, used by shell completions
+    #[command(hide = true)]
+    Complete {
+        #[clap(subcommand)]
+        complete: CompleteCommands,
+    },
     /// Generate shell completions for mihoro
     Completions {
         #[clap(subcommand)]
         shell: Option<ClapShell>,
     },
+    /// Plugin `mihoro-<name>` found on PATH, run with its arguments
+    #[command(external_subcommand)]
+    Plugin(Vec<String>),
+}
+
+#[derive(Subcommand)]
+pub enum CompleteCommands {
+    /// Names of proxy groups
+    Groups {
+        /// Only selector groups
+        #[arg(long)]
+        selector: bool,
+    },
+    /// Names of members of a proxy group, or of all nodes if no group is given
+    Nodes { group: Option<String> },
 }
 
 #[derive(Subcommand)]
@@ -56,6 +370,341 @@ pub enum ProxyCommands {
     Unset,
 }
 
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum PacCommands {
+    /// Output PAC file, or write it to a path
+    Generate {
+        /// Write PAC file to path instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Use LAN ip address as proxy host, for other devices
+        #[arg(long)]
+        lan: bool,
+    },
+    /// Serve PAC file over http at `/proxy.pac`
+    Serve {
+        #[arg(short, long, default_value_t = 7899)]
+        port: u16,
+        /// Listen on all interfaces and use LAN ip address as proxy host
+        #[arg(long)]
+        lan: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Browser {
+    /// Chrome and Chromium
+    Chrome,
+    Firefox,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum QrCommands {
+    /// Render QR code of a node's share link
+    Node {
+        /// Node name in the generated mihomo config
+        name: String,
+    },
+    /// Render QR code of the subscription url
+    Subscription,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum FakeIpFilterCommands {
+    /// Add patterns to `dns.fake-ip-filter` in mihoro config
+    Add {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// Remove patterns from `dns.fake-ip-filter` in mihoro config, not from the remote config
+    Remove {
+        #[arg(required = true)]
+        patterns: Vec<String>,
+    },
+    /// List patterns in `dns.fake-ip-filter` of mihoro config
+    List,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum NameserverPolicyCommands {
+    /// Resolve a domain and its subdomains with nameservers in `dns.nameserver-policy`
+    Set {
+        /// Domain such as `corp.example`, or a key of mihomo such as `geosite:cn`
+        domain: String,
+        /// Nameservers such as `10.0.0.53` or `https://dns.corp.example/dns-query`
+        #[arg(required = true)]
+        nameservers: Vec<String>,
+    },
+    /// Remove domains from `dns.nameserver-policy` in mihoro config
+    Remove {
+        #[arg(required = true)]
+        domains: Vec<String>,
+    },
+    /// List domains and nameservers in `dns.nameserver-policy` of mihoro config
+    List,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum NodeCommands {
+    /// Import nodes from ss://, vmess://, vless://, trojan:// or hysteria2:// share links
+    Import {
+        /// Share links, read from stdin if omitted
+        uris: Vec<String>,
+        /// Read share links from the clipboard
+        #[arg(short, long, conflicts_with = "uris")]
+        clipboard: bool,
+    },
+    /// Add a WireGuard node from a `wg-quick` config
+    AddWireguard {
+        /// Path to the WireGuard config, e.g. `/etc/wireguard/wg0.conf`
+        #[arg(short, long)]
+        config: String,
+        /// Node name [default: file name of the config without extension]
+        #[arg(short, long)]
+        name: Option<String>,
+    },
+    /// Add an SSH server as a node
+    AddSsh {
+        /// SSH endpoint as `user@host[:port]`
+        target: String,
+        /// Node name [default: the endpoint]
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Private key [default: first of `~/.ssh/id_ed25519`, `id_ecdsa` or `id_rsa`]
+        #[arg(short, long)]
+        identity: Option<String>,
+        /// Authenticate with a password instead of a private key
+        #[arg(short, long)]
+        password: Option<String>,
+    },
+    /// List imported nodes
+    List,
+    /// Remove imported nodes by name
+    Remove {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum RulesCommands {
+    /// Import a community ruleset preset (acl4ssr, loyalsoldier) or a ruleset url
+    Import {
+        /// Preset name or ruleset url
+        source: String,
+        /// Policy of rulesets imported from url
+        #[arg(short, long, default_value = "PROXY")]
+        policy: String,
+        /// Behavior of rulesets imported from url, `domain` for .mrs and `classical` otherwise
+        #[arg(short, long, value_enum)]
+        behavior: Option<RulesetBehavior>,
+    },
+    /// Convert a plaintext domain or ip cidr list to mihomo's binary .mrs format and add it
+    Convert {
+        /// Plaintext list, one domain or ip cidr per line
+        input: String,
+        /// Ruleset name, the file name of the list by default
+        #[arg(short, long)]
+        name: Option<String>,
+        #[arg(short, long, default_value = "PROXY")]
+        policy: String,
+        #[arg(short, long, value_enum, default_value_t = RulesetBehavior::Domain)]
+        behavior: RulesetBehavior,
+    },
+    /// List imported rulesets
+    List,
+    /// Remove imported rulesets by name
+    Remove {
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum RulesetBehavior {
+    Domain,
+    Ipcidr,
+    Classical,
+}
+
+impl RulesetBehavior {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RulesetBehavior::Domain => "domain",
+            RulesetBehavior::Ipcidr => "ipcidr",
+            RulesetBehavior::Classical => "classical",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum GeositeCommands {
+    /// Show GeoSite categories of a domain and `GEOSITE` rules of the config matching it
+    Lookup { domain: String },
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum RouteCommands {
+    /// Route a domain (and its subdomains) or ip cidr with a policy
+    Add {
+        /// Domain, ip or ip cidr
+        target: String,
+        #[arg(value_enum, ignore_case = true)]
+        policy: RoutePolicy,
+    },
+    /// Route a domain or ip cidr with a policy right away by reloading mihomo, until the config
+    /// is applied again
+    Now {
+        /// Domain, ip or ip cidr
+        target: String,
+        #[arg(value_enum, ignore_case = true)]
+        policy: RoutePolicy,
+    },
+    /// List routing rules
+    List,
+    /// Remove routing rules of domains or ips
+    Remove {
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum RoutePolicy {
+    #[value(name = "DIRECT")]
+    Direct,
+    #[value(name = "PROXY")]
+    Proxy,
+    #[value(name = "REJECT")]
+    Reject,
+}
+
+impl RoutePolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RoutePolicy::Direct => "DIRECT",
+            RoutePolicy::Proxy => "PROXY",
+            RoutePolicy::Reject => "REJECT",
+        }
+    }
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum AdblockCommands {
+    /// Reject ads with the ruleset from `adblock_url` ahead of all other rules
+    Enable,
+    /// Remove the ad-blocking ruleset
+    Disable,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum TransparentCommands {
+    /// Output nftables and iproute2 commands diverting traffic to `tproxy_port`, or apply them
+    Enable {
+        /// Apply the commands, with sudo unless running as root
+        #[arg(long)]
+        apply: bool,
+        /// Also proxy traffic of this machine, besides forwarded traffic
+        #[arg(long)]
+        local: bool,
+    },
+    /// Output commands reverting transparent proxying, or apply them
+    Disable {
+        /// Apply the commands, with sudo unless running as root
+        #[arg(long)]
+        apply: bool,
+    },
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum TunCommands {
+    /// Route all traffic through mihomo's TUN device and resolve domains with mihomo's DNS
+    Enable,
+    /// Turn TUN mode off and restore the system resolver
+    Disable,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum DnsCommands {
+    /// Switch `enhanced-mode` between fake-ip and redir-host, flush the fake-ip cache and reload
+    FakeIp {
+        #[arg(value_enum)]
+        state: Switch,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Switch {
+    On,
+    Off,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum SecretCommands {
+    /// Replace the secret with a random one and reload mihomo with it
+    Rotate,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum SyncCommands {
+    /// Clone the git repository to sync with
+    Init {
+        /// Url of the git repository, which may be empty
+        remote: String,
+    },
+    /// Commit local changes without secrets and push them
+    Push {
+        /// Commit message
+        #[arg(short, long)]
+        message: Option<String>,
+    },
+    /// Pull synced changes and write them locally, keeping local secrets
+    Pull,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum StateSyncCommands {
+    /// Upload selected nodes and this machine's usage history
+    Push,
+    /// Switch to shared selected nodes and download usage history of other machines
+    Pull,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum NmDispatcherCommands {
+    /// Install the dispatcher script, with sudo unless running as root
+    Enable,
+    /// Remove the dispatcher script
+    Disable,
+    /// Print the dispatcher script
+    Show,
+}
+
+#[derive(Subcommand)]
+#[command(arg_required_else_help(true))]
+pub enum SysproxyCommands {
+    /// Point the system proxy to mihomo, saving previous settings
+    On,
+    /// Restore the system proxy settings saved by `on`
+    Off,
+}
+
 #[derive(Subcommand)]
 #[command(arg_required_else_help(true))]
 pub enum ClapShell {
@@ -70,3 +719,10 @@ pub enum ClapShell {
     // #[command(about = "Generate elvish completions")]
     // Elvish,
 }
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
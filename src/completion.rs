@@ -0,0 +1,99 @@
+use crate::cmd::ClapShell;
+
+/// Bash completion of group and node names, queried from mihomo's controller with the hidden
+/// `mihoro complete` and falling back to the static completion.
+const BASH_DYNAMIC: &str = r#"
+# Dynamic completion of group and node names, queried from mihomo's controller
+_mihoro_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}" names
+    # Unescape the group name typed so far, e.g. `HK\ 01`
+    local group="${COMP_WORDS[2]//\\/}"
+    group="${group//[\"\']/}"
+    case "${COMP_WORDS[1]}:${COMP_CWORD}" in
+        select:2|autoselect:2) names="$(mihoro complete groups --selector 2>/dev/null)" ;;
+        select:3) names="$(mihoro complete nodes "$group" 2>/dev/null)" ;;
+        test-udp:2) names="$(mihoro complete nodes 2>/dev/null)" ;;
+        ping:*)
+            if [[ "$prev" == "-g" || "$prev" == "--group" ]]; then
+                names="$(mihoro complete groups 2>/dev/null)"
+            fi
+            ;;
+    esac
+    if [[ -z "$names" ]]; then
+        _mihoro "$@"
+        return
+    fi
+    local IFS=$'\n'
+    COMPREPLY=($(compgen -W "$names" -- "$cur"))
+    if (( ${#COMPREPLY[@]} )); then
+        COMPREPLY=($(printf '%q\n' "${COMPREPLY[@]}"))
+    fi
+}
+complete -F _mihoro_dynamic -o bashdefault -o default mihoro
+"#;
+
+/// Zsh counterpart of `BASH_DYNAMIC`.
+const ZSH_DYNAMIC: &str = r#"
+# Dynamic completion of group and node names, queried from mihomo's controller
+_mihoro_dynamic() {
+    local -a names
+    case "$words[2]:$CURRENT" in
+        select:3|autoselect:3) names=("${(@f)$(mihoro complete groups --selector 2>/dev/null)}") ;;
+        select:4) names=("${(@f)$(mihoro complete nodes "${(Q)words[3]}" 2>/dev/null)}") ;;
+        test-udp:3) names=("${(@f)$(mihoro complete nodes 2>/dev/null)}") ;;
+        ping:*)
+            if [[ "$words[CURRENT-1]" == (-g|--group) ]]; then
+                names=("${(@f)$(mihoro complete groups 2>/dev/null)}")
+            fi
+            ;;
+    esac
+    names=(${names:#})
+    if (( $#names )); then
+        compadd -a names
+    else
+        _mihoro "$@"
+    fi
+}
+"#;
+
+/// Tail of clap's zsh completion, registering `_mihoro` both when autoloaded from `fpath` and
+/// when sourced.
+const ZSH_REGISTRATION: &str = r#"if [ "$funcstack[1]" = "_mihoro" ]; then
+    _mihoro "$@"
+else
+    compdef _mihoro mihoro
+fi
+"#;
+
+const ZSH_DYNAMIC_REGISTRATION: &str = r#"compdef _mihoro_dynamic mihoro
+if [ "$funcstack[1]" = "_mihoro" ]; then
+    _mihoro_dynamic "$@"
+fi
+"#;
+
+/// Fish counterpart of `BASH_DYNAMIC`, adding to the conditions of clap's fish completion.
+const FISH_DYNAMIC: &str = r#"
+# Dynamic completion of group and node names, queried from mihomo's controller
+complete -c mihoro -n "__fish_mihoro_using_subcommand select; and test (count (commandline -opc)) -eq 2" -f -a "(mihoro complete groups --selector 2>/dev/null)"
+complete -c mihoro -n "__fish_mihoro_using_subcommand select; and test (count (commandline -opc)) -eq 3" -f -a "(mihoro complete nodes (commandline -opc)[3] 2>/dev/null)"
+complete -c mihoro -n "__fish_mihoro_using_subcommand autoselect; and test (count (commandline -opc)) -eq 2" -f -a "(mihoro complete groups --selector 2>/dev/null)"
+complete -c mihoro -n "__fish_mihoro_using_subcommand test-udp; and test (count (commandline -opc)) -eq 2" -f -a "(mihoro complete nodes 2>/dev/null)"
+complete -c mihoro -n "__fish_mihoro_using_subcommand ping" -s g -l group -r -f -a "(mihoro complete groups 2>/dev/null)"
+"#;
+
+/// Add dynamic completion of group and node names to the static completion generated by clap,
+/// so that e.g. `mihoro select <TAB>` offers the running mihomo's groups.
+pub fn with_dynamic_completions(shell: &ClapShell, script: String) -> String {
+    match shell {
+        ClapShell::Bash => script + BASH_DYNAMIC,
+        ClapShell::Zsh => {
+            // Dispatch to the dynamic completion where clap registers its own
+            let script = match script.strip_suffix(ZSH_REGISTRATION) {
+                Some(definitions) => definitions.to_string(),
+                None => script,
+            };
+            script + ZSH_DYNAMIC + "\n" + ZSH_DYNAMIC_REGISTRATION
+        }
+        ClapShell::Fish => script + FISH_DYNAMIC,
+    }
+}
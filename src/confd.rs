@@ -0,0 +1,75 @@
+use crate::nodes::entry_name;
+use crate::template::Template;
+use crate::utils::list_yaml_files;
+
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use serde_yaml::{Mapping, Value};
+
+/// Directory of partial configs, kept next to mihomo's `config.yaml`.
+pub const CONF_D_DIR: &str = "conf.d";
+
+/// Merge partial configs (`*.yaml`, `*.yml`) in `dir` into the config, in lexical order of file
+/// names:
+///
+/// * `rules` of all partials are prepended, in file order, ahead of existing rules.
+/// * List entries with a `name` (e.g. `proxies`, `proxy-groups`) replace entries of the same name,
+///   other list entries are appended, skipping duplicates.
+/// * Mappings (e.g. `dns`, `rule-providers`) are merged recursively, other values are replaced.
+pub fn merge_conf_d(dir: &Path, template: &Template, config: &mut Value) -> Result<()> {
+    let Some(config) = config.as_mapping_mut() else {
+        return Ok(());
+    };
+
+    let mut rules = Vec::new();
+    for partial_file in list_yaml_files(dir)? {
+        let partial: Mapping = template.read_yaml(&partial_file)?;
+        for (key, value) in partial {
+            if key.as_str() == Some("rules") {
+                let Value::Sequence(partial_rules) = value else {
+                    bail!("`rules` of {} must be a list", partial_file.display());
+                };
+                rules.extend(partial_rules);
+                continue;
+            }
+            merge_partial(config.entry(key).or_insert(Value::Null), value);
+        }
+    }
+
+    if !rules.is_empty() {
+        let existing_rules = config
+            .entry(Value::from("rules"))
+            .or_insert_with(|| Value::Sequence(Vec::new()));
+        if let Some(existing_rules) = existing_rules.as_sequence_mut() {
+            rules.append(existing_rules);
+        }
+        *existing_rules = Value::Sequence(rules);
+    }
+    Ok(())
+}
+
+fn merge_partial(target: &mut Value, partial: Value) {
+    match (target, partial) {
+        (Value::Mapping(target), Value::Mapping(partial)) => {
+            for (key, value) in partial {
+                merge_partial(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (Value::Sequence(target), Value::Sequence(partial)) => {
+            for entry in partial {
+                let existing = entry_name(&entry).and_then(|name| {
+                    target
+                        .iter()
+                        .position(|existing| entry_name(existing) == Some(name))
+                });
+                match existing {
+                    Some(index) => target[index] = entry,
+                    None if !target.contains(&entry) => target.push(entry),
+                    None => {}
+                }
+            }
+        }
+        (target, partial) => *target = partial,
+    }
+}
@@ -1,23 +1,104 @@
-use crate::utils::create_parent_dir;
+use crate::backup::{backup_file, BACKUPS_DIR, DEFAULT_BACKUP_RETENTION};
+use crate::confd::{merge_conf_d, CONF_D_DIR};
+use crate::i18n::Language;
+use crate::inspect::{Origin, Trace};
+use crate::migrate::{migrate_config, CONFIG_VERSION};
+use crate::nodes::{
+    merge_local_proxies, merge_static_proxies, process_nodes, LocalProxies, LOCAL_PROXIES_FILE,
+};
+use crate::patch::{apply_patches, PATCHES_DIR};
+use crate::rules::{merge_local_rules, LocalRules, LOCAL_RULES_FILE};
+use crate::script::{run_transform_script, TRANSFORM_SCRIPT};
+use crate::template::{expand_env, load_env_file, Template, ENV_FILE};
+use crate::theme::Theme;
+use crate::transparent::merge_app_route_listeners;
+use crate::utils::{create_parent_dir, expand_path, read_keyring};
 
-use std::{collections::HashMap, fs, path::Path};
+use std::{collections::BTreeMap, fs, path::Path, time::Duration};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use base64::{prelude::BASE64_STANDARD, Engine};
 use colored::Colorize;
 use serde::{Deserialize, Serialize};
+use toml_edit::{DocumentMut, InlineTable, Item, Table, TableLike, Value};
 
 /// `mihoro` configurations.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
-    pub remote_mihomo_binary_url: String,
-    pub remote_config_url: String,
+    pub config_version: Option<i64>,
+    pub remote_mihomo_binary_url: RemoteUrl,
+    pub remote_config_url: RemoteUrl,
     pub remote_config_encoding: EncodingMode,
+    pub subscription: Option<Subscription>,
     pub mihomo_binary_path: String,
     pub mihomo_config_root: String,
     pub user_systemd_root: String,
+    pub node_filter: Option<String>,
+    pub node_exclude: Option<String>,
+    pub node_countries: Option<Vec<String>>,
+    pub node_rename: Option<String>,
+    pub auto_groups: Option<Vec<AutoGroup>>,
+    pub static_proxies: Option<Vec<StaticProxy>>,
+    pub app_routes: Option<Vec<AppRoute>>,
+    pub adblock_url: Option<String>,
+    pub bypass: Option<Vec<String>>,
+    pub bypass_direct: Option<bool>,
+    pub merge: Option<BTreeMap<String, MergeStrategy>>,
+    pub max_download_rate: Option<u64>,
+    pub mirror_prefixes: Option<Vec<String>>,
+    pub mirror_timeout: Option<u64>,
+    pub minisign_public_key: Option<String>,
+    pub backup_retention: Option<usize>,
+    pub quota: Option<QuotaAlert>,
+    pub latency: Option<Latency>,
+    pub failover: Option<Failover>,
+    pub install_scope: Option<InstallScope>,
+    pub resolved: Option<ResolvedDns>,
+    pub watchdog_sec: Option<u64>,
+    pub memory_limit: Option<MemoryLimit>,
+    pub rollback_sec: Option<u64>,
+    pub auto_ports: Option<bool>,
+    pub state_sync: Option<StateSync>,
+    pub language: Option<Language>,
+    pub theme: Option<Theme>,
     pub mihomo_config: MihomoConfig,
 }
 
+/// Remote url of a download, either a single url or a list of mirrors tried in order.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RemoteUrl {
+    Single(String),
+    Mirrors(Vec<String>),
+}
+
+impl RemoteUrl {
+    pub fn urls(&self) -> &[String] {
+        match self {
+            RemoteUrl::Single(url) => std::slice::from_ref(url),
+            RemoteUrl::Mirrors(urls) => urls,
+        }
+    }
+
+    /// The first url, which identifies the remote, e.g. in mihomo's config.
+    pub fn primary(&self) -> &str {
+        self.urls().first().map(String::as_str).unwrap_or_default()
+    }
+}
+
+/// Scope mihomo is installed in.
+///
+/// * `user` - user service, binary and config under the home directory (default).
+/// * `system` - system service in `/etc/systemd/system`, binary in `/usr/local/bin` and config
+///   under `/etc/mihomo`. Commands writing them re-run themselves with sudo.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallScope {
+    #[default]
+    User,
+    System,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum EncodingMode {
     #[serde(alias = "plain", rename(serialize = "plain"))]
@@ -26,6 +107,354 @@ pub enum EncodingMode {
     Base64,
 }
 
+/// Request settings of the subscription downloader, for providers that serve different formats
+/// by `User-Agent` (e.g. `clash.meta` instead of a browser) or require extra headers.
+///
+/// `userinfo_url` is requested on updates for the traffic and expiry of the subscription, if the
+/// config response has no `subscription-userinfo` header. It is read from the same header of its
+/// response, or from the response body in the same format.
+///
+/// `backup_urls` are other sources of the same subscription (e.g. the provider's mirror domains),
+/// tried in order for an update once `remote_config_url` failed `retries` times in a row.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Subscription {
+    pub user_agent: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub auth: Option<SubscriptionAuth>,
+    pub tls: Option<SubscriptionTls>,
+    pub userinfo_url: Option<String>,
+    pub backup_urls: Option<Vec<String>>,
+    pub retries: Option<u32>,
+}
+
+/// Attempts of `remote_config_url` before falling back to `backup_urls` if `retries` is undefined.
+pub const DEFAULT_SUBSCRIPTION_RETRIES: u32 = 3;
+
+/// Pause between attempts of `remote_config_url`.
+pub const SUBSCRIPTION_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Thresholds of traffic quota and expiry warnings, printed by every command once crossed.
+///
+/// * `min_remaining_percent` - warn when the traffic left on the subscription, as reported by the
+///   provider's `subscription-userinfo` header, falls below this percentage of the total.
+/// * `monthly_limit_gib` - traffic allowance per month, compared to the traffic recorded by
+///   `mihoro usage`.
+/// * `monthly_warn_percent` - warn when this month's recorded traffic reaches this percentage of
+///   `monthly_limit_gib`, 80 by default.
+/// * `expiry_warn_days` - warn this many days before the subscription expires, 7 by default.
+/// * `notify` - also send a desktop notification with `notify-send`, at most once a day.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct QuotaAlert {
+    pub min_remaining_percent: Option<f64>,
+    pub monthly_limit_gib: Option<f64>,
+    pub monthly_warn_percent: Option<f64>,
+    pub expiry_warn_days: Option<i64>,
+    pub notify: Option<bool>,
+}
+
+/// Url requested through nodes by delay tests if `test_url` is undefined.
+pub const DEFAULT_LATENCY_TEST_URL: &str = "https://www.gstatic.com/generate_204";
+
+/// Timeout of delay tests in milliseconds if `timeout_ms` is undefined.
+pub const DEFAULT_LATENCY_TIMEOUT_MS: u64 = 5000;
+
+/// Number of nodes tested at once if `concurrency` is undefined.
+pub const DEFAULT_LATENCY_CONCURRENCY: usize = 16;
+
+/// Delay tests of nodes by `mihoro ping` and `mihoro autoselect`, unless overridden by their
+/// command line options.
+///
+/// * `test_url` - url requested through each node, `https://www.gstatic.com/generate_204` by
+///   default.
+/// * `timeout_ms` - timeout of each test in milliseconds, 5000 by default.
+/// * `concurrency` - number of nodes tested at once, 16 by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Latency {
+    pub test_url: Option<String>,
+    pub timeout_ms: Option<u64>,
+    pub concurrency: Option<usize>,
+}
+
+impl Latency {
+    /// These settings with the ones given on the command line taking precedence.
+    pub fn overridden(
+        &self,
+        test_url: &Option<String>,
+        timeout_ms: Option<u64>,
+        concurrency: Option<usize>,
+    ) -> Latency {
+        Latency {
+            test_url: test_url.clone().or_else(|| self.test_url.clone()),
+            timeout_ms: timeout_ms.or(self.timeout_ms),
+            concurrency: concurrency.or(self.concurrency),
+        }
+    }
+
+    pub fn test_url(&self) -> &str {
+        self.test_url.as_deref().unwrap_or(DEFAULT_LATENCY_TEST_URL)
+    }
+
+    pub fn timeout_ms(&self) -> u64 {
+        self.timeout_ms.unwrap_or(DEFAULT_LATENCY_TIMEOUT_MS)
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(DEFAULT_LATENCY_CONCURRENCY)
+    }
+}
+
+/// Switch of `mihoro failover` to another profile while every node of the subscription is down.
+///
+/// * `backup_profile` - path of the backup profile's mihoro config, sharing `mihomo_config_root`
+///   with this one, e.g. a copy with the `remote_config_url` of another provider.
+/// * `interval_sec` - seconds between health checks, 60 by default.
+/// * `failures` - consecutive failed health checks before switching, 3 by default.
+/// * `notify` - also send a desktop notification with `notify-send` on every switch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Failover {
+    pub backup_profile: String,
+    pub interval_sec: Option<u64>,
+    pub failures: Option<u32>,
+    pub notify: Option<bool>,
+}
+
+/// Proxy group or node that programs run by `mihoro app-route` are routed to, through a TPROXY
+/// listener on `port` generated in mihomo's `listeners`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AppRoute {
+    pub policy: String,
+    pub port: u16,
+}
+
+/// Restart of mihomo by `mihoro watchdog` or `mihoro run` once its memory in use, as reported by
+/// its external controller, grows past a ceiling.
+///
+/// * `max_mb` - memory ceiling in MiB.
+/// * `interval_sec` - seconds between checks, 60 by default.
+/// * `cooldown_sec` - minimum seconds between restarts, 600 by default, so that a ceiling set too
+///   low does not keep mihomo restarting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryLimit {
+    pub max_mb: u64,
+    pub interval_sec: Option<u64>,
+    pub cooldown_sec: Option<u64>,
+}
+
+/// Queries of systemd-resolved forwarded to mihomo's DNS listener (`dns.listen`) with
+/// `resolvectl` while mihomo.service runs.
+///
+/// * `domains` - domains (and their subdomains) resolved by mihomo, all domains if unset or empty.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ResolvedDns {
+    pub domains: Option<Vec<String>>,
+}
+
+/// Remote store of state shared with other machines by `mihoro state-sync`.
+///
+/// * `url` - WebDAV directory, or S3 bucket and prefix in path style such as
+///   `https://s3.us-east-1.amazonaws.com/bucket/mihoro/`.
+/// * `backend` - `webdav` (default) or `s3`.
+/// * `username` and `password` - WebDAV basic auth, or S3 access key id and secret access key.
+/// * `keyring` - name of a secret in the system keyring used as the password, looked up as in
+///   `SubscriptionAuth`.
+/// * `region` - S3 region, `us-east-1` by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateSync {
+    pub url: String,
+    pub backend: Option<StateSyncBackend>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub keyring: Option<String>,
+    pub region: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StateSyncBackend {
+    #[default]
+    Webdav,
+    S3,
+}
+
+impl StateSync {
+    /// Password (or S3 secret access key), inline or from the system keyring.
+    pub fn password(&self) -> Result<Option<String>> {
+        match (&self.password, &self.keyring) {
+            (Some(_), Some(_)) => bail!("`keyring` conflicts with `password`"),
+            (Some(password), None) => Ok(Some(password.clone())),
+            (None, Some(keyring)) => read_keyring(keyring).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// TLS settings of the subscription, for servers protected by mutual TLS.
+///
+/// * `client_cert` and `client_key` - PEM client certificate and PKCS#8 PEM key presented to the
+///   server.
+/// * `ca_cert` - PEM CA certificate the server is verified against, instead of system roots.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubscriptionTls {
+    pub client_cert: Option<String>,
+    pub client_key: Option<String>,
+    pub ca_cert: Option<String>,
+}
+
+/// Credentials of the subscription, sent as an `Authorization` header instead of being embedded in
+/// `remote_config_url`.
+///
+/// * `username` with `password` - basic auth.
+/// * `token` - bearer token.
+/// * `keyring` - name of a secret in the system keyring, looked up with `secret-tool` as
+///   `service mihoro account {keyring}`, used as the password if `username` is set or as the
+///   token otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SubscriptionAuth {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub token: Option<String>,
+    pub keyring: Option<String>,
+}
+
+impl SubscriptionAuth {
+    /// Value of the `Authorization` header.
+    pub fn authorization(&self) -> Result<String> {
+        let secret = |inline: &Option<String>| -> Result<Option<String>> {
+            match (inline, &self.keyring) {
+                (Some(_), Some(_)) => bail!("`keyring` conflicts with inline credentials"),
+                (Some(secret), None) => Ok(Some(secret.clone())),
+                (None, Some(keyring)) => read_keyring(keyring).map(Some),
+                (None, None) => Ok(None),
+            }
+        };
+        match (&self.username, &self.token) {
+            (Some(_), Some(_)) => bail!("`username` conflicts with `token`"),
+            (Some(username), None) => {
+                let password = secret(&self.password)?.unwrap_or_default();
+                let credentials = BASE64_STANDARD.encode(format!("{}:{}", username, password));
+                Ok(format!("Basic {}", credentials))
+            }
+            (None, token) => match secret(token)? {
+                Some(token) => Ok(format!("Bearer {}", token)),
+                None => bail!("one of `username`, `token` or `keyring` is required"),
+            },
+        }
+    }
+}
+
+/// How an override field in `mihoro.toml` is combined with the same field of the remote config.
+///
+/// * `replace` - use the local value, removing the field if undefined locally.
+/// * `keep-remote` - keep the remote value, falling back to the local value if undefined remotely.
+/// * `append` - merge mappings recursively with local values taking precedence, and append local
+///   list items to remote lists, skipping duplicates.
+/// * `deep-merge` - same as `append`, except that local lists replace remote lists.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MergeStrategy {
+    #[serde(alias = "replace", rename(serialize = "replace"))]
+    Replace,
+    #[serde(alias = "keep-remote", rename(serialize = "keep-remote"))]
+    KeepRemote,
+    #[serde(alias = "append", rename(serialize = "append"))]
+    Append,
+    #[serde(alias = "deep-merge", rename(serialize = "deep-merge"))]
+    DeepMerge,
+}
+
+/// Proxy group synthesized from subscription nodes during config generation.
+///
+/// * `filter` and `countries` select which nodes the group contains, defaulting to all nodes.
+/// * `by_country` generates one group per detected country instead, named `{name} | {country}`.
+/// * `include_direct` appends `DIRECT` as the last resort, e.g. for fallback groups.
+/// * `add_to` lists groups the generated groups are prepended to, defaulting to the first
+///   `select` group.
+/// * `interval` sets seconds between delay tests (300 by default), `tolerance` the delay in
+///   milliseconds a faster node must gain to be switched to (`url-test` only), and `lazy` skips
+///   tests while the group is unused.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AutoGroup {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub group_type: AutoGroupType,
+    pub filter: Option<String>,
+    pub countries: Option<Vec<String>>,
+    pub by_country: Option<bool>,
+    pub include_direct: Option<bool>,
+    pub add_to: Option<Vec<String>>,
+    pub url: Option<String>,
+    pub interval: Option<u32>,
+    pub tolerance: Option<u32>,
+    pub lazy: Option<bool>,
+}
+
+/// Self-hosted node always merged into the generated config next to the subscription's nodes,
+/// written as a mihomo proxy entry (`name`, `type`, `server`, `port`, ...).
+///
+/// * `add_to` lists groups the node is appended to, defaulting to the first `select` group.
+///
+/// Keys may be written in snake_case (`skip_cert_verify`, `alter_id`) like the rest of
+/// `mihoro.toml`. Fields required by `ss`, `trojan` and `vmess` nodes are checked, so simple
+/// setups can declare their nodes in TOML alone.
+///
+/// Like local nodes, static nodes are not filtered, renamed nor added to generated groups, and
+/// replace subscription nodes of the same name.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StaticProxy {
+    pub name: String,
+    pub add_to: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub fields: BTreeMap<String, serde_yaml::Value>,
+}
+
+impl StaticProxy {
+    /// The node as an entry of mihomo's `proxies`, without `add_to`.
+    pub fn node(&self) -> Result<serde_yaml::Value> {
+        let mut node = serde_yaml::Mapping::new();
+        node.insert("name".into(), self.name.clone().into());
+        for (key, value) in &self.fields {
+            let key = match key.as_str() {
+                "alter_id" => String::from("alterId"),
+                key => key.replace('_', "-"),
+            };
+            node.insert(key.into(), value.clone());
+        }
+
+        let node_type = node
+            .get("type")
+            .and_then(serde_yaml::Value::as_str)
+            .with_context(|| format!("static proxy `{}` has no `type`", self.name))?;
+        let required: &[&str] = match node_type {
+            "ss" => &["server", "port", "cipher", "password"],
+            "trojan" => &["server", "port", "password"],
+            "vmess" => &["server", "port", "uuid"],
+            _ => &[],
+        };
+        if let Some(missing) = required.iter().find(|key| !node.contains_key(**key)) {
+            bail!(
+                "static proxy `{}` of type `{}` has no `{}`",
+                self.name,
+                node_type,
+                missing
+            );
+        }
+        if node_type == "vmess" {
+            node.entry("alterId".into()).or_insert(0.into());
+            node.entry("cipher".into()).or_insert("auto".into());
+        }
+        Ok(serde_yaml::Value::Mapping(node))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum AutoGroupType {
+    #[serde(alias = "url-test", rename(serialize = "url-test"))]
+    UrlTest,
+    #[serde(alias = "fallback", rename(serialize = "fallback"))]
+    Fallback,
+    #[serde(alias = "load-balance", rename(serialize = "load-balance"))]
+    LoadBalance,
+}
+
 /// `mihomo` configurations (partial).
 ///
 /// Referenced from https://wiki.metacubex.one/config
@@ -34,18 +463,29 @@ pub struct MihomoConfig {
     pub port: u16,
     pub socks_port: u16,
     pub mixed_port: Option<u16>,
+    pub tproxy_port: Option<u16>,
+    pub routing_mark: Option<u32>,
     pub allow_lan: Option<bool>,
     pub bind_address: Option<String>,
     mode: MihomoMode,
     log_level: MihomoLogLevel,
-    ipv6: Option<bool>,
-    external_controller: Option<String>,
-    external_ui: Option<String>,
-    secret: Option<String>,
+    pub ipv6: Option<bool>,
+    pub external_controller: Option<String>,
+    pub external_controller_unix: Option<String>,
+    pub external_ui: Option<String>,
+    pub external_ui_url: Option<String>,
+    pub external_ui_name: Option<String>,
+    pub secret: Option<String>,
     pub geodata_mode: Option<bool>,
     pub geo_auto_update: Option<bool>,
     pub geo_update_interval: Option<u16>,
     pub geox_url: Option<GeoxUrl>,
+    pub profile: Option<MihomoProfile>,
+    pub hosts: Option<BTreeMap<String, String>>,
+    pub dns: Option<MihomoDns>,
+    pub tun: Option<MihomoTun>,
+    pub listeners: Option<Vec<MihomoListener>>,
+    pub tunnels: Option<Vec<MihomoTunnel>>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -74,59 +514,367 @@ pub enum MihomoLogLevel {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct GeoxUrl {
-    pub geoip: String,
-    pub geosite: String,
-    pub mmdb: String,
+    pub geoip: RemoteUrl,
+    pub geosite: RemoteUrl,
+    pub mmdb: RemoteUrl,
+}
+
+impl GeoxUrl {
+    /// Geodata urls without mirrors, as mihomo only accepts a single url for each.
+    fn primary(&self) -> GeoxUrl {
+        let primary = |url: &RemoteUrl| RemoteUrl::Single(url.primary().to_string());
+        GeoxUrl {
+            geoip: primary(&self.geoip),
+            geosite: primary(&self.geosite),
+            mmdb: primary(&self.mmdb),
+        }
+    }
+}
+
+/// `mihomo` cache persistence settings, kept across restarts in `cache.db`.
+///
+/// Reference: https://wiki.metacubex.one/config/general
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MihomoProfile {
+    #[serde(alias = "store-selected", rename(serialize = "store-selected"))]
+    pub store_selected: Option<bool>,
+    #[serde(alias = "store-fake-ip", rename(serialize = "store-fake-ip"))]
+    pub store_fake_ip: Option<bool>,
+}
+
+/// `mihomo` DNS settings, merged into the remote `dns` block instead of replacing it by default.
+///
+/// Only `fake-ip-filter` and `nameserver-policy` are managed by `mihoro` commands, other fields
+/// (e.g. `nameserver`) are passed through as is.
+///
+/// Reference: https://wiki.metacubex.one/config/dns
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MihomoDns {
+    #[serde(
+        alias = "fake-ip-filter",
+        rename(serialize = "fake-ip-filter"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub fake_ip_filter: Option<Vec<String>>,
+    #[serde(
+        alias = "nameserver-policy",
+        rename(serialize = "nameserver-policy"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub nameserver_policy: Option<BTreeMap<String, Nameservers>>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, toml::Value>,
+}
+
+impl MihomoDns {
+    /// The DNS settings as mihomo's `dns` block, with plain domains of `nameserver-policy` turned
+    /// into domain suffixes, e.g. `corp.example` into `+.corp.example`.
+    fn to_mapping(&self) -> Result<serde_yaml::Mapping> {
+        let serde_yaml::Value::Mapping(mut dns) = serde_yaml::to_value(self)? else {
+            bail!("failed to serialize `dns` overrides");
+        };
+        if let Some(policy) = &self.nameserver_policy {
+            let policy: serde_yaml::Mapping = policy
+                .iter()
+                .map(|(domain, nameservers)| {
+                    Ok((
+                        serde_yaml::Value::from(policy_domain(domain)),
+                        serde_yaml::to_value(nameservers)?,
+                    ))
+                })
+                .collect::<Result<_>>()?;
+            dns.insert(
+                "nameserver-policy".into(),
+                serde_yaml::Value::Mapping(policy),
+            );
+        }
+        Ok(dns)
+    }
+}
+
+/// Nameserver, or nameservers, of a `nameserver-policy` domain.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Nameservers {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl Nameservers {
+    pub fn list(&self) -> &[String] {
+        match self {
+            Nameservers::Single(nameserver) => std::slice::from_ref(nameserver),
+            Nameservers::Multiple(nameservers) => nameservers,
+        }
+    }
+}
+
+/// Key of mihomo's `nameserver-policy` for a domain of `mihoro.toml`: plain domains match the
+/// domain and its subdomains, while wildcards (`*.`, `+.`, `.`) and `geosite:` or `rule-set:`
+/// keys are kept as is.
+fn policy_domain(domain: &str) -> String {
+    if domain.contains(':') || domain.starts_with(['+', '*', '.']) {
+        domain.to_string()
+    } else {
+        format!("+.{}", domain)
+    }
+}
+
+/// `mihomo` TUN settings, merged into the remote `tun` block instead of replacing it by default.
+///
+/// Fields set by `mihoro tun enable` are listed, others (e.g. `device`, `mtu`) are passed through
+/// as is.
+///
+/// Reference: https://wiki.metacubex.one/config/inbound/tun
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MihomoTun {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stack: Option<String>,
+    #[serde(
+        alias = "auto-route",
+        rename(serialize = "auto-route"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub auto_route: Option<bool>,
+    #[serde(
+        alias = "auto-detect-interface",
+        rename(serialize = "auto-detect-interface"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub auto_detect_interface: Option<bool>,
+    #[serde(
+        alias = "dns-hijack",
+        rename(serialize = "dns-hijack"),
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dns_hijack: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, toml::Value>,
+}
+
+/// Inbound of mihomo's `listeners`, such as a mixed or SOCKS listener bound to the address of one
+/// network on hosts with several. Listeners of the remote config with the same `name` are replaced
+/// by default.
+///
+/// Fields other than those listed (e.g. `users`, `rule`) are passed through as is.
+///
+/// Reference: https://wiki.metacubex.one/config/inbound/listeners
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MihomoListener {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub listener_type: String,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub udp: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    #[serde(flatten)]
+    pub extra: BTreeMap<String, toml::Value>,
+}
+
+/// Port forward of mihomo's `tunnels`: connections to the local `address` are relayed to `target`
+/// through `proxy`, or by rules if unset. Tunnels of the remote config on the same `address` are
+/// replaced by default.
+///
+/// Reference: https://wiki.metacubex.one/config/inbound/tunnels
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MihomoTunnel {
+    pub network: Vec<String>,
+    pub address: String,
+    pub target: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Config {
     pub fn new() -> Config {
         Config {
-            remote_mihomo_binary_url: String::from(""),
-            remote_config_url: String::from(""),
+            config_version: Some(CONFIG_VERSION),
+            remote_mihomo_binary_url: RemoteUrl::Single(String::from("")),
+            remote_config_url: RemoteUrl::Single(String::from("")),
             remote_config_encoding: EncodingMode::Plain,
+            subscription: None,
             mihomo_binary_path: String::from("~/.local/bin/mihomo"),
             mihomo_config_root: String::from("~/.config/mihomo"),
             user_systemd_root: String::from("~/.config/systemd/user"),
+            node_filter: None,
+            node_exclude: None,
+            node_countries: None,
+            node_rename: None,
+            auto_groups: None,
+            static_proxies: None,
+            app_routes: None,
+            adblock_url: None,
+            bypass: Some(
+                [
+                    "localhost",
+                    "127.0.0.1",
+                    "::1",
+                    "10.0.0.0/8",
+                    "172.16.0.0/12",
+                    "192.168.0.0/16",
+                ]
+                .map(String::from)
+                .to_vec(),
+            ),
+            bypass_direct: Some(false),
+            merge: None,
+            max_download_rate: None,
+            mirror_prefixes: None,
+            mirror_timeout: None,
+            minisign_public_key: None,
+            backup_retention: None,
+            quota: None,
+            latency: None,
+            failover: None,
+            install_scope: None,
+            resolved: None,
+            watchdog_sec: None,
+            memory_limit: None,
+            rollback_sec: None,
+            auto_ports: None,
+            state_sync: None,
+            language: None,
+            theme: None,
 
             // https://wiki.metacubex.one/config/general
             mihomo_config: MihomoConfig {
                 port: 7891,
                 socks_port: 7892,
                 mixed_port: Some(7890),
+                tproxy_port: None,
+                routing_mark: None,
                 allow_lan: Some(false),
                 bind_address: Some(String::from("*")),
                 mode: MihomoMode::Rule,
                 log_level: MihomoLogLevel::Info,
                 ipv6: Some(true),
                 external_controller: Some(String::from("0.0.0.0:9090")),
+                external_controller_unix: None,
                 external_ui: Some(String::from("ui")),
+                external_ui_url: None,
+                external_ui_name: None,
                 secret: None,
                 geodata_mode: Some(false),
                 geo_auto_update: Some(true),
                 geo_update_interval: Some(24),
                 geox_url: Some(GeoxUrl {
-                    geoip: String::from(
+                    geoip: RemoteUrl::Single(String::from(
                         "https://cdn.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/geoip.dat",
-                    ),
-                    geosite: String::from(
+                    )),
+                    geosite: RemoteUrl::Single(String::from(
                         "https://cdn.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/geosite.dat",
-                    ),
-                    mmdb: String::from(
+                    )),
+                    mmdb: RemoteUrl::Single(String::from(
                         "https://cdn.jsdelivr.net/gh/MetaCubeX/meta-rules-dat@release/country.mmdb",
-                    ),
+                    )),
                 }),
+                profile: Some(MihomoProfile {
+                    store_selected: Some(true),
+                    store_fake_ip: Some(true),
+                }),
+                hosts: None,
+                dns: None,
+                tun: None,
+                listeners: None,
+                tunnels: None,
             },
         }
     }
 
-    /// Read raw config string from path and parse with crate toml.
+    /// Parse a config file, migrated to the current layout in memory if it is older.
     pub fn setup_from(path: &str) -> Result<Config> {
         let raw_config = fs::read_to_string(path)?;
+        let raw_config = migrate_config(&raw_config)?.unwrap_or(raw_config);
         let config: Config = toml::from_str(&raw_config)?;
         Ok(config)
     }
 
+    /// The config with `{{env.NAME}}` environment variables expanded in its values, as used at
+    /// runtime. Configs written back to `mihoro.toml` are kept unexpanded.
+    pub fn expanded(&self) -> Result<Config> {
+        fn expand_strings(value: &mut toml::Value) -> Result<()> {
+            match value {
+                toml::Value::String(text) => *text = expand_env(text)?,
+                toml::Value::Array(items) => items.iter_mut().try_for_each(expand_strings)?,
+                toml::Value::Table(table) => table
+                    .iter_mut()
+                    .try_for_each(|(_, value)| expand_strings(value))?,
+                _ => {}
+            }
+            Ok(())
+        }
+
+        let mut config = toml::Value::try_from(self)?;
+        expand_strings(&mut config)?;
+        Ok(config.try_into()?)
+    }
+
+    pub fn install_scope(&self) -> InstallScope {
+        self.install_scope.unwrap_or_default()
+    }
+
+    pub fn backup_retention(&self) -> usize {
+        self.backup_retention.unwrap_or(DEFAULT_BACKUP_RETENTION)
+    }
+
+    /// Move paths left at their user scope defaults to system locations, if installed system-wide.
+    pub fn apply_install_scope(&mut self) {
+        if self.install_scope() != InstallScope::System {
+            return;
+        }
+        let user_defaults = Config::new();
+        for (path, user_default, system_default) in [
+            (
+                &mut self.mihomo_binary_path,
+                user_defaults.mihomo_binary_path,
+                "/usr/local/bin/mihomo",
+            ),
+            (
+                &mut self.mihomo_config_root,
+                user_defaults.mihomo_config_root,
+                "/etc/mihomo",
+            ),
+            (
+                &mut self.user_systemd_root,
+                user_defaults.user_systemd_root,
+                "/etc/systemd/system",
+            ),
+        ] {
+            if *path == user_default {
+                *path = String::from(system_default);
+            }
+        }
+    }
+
+    /// Urls to download a remote from, in order: each configured url, followed by the url behind
+    /// each of `mirror_prefixes` (e.g. `https://ghproxy.net/`). Prefixes only apply to http(s) urls.
+    pub fn mirrors(&self, url: &RemoteUrl) -> Vec<String> {
+        let mut mirrors = Vec::new();
+        for url in url.urls() {
+            mirrors.push(url.clone());
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                continue;
+            }
+            for prefix in self.mirror_prefixes.iter().flatten() {
+                let prefix = prefix.trim_end_matches('/');
+                mirrors.push(format!("{}/{}", prefix, url));
+            }
+        }
+        mirrors
+    }
+
     pub fn write(&mut self, path: &Path) -> Result<()> {
         let serialized_config = toml::to_string(&self)?;
         fs::write(path, serialized_config)?;
@@ -152,13 +900,34 @@ pub fn parse_config(path: &str) -> Result<Config> {
         );
     }
 
+    // Load secrets referenced as `{{env.NAME}}` from the dotenv file next to the config
+    load_env_file(&config_path.with_file_name(ENV_FILE))?;
+
+    // Upgrade configs of older layouts in place, backed up to mihomo's config root
+    let raw_config = fs::read_to_string(path)?;
+    if let Some(migrated_config) = migrate_config(&raw_config)? {
+        let mut config = toml::from_str::<Config>(&migrated_config)?.expanded()?;
+        config.apply_install_scope();
+        let backups_dir = Path::new(&expand_path(&config.mihomo_config_root)).join(BACKUPS_DIR);
+        backup_file(path, &backups_dir, config.backup_retention())?;
+        fs::write(path, migrated_config)?;
+        println!(
+            "{} Migrated `{}` to config_version {}, previous config backed up to {}",
+            "mihoro:".cyan(),
+            path,
+            CONFIG_VERSION,
+            backups_dir.display().to_string().underline()
+        );
+    }
+
     // Parse config file
-    let config = Config::setup_from(path)?;
+    let mut config = Config::setup_from(path)?.expanded()?;
+    config.apply_install_scope();
     let required_urls = [
-        ("remote_config_url", &config.remote_config_url),
-        ("mihomo_binary_path", &config.mihomo_binary_path),
-        ("mihomo_config_root", &config.mihomo_config_root),
-        ("user_systemd_root", &config.user_systemd_root),
+        ("remote_config_url", config.remote_config_url.primary()),
+        ("mihomo_binary_path", config.mihomo_binary_path.as_str()),
+        ("mihomo_config_root", config.mihomo_config_root.as_str()),
+        ("user_systemd_root", config.user_systemd_root.as_str()),
     ];
 
     // Validate if urls are defined
@@ -171,6 +940,90 @@ pub fn parse_config(path: &str) -> Result<Config> {
     Ok(config)
 }
 
+/// Edit mihoro config at path in place, keeping comments, formatting and the order of the keys
+/// that `edit` leaves untouched. The edited config must still parse before it is written.
+pub fn edit_config(path: &str, edit: impl FnOnce(&mut DocumentMut) -> Result<()>) -> Result<()> {
+    let mut document: DocumentMut = fs::read_to_string(path)?
+        .parse()
+        .with_context(|| format!("failed to parse {}", path))?;
+    edit(&mut document)?;
+
+    let raw_config = document.to_string();
+    let migrated_config = migrate_config(&raw_config)?;
+    toml::from_str::<Config>(migrated_config.as_deref().unwrap_or(&raw_config))
+        .with_context(|| format!("edited config {} is invalid", path))?;
+    fs::write(path, raw_config)?;
+    Ok(())
+}
+
+/// The table at a path of keys in a config document, created if missing.
+pub fn table_mut<'a>(
+    document: &'a mut DocumentMut,
+    path: &[&str],
+) -> Result<&'a mut dyn TableLike> {
+    let not_a_table = || format!("`{}` of mihoro config is not a table", path.join("."));
+    let mut item = document.as_item_mut();
+    for key in path {
+        let table = if item.is_inline_table() {
+            Item::Value(Value::InlineTable(InlineTable::new()))
+        } else {
+            // Implicit tables are left out while empty, e.g. `[a]` of `[a.b]`
+            let mut table = Table::new();
+            table.set_implicit(true);
+            Item::Table(table)
+        };
+        item = item
+            .as_table_like_mut()
+            .with_context(not_a_table)?
+            .entry(key)
+            .or_insert(table);
+    }
+    item.as_table_like_mut().with_context(not_a_table)
+}
+
+/// The key of a field accepted under several names in a table, the first of them present or else
+/// the first of them.
+pub fn field_key<'a>(table: &dyn TableLike, names: &[&'a str]) -> &'a str {
+    names
+        .iter()
+        .find(|name| table.contains_key(name))
+        .unwrap_or(&names[0])
+}
+
+/// Set a key of a table to a value, keeping comments on the line of the previous value.
+pub fn set_value(table: &mut dyn TableLike, key: &str, new: impl Into<Value>) {
+    let mut new = new.into();
+    match table.get_mut(key) {
+        Some(Item::Value(previous)) => {
+            *new.decor_mut() = previous.decor().clone();
+            *previous = new;
+        }
+        Some(previous) => *previous = Item::Value(new),
+        None => {
+            table.insert(key, Item::Value(new));
+        }
+    }
+}
+
+/// Set a field accepted under several names to a value, unless it is set already.
+pub fn set_default(table: &mut dyn TableLike, names: &[&str], default: impl Into<Value>) {
+    if !names.iter().any(|name| table.contains_key(name)) {
+        set_value(table, names[0], default);
+    }
+}
+
+/// Check that a downloaded remote config is a YAML mapping with nodes, i.e. a `proxies` or
+/// `proxy-providers` section, before it replaces the current one.
+pub fn validate_remote_config(path: &str) -> Result<()> {
+    let raw_remote_yaml = fs::read_to_string(path).context("not a text file")?;
+    let remote_yaml: serde_yaml::Mapping =
+        serde_yaml::from_str(&raw_remote_yaml).context("not a YAML mapping")?;
+    if !remote_yaml.contains_key("proxies") && !remote_yaml.contains_key("proxy-providers") {
+        bail!("no `proxies` or `proxy-providers` section");
+    }
+    Ok(())
+}
+
 /// `mihomoYamlConfig` is defined to support serde serialization and deserialization of arbitrary
 /// mihomo `config.yaml`, with support for fields defined in `mihomoConfig` for overrides and also
 /// extra fields that are not managed by `mihoro` by design (namely `proxies`, `proxy-groups`,
@@ -185,6 +1038,12 @@ pub struct MihomoYamlConfig {
     #[serde(rename = "mixed-port", skip_serializing_if = "Option::is_none")]
     mixed_port: Option<u16>,
 
+    #[serde(rename = "tproxy-port", skip_serializing_if = "Option::is_none")]
+    tproxy_port: Option<u16>,
+
+    #[serde(rename = "routing-mark", skip_serializing_if = "Option::is_none")]
+    routing_mark: Option<u32>,
+
     #[serde(rename = "allow-lan", skip_serializing_if = "Option::is_none")]
     allow_lan: Option<bool>,
 
@@ -205,9 +1064,21 @@ pub struct MihomoYamlConfig {
     )]
     external_controller: Option<String>,
 
+    #[serde(
+        rename = "external-controller-unix",
+        skip_serializing_if = "Option::is_none"
+    )]
+    external_controller_unix: Option<String>,
+
     #[serde(rename = "external-ui", skip_serializing_if = "Option::is_none")]
     external_ui: Option<String>,
 
+    #[serde(rename = "external-ui-url", skip_serializing_if = "Option::is_none")]
+    external_ui_url: Option<String>,
+
+    #[serde(rename = "external-ui-name", skip_serializing_if = "Option::is_none")]
+    external_ui_name: Option<String>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     secret: Option<String>,
 
@@ -226,41 +1097,397 @@ pub struct MihomoYamlConfig {
     #[serde(rename = "geox-url", skip_serializing_if = "Option::is_none")]
     geox_url: Option<GeoxUrl>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<MihomoProfile>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hosts: Option<BTreeMap<String, serde_yaml::Value>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dns: Option<serde_yaml::Mapping>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tun: Option<serde_yaml::Mapping>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    listeners: Option<Vec<serde_yaml::Value>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tunnels: Option<Vec<serde_yaml::Value>>,
+
+    // Sorted, so that rebuilding an unchanged config gives identical output
     #[serde(flatten)]
-    extra: HashMap<String, serde_yaml::Value>,
+    extra: BTreeMap<String, serde_yaml::Value>,
 }
 
-/// Apply config overrides to mihomo's `config.yaml`.
-///
-/// Only a subset of mihomo's config fields are supported, as defined in `mihomoConfig`.
+/// Fields of mihomo's `config.yaml` overridden by `mihoro.toml`, as defined in `MihomoYamlConfig`.
+const MANAGED_FIELDS: &[&str] = &[
+    "port",
+    "socks-port",
+    "mixed-port",
+    "tproxy-port",
+    "routing-mark",
+    "allow-lan",
+    "bind-address",
+    "mode",
+    "log-level",
+    "ipv6",
+    "external-controller",
+    "external-controller-unix",
+    "external-ui",
+    "external-ui-url",
+    "external-ui-name",
+    "secret",
+    "geodata-mode",
+    "geo-auto-update",
+    "geo-update-interval",
+    "geox-url",
+    "profile",
+    "hosts",
+    "dns",
+    "tun",
+    "listeners",
+    "tunnels",
+];
+
+/// Merge strategy of a managed field, from `merge` in `mihoro.toml` or the field's default.
+fn merge_strategy(config: &Config, field: &str) -> MergeStrategy {
+    let configured = config
+        .merge
+        .iter()
+        .flatten()
+        .find_map(|(name, strategy)| (name.replace('_', "-") == field).then_some(*strategy));
+    match (configured, field) {
+        (Some(strategy), _) => strategy,
+        (None, "hosts" | "dns" | "tun" | "listeners" | "tunnels") => MergeStrategy::Append,
+        (None, _) => MergeStrategy::Replace,
+    }
+}
+
+/// Merge a local value into a remote value, recursing into mappings. Lists are appended to if
+/// `append`, replaced otherwise.
+fn merge_value(remote: &mut serde_yaml::Value, local: serde_yaml::Value, append: bool) {
+    match (remote, local) {
+        (serde_yaml::Value::Mapping(remote), serde_yaml::Value::Mapping(local)) => {
+            for (key, local_value) in local {
+                match remote.get_mut(&key) {
+                    Some(remote_value) => merge_value(remote_value, local_value, append),
+                    None => {
+                        remote.insert(key, local_value);
+                    }
+                }
+            }
+        }
+        (serde_yaml::Value::Sequence(remote), serde_yaml::Value::Sequence(local)) if append => {
+            for value in local {
+                if !remote.contains(&value) {
+                    remote.push(value);
+                }
+            }
+        }
+        (remote, local) => *remote = local,
+    }
+}
+
+/// Merge local entries of a list into remote ones, replacing remote entries of the same `key`.
+fn merge_entries(
+    remote: &mut serde_yaml::Value,
+    local: serde_yaml::Value,
+    key: fn(&serde_yaml::Value) -> Option<String>,
+) {
+    match (remote, local) {
+        (serde_yaml::Value::Sequence(remote), serde_yaml::Value::Sequence(local)) => {
+            remote.retain(|entry| {
+                let entry_key = key(entry);
+                entry_key.is_none() || !local.iter().any(|local| key(local) == entry_key)
+            });
+            remote.extend(local);
+        }
+        (remote, local) => *remote = local,
+    }
+}
+
+/// Name of a listener of mihomo's `listeners`.
+fn listener_key(listener: &serde_yaml::Value) -> Option<String> {
+    listener.get("name")?.as_str().map(String::from)
+}
+
+/// Local address of a tunnel of mihomo's `tunnels`, either a mapping or in the short form
+/// `tcp/udp,127.0.0.1:6553,114.114.114.114:53,proxy`.
+fn tunnel_key(tunnel: &serde_yaml::Value) -> Option<String> {
+    match tunnel {
+        serde_yaml::Value::String(tunnel) => tunnel
+            .split(',')
+            .nth(1)
+            .map(|address| address.trim().to_string()),
+        tunnel => tunnel.get("address")?.as_str().map(String::from),
+    }
+}
+
+/// Apply config overrides to the remote config at `source`, writing mihomo's `config.yaml` to
+/// `path`.
 ///
-/// Rules:
-/// * Fields defined in `mihoro.toml` will override the downloaded remote `config.yaml`.
-/// * Fields undefined will be removed from the downloaded `config.yaml`.
+/// Only a subset of mihomo's config fields are supported, as defined in `mihomoConfig`. Each field
+/// is combined with the remote config by its strategy in `merge`:
+/// * `hosts` and `dns` default to `append`, i.e. local hosts take precedence over remote ones and
+///   `dns.fake-ip-filter` patterns are appended to the remote list.
+/// * `listeners` and `tunnels` default to `append` too, local listeners replacing remote ones of
+///   the same name, and local tunnels remote ones on the same address.
+/// * Other fields default to `replace`, i.e. fields defined in `mihoro.toml` override the remote
+///   `config.yaml` and fields undefined are removed from it.
 /// * Fields not supported by `mihoro` will be kept as is.
-pub fn apply_mihomo_override(path: &str, override_config: &MihomoConfig) -> Result<()> {
-    let raw_mihomo_yaml = fs::read_to_string(path)?;
-    let mut mihomo_yaml: MihomoYamlConfig = serde_yaml::from_str(&raw_mihomo_yaml)?;
-
-    // Apply config overrides
-    mihomo_yaml.port = Some(override_config.port);
-    mihomo_yaml.socks_port = Some(override_config.socks_port);
-    mihomo_yaml.mixed_port = override_config.mixed_port;
-    mihomo_yaml.allow_lan = override_config.allow_lan;
-    mihomo_yaml.bind_address = override_config.bind_address.clone();
-    mihomo_yaml.mode = Some(override_config.mode.clone());
-    mihomo_yaml.log_level = Some(override_config.log_level.clone());
-    mihomo_yaml.ipv6 = override_config.ipv6;
-    mihomo_yaml.external_controller = override_config.external_controller.clone();
-    mihomo_yaml.external_ui = override_config.external_ui.clone();
-    mihomo_yaml.secret = override_config.secret.clone();
-    mihomo_yaml.geodata_mode = override_config.geodata_mode;
-    mihomo_yaml.geo_auto_update = override_config.geo_auto_update;
-    mihomo_yaml.geo_update_interval = override_config.geo_update_interval;
-    mihomo_yaml.geox_url = override_config.geox_url.clone();
+///
+/// Nodes are filtered by `node_filter`, `node_exclude` and `node_countries`, deduplicated, and
+/// renamed with `node_rename` before writing. Local nodes in `proxies.local.yaml` next to `path`
+/// are added as is. Local rules in `rules.local.yaml`, and `bypass` as `DIRECT` rules if
+/// `bypass_direct` is set, are merged ahead of the remote rules. Partial configs in `conf.d/` are
+/// merged next. Finally, patch files in `patches/` are applied to the result, followed by the
+/// `transform.rhai` script.
+///
+/// Template variables in local fragments, partial configs and patch files are expanded, see `Template`.
+pub fn apply_mihomo_override(
+    source: &str,
+    path: &str,
+    config: &Config,
+    profile: &str,
+) -> Result<()> {
+    let config_root = Path::new(path).parent().unwrap_or(Path::new("."));
+    let mihomo_yaml = build_mihomo_config(source, config_root, config, profile)?;
 
     // Write to file
     let serialized_mihomo_yaml = serde_yaml::to_string(&mihomo_yaml)?;
     fs::write(path, serialized_mihomo_yaml)?;
     Ok(())
 }
+
+/// mihomo's config built from the remote config at `source` as by `apply_mihomo_override`, with
+/// local fragments, partial configs and patches read from `config_root`.
+pub fn build_mihomo_config(
+    source: &str,
+    config_root: &Path,
+    config: &Config,
+    profile: &str,
+) -> Result<serde_yaml::Value> {
+    trace_mihomo_config(source, config_root, config, profile, None)
+}
+
+/// Build mihomo's config as `build_mihomo_config`, recording it after each step in `trace`.
+pub fn trace_mihomo_config(
+    source: &str,
+    config_root: &Path,
+    config: &Config,
+    profile: &str,
+    mut trace: Option<&mut Trace>,
+) -> Result<serde_yaml::Value> {
+    for field in config.merge.iter().flatten().map(|(field, _)| field) {
+        if !MANAGED_FIELDS.contains(&field.replace('_', "-").as_str()) {
+            bail!("`merge` field `{}` is not overridden by mihoro", field);
+        }
+    }
+    let listeners = config
+        .mihomo_config
+        .listeners
+        .as_deref()
+        .unwrap_or_default();
+    for (index, listener) in listeners.iter().enumerate() {
+        if listeners[..index]
+            .iter()
+            .any(|other| other.name == listener.name)
+        {
+            bail!("duplicate listener `{}` in `mihomo_config`", listener.name);
+        }
+    }
+    let tunnels = config.mihomo_config.tunnels.as_deref().unwrap_or_default();
+    for (index, tunnel) in tunnels.iter().enumerate() {
+        if tunnels[..index]
+            .iter()
+            .any(|other| other.address == tunnel.address)
+        {
+            bail!(
+                "duplicate tunnel on `{}` in `mihomo_config`",
+                tunnel.address
+            );
+        }
+    }
+
+    let raw_mihomo_yaml = fs::read_to_string(source)?;
+    let mut merged_yaml: serde_yaml::Mapping = serde_yaml::from_str(&raw_mihomo_yaml)?;
+    record(&mut trace, Origin::Remote, &merged_yaml)?;
+    let override_config = &config.mihomo_config;
+
+    // Collect config overrides
+    let override_yaml = MihomoYamlConfig {
+        port: Some(override_config.port),
+        socks_port: Some(override_config.socks_port),
+        mixed_port: override_config.mixed_port,
+        tproxy_port: override_config.tproxy_port,
+        routing_mark: override_config.routing_mark,
+        allow_lan: override_config.allow_lan,
+        bind_address: override_config.bind_address.clone(),
+        mode: Some(override_config.mode.clone()),
+        log_level: Some(override_config.log_level.clone()),
+        ipv6: override_config.ipv6,
+        external_controller: override_config.external_controller.clone(),
+        external_controller_unix: override_config.external_controller_unix.clone(),
+        external_ui: override_config.external_ui.clone(),
+        external_ui_url: override_config.external_ui_url.clone(),
+        external_ui_name: override_config.external_ui_name.clone(),
+        secret: override_config.secret.clone(),
+        geodata_mode: override_config.geodata_mode,
+        geo_auto_update: override_config.geo_auto_update,
+        geo_update_interval: override_config.geo_update_interval,
+        geox_url: override_config.geox_url.as_ref().map(GeoxUrl::primary),
+        profile: override_config.profile.clone(),
+        hosts: override_config.hosts.as_ref().map(|hosts| {
+            hosts
+                .iter()
+                .map(|(domain, ip)| (domain.clone(), serde_yaml::Value::from(ip.as_str())))
+                .collect()
+        }),
+        dns: override_config
+            .dns
+            .as_ref()
+            .map(MihomoDns::to_mapping)
+            .transpose()?,
+        tun: override_config
+            .tun
+            .as_ref()
+            .map(serde_yaml::to_value)
+            .transpose()?
+            .and_then(|tun| tun.as_mapping().cloned()),
+        listeners: override_config
+            .listeners
+            .as_ref()
+            .map(|listeners| {
+                listeners
+                    .iter()
+                    .map(serde_yaml::to_value)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?,
+        tunnels: override_config
+            .tunnels
+            .as_ref()
+            .map(|tunnels| {
+                tunnels
+                    .iter()
+                    .map(serde_yaml::to_value)
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .transpose()?,
+        extra: BTreeMap::new(),
+    };
+    let serde_yaml::Value::Mapping(mut override_yaml) = serde_yaml::to_value(&override_yaml)?
+    else {
+        bail!("failed to serialize config overrides");
+    };
+
+    // Apply config overrides by merge strategy
+    for field in MANAGED_FIELDS {
+        let local = override_yaml.remove(*field);
+        let remote = merged_yaml.get_mut(*field);
+        match (merge_strategy(config, field), remote, local) {
+            (MergeStrategy::Replace, _, Some(local)) => {
+                merged_yaml.insert(serde_yaml::Value::from(*field), local);
+            }
+            (MergeStrategy::Replace, _, None) => {
+                merged_yaml.remove(*field);
+            }
+            (MergeStrategy::KeepRemote, Some(_), _) | (_, _, None) => {}
+            (MergeStrategy::Append | MergeStrategy::DeepMerge, Some(remote), Some(local))
+                if matches!(*field, "listeners" | "tunnels") =>
+            {
+                let key = if *field == "listeners" {
+                    listener_key
+                } else {
+                    tunnel_key
+                };
+                merge_entries(remote, local, key);
+            }
+            (strategy, Some(remote), Some(local)) => {
+                merge_value(remote, local, strategy == MergeStrategy::Append);
+            }
+            (_, None, Some(local)) => {
+                merged_yaml.insert(serde_yaml::Value::from(*field), local);
+            }
+        }
+    }
+    let mut mihomo_yaml: MihomoYamlConfig =
+        serde_yaml::from_value(serde_yaml::Value::Mapping(merged_yaml))?;
+    merge_app_route_listeners(
+        &mut mihomo_yaml.listeners,
+        config.app_routes.as_deref().unwrap_or_default(),
+    );
+    record(&mut trace, Origin::Override, &mihomo_yaml)?;
+
+    // Post-process nodes
+    process_nodes(&mut mihomo_yaml.extra, config)?;
+    record(&mut trace, Origin::Nodes, &mihomo_yaml)?;
+
+    // Merge static nodes of the config and the local proxies fragment
+    merge_static_proxies(
+        &mut mihomo_yaml.extra,
+        config.static_proxies.as_deref().unwrap_or_default(),
+    )?;
+    record(&mut trace, Origin::StaticProxies, &mihomo_yaml)?;
+    let template = Template::new(config, profile, config_root);
+    let local_proxies = LocalProxies::load_expanded(
+        &config_root.join(LOCAL_PROXIES_FILE).to_string_lossy(),
+        &template,
+    )?;
+    merge_local_proxies(&mut mihomo_yaml.extra, &local_proxies);
+
+    // Merge local rules fragment
+    let mut local_rules = LocalRules::load_expanded(
+        &config_root.join(LOCAL_RULES_FILE).to_string_lossy(),
+        &template,
+    )?;
+    if config.bypass_direct.unwrap_or(false) {
+        for target in config.bypass.iter().flatten() {
+            local_rules.add_bypass(target);
+        }
+    }
+    merge_local_rules(&mut mihomo_yaml.extra, &local_rules);
+    record(&mut trace, Origin::LocalFragment, &mihomo_yaml)?;
+
+    // Merge partial configs, then apply user patches
+    let mut mihomo_yaml = serde_yaml::to_value(&mihomo_yaml)?;
+    merge_conf_d(&config_root.join(CONF_D_DIR), &template, &mut mihomo_yaml)?;
+    record(&mut trace, Origin::ConfD, &mihomo_yaml)?;
+    apply_patches(&config_root.join(PATCHES_DIR), &template, &mut mihomo_yaml)?;
+    record(&mut trace, Origin::Patch, &mihomo_yaml)?;
+    run_transform_script(&config_root.join(TRANSFORM_SCRIPT), &mut mihomo_yaml)?;
+    record(&mut trace, Origin::Script, &mihomo_yaml)?;
+    Ok(mihomo_yaml)
+}
+
+/// Record `config` in `trace`, if tracing.
+fn record(trace: &mut Option<&mut Trace>, origin: Origin, config: &impl Serialize) -> Result<()> {
+    match trace {
+        Some(trace) => trace.record(origin, config),
+        None => Ok(()),
+    }
+}
+
+/// Fields of mihomo's `config.yaml` that grant control over the running instance, stripped when
+/// sharing the config with other devices.
+const CONTROLLER_FIELDS: &[&str] = &[
+    "secret",
+    "external-controller",
+    "external-controller-tls",
+    "external-controller-unix",
+    "external-ui",
+    "external-ui-name",
+    "external-ui-url",
+    "profile",
+];
+
+/// Read mihomo's `config.yaml` with controller access fields removed, for sharing.
+pub fn sanitized_mihomo_config(path: &str) -> Result<String> {
+    let raw_mihomo_yaml = fs::read_to_string(path)?;
+    let mut mihomo_yaml: serde_yaml::Mapping = serde_yaml::from_str(&raw_mihomo_yaml)?;
+    for field in CONTROLLER_FIELDS {
+        mihomo_yaml.remove(*field);
+    }
+    Ok(serde_yaml::to_string(&mihomo_yaml)?)
+}
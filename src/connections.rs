@@ -0,0 +1,397 @@
+use crate::api::{Connection, MihomoApi};
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::time::Duration;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use colored::Colorize;
+use crossterm::{
+    cursor,
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use futures_util::StreamExt;
+use indicatif::HumanBytes;
+use truncatable::Truncatable;
+
+/// Key the connection table is sorted by, highest first.
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Speed,
+    Total,
+    Duration,
+}
+
+impl SortKey {
+    fn next(self) -> SortKey {
+        match self {
+            SortKey::Speed => SortKey::Total,
+            SortKey::Total => SortKey::Duration,
+            SortKey::Duration => SortKey::Speed,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SortKey::Speed => "speed",
+            SortKey::Total => "total",
+            SortKey::Duration => "duration",
+        }
+    }
+}
+
+/// Connection with its transfer speed since the previous refresh.
+struct Row {
+    connection: Connection,
+    upload_speed: u64,
+    download_speed: u64,
+    duration: Duration,
+}
+
+impl Row {
+    fn total(&self) -> u64 {
+        self.connection.upload + self.connection.download
+    }
+
+    /// Whether the host, ip, process, chain or rule of the connection contains `filter`,
+    /// ignoring case.
+    fn matches(&self, filter: &str) -> bool {
+        let filter = filter.to_lowercase();
+        let metadata = &self.connection.metadata;
+        [
+            &metadata.host,
+            &metadata.destination_ip,
+            &metadata.process,
+            &self.connection.rule,
+        ]
+        .into_iter()
+        .chain(&self.connection.chains)
+        .any(|field| field.to_lowercase().contains(&filter))
+    }
+}
+
+/// State of the live view between refreshes.
+struct LiveView {
+    rows: Vec<Row>,
+    /// Upload and download totals of each connection at the previous refresh
+    previous: HashMap<String, (u64, u64)>,
+    sort_key: SortKey,
+    filter: String,
+    /// Whether keys are typed into the filter
+    editing_filter: bool,
+    /// Index of the highlighted row among the shown rows
+    selected: usize,
+    /// Last error of the API, shown in the status line
+    error: Option<String>,
+}
+
+impl LiveView {
+    fn update(&mut self, connections: Vec<Connection>, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64().max(0.001);
+        let now = Utc::now();
+        let mut previous = HashMap::new();
+        self.rows = connections
+            .into_iter()
+            .map(|connection| {
+                let (upload, download) = self
+                    .previous
+                    .get(&connection.id)
+                    .copied()
+                    .unwrap_or((connection.upload, connection.download));
+                previous.insert(
+                    connection.id.clone(),
+                    (connection.upload, connection.download),
+                );
+                Row {
+                    upload_speed: (connection.upload.saturating_sub(upload) as f64 / seconds)
+                        as u64,
+                    download_speed: (connection.download.saturating_sub(download) as f64 / seconds)
+                        as u64,
+                    duration: connection_duration(&connection, now),
+                    connection,
+                }
+            })
+            .collect();
+        self.previous = previous;
+        self.sort();
+    }
+
+    fn sort(&mut self) {
+        match self.sort_key {
+            SortKey::Speed => self
+                .rows
+                .sort_by_key(|row| std::cmp::Reverse(row.upload_speed + row.download_speed)),
+            SortKey::Total => self.rows.sort_by_key(|row| std::cmp::Reverse(row.total())),
+            SortKey::Duration => self.rows.sort_by_key(|row| std::cmp::Reverse(row.duration)),
+        }
+    }
+
+    fn shown(&self) -> Vec<&Row> {
+        self.rows
+            .iter()
+            .filter(|row| row.matches(&self.filter))
+            .collect()
+    }
+
+    /// Draw the status line, column headers and as many rows as fit the terminal.
+    fn render(&self, out: &mut impl Write) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let width = width as usize;
+        let shown = self.shown();
+
+        let filter = match (self.editing_filter, self.filter.is_empty()) {
+            (true, _) => format!("filter: {}_", self.filter),
+            (false, false) => format!("filter: {}", self.filter),
+            (false, true) => String::from("no filter"),
+        };
+        let status = format!(
+            "{}/{} connections | sort: {} | {}",
+            shown.len(),
+            self.rows.len(),
+            self.sort_key.as_str(),
+            filter
+        );
+        let keys = match &self.error {
+            Some(error) => error.clone(),
+            None => {
+                String::from("q quit | s sort | / filter | up/down select | x close connection")
+            }
+        };
+
+        queue!(
+            out,
+            cursor::MoveTo(0, 0),
+            terminal::Clear(ClearType::All),
+            Print(fit(&status, width)),
+            cursor::MoveToNextLine(1),
+            SetAttribute(Attribute::Dim),
+            Print(fit(&keys, width)),
+            SetAttribute(Attribute::Reset),
+            cursor::MoveToNextLine(1),
+            SetAttribute(Attribute::Bold),
+            Print(fit(
+                &row_line("Host", "Process", "Chain", "Up", "Down", "Total", "Time"),
+                width
+            )),
+            SetAttribute(Attribute::Reset),
+        )?;
+        let capacity = (height as usize).saturating_sub(3);
+        let offset = self.selected.saturating_sub(capacity.saturating_sub(1));
+        for (index, row) in shown.iter().enumerate().skip(offset).take(capacity) {
+            let metadata = &row.connection.metadata;
+            let host = format!("{}:{}", metadata.destination(), metadata.destination_port);
+            let line = row_line(
+                &host,
+                &metadata.process,
+                row.connection.chains.first().map_or("", String::as_str),
+                &format!("{}/s", HumanBytes(row.upload_speed)),
+                &format!("{}/s", HumanBytes(row.download_speed)),
+                &HumanBytes(row.total()).to_string(),
+                &format_duration(row.duration),
+            );
+            queue!(out, cursor::MoveToNextLine(1))?;
+            if index == self.selected {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(
+                out,
+                Print(fit(&line, width)),
+                SetAttribute(Attribute::Reset)
+            )?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Handle a key press, returning whether to quit.
+    async fn handle_key(&mut self, api: &MihomoApi, key: KeyEvent) -> bool {
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return true;
+        }
+        if self.editing_filter {
+            match key.code {
+                KeyCode::Enter | KeyCode::Esc => self.editing_filter = false,
+                KeyCode::Backspace => {
+                    self.filter.pop();
+                }
+                KeyCode::Char(c) => self.filter.push(c),
+                _ => {}
+            }
+            self.selected = 0;
+            return false;
+        }
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return true,
+            KeyCode::Char('s') => {
+                self.sort_key = self.sort_key.next();
+                self.sort();
+            }
+            KeyCode::Char('/') => {
+                self.editing_filter = true;
+                self.filter.clear();
+            }
+            KeyCode::Up | KeyCode::Char('k') => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down | KeyCode::Char('j') => self.selected += 1,
+            KeyCode::Char('x') | KeyCode::Delete => {
+                let id = self
+                    .shown()
+                    .get(self.selected)
+                    .map(|row| row.connection.id.clone());
+                if let Some(id) = id {
+                    self.error = api
+                        .close_connection(&id)
+                        .await
+                        .err()
+                        .map(|error| format!("{:#}", error));
+                    self.rows.retain(|row| row.connection.id != id);
+                }
+            }
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Print active connections once, sorted by total traffic.
+pub async fn print_connections(api: &MihomoApi) -> Result<()> {
+    let now = Utc::now();
+    let mut connections = api.connections().await?;
+    connections
+        .sort_by_key(|connection| std::cmp::Reverse(connection.upload + connection.download));
+    println!(
+        "{}",
+        row_line("Host", "Process", "Chain", "Up", "Down", "Total", "Time").bold()
+    );
+    for connection in &connections {
+        let metadata = &connection.metadata;
+        let host = format!("{}:{}", metadata.destination(), metadata.destination_port);
+        println!(
+            "{}",
+            row_line(
+                &host,
+                &metadata.process,
+                connection.chains.first().map_or("", String::as_str),
+                &HumanBytes(connection.upload).to_string(),
+                &HumanBytes(connection.download).to_string(),
+                &HumanBytes(connection.upload + connection.download).to_string(),
+                &format_duration(connection_duration(connection, now)),
+            )
+        );
+    }
+    Ok(())
+}
+
+/// Continuously refreshing table of active connections, until `q` is pressed.
+pub async fn live_connections(api: &MihomoApi, refresh: Duration) -> Result<()> {
+    let mut view = LiveView {
+        rows: Vec::new(),
+        previous: HashMap::new(),
+        sort_key: SortKey::Speed,
+        filter: String::new(),
+        editing_filter: false,
+        selected: 0,
+        error: None,
+    };
+    // Fail before taking over the terminal if mihomo is unreachable
+    view.update(api.connections().await?, refresh);
+
+    let mut out = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+    let result = run_live_view(api, &mut view, refresh, &mut out).await;
+    execute!(out, cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+async fn run_live_view(
+    api: &MihomoApi,
+    view: &mut LiveView,
+    refresh: Duration,
+    out: &mut impl Write,
+) -> Result<()> {
+    let mut events = EventStream::new();
+    let mut ticks = tokio::time::interval(refresh);
+    let mut last_refresh = tokio::time::Instant::now();
+    loop {
+        view.selected = view.selected.min(view.shown().len().saturating_sub(1));
+        view.render(out)?;
+        tokio::select! {
+            _ = ticks.tick() => {
+                match api.connections().await {
+                    Ok(connections) => {
+                        view.update(connections, last_refresh.elapsed());
+                        view.error = None;
+                    }
+                    Err(error) => view.error = Some(format!("{:#}", error)),
+                }
+                last_refresh = tokio::time::Instant::now();
+            }
+            event = events.next() => match event {
+                Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                    if view.handle_key(api, key).await {
+                        return Ok(());
+                    }
+                }
+                Some(Ok(_)) => {}
+                Some(Err(error)) => return Err(error.into()),
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Time since a connection started, zero if its start time is malformed.
+fn connection_duration(connection: &Connection, now: DateTime<Utc>) -> Duration {
+    DateTime::parse_from_rfc3339(&connection.start)
+        .ok()
+        .and_then(|start| (now - start.with_timezone(&Utc)).to_std().ok())
+        .unwrap_or_default()
+}
+
+/// Format a duration as e.g. `1h02m`, `3m04s` or `12s`.
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m{:02}s", seconds / 60, seconds % 60),
+        _ => format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// Table row with fixed width columns, long hosts, processes and chains are truncated.
+fn row_line(
+    host: &str,
+    process: &str,
+    chain: &str,
+    upload: &str,
+    download: &str,
+    total: &str,
+    time: &str,
+) -> String {
+    let column = |value: &str, width: usize| {
+        Truncatable::from(value)
+            .truncator("…".into())
+            .truncate(width - 1)
+    };
+    format!(
+        "{:<36} {:<14} {:<18} {:>12} {:>12} {:>11} {:>6}",
+        column(host, 36),
+        column(process, 14),
+        column(chain, 18),
+        upload,
+        download,
+        total,
+        time
+    )
+}
+
+/// Cut a line to the terminal width.
+fn fit(line: &str, width: usize) -> String {
+    line.chars().take(width).collect()
+}
@@ -0,0 +1,25 @@
+/// NetworkManager dispatcher script restarting mihomo, run by NetworkManager as root.
+pub const NM_DISPATCHER_PATH: &str = "/etc/NetworkManager/dispatcher.d/90-mihoro";
+
+/// NetworkManager dispatcher script restarting mihomo.service with `restart_command` whenever a
+/// connection or VPN goes up or down, or its addresses change, so that mihomo drops sockets and
+/// interface bindings of the previous connection.
+///
+/// Events of the `ignored` interfaces (such as mihomo's own TUN device) are skipped, as restarting
+/// mihomo changes them in turn.
+///
+/// Reference: https://networkmanager.dev/docs/api/latest/NetworkManager-dispatcher.html
+pub fn nm_dispatcher_script(restart_command: &str, ignored: &[&str]) -> String {
+    format!(
+        "#!/bin/sh
+# Restart mihomo when the active connection changes, generated by mihoro
+case \"$1\" in
+    lo|{ignored}) exit 0 ;;
+esac
+case \"$2\" in
+    up|down|vpn-up|vpn-down|dhcp4-change|dhcp6-change) {restart_command} ;;
+esac
+",
+        ignored = ignored.join("|"),
+    )
+}
@@ -0,0 +1,51 @@
+use crate::config::Latency;
+
+use std::time::Duration;
+
+use futures_util::stream::{self, StreamExt};
+use serde_yaml::Value;
+use tokio::net::TcpStream;
+
+/// Seconds between health checks of `mihoro failover` if `interval_sec` is undefined.
+pub const DEFAULT_FAILOVER_INTERVAL_SEC: u64 = 60;
+
+/// Consecutive failed health checks before failing over if `failures` is undefined.
+pub const DEFAULT_FAILOVER_FAILURES: u32 = 3;
+
+/// Remote config of the primary profile downloaded to probe its servers while failed over, next
+/// to mihomo's `config.yaml`.
+pub const FAILOVER_PROBE_FILE: &str = "failover-probe.yaml";
+
+/// Whether any node of a remote config accepts TCP connections at its `server` and `port` within
+/// the delay test timeout. Nodes of `proxy-providers` are not listed in the config, so a config
+/// with no `proxies` counts as reachable once downloaded.
+pub async fn servers_reachable(raw_config: &str, latency: &Latency) -> bool {
+    let Ok(config) = serde_yaml::from_str::<Value>(raw_config) else {
+        return false;
+    };
+    let servers: Vec<(String, u16)> = config
+        .get("proxies")
+        .and_then(Value::as_sequence)
+        .into_iter()
+        .flatten()
+        .filter_map(|proxy| {
+            let server = proxy.get("server")?.as_str()?.to_string();
+            let port = u16::try_from(proxy.get("port")?.as_u64()?).ok()?;
+            Some((server, port))
+        })
+        .collect();
+    if servers.is_empty() {
+        return true;
+    }
+
+    let timeout = Duration::from_millis(latency.timeout_ms());
+    let reachable: Vec<bool> = stream::iter(servers)
+        .map(|(server, port)| async move {
+            let connect = TcpStream::connect((server.as_str(), port));
+            matches!(tokio::time::timeout(timeout, connect).await, Ok(Ok(_)))
+        })
+        .buffer_unordered(latency.concurrency().max(1))
+        .collect()
+        .await;
+    reachable.contains(&true)
+}
@@ -0,0 +1,176 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+
+/// File name of the GeoSite database in mihomo's config root, matched case-insensitively as mihomo
+/// does.
+pub const GEOSITE_FILE: &str = "GeoSite.dat";
+
+/// Category of `geosite.dat` a domain belongs to, with the attributes (e.g. `cn`, `ads`) of the
+/// entries matching it.
+#[derive(Debug)]
+pub struct CategoryMatch {
+    pub category: String,
+    pub attributes: Vec<String>,
+}
+
+impl CategoryMatch {
+    /// Whether a `GEOSITE` rule's value, `category` or `category@attribute`, matches.
+    pub fn matches_rule(&self, value: &str) -> bool {
+        let (category, attribute) = match value.split_once('@') {
+            Some((category, attribute)) => (category, Some(attribute)),
+            None => (value, None),
+        };
+        category.eq_ignore_ascii_case(&self.category)
+            && attribute.is_none_or(|attribute| {
+                self.attributes
+                    .iter()
+                    .any(|known| known.eq_ignore_ascii_case(attribute))
+            })
+    }
+}
+
+/// The GeoSite database under mihomo's config root `config_root`, if downloaded.
+pub fn find_geosite(config_root: &str) -> Option<PathBuf> {
+    fs::read_dir(config_root)
+        .ok()?
+        .flatten()
+        .find(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .eq_ignore_ascii_case(GEOSITE_FILE)
+        })
+        .map(|entry| entry.path())
+}
+
+/// Categories of the GeoSite database at `path` containing `domain`, matched as by mihomo's
+/// `GEOSITE` rules: keywords anywhere in the domain, regexes, root domains with their subdomains,
+/// and full domains exactly.
+pub fn lookup(path: &Path, domain: &str) -> Result<Vec<CategoryMatch>> {
+    let data = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let domain = domain.trim_end_matches('.').to_lowercase();
+
+    let mut matches = Vec::new();
+    for (number, entry) in fields(&data)? {
+        let (1, Field::Bytes(entry)) = (number, entry) else {
+            continue;
+        };
+        let mut category = String::new();
+        let mut attributes: Vec<String> = Vec::new();
+        let mut matched = false;
+        for (number, field) in fields(entry)? {
+            match (number, field) {
+                (1, Field::Bytes(code)) => category = String::from_utf8_lossy(code).to_lowercase(),
+                (2, Field::Bytes(rule)) => {
+                    let Some(rule_attributes) = match_rule(rule, &domain)? else {
+                        continue;
+                    };
+                    matched = true;
+                    for attribute in rule_attributes {
+                        if !attributes.contains(&attribute) {
+                            attributes.push(attribute);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if matched {
+            attributes.sort();
+            matches.push(CategoryMatch {
+                category,
+                attributes,
+            });
+        }
+    }
+    matches.sort_by(|a, b| a.category.cmp(&b.category));
+    Ok(matches)
+}
+
+/// Attributes of a domain entry of a category if it matches `domain`.
+fn match_rule(rule: &[u8], domain: &str) -> Result<Option<Vec<String>>> {
+    let mut rule_type = 0;
+    let mut value = "";
+    let mut attributes = Vec::new();
+    for (number, field) in fields(rule)? {
+        match (number, field) {
+            (1, Field::Varint(varint)) => rule_type = varint,
+            (2, Field::Bytes(bytes)) => value = std::str::from_utf8(bytes).unwrap_or_default(),
+            (3, Field::Bytes(attribute)) => {
+                for (number, field) in fields(attribute)? {
+                    if let (1, Field::Bytes(key)) = (number, field) {
+                        attributes.push(String::from_utf8_lossy(key).to_string());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let matched = match rule_type {
+        // Plain, i.e. keyword
+        0 => domain.contains(value),
+        1 => Regex::new(value).is_ok_and(|regex| regex.is_match(domain)),
+        // Root domain, including subdomains
+        2 => {
+            domain == value
+                || domain
+                    .strip_suffix(value)
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        }
+        // Full domain
+        3 => domain == value,
+        _ => false,
+    };
+    Ok(matched.then_some(attributes))
+}
+
+/// Field of a protobuf message, of the wire types found in `geosite.dat`.
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Fields of a protobuf message by field number, enough to walk `geosite.dat` without its schema.
+fn fields(mut data: &[u8]) -> Result<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = varint(&mut data)?;
+        let field = match key & 0x7 {
+            0 => Field::Varint(varint(&mut data)?),
+            2 => {
+                let length = varint(&mut data)? as usize;
+                if length > data.len() {
+                    bail!("truncated GeoSite database");
+                }
+                let (bytes, rest) = data.split_at(length);
+                data = rest;
+                Field::Bytes(bytes)
+            }
+            wire_type @ (1 | 5) => {
+                let length = if wire_type == 1 { 8 } else { 4 };
+                data = data.get(length..).context("truncated GeoSite database")?;
+                Field::Fixed
+            }
+            wire_type => bail!("invalid GeoSite database, unknown wire type {}", wire_type),
+        };
+        fields.push((key >> 3, field));
+    }
+    Ok(fields)
+}
+
+fn varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data.split_first().context("truncated GeoSite database")?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("invalid GeoSite database, varint too long")
+}
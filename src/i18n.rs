@@ -0,0 +1,230 @@
+use std::env;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+/// Language of mihoro's messages, detected from `LC_ALL`, `LC_MESSAGES` or `LANG` unless set by
+/// `language` in the config.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum Language {
+    #[default]
+    #[serde(rename = "en")]
+    En,
+    #[serde(rename = "zh-CN", alias = "zh")]
+    ZhCn,
+}
+
+static LANGUAGE: OnceLock<Language> = OnceLock::new();
+
+/// Set the language of messages for the rest of the process, once.
+pub fn set_language(language: Option<Language>) {
+    LANGUAGE.get_or_init(|| language.unwrap_or_else(language_from_env));
+}
+
+pub fn language() -> Language {
+    *LANGUAGE.get_or_init(language_from_env)
+}
+
+/// Language of the first locale variable set, following POSIX precedence.
+fn language_from_env() -> Language {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default();
+    if locale.starts_with("zh") {
+        Language::ZhCn
+    } else {
+        Language::En
+    }
+}
+
+/// Translation of an English message into the current language, the message itself if it has no
+/// translation.
+pub fn tr(message: &'static str) -> &'static str {
+    let catalog = match language() {
+        Language::En => return message,
+        Language::ZhCn => ZH_CN,
+    };
+    catalog
+        .iter()
+        .find(|(english, _)| *english == message)
+        .map_or(message, |(_, translation)| translation)
+}
+
+/// Translation of an English message template with `{}` placeholders, filled with `args` in order.
+pub fn tr_args(template: &'static str, args: &[&dyn Display]) -> String {
+    let mut args = args.iter();
+    let mut parts = tr(template).split("{}");
+    let mut message = parts.next().unwrap_or_default().to_string();
+    for part in parts {
+        if let Some(arg) = args.next() {
+            message.push_str(&arg.to_string());
+        }
+        message.push_str(part);
+    }
+    message
+}
+
+/// Simplified Chinese catalog, keyed by the English message.
+const ZH_CN: &[(&str, &str)] = &[
+    ("error:", "错误:"),
+    ("warning:", "警告:"),
+    ("Started mihomo.service", "已启动 mihomo.service"),
+    ("Stopped mihomo.service", "已停止 mihomo.service"),
+    ("Restarted mihomo.service", "已重启 mihomo.service"),
+    ("Restart mihomo.service", "正在重启 mihomo.service"),
+    (
+        "Setting up mihomo's binary, config, and systemd service...",
+        "正在安装 mihomo 程序、配置和 systemd 服务...",
+    ),
+    (
+        "Assuming mihomo binary already installed at {}, skipping setup",
+        "mihomo 程序已安装在 {}，跳过安装",
+    ),
+    ("Installed mihomo {} to {}", "已将 mihomo {} 安装到 {}"),
+    ("Extracted to {}", "已解压到 {}"),
+    (
+        "Created mihomo.service at {}",
+        "已在 {} 创建 mihomo.service",
+    ),
+    (
+        "Failed to get subscription userinfo: {}",
+        "获取订阅流量信息失败: {}",
+    ),
+    ("Fetched remote config to {}", "已下载远程配置到 {}"),
+    (
+        "Built config with overrides to {}",
+        "已应用覆盖项生成配置到 {}",
+    ),
+    (
+        "mihomo is not installed, activating without testing the config",
+        "mihomo 未安装，跳过配置测试直接启用",
+    ),
+    ("Activated staged config", "已启用暂存的配置"),
+    (
+        "Remote config and mihomo config unchanged, mihomo.service left running",
+        "远程配置与 mihomo 配置均未变化，mihomo.service 保持运行",
+    ),
+    (
+        "mihomo.service did not come up within {}s, rolling back",
+        "mihomo.service 未能在 {} 秒内正常运行，正在回滚",
+    ),
+    (
+        "mihomo did not download the web UI from {}, installing it with mihoro",
+        "mihomo 未能从 {} 下载网页面板，改由 mihoro 安装",
+    ),
+    ("Installed web UI to {}", "已将网页面板安装到 {}"),
+    (
+        "Applied mihomo config overrides",
+        "已应用 mihomo 配置覆盖项",
+    ),
+    ("Downloaded and updated geodata", "已下载并更新 geodata"),
+    (
+        "`geox_url` undefined, refer to {}",
+        "未设置 `geox_url`，请参考 {}",
+    ),
+    (
+        "Stopping mihomo.service and removing:",
+        "将停止 mihomo.service 并删除:",
+    ),
+    (
+        "Keeping {}, pass `--purge` to remove it",
+        "保留 {}，使用 `--purge` 一并删除",
+    ),
+    ("Uninstall?", "确认卸载？"),
+    ("Uninstall cancelled", "已取消卸载"),
+    ("Removed {}", "已删除 {}"),
+    (
+        "Disabled and reloaded systemd services",
+        "已禁用并重新加载 systemd 服务",
+    ),
+    (
+        "Kept {} with files not created by mihoro",
+        "{} 中有非 mihoro 创建的文件，已保留",
+    ),
+    ("Removed download cache {}", "已删除下载缓存 {}"),
+    ("Reclaimed {}", "共释放 {}"),
+    ("`{}` already selected {}", "`{}` 已选择 {}"),
+    ("Switched `{}` to {}", "已将 `{}` 切换到 {}"),
+    (
+        "only {} ({}) of subscription traffic left, as of the last update",
+        "截至上次更新，订阅流量仅剩 {}（{}）",
+    ),
+    ("subscription has expired", "订阅已过期"),
+    ("subscription expires in {} day(s)", "订阅将在 {} 天后过期"),
+    (
+        "{} of {} monthly traffic used ({})",
+        "本月已用流量 {} / {}（{}）",
+    ),
+    (
+        "Port {} of `{}` is busy, using {} instead",
+        "端口 {}（`{}`）已被占用，改用 {}",
+    ),
+    ("Generated browser policy at {}", "已在 {} 生成浏览器策略"),
+    ("Set system proxy of {}", "已设置 {} 的系统代理"),
+    ("Restored system proxy of {}", "已恢复 {} 的系统代理"),
+    ("Set {} proxy settings", "已设置 {} 的代理"),
+    ("Restored {} proxy settings", "已恢复 {} 的代理"),
+    (
+        "System proxy is not set by mihoro",
+        "系统代理不是由 mihoro 设置的",
+    ),
+    ("Generated PAC file at {}", "已在 {} 生成 PAC 文件"),
+    (
+        "`{}` already selected {} ({} ms)",
+        "`{}` 已选择 {}（{} ms）",
+    ),
+    (
+        "Switched `{}` to {} ({} ms)",
+        "已将 `{}` 切换到 {}（{} ms）",
+    ),
+    (
+        "Rotated `secret` in {} and {}",
+        "已更换 {} 和 {} 中的 `secret`",
+    ),
+    (
+        "Reloaded mihomo with the new secret",
+        "已使用新的 secret 重新加载 mihomo",
+    ),
+    (
+        "Failed to reload mihomo ({}), restarting mihomo.service",
+        "重新加载 mihomo 失败（{}），正在重启 mihomo.service",
+    ),
+    ("Dashboard: {}", "面板: {}"),
+    (
+        "Cloned {} into {}, run `mihoro sync push` to upload this machine's config or `mihoro \
+         sync pull` to use the synced one",
+        "已将 {} 克隆到 {}，运行 `mihoro sync push` 上传本机配置，或运行 `mihoro sync pull` \
+         使用已同步的配置",
+    ),
+    ("No local changes to commit", "本地没有需要提交的变更"),
+    ("Pushed config from {}", "已从 {} 推送配置"),
+    (
+        "Pulled config into {} and {}, run `mihoro apply` to take effect",
+        "已拉取配置到 {} 和 {}，运行 `mihoro apply` 使其生效",
+    ),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translations_keep_the_placeholders_of_their_message() {
+        for (index, (english, translation)) in ZH_CN.iter().enumerate() {
+            assert_eq!(
+                english.matches("{}").count(),
+                translation.matches("{}").count(),
+                "{}",
+                english
+            );
+            assert!(
+                !ZH_CN[..index].iter().any(|(other, _)| other == english),
+                "{} is translated twice",
+                english
+            );
+        }
+    }
+}
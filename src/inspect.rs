@@ -0,0 +1,169 @@
+use anyhow::Result;
+use colored::{Color, Colorize};
+use serde::Serialize;
+use serde_yaml::Value;
+
+/// Step of building mihomo's config a value comes from, in the order they are applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// The remote config as downloaded.
+    Remote,
+    /// Overrides of `mihomo_config` in `mihoro.toml`, merged by `merge` strategy, and listeners of
+    /// `app_routes`.
+    Override,
+    /// Node filtering, renaming and generated groups.
+    Nodes,
+    /// `static_proxies` of `mihoro.toml`.
+    StaticProxies,
+    /// `proxies.local.yaml` or `rules.local.yaml` (with `bypass`).
+    LocalFragment,
+    /// Partial configs in `conf.d/`.
+    ConfD,
+    /// Patch files in `patches/`.
+    Patch,
+    /// The `transform.rhai` script.
+    Script,
+}
+
+impl Origin {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Origin::Remote => "remote",
+            Origin::Override => "override",
+            Origin::Nodes => "nodes",
+            Origin::StaticProxies => "static proxies",
+            Origin::LocalFragment => "local fragment",
+            Origin::ConfD => "conf.d",
+            Origin::Patch => "patch",
+            Origin::Script => "script",
+        }
+    }
+
+    fn color(&self) -> Color {
+        match self {
+            Origin::Remote => Color::BrightBlack,
+            Origin::Override => Color::Green,
+            Origin::Nodes => Color::Blue,
+            Origin::StaticProxies => Color::Cyan,
+            Origin::LocalFragment => Color::Yellow,
+            Origin::ConfD => Color::Magenta,
+            Origin::Patch => Color::Red,
+            Origin::Script => Color::BrightRed,
+        }
+    }
+}
+
+/// Snapshots of mihomo's config after each step of building it, to tell where values come from.
+#[derive(Debug, Default)]
+pub struct Trace {
+    stages: Vec<(Origin, Value)>,
+}
+
+impl Trace {
+    /// Record the config as it is after the step of `origin`.
+    pub fn record(&mut self, origin: Origin, config: &impl Serialize) -> Result<()> {
+        self.stages.push((origin, serde_yaml::to_value(config)?));
+        Ok(())
+    }
+
+    /// Origin of `value` found at `path` in the final config: the earliest step after which it
+    /// is left unchanged. Items of a list only need to stay in the list.
+    fn origin(&self, path: &[&Value], value: &Value, item: bool) -> Origin {
+        let mut origin = Origin::Remote;
+        for (stage_origin, stage) in self.stages.iter().rev() {
+            let found = path
+                .iter()
+                .try_fold(stage, |parent, key| match parent {
+                    Value::Mapping(mapping) => mapping.get(*key),
+                    _ => None,
+                })
+                .is_some_and(|found| match (item, found) {
+                    (true, Value::Sequence(items)) => items.contains(value),
+                    (true, _) => false,
+                    (false, found) => found == value,
+                });
+            if !found {
+                break;
+            }
+            origin = *stage_origin;
+        }
+        origin
+    }
+
+    /// Render the final config as YAML, each value annotated with the step it comes from.
+    /// Only the top-level `field` is rendered if given.
+    pub fn annotate(&self, field: Option<&str>) -> Result<String> {
+        let Some((_, Value::Mapping(config))) = self.stages.last() else {
+            return Ok(String::new());
+        };
+        let mut lines = Vec::new();
+        for (key, value) in config {
+            if field.is_some_and(|field| key.as_str() != Some(field)) {
+                continue;
+            }
+            self.annotate_entry(&mut Vec::new(), key, value, 0, &mut lines)?;
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn annotate_entry<'a>(
+        &self,
+        path: &mut Vec<&'a Value>,
+        key: &'a Value,
+        value: &'a Value,
+        indent: usize,
+        lines: &mut Vec<String>,
+    ) -> Result<()> {
+        path.push(key);
+        let key_text = scalar(key)?;
+        match value {
+            Value::Mapping(mapping) if !mapping.is_empty() => {
+                lines.push(format!("{}{}:", " ".repeat(indent), key_text));
+                for (child_key, child) in mapping {
+                    self.annotate_entry(path, child_key, child, indent + 2, lines)?;
+                }
+            }
+            Value::Sequence(items) if !items.is_empty() => {
+                lines.push(format!("{}{}:", " ".repeat(indent), key_text));
+                for item in items {
+                    let origin = self.origin(path, item, true);
+                    let item_text = serde_yaml::to_string(item)?;
+                    let mut item_lines = item_text.trim_end().lines();
+                    let first = item_lines.next().unwrap_or_default();
+                    lines.push(format!(
+                        "{}- {}  {}",
+                        " ".repeat(indent + 2),
+                        first,
+                        comment(origin)
+                    ));
+                    for line in item_lines {
+                        lines.push(format!("{}  {}", " ".repeat(indent + 2), line));
+                    }
+                }
+            }
+            _ => {
+                let origin = self.origin(path, value, false);
+                lines.push(format!(
+                    "{}{}: {}  {}",
+                    " ".repeat(indent),
+                    key_text,
+                    scalar(value)?,
+                    comment(origin)
+                ));
+            }
+        }
+        path.pop();
+        Ok(())
+    }
+}
+
+/// A scalar (or empty collection) as inline YAML.
+fn scalar(value: &Value) -> Result<String> {
+    Ok(serde_yaml::to_string(value)?.trim_end().to_string())
+}
+
+fn comment(origin: Origin) -> String {
+    format!("# {}", origin.label())
+        .color(origin.color())
+        .to_string()
+}
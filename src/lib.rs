@@ -0,0 +1,60 @@
+//! Mihomo CLI client on Linux.
+//!
+//! Besides the `mihoro` binary, the crate exposes its building blocks for other tools and GUIs:
+//!
+//! * [`config`] - `mihoro.toml` parsing and the override engine generating mihomo's `config.yaml`.
+//! * [`api`] - client of mihomo's RESTful API.
+//! * [`utils`] - downloader and file helpers.
+//! * [`mihoro::Mihoro`] - high level operations behind the CLI commands.
+
+pub mod api;
+pub mod archive;
+pub mod backup;
+pub mod bundle;
+pub mod cache;
+pub mod cmd;
+pub mod completion;
+pub mod confd;
+pub mod config;
+pub mod connections;
+pub mod dispatcher;
+pub mod failover;
+pub mod geosite;
+pub mod i18n;
+pub mod inspect;
+pub mod memory;
+pub mod migrate;
+pub mod mihoro;
+pub mod nodes;
+pub mod outbound;
+pub mod pac;
+pub mod patch;
+pub mod picker;
+pub mod policy;
+pub mod ports;
+pub mod probe;
+pub mod proxy;
+pub mod query;
+pub mod resolved;
+pub mod rules;
+pub mod script;
+pub mod server;
+pub mod sparkline;
+pub mod staging;
+pub mod state;
+pub mod statesync;
+pub mod supervisor;
+pub mod sync;
+pub mod sysproxy;
+pub mod systemctl;
+pub mod template;
+pub mod theme;
+pub mod transparent;
+pub mod tun;
+pub mod udp;
+pub mod ui;
+pub mod uri;
+pub mod usage;
+pub mod utils;
+pub mod vpn;
+pub mod watchdog;
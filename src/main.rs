@@ -1,10 +1,3 @@
-mod cmd;
-mod config;
-mod mihoro;
-mod proxy;
-mod systemctl;
-mod utils;
-
 use anyhow::Result;
 use clap::{CommandFactory, Parser};
 use clap_complete::{
@@ -13,60 +6,201 @@ use clap_complete::{
 };
 use colored::Colorize;
 use reqwest::Client;
-use std::{io, process::Command};
+use std::process::Command;
 
-use cmd::{Args, ClapShell, Commands};
-use mihoro::Mihoro;
-use systemctl::Systemctl;
+use mihoro::cmd::{Args, ClapShell, Commands};
+use mihoro::completion::with_dynamic_completions;
+use mihoro::config::InstallScope;
+use mihoro::i18n::{set_language, tr};
+use mihoro::mihoro::Mihoro;
+use mihoro::theme::set_color_mode;
+use mihoro::utils::{portable_root, rerun_as_root, set_root};
 
 #[tokio::main]
 async fn main() {
     if let Err(err) = cli().await {
-        eprintln!("{} {}", "error:".bright_red().bold(), err);
+        eprintln!("{} {:#}", tr("error:").bright_red().bold(), err);
         std::process::exit(1);
     }
 }
 
 async fn cli() -> Result<()> {
     let args = Args::parse();
+    set_color_mode(args.color);
+    match &args.root {
+        Some(root) => set_root(Some(root)),
+        None => {
+            let portable_root = portable_root(args.portable)?;
+            set_root(portable_root.as_ref().and_then(|root| root.to_str()));
+        }
+    }
     let client = Client::new();
+    // Importing a setup replaces an unconfigured (or missing) mihoro config
+    if let Some(Commands::Import { path }) = &args.command {
+        return Mihoro::import(&args.mihoro_config, path);
+    }
     let mihoro = Mihoro::new(&args.mihoro_config)?;
+    set_language(mihoro.config.language);
+    if let Some(command) = &args.command {
+        if mihoro.requires_root(command) {
+            let status = rerun_as_root(&mihoro.config_path)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+    }
+    mihoro.warn_quota();
 
     match &args.command {
         Some(Commands::Setup) => mihoro.setup(client).await?,
         Some(Commands::Update) => mihoro.update(client).await?,
+        Some(Commands::Fetch) => mihoro.fetch(client).await?,
+        Some(Commands::Build) => mihoro.build()?,
+        Some(Commands::Activate) => mihoro.activate(client).await?,
+        Some(Commands::Inspect { field }) => mihoro.inspect(field.as_deref())?,
+        Some(Commands::Query { filter }) => mihoro.query(filter)?,
         Some(Commands::UpdateGeodata) => mihoro.update_geodata(client).await?,
-        Some(Commands::Apply) => mihoro.apply().await?,
-        Some(Commands::Uninstall) => mihoro.uninstall()?,
+        Some(Commands::Apply) => mihoro.apply(client).await?,
+        Some(Commands::Clean) => mihoro.clean()?,
+        Some(Commands::Uninstall { yes, purge }) => mihoro.uninstall(*yes, *purge)?,
         Some(Commands::Proxy { proxy }) => mihoro.proxy_commands(proxy)?,
+        Some(Commands::Exec { command }) => {
+            let status = mihoro.exec(command)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Some(Commands::Sysproxy { sysproxy }) => mihoro.sysproxy_commands(sysproxy)?,
+        Some(Commands::Pac { pac }) => mihoro.pac_commands(pac).await?,
+        Some(Commands::BrowserPolicy {
+            browser,
+            pac,
+            lan,
+            output,
+        }) => mihoro.browser_policy(*browser, pac, *lan, output)?,
+        Some(Commands::Ping {
+            group,
+            concurrency,
+            url,
+            timeout,
+        }) => {
+            mihoro
+                .ping(client, group, *concurrency, url, *timeout)
+                .await?
+        }
+        Some(Commands::Select {
+            group,
+            node,
+            interactive,
+        }) => mihoro.select(client, group, node, *interactive).await?,
+        Some(Commands::Autoselect {
+            group,
+            exclude,
+            concurrency,
+            url,
+            timeout,
+        }) => {
+            mihoro
+                .autoselect(client, group, exclude, *concurrency, url, *timeout)
+                .await?
+        }
+        Some(Commands::Top { window, limit }) => mihoro.top(client, *window, *limit).await?,
+        Some(Commands::Connections { live, refresh }) => {
+            mihoro.connections(client, *live, *refresh).await?
+        }
+        Some(Commands::Api { method, path, data }) => {
+            mihoro
+                .api_request(client, method, path, data.as_deref())
+                .await?
+        }
+        Some(Commands::Usage { month, record }) => mihoro.usage(client, *month, *record).await?,
+        Some(Commands::TestUdp { node }) => mihoro.test_udp(client, node).await?,
+        Some(Commands::Qr { qr }) => mihoro.qr_commands(qr)?,
+        Some(Commands::Geosite { geosite }) => mihoro.geosite_commands(geosite)?,
+        Some(Commands::Share { port, config }) => mihoro.share(*port, *config).await?,
+        Some(Commands::FakeIpFilter { filter }) => mihoro.fake_ip_filter_commands(filter)?,
+        Some(Commands::NameserverPolicy { policy }) => mihoro.nameserver_policy_commands(policy)?,
+        Some(Commands::Node { node }) => mihoro.node_commands(node)?,
+        Some(Commands::Rules { rules }) => mihoro.rules_commands(rules)?,
+        Some(Commands::Route { route }) => mihoro.route_commands(client, route).await?,
+        Some(Commands::Adblock { adblock }) => mihoro.adblock_commands(adblock)?,
+        Some(Commands::Transparent { transparent }) => mihoro.transparent_commands(transparent)?,
+        Some(Commands::AppRoute { clear: true, .. }) => mihoro.clear_app_routes()?,
+        Some(Commands::AppRoute {
+            policy: Some(policy),
+            command,
+            ..
+        }) => {
+            let status = mihoro.app_route(policy, command)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Some(Commands::AppRoute { .. }) => (),
+        Some(Commands::Doctor { fix }) => mihoro.doctor(client, *fix).await?,
+        Some(Commands::Tun { tun }) => mihoro.tun_commands(client, tun).await?,
+        Some(Commands::Dns { dns }) => mihoro.dns_commands(client, dns).await?,
+        Some(Commands::Secret { secret }) => mihoro.secret_commands(client, secret).await?,
+        Some(Commands::NmDispatcher { dispatcher }) => mihoro.nm_dispatcher_commands(dispatcher)?,
+        Some(Commands::Watchdog) => mihoro.watchdog(client).await?,
+        Some(Commands::Failover) => mihoro.failover(client).await?,
+        Some(Commands::Sync { sync }) => mihoro.sync_commands(sync)?,
+        Some(Commands::StateSync { state_sync }) => {
+            mihoro.state_sync_commands(client, state_sync).await?
+        }
+        Some(Commands::Export { path, geodata }) => mihoro.export(path, *geodata)?,
+        Some(Commands::DebugBundle { path }) => mihoro.debug_bundle(path)?,
+        // Handled before loading the mihoro config
+        Some(Commands::Import { .. }) => (),
 
-        Some(Commands::Start) => Systemctl::new()
-            .start("mihomo.service")
-            .execute()
-            .map(|_| {
-                println!("{} Started mihomo.service", mihoro.prefix.green());
-            })?,
-
-        Some(Commands::Status) => {
-            Systemctl::new().status("mihomo.service").execute()?;
+        Some(Commands::Start) => {
+            mihoro
+                .systemctl()
+                .start("mihomo.service")
+                .execute()
+                .map(|_| {
+                    println!(
+                        "{} {}",
+                        mihoro.prefix.success(),
+                        tr("Started mihomo.service")
+                    );
+                })?;
+            mihoro.sync_resolved()?;
         }
 
-        Some(Commands::Stop) => Systemctl::new().stop("mihomo.service").execute().map(|_| {
-            println!("{} Stopped mihomo.service", mihoro.prefix.green());
-        })?,
+        Some(Commands::Status) => mihoro.status(client).await?,
+
+        Some(Commands::Stop) => {
+            mihoro.revert_resolved()?;
+            mihoro
+                .systemctl()
+                .stop("mihomo.service")
+                .execute()
+                .map(|_| {
+                    println!(
+                        "{} {}",
+                        mihoro.prefix.success(),
+                        tr("Stopped mihomo.service")
+                    );
+                })?
+        }
 
         Some(Commands::Restart) => {
-            Systemctl::new()
+            mihoro
+                .systemctl()
                 .restart("mihomo.service")
                 .execute()
                 .map(|_| {
-                    println!("{} Restarted mihomo.service", mihoro.prefix.green());
-                })?
+                    println!(
+                        "{} {}",
+                        mihoro.prefix.success(),
+                        tr("Restarted mihomo.service")
+                    );
+                })?;
+            mihoro.sync_resolved()?;
         }
 
+        Some(Commands::Log) if mihoro.supervised() => mihoro.follow_log().await?,
         Some(Commands::Log) => {
-            Command::new("journalctl")
-                .arg("--user")
+            let mut journalctl = Command::new("journalctl");
+            if mihoro.config.install_scope() == InstallScope::User {
+                journalctl.arg("--user");
+            }
+            journalctl
                 .arg("-xeu")
                 .arg("mihomo.service")
                 .arg("-n")
@@ -77,18 +211,32 @@ async fn cli() -> Result<()> {
                 .wait()?;
         }
 
-        Some(Commands::Completions { shell }) => match shell {
-            Some(ClapShell::Bash) => {
-                generate(Bash, &mut Args::command(), "mihoro", &mut io::stdout())
-            }
-            Some(ClapShell::Zsh) => {
-                generate(Zsh, &mut Args::command(), "mihoro", &mut io::stdout())
-            }
-            Some(ClapShell::Fish) => {
-                generate(Fish, &mut Args::command(), "mihoro", &mut io::stdout())
+        Some(Commands::Run {
+            max_log_size,
+            max_log_age,
+            log_files,
+        }) => {
+            mihoro
+                .run(client, *max_log_size, *max_log_age, *log_files)
+                .await?
+        }
+
+        Some(Commands::Complete { complete }) => mihoro.complete(client, complete).await,
+        Some(Commands::Completions { shell: Some(shell) }) => {
+            let mut script = Vec::new();
+            match shell {
+                ClapShell::Bash => generate(Bash, &mut Args::command(), "mihoro", &mut script),
+                ClapShell::Zsh => generate(Zsh, &mut Args::command(), "mihoro", &mut script),
+                ClapShell::Fish => generate(Fish, &mut Args::command(), "mihoro", &mut script),
             }
-            _ => (),
-        },
+            let script = String::from_utf8(script)?;
+            print!("{}", with_dynamic_completions(shell, script));
+        }
+        Some(Commands::Completions { shell: None }) => (),
+        Some(Commands::Plugin(args)) => {
+            let status = mihoro.run_plugin(args)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
 
         None => (),
     }
@@ -0,0 +1,79 @@
+use crate::api::MihomoApi;
+use crate::config::MemoryLimit;
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Seconds between memory checks if `interval_sec` of `[memory_limit]` is undefined.
+pub const DEFAULT_MEMORY_CHECK_INTERVAL_SEC: u64 = 60;
+
+/// Minimum seconds between restarts if `cooldown_sec` of `[memory_limit]` is undefined.
+pub const DEFAULT_MEMORY_RESTART_COOLDOWN_SEC: u64 = 600;
+
+/// Outcome of a memory check of the running mihomo.
+pub enum MemoryCheck {
+    /// Below the ceiling, or mihomo could not be reached.
+    Normal,
+    /// Memory in use in bytes is over the ceiling, mihomo is to be restarted.
+    Restart(u64),
+    /// Memory in use in bytes is over the ceiling, but mihomo was restarted too recently.
+    Cooldown(u64),
+}
+
+/// Memory ceiling of mihomo, checked through its external controller by the processes running
+/// it, with restarts rate-limited by a cooldown.
+pub struct MemoryGuard {
+    api: MihomoApi,
+    max_bytes: u64,
+    interval: Duration,
+    cooldown: Duration,
+    last_restart: Option<Instant>,
+}
+
+impl MemoryGuard {
+    pub fn new(api: MihomoApi, limit: &MemoryLimit) -> MemoryGuard {
+        MemoryGuard {
+            api,
+            max_bytes: limit.max_mb * 1024 * 1024,
+            interval: Duration::from_secs(
+                limit
+                    .interval_sec
+                    .unwrap_or(DEFAULT_MEMORY_CHECK_INTERVAL_SEC)
+                    .max(1),
+            ),
+            cooldown: Duration::from_secs(
+                limit
+                    .cooldown_sec
+                    .unwrap_or(DEFAULT_MEMORY_RESTART_COOLDOWN_SEC),
+            ),
+            last_restart: None,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Check memory in use by mihomo, counting a `Restart` outcome as a restart.
+    pub async fn check(&mut self) -> MemoryCheck {
+        let Ok(Ok(inuse)) = tokio::time::timeout(self.interval, self.api.memory()).await else {
+            return MemoryCheck::Normal;
+        };
+        if inuse <= self.max_bytes {
+            return MemoryCheck::Normal;
+        }
+        if self
+            .last_restart
+            .is_some_and(|last_restart| last_restart.elapsed() < self.cooldown)
+        {
+            return MemoryCheck::Cooldown(inuse);
+        }
+        self.last_restart = Some(Instant::now());
+        MemoryCheck::Restart(inuse)
+    }
+}
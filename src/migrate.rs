@@ -0,0 +1,119 @@
+use anyhow::{bail, Result};
+use toml_edit::{value, DocumentMut, Item, Key};
+
+/// Layout version of `mihoro.toml` written by this mihoro, stored as `config_version`. Configs
+/// without it predate versioning and are version 0.
+pub const CONFIG_VERSION: i64 = 2;
+
+/// Migrations of the config layout, the one at index `n` upgrading version `n` to `n + 1`.
+const MIGRATIONS: &[fn(&mut DocumentMut)] = &[rename_clash_fields, default_remote_config_encoding];
+
+/// Upgrade a config of an older layout to `CONFIG_VERSION`, keeping comments and formatting of
+/// untouched entries. Returns the migrated config, or `None` if it is up to date.
+pub fn migrate_config(raw_config: &str) -> Result<Option<String>> {
+    let mut document: DocumentMut = raw_config.parse()?;
+    let version = match document.get("config_version") {
+        Some(version) => match version.as_integer() {
+            Some(version) if version >= 0 => version,
+            _ => bail!("`config_version` must be a non-negative integer"),
+        },
+        None => 0,
+    };
+    if version > CONFIG_VERSION {
+        bail!(
+            "`config_version` {} is newer than {} supported by this mihoro, upgrade mihoro",
+            version,
+            CONFIG_VERSION
+        );
+    }
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(&mut document);
+    }
+    document["config_version"] = value(CONFIG_VERSION);
+    Ok(Some(document.to_string()))
+}
+
+/// Rename a top level key in place, keeping its comments, unless the new key is already set.
+fn rename(document: &mut DocumentMut, from: &str, to: &str) {
+    if document.contains_key(to) || !document.contains_key(from) {
+        return;
+    }
+    // Re-insert every entry, as keys can only be appended
+    let table = document.as_table_mut();
+    let keys: Vec<String> = table.iter().map(|(key, _)| key.to_string()).collect();
+    let entries: Vec<(Key, Item)> = keys
+        .iter()
+        .filter_map(|key| table.remove_entry(key))
+        .collect();
+    for (key, item) in entries {
+        if key.get() == from {
+            let mut renamed = Key::new(to);
+            *renamed.leaf_decor_mut() = key.leaf_decor().clone();
+            table.insert_formatted(&renamed, item);
+        } else {
+            table.insert_formatted(&key, item);
+        }
+    }
+}
+
+/// 0 to 1: fields of the clash era (`clashrup`) were renamed after mihomo.
+fn rename_clash_fields(document: &mut DocumentMut) {
+    for (from, to) in [
+        ("remote_clash_binary_url", "remote_mihomo_binary_url"),
+        ("clash_binary_path", "mihomo_binary_path"),
+        ("clash_config_root", "mihomo_config_root"),
+        ("clash_config", "mihomo_config"),
+    ] {
+        rename(document, from, to);
+    }
+}
+
+/// 1 to 2: `remote_config_encoding` became required, with plain text subscriptions as before.
+fn default_remote_config_encoding(document: &mut DocumentMut) {
+    if !document.contains_key("remote_config_encoding") {
+        document["remote_config_encoding"] = value("plain");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clash_fields_are_renamed_keeping_comments() {
+        let migrated = migrate_config(
+            "# binary\nclash_binary_path = \"~/.local/bin/clash\"\n\n[clash_config]\nport = 7891\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            migrated,
+            "# binary\nmihomo_binary_path = \"~/.local/bin/clash\"\nremote_config_encoding = \
+             \"plain\"\nconfig_version = 2\n\n[mihomo_config]\nport = 7891\n"
+        );
+    }
+
+    #[test]
+    fn set_fields_are_not_overwritten() {
+        let migrated = migrate_config(
+            "config_version = 1\nremote_config_encoding = \"base64\"\nclash_config_root = \"a\"\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            migrated,
+            "config_version = 2\nremote_config_encoding = \"base64\"\nclash_config_root = \"a\"\n"
+        );
+    }
+
+    #[test]
+    fn current_configs_are_kept_and_newer_ones_rejected() {
+        assert_eq!(migrate_config("config_version = 2\n").unwrap(), None);
+        assert!(migrate_config("config_version = 3\n").is_err());
+        assert!(migrate_config("config_version = -1\n").is_err());
+        assert!(migrate_config("config_version = \"2\"\n").is_err());
+    }
+}
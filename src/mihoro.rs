@@ -1,291 +1,4648 @@
-use crate::cmd::ProxyCommands;
-use crate::config::{apply_mihomo_override, parse_config, Config, EncodingMode};
-use crate::proxy::{proxy_export_cmd, proxy_unset_cmd};
+use crate::api::{controller_url, Connection, MihomoApi, Proxy};
+use crate::archive::{export_setup, import_setup, GEODATA_FILES};
+use crate::backup::{backup_file, list_backups, BACKUPS_DIR};
+use crate::bundle::{write_bundle, Redactor, BUNDLE_LOG_LINES};
+use crate::cache::{cache_root, DownloadCache};
+use crate::cmd::{
+    AdblockCommands, Browser, Commands, CompleteCommands, DnsCommands, FakeIpFilterCommands,
+    GeositeCommands, NameserverPolicyCommands, NmDispatcherCommands, NodeCommands, PacCommands,
+    ProxyCommands, QrCommands, RouteCommands, RulesCommands, RulesetBehavior, SecretCommands,
+    StateSyncCommands, Switch, SyncCommands, SysproxyCommands, TransparentCommands, TunCommands,
+};
+use crate::config::{
+    apply_mihomo_override, build_mihomo_config, edit_config, field_key, parse_config,
+    sanitized_mihomo_config, set_default, set_value, table_mut, trace_mihomo_config,
+    validate_remote_config, Config, EncodingMode, InstallScope, Latency, RemoteUrl,
+    DEFAULT_SUBSCRIPTION_RETRIES, SUBSCRIPTION_RETRY_DELAY,
+};
+use crate::connections::{live_connections, print_connections};
+use crate::dispatcher::{nm_dispatcher_script, NM_DISPATCHER_PATH};
+use crate::failover::{
+    servers_reachable, DEFAULT_FAILOVER_FAILURES, DEFAULT_FAILOVER_INTERVAL_SEC,
+    FAILOVER_PROBE_FILE,
+};
+use crate::geosite::{find_geosite, lookup, GEOSITE_FILE};
+use crate::i18n::{tr, tr_args};
+use crate::inspect::Trace;
+use crate::memory::MemoryGuard;
+use crate::nodes::{entry_name, find_node, LocalProxies, LOCAL_PROXIES_FILE};
+use crate::outbound::{ssh_to_node, wireguard_to_node};
+use crate::pac::{pac_file, PAC_CONTENT_TYPE};
+use crate::picker::{self, PickerItem};
+use crate::policy::{chrome_policy, firefox_policy, PolicyProxy};
+use crate::ports::{config_ports, port_listeners, reassign_busy_ports};
+use crate::probe::{reachable_through, IPV4_PROBE_URL, IPV6_PROBE_URL};
+use crate::proxy::{proxy_env, proxy_export_cmd, proxy_unset_cmd};
+use crate::query::{format_result, Query};
+use crate::resolved::{
+    listener_address, resolvectl_revert_script, resolvectl_script, resolved_link_exists,
+    RESOLVED_LINK,
+};
+use crate::rules::{
+    clear_ruleset_cache, converted_ruleset_path, merge_local_rules, resolve_rulesets,
+    ruleset_cache_paths, ruleset_format, LocalRules, Ruleset, ADBLOCK_RULESET, DEFAULT_ADBLOCK_URL,
+    LOCAL_RULES_FILE, TEMPORARY_RULES_FILE,
+};
+use crate::server::{serve, Response};
+use crate::sparkline::sparkline;
+use crate::staging::{
+    test_mihomo_config, unchanged, HEALTHY_CHECKS, HEALTH_CHECK_INTERVAL, STAGING_DIR,
+};
+use crate::state::{State, SubscriptionInfo, STATE_FILE};
+use crate::statesync::{machine_name, StateStore};
+use crate::supervisor::{follow_log, restart_on_memory, supervise, RotatingLog, LOG_FILE};
+use crate::sync::{
+    git, git_output, replace_with_copy, restore_secrets, without_secrets, SYNCED_CONFIG_FILE,
+    SYNCED_FILES, SYNC_DIR,
+};
+use crate::sysproxy::{active_services, Desktop, DesktopProxies, ProxySetting, ServiceProxies};
 use crate::systemctl::Systemctl;
-use crate::utils::{create_parent_dir, delete_file, download_file, extract_gzip, get_file_from_system_or_remote, try_decode_base64_and_overwrite_file};
+use crate::template::{env_reference, replace_env_var, Template, ENV_FILE};
+use crate::theme::Prefix;
+use crate::transparent::{
+    app_route_disable_script, app_route_enable_script, app_route_listener, tproxy_disable_script,
+    tproxy_enable_script,
+};
+use crate::tun::{
+    has_tun_capabilities, resolved_disable_script, resolved_enable_script, tun_capabilities_script,
+    Resolver, DEFAULT_TUN_DEVICE, RESOLVED_DROP_IN, RESOLV_CONF,
+};
+use crate::udp::test_udp_relay;
+use crate::ui::{extract_ui, ui_installed, UI_DOWNLOAD_TIMEOUT};
+use crate::uri::{node_to_uri, split_uris, uri_to_node};
+use crate::usage::{Traffic, Usage, LAST_HOUR_SLOTS, USAGE_FILE};
+use crate::utils::{confirm, create_parent_dir, delete_file, expand_path, root, extract_gzip, get_file_from_system_or_remote, is_root, notify_desktop, random_token, read_clipboard, run_root_script, send_signal, try_decode_base64_and_overwrite_file, verify_signature, DownloadOptions};
+use crate::vpn::{default_route_interfaces, detect_vpns};
+use crate::watchdog::{sd_notify, watchdog_timeout, DEFAULT_WATCHDOG_SEC};
 
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
 use std::fs;
+use std::io::{self, IsTerminal, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::os::unix::prelude::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Datelike, Local, Utc};
 use colored::Colorize;
+use futures_util::future::join_all;
+use futures_util::stream::{self, StreamExt};
+use indicatif::HumanBytes;
 use local_ip_address::local_ip;
-use reqwest::Client;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, USER_AGENT};
+use reqwest::{Certificate, Client, Identity, Method};
 use shellexpand::tilde;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::oneshot;
+use toml_edit::{Array, DocumentMut, Item, Value};
+use url::Url;
+
+/// Archive `mihoro setup` downloads the mihomo binary to, in the working directory.
+const DOWNLOADED_BINARY_ARCHIVE: &str = "mihomo-downloaded-binary.tar.gz";
 
 #[derive(Debug)]
 pub struct Mihoro {
     // global mihoro config
-    pub prefix: String,
+    pub prefix: Prefix,
     pub config: Config,
+    pub config_path: String,
+    pub profile: String,
 
     // mihomo global variables derived from mihoro config
     pub mihomo_target_binary_path: String,
     pub mihomo_target_config_root: String,
     pub mihomo_target_config_path: String,
+    pub mihomo_target_remote_config_path: String,
+    pub mihomo_target_local_rules_path: String,
+    pub mihomo_target_local_proxies_path: String,
+    pub mihomo_target_temporary_rules_path: String,
+    pub mihomo_target_staged_remote_config_path: String,
+    pub mihomo_target_staged_config_path: String,
     pub mihomo_target_service_path: String,
+    pub mihomo_target_state_path: String,
+    pub mihomo_target_backups_root: String,
+    pub mihomo_target_sync_root: String,
+    pub mihomo_target_usage_path: String,
+    pub mihomo_target_log_path: String,
+    pub mihoro_usage_service_path: String,
+    pub mihoro_usage_timer_path: String,
 }
 
 impl Mihoro {
-    pub fn new(config_path: &String) -> Result<Mihoro> {
-        let config = parse_config(tilde(&config_path).as_ref())?;
+    pub fn new(config_path: &str) -> Result<Mihoro> {
+        let config = parse_config(&expand_path(config_path))?;
         Ok(Mihoro {
-            prefix: String::from("mihoro:"),
+            prefix: Prefix::new("mihoro:", &config.theme.clone().unwrap_or_default())?,
             config: config.clone(),
-            mihomo_target_binary_path: tilde(&config.mihomo_binary_path).to_string(),
-            mihomo_target_config_root: tilde(&config.mihomo_config_root).to_string(),
-            mihomo_target_config_path: tilde(&format!("{}/config.yaml", config.mihomo_config_root))
-                .to_string(),
-            mihomo_target_service_path: tilde(&format!(
+            config_path: expand_path(config_path),
+            profile: profile_name(config_path),
+            mihomo_target_binary_path: expand_path(&config.mihomo_binary_path),
+            mihomo_target_config_root: expand_path(&config.mihomo_config_root),
+            mihomo_target_config_path: expand_path(&format!(
+                "{}/config.yaml",
+                config.mihomo_config_root
+            )),
+            mihomo_target_remote_config_path: expand_path(&format!(
+                "{}/remote-config.yaml",
+                config.mihomo_config_root
+            )),
+            mihomo_target_local_rules_path: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, LOCAL_RULES_FILE
+            )),
+            mihomo_target_local_proxies_path: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, LOCAL_PROXIES_FILE
+            )),
+            mihomo_target_temporary_rules_path: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, TEMPORARY_RULES_FILE
+            )),
+            mihomo_target_staged_remote_config_path: expand_path(&format!(
+                "{}/{}/remote-config.yaml",
+                config.mihomo_config_root, STAGING_DIR
+            )),
+            mihomo_target_staged_config_path: expand_path(&format!(
+                "{}/{}/config.yaml",
+                config.mihomo_config_root, STAGING_DIR
+            )),
+            mihomo_target_service_path: expand_path(&format!(
                 "{}/mihomo.service",
                 config.user_systemd_root
-            ))
-                .to_string(),
+            )),
+            mihomo_target_state_path: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, STATE_FILE
+            )),
+            mihomo_target_backups_root: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, BACKUPS_DIR
+            )),
+            mihomo_target_sync_root: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, SYNC_DIR
+            )),
+            mihomo_target_usage_path: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, USAGE_FILE
+            )),
+            mihomo_target_log_path: expand_path(&format!(
+                "{}/{}",
+                config.mihomo_config_root, LOG_FILE
+            )),
+            mihoro_usage_service_path: expand_path(&format!(
+                "{}/mihoro-usage.service",
+                config.user_systemd_root
+            )),
+            mihoro_usage_timer_path: expand_path(&format!(
+                "{}/mihoro-usage.timer",
+                config.user_systemd_root
+            )),
         })
     }
 
+    /// Generate mihomo's `config.yaml` from the downloaded remote config with overrides applied.
+    ///
+    /// Installs set up before the remote config was kept separately only have `config.yaml`, in
+    /// which case overrides are applied to it in place.
+    pub fn generate_config(&self) -> Result<()> {
+        let source = if Path::new(&self.mihomo_target_remote_config_path).exists() {
+            &self.mihomo_target_remote_config_path
+        } else {
+            &self.mihomo_target_config_path
+        };
+
+        // Temporary routes are dropped along with the running config
+        if Path::new(&self.mihomo_target_temporary_rules_path).exists() {
+            fs::remove_file(&self.mihomo_target_temporary_rules_path)?;
+        }
+        let held_ports = self.held_ports()?;
+        let created = !Path::new(&self.mihomo_target_config_path).exists();
+        let staged_path = format!("{}.new", self.mihomo_target_config_path);
+        apply_mihomo_override(source, &staged_path, &self.config, &self.profile)?;
+        self.assign_free_ports(&staged_path, &held_ports)?;
+        self.backup_replaced(&self.mihomo_target_config_path, &staged_path)?;
+        fs::rename(&staged_path, &self.mihomo_target_config_path)?;
+        if created {
+            self.track(&[&self.mihomo_target_config_path])?;
+        }
+        Ok(())
+    }
+
+    /// Ports listened on by the running mihomo, as set in the current `config.yaml`.
+    fn held_ports(&self) -> Result<Vec<u16>> {
+        if !self.config.auto_ports.unwrap_or(false)
+            || !Path::new(&self.mihomo_target_config_path).exists()
+            || !self
+                .systemctl()
+                .is_active("mihomo.service")
+                .execute()?
+                .success()
+        {
+            return Ok(Vec::new());
+        }
+        let raw_mihomo_yaml = fs::read_to_string(&self.mihomo_target_config_path)?;
+        let mihomo_yaml: serde_yaml::Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+        Ok(config_ports(&mihomo_yaml)
+            .into_iter()
+            .map(|(_, port)| port)
+            .collect())
+    }
+
+    /// With `auto_ports`, move busy listening ports of the config at `path` to free ones, except
+    /// `held` ports of the running mihomo, and report the ports chosen.
+    fn assign_free_ports(&self, path: &str, held: &[u16]) -> Result<()> {
+        if !self.config.auto_ports.unwrap_or(false) {
+            return Ok(());
+        }
+        let raw_mihomo_yaml = fs::read_to_string(path)?;
+        let mut mihomo_yaml: serde_yaml::Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+        let reassigned = reassign_busy_ports(&mut mihomo_yaml, held)?;
+        if reassigned.is_empty() {
+            return Ok(());
+        }
+        fs::write(path, serde_yaml::to_string(&mihomo_yaml)?)?;
+        for (field, port, free_port) in reassigned {
+            println!(
+                "{} {}",
+                self.prefix.warning(),
+                tr_args(
+                    "Port {} of `{}` is busy, using {} instead",
+                    &[&port, &field, &free_port.to_string().bold()]
+                )
+            );
+        }
+        Ok(())
+    }
+
+    /// Fetch the remote config to `path`, decoding it if base64 encoded.
+    ///
+    /// The payload only replaces the file at `path` if it is valid, otherwise it is moved to
+    /// `remote-config.quarantine.yaml` for inspection, leaving a working setup untouched.
+    async fn fetch_remote_config(&self, client: &Client, path: &str) -> Result<()> {
+        let staged_path = format!("{}.new", path);
+        let response_headers = Arc::new(Mutex::new(HeaderMap::new()));
+        let options = DownloadOptions {
+            response_headers: Some(response_headers.clone()),
+            ..self.subscription_download_options()?
+        };
+        let source = self
+            .download_subscription(&self.subscription_client(client)?, &staged_path, &options)
+            .await?;
+
+        //Try to Decode base64 config if set
+        let decoded = if self.config.remote_config_encoding == EncodingMode::Base64 {
+            try_decode_base64_and_overwrite_file(&staged_path).context("failed to decode base64")
+        } else {
+            Ok(())
+        };
+
+        if let Err(error) = decoded.and_then(|()| validate_remote_config(&staged_path)) {
+            let quarantine_path = format!(
+                "{}/remote-config.quarantine.yaml",
+                self.mihomo_target_config_root
+            );
+            let created = !Path::new(&quarantine_path).exists();
+            fs::rename(&staged_path, &quarantine_path)?;
+            if created {
+                self.track(&[&quarantine_path])?;
+            }
+            return Err(error.context(format!(
+                "downloaded config is invalid, kept the current config and saved the download \
+                 to {} for inspection",
+                quarantine_path
+            )));
+        }
+        let created = !Path::new(path).exists();
+        fs::rename(&staged_path, path)?;
+        if created {
+            self.track(&[&path.to_string()])?;
+        }
+
+        // Keep the provider's traffic and expiry report for quota warnings
+        let mut subscription_info = response_headers
+            .lock()
+            .ok()
+            .and_then(|headers| subscription_userinfo(&headers));
+        if subscription_info.is_none() {
+            subscription_info = self.fetch_userinfo(client).await.unwrap_or_else(|error| {
+                println!(
+                    "{} {}",
+                    self.prefix.warning(),
+                    tr_args(
+                        "Failed to get subscription userinfo: {}",
+                        &[&format!("{:#}", error)]
+                    )
+                );
+                None
+            });
+        }
+        let mut state = State::load(&self.mihomo_target_state_path)?;
+        if subscription_info.is_some() {
+            state.subscription = subscription_info;
+        }
+        state.subscription_source = Some(source);
+        state.write(&self.mihomo_target_state_path)?;
+        Ok(())
+    }
+
+    /// Download the subscription to `path`, attempting `remote_config_url` `subscription.retries`
+    /// times before trying each of `subscription.backup_urls`. Returns the source downloaded from,
+    /// as recorded in state.
+    async fn download_subscription(
+        &self,
+        client: &Client,
+        path: &str,
+        options: &DownloadOptions,
+    ) -> Result<String> {
+        let subscription = self.config.subscription.clone().unwrap_or_default();
+        let backup_urls = subscription.backup_urls.unwrap_or_default();
+        // Without a backup to fall back to, fail as soon as the mirrors did
+        let retries = if backup_urls.is_empty() {
+            1
+        } else {
+            subscription
+                .retries
+                .unwrap_or(DEFAULT_SUBSCRIPTION_RETRIES)
+                .max(1)
+        };
+
+        let primary = self.config.mirrors(&self.config.remote_config_url);
+        let mut attempt = 1;
+        let error = loop {
+            match get_file_from_system_or_remote(client, &primary, path, options).await {
+                Ok(()) => return Ok(String::from("remote_config_url")),
+                Err(error) if attempt >= retries => break error,
+                Err(error) => println!(
+                    "{} {:#}, retrying ({}/{})",
+                    self.prefix.warning(),
+                    error,
+                    attempt,
+                    retries
+                ),
+            }
+            attempt += 1;
+            tokio::time::sleep(SUBSCRIPTION_RETRY_DELAY).await;
+        };
+
+        let mut error = error.context("failed to download the subscription");
+        for (index, url) in backup_urls.iter().enumerate() {
+            let source = format!("backup_urls[{}]", index);
+            println!(
+                "{} {:#}, trying {}",
+                self.prefix.warning(),
+                error,
+                source.bold()
+            );
+            let mirrors = self.config.mirrors(&RemoteUrl::Single(url.clone()));
+            match get_file_from_system_or_remote(client, &mirrors, path, options).await {
+                Ok(()) => {
+                    println!(
+                        "{} Downloaded the subscription from {}",
+                        self.prefix.warning(),
+                        source
+                    );
+                    return Ok(source);
+                }
+                Err(backup_error) => {
+                    error = backup_error.context(format!("failed to download from {}", source))
+                }
+            }
+        }
+        Err(error)
+    }
+
+    /// Traffic and expiry of the subscription from `subscription.userinfo_url`, if defined.
+    async fn fetch_userinfo(&self, client: &Client) -> Result<Option<SubscriptionInfo>> {
+        let Some(url) = self
+            .config
+            .subscription
+            .as_ref()
+            .and_then(|subscription| subscription.userinfo_url.as_ref())
+        else {
+            return Ok(None);
+        };
+        let options = self.subscription_download_options()?;
+        let response = self
+            .subscription_client(client)?
+            .get(url)
+            .headers(options.headers)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("failed to GET from '{}'", url))?;
+        if let Some(subscription_info) = subscription_userinfo(response.headers()) {
+            return Ok(Some(subscription_info));
+        }
+        Ok(SubscriptionInfo::parse(&response.text().await?))
+    }
+
+    /// Extract the downloaded mihomo binary and swap it in, once it runs and reports the release
+    /// expected from `remote_mihomo_binary_url`. The installed version is recorded in state.
+    fn install_binary(&self, gzip_path: &str) -> Result<()> {
+        let staged_path = format!("{}.new", self.mihomo_target_binary_path);
+        extract_gzip(gzip_path, &staged_path, &self.prefix)?;
+        let executable = fs::Permissions::from_mode(0o755);
+        fs::set_permissions(&staged_path, executable)?;
+
+        let expected_version = release_version(self.config.remote_mihomo_binary_url.primary());
+        let checked = mihomo_version(&staged_path).and_then(|version| match expected_version {
+            Some(expected) if version_numbers(&version) != version_numbers(expected) => {
+                bail!("binary reports {}, expected {}", version, expected)
+            }
+            _ => Ok(version),
+        });
+        let version = match checked {
+            Ok(version) => version,
+            Err(error) => {
+                fs::remove_file(&staged_path)?;
+                return Err(error
+                    .context("downloaded mihomo binary failed the sanity check, not installed"));
+            }
+        };
+        self.backup_replaced(&self.mihomo_target_binary_path, &staged_path)?;
+        fs::rename(&staged_path, &self.mihomo_target_binary_path)?;
+
+        // File capabilities for TUN mode do not carry over to the new binary
+        let tun_enabled =
+            (self.config.mihomo_config.tun.as_ref()).is_some_and(|tun| tun.enable == Some(true));
+        let capabilities = tun_capabilities_script(&self.mihomo_target_binary_path);
+        if tun_enabled
+            && self.config.install_scope() == InstallScope::User
+            && !run_root_script(&capabilities)?.success()
+        {
+            bail!("failed to grant the mihomo binary capabilities for TUN mode");
+        }
+
+        let mut state = State::load(&self.mihomo_target_state_path)?;
+        state.mihomo_version = Some(version.clone());
+        state
+            .manifest
+            .insert(self.mihomo_target_binary_path.clone());
+        state.write(&self.mihomo_target_state_path)?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Installed mihomo {} to {}",
+                &[
+                    &version.bold(),
+                    &self.mihomo_target_binary_path.underline().yellow()
+                ]
+            )
+        );
+        Ok(())
+    }
+
     pub async fn setup(&self, client: Client) -> Result<()> {
         println!(
-            "{} Setting up mihomo's binary, config, and systemd service...",
-            &self.prefix.cyan()
+            "{} {}",
+            &self.prefix.info(),
+            tr("Setting up mihomo's binary, config, and systemd service...")
         );
 
         // Attempt to download and setup mihomo binary if needed
         if fs::metadata(&self.mihomo_target_binary_path).is_ok() {
             // If mihomo binary already exists at `mihomo_target_binary_path`, then skip setup
             println!(
-                "{} Assuming mihomo binary already installed at {}, skipping setup",
-                self.prefix.yellow(),
-                self.mihomo_target_binary_path.underline().green()
+                "{} {}",
+                self.prefix.warning(),
+                tr_args(
+                    "Assuming mihomo binary already installed at {}, skipping setup",
+                    &[&self.mihomo_target_binary_path.underline().green()]
+                )
             );
         } else {
             // Download mihomo binary and set permission to executable
-            get_file_from_system_or_remote(
+            self.get_verified_file(
                 &client,
                 &self.config.remote_mihomo_binary_url,
-                "mihomo-downloaded-binary.tar.gz",
+                DOWNLOADED_BINARY_ARCHIVE,
             )
-                .await?;
-            extract_gzip(
-                "mihomo-downloaded-binary.tar.gz",
-                &self.mihomo_target_binary_path,
-                &self.prefix,
-            )?;
-
-            let executable = fs::Permissions::from_mode(0o755);
-            fs::set_permissions(&self.mihomo_target_binary_path, executable)?;
+            .await?;
+            let installed = self.install_binary(DOWNLOADED_BINARY_ARCHIVE);
+            delete_file(DOWNLOADED_BINARY_ARCHIVE, &self.prefix)?;
+            installed?;
         }
 
         // Copy Or Download remote mihomo config and apply override
-        get_file_from_system_or_remote(
-            &client,
-            &self.config.remote_config_url,
-            &self.mihomo_target_config_path,
-        )
+        self.fetch_remote_config(&client, &self.mihomo_target_remote_config_path)
             .await?;
-
-        //Try to Decode base64 config if set
-        if self.config.remote_config_encoding == EncodingMode::Base64 {
-            try_decode_base64_and_overwrite_file(&self.mihomo_target_config_path)?;
-        }
-
-        apply_mihomo_override(&self.mihomo_target_config_path, &self.config.mihomo_config)?;
+        self.generate_config()?;
 
         // Download geodata
-        self.update_geodata(client).await?;
+        self.update_geodata(client.clone()).await?;
 
         // Create mihomo.service systemd file
+        let created = !Path::new(&self.mihomo_target_service_path).exists();
         create_mihomo_service(
-            &self.mihomo_target_binary_path,
-            &self.mihomo_target_config_root,
+            &self.mihomo_service_unit()?,
             &self.mihomo_target_service_path,
             &self.prefix,
         )?;
+        if created {
+            self.track(&[&self.mihomo_target_service_path])?;
+        }
+
+        self.systemctl().enable("mihomo.service").execute()?;
+        self.systemctl().start("mihomo.service").execute()?;
+        self.sync_resolved()?;
+        self.ensure_external_ui(&client).await?;
 
-        Systemctl::new().enable("mihomo.service").execute()?;
-        Systemctl::new().start("mihomo.service").execute()?;
+        // Record traffic periodically for `mihoro usage`
+        self.create_usage_timer()?;
+        self.systemctl().enable("mihoro-usage.timer").execute()?;
+        self.systemctl().start("mihoro-usage.timer").execute()?;
         Ok(())
     }
 
+    /// Update in one go: fetch the remote config, build mihomo's config and activate it, unless
+    /// both are unchanged since they were last activated or applied.
     pub async fn update(&self, client: Client) -> Result<()> {
-        // Download remote mihomo config and apply override
-        download_file(
-            &client,
-            &self.config.remote_config_url,
+        self.fetch(client.clone()).await?;
+        self.build()?;
+        if unchanged(
+            &self.mihomo_target_staged_remote_config_path,
+            &self.mihomo_target_remote_config_path,
+        )? && unchanged(
+            &self.mihomo_target_staged_config_path,
             &self.mihomo_target_config_path,
-        )
+        )? {
+            fs::remove_file(&self.mihomo_target_staged_remote_config_path)?;
+            fs::remove_file(&self.mihomo_target_staged_config_path)?;
+            println!(
+                "{} {}",
+                self.prefix.info(),
+                tr("Remote config and mihomo config unchanged, mihomo.service left running")
+            );
+            return Ok(());
+        }
+        self.activate(client).await
+    }
+
+    /// Download the remote config to `staging/`, leaving the running config untouched.
+    pub async fn fetch(&self, client: Client) -> Result<()> {
+        create_parent_dir(&self.mihomo_target_staged_remote_config_path)?;
+        self.fetch_remote_config(&client, &self.mihomo_target_staged_remote_config_path)
             .await?;
-        apply_mihomo_override(&self.mihomo_target_config_path, &self.config.mihomo_config)?;
         println!(
-            "{} Updated and applied config overrides",
-            self.prefix.yellow()
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Fetched remote config to {}",
+                &[&self.mihomo_target_staged_remote_config_path.underline()]
+            )
         );
+        Ok(())
+    }
 
-        // Restart mihomo systemd service
-        println!("{} Restart mihomo.service", self.prefix.green());
-        Systemctl::new().restart("mihomo.service").execute()?;
+    /// Build mihomo's config with overrides to `staging/`, from the remote config staged by
+    /// `fetch` or else the current one.
+    pub fn build(&self) -> Result<()> {
+        let source = [
+            &self.mihomo_target_staged_remote_config_path,
+            &self.mihomo_target_remote_config_path,
+            &self.mihomo_target_config_path,
+        ]
+        .into_iter()
+        .find(|path| Path::new(path).exists())
+        .context("no remote config to build from, run `mihoro fetch` first")?;
+        let mihomo_yaml = build_mihomo_config(
+            source,
+            Path::new(&self.mihomo_target_config_root),
+            &self.config,
+            &self.profile,
+        )?;
+        create_parent_dir(&self.mihomo_target_staged_config_path)?;
+        fs::write(
+            &self.mihomo_target_staged_config_path,
+            serde_yaml::to_string(&mihomo_yaml)?,
+        )?;
+        self.assign_free_ports(&self.mihomo_target_staged_config_path, &self.held_ports()?)?;
+        self.track(&[&self.mihomo_target_staged_config_path])?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Built config with overrides to {}",
+                &[&self.mihomo_target_staged_config_path.underline()]
+            )
+        );
+        Ok(())
+    }
+
+    /// Print the config `generate_config` would write, each value annotated with the step of
+    /// building it that set the value, or only the top-level `field` if given.
+    pub fn inspect(&self, field: Option<&str>) -> Result<()> {
+        let source = [
+            &self.mihomo_target_remote_config_path,
+            &self.mihomo_target_config_path,
+        ]
+        .into_iter()
+        .find(|path| Path::new(path).exists())
+        .context("no remote config to inspect, run `mihoro setup` first")?;
+        let mut trace = Trace::default();
+        trace_mihomo_config(
+            source,
+            Path::new(&self.mihomo_target_config_root),
+            &self.config,
+            &self.profile,
+            Some(&mut trace),
+        )?;
+        let annotated = trace.annotate(field)?;
+        if annotated.is_empty() {
+            if let Some(field) = field {
+                bail!("field `{}` is not in the generated config", field);
+            }
+        }
+        println!("{}", annotated);
+        Ok(())
+    }
+
+    /// Print results of a jq-like `filter` over mihomo's generated `config.yaml`, one per line.
+    pub fn query(&self, filter: &str) -> Result<()> {
+        let query = Query::parse(filter)?;
+        let raw_mihomo_yaml = fs::read_to_string(&self.mihomo_target_config_path)
+            .with_context(|| format!("failed to read {}", self.mihomo_target_config_path))?;
+        let mihomo_yaml: serde_yaml::Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+        for result in query.run(&mihomo_yaml)? {
+            println!("{}", format_result(&result)?);
+        }
         Ok(())
     }
 
+    /// Test the config staged by `build` with mihomo, then swap it in along with the staged
+    /// remote config and restart mihomo.service. With `rollback_sec` set, the previous configs are
+    /// restored if mihomo does not come up with the new one in time.
+    pub async fn activate(&self, client: Client) -> Result<()> {
+        if !Path::new(&self.mihomo_target_staged_config_path).exists() {
+            bail!("no staged config to activate, run `mihoro build` first");
+        }
+        if Path::new(&self.mihomo_target_binary_path).exists() {
+            test_mihomo_config(
+                &self.mihomo_target_binary_path,
+                &self.mihomo_target_config_root,
+                &self.mihomo_target_staged_config_path,
+            )?;
+        } else {
+            println!(
+                "{} {}",
+                self.prefix.warning(),
+                tr("mihomo is not installed, activating without testing the config")
+            );
+        }
+
+        // Temporary routes are dropped along with the running config
+        if Path::new(&self.mihomo_target_temporary_rules_path).exists() {
+            fs::remove_file(&self.mihomo_target_temporary_rules_path)?;
+        }
+        self.backup_replaced(
+            &self.mihomo_target_config_path,
+            &self.mihomo_target_staged_config_path,
+        )?;
+        let previous: Vec<(&String, Option<Vec<u8>>)> = [
+            &self.mihomo_target_config_path,
+            &self.mihomo_target_remote_config_path,
+        ]
+        .into_iter()
+        .map(|path| (path, fs::read(path).ok()))
+        .collect();
+        if Path::new(&self.mihomo_target_staged_remote_config_path).exists() {
+            fs::rename(
+                &self.mihomo_target_staged_remote_config_path,
+                &self.mihomo_target_remote_config_path,
+            )?;
+            // Refresh imported rulesets along with the remote config
+            clear_ruleset_cache(
+                &self.mihomo_target_config_root,
+                &LocalRules::load_expanded(&self.mihomo_target_local_rules_path, &self.template())?,
+            )?;
+        }
+        fs::rename(
+            &self.mihomo_target_staged_config_path,
+            &self.mihomo_target_config_path,
+        )?;
+        let created: Vec<&String> = previous
+            .iter()
+            .filter(|(path, contents)| contents.is_none() && Path::new(path).exists())
+            .map(|(path, _)| *path)
+            .collect();
+        self.track(&created)?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr("Activated staged config")
+        );
+
+        // Restart mihomo systemd service
+        println!("{} {}", self.prefix.success(), tr("Restart mihomo.service"));
+        self.systemctl().restart("mihomo.service").execute()?;
+        if let Some(rollback_sec) = self.config.rollback_sec {
+            let api = self.api(client.clone())?;
+            let timeout = Duration::from_secs(rollback_sec);
+            if !self.wait_until_healthy(&api, timeout).await? {
+                return self.roll_back(&api, &previous, rollback_sec).await;
+            }
+        }
+        self.sync_resolved()?;
+        self.ensure_external_ui(&client).await
+    }
+
+    /// Whether mihomo.service comes up within `timeout` of a restart: active, with its external
+    /// controller passing `HEALTHY_CHECKS` consecutive health checks, so that a mihomo restarted
+    /// over and over by systemd does not pass.
+    async fn wait_until_healthy(&self, api: &MihomoApi, timeout: Duration) -> Result<bool> {
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut checks = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        let mut passed = 0;
+        while tokio::time::Instant::now() < deadline {
+            checks.tick().await;
+            let responding = matches!(
+                tokio::time::timeout(HEALTH_CHECK_INTERVAL, api.version()).await,
+                Ok(Ok(_))
+            );
+            if responding
+                && self
+                    .systemctl()
+                    .is_active("mihomo.service")
+                    .execute()?
+                    .success()
+            {
+                passed += 1;
+                if passed == HEALTHY_CHECKS {
+                    return Ok(true);
+                }
+            } else {
+                passed = 0;
+            }
+        }
+        Ok(false)
+    }
+
+    /// Move the activated configs back to `staging/` for inspection, restore `previous` ones and
+    /// restart mihomo.service with them, failing with what happened.
+    async fn roll_back(
+        &self,
+        api: &MihomoApi,
+        previous: &[(&String, Option<Vec<u8>>)],
+        rollback_sec: u64,
+    ) -> Result<()> {
+        eprintln!(
+            "{} {}",
+            self.prefix.error(),
+            tr_args(
+                "mihomo.service did not come up within {}s, rolling back",
+                &[&rollback_sec]
+            )
+        );
+        let had_config = previous
+            .iter()
+            .any(|(path, content)| **path == self.mihomo_target_config_path && content.is_some());
+        if !had_config {
+            bail!(
+                "mihomo did not come up with the activated config, and there is no previous \
+                 config to roll back to"
+            );
+        }
+        for (path, content) in previous {
+            if fs::read(path).ok() == *content {
+                continue;
+            }
+            let staged_path = if **path == self.mihomo_target_config_path {
+                &self.mihomo_target_staged_config_path
+            } else {
+                &self.mihomo_target_staged_remote_config_path
+            };
+            if Path::new(path).exists() {
+                fs::rename(path, staged_path)?;
+            }
+            if let Some(content) = content {
+                fs::write(path, content)?;
+            }
+        }
+        self.systemctl().restart("mihomo.service").execute()?;
+        self.sync_resolved()?;
+
+        let timeout = Duration::from_secs(rollback_sec);
+        if self.wait_until_healthy(api, timeout).await? {
+            bail!(
+                "mihomo did not come up with the activated config, restored the previous config \
+                 (the activated one is kept in {})",
+                self.mihomo_target_staged_config_path
+            );
+        }
+        bail!(
+            "mihomo did not come up with the activated config, nor with the restored previous \
+             config, check `mihoro log`"
+        );
+    }
+
     pub async fn update_geodata(&self, client: Client) -> Result<()> {
         if let Some(geox_url) = self.config.mihomo_config.geox_url.clone() {
             // Download geodata files based on `geodata_mode`
             let geodata_mode = self.config.mihomo_config.geodata_mode.unwrap_or(false);
-            if geodata_mode {
-                get_file_from_system_or_remote(
-                    &client,
-                    &geox_url.geoip,
-                    format!("{}/geoip.dat", &self.mihomo_target_config_root).as_str(),
-                )
-                    .await?;
-                get_file_from_system_or_remote(
-                    &client,
-                    &geox_url.geosite,
-                    format!("{}/geosite.dat", &self.mihomo_target_config_root).as_str(),
-                )
-                    .await?;
+            let geodata = if geodata_mode {
+                vec![
+                    (&geox_url.geoip, "geoip.dat"),
+                    (&geox_url.geosite, "geosite.dat"),
+                ]
             } else {
-                get_file_from_system_or_remote(
-                    &client,
-                    &geox_url.mmdb,
-                    format!("{}/country.mmdb", &self.mihomo_target_config_root).as_str(),
-                )
-                    .await?;
+                vec![(&geox_url.mmdb, "country.mmdb")]
+            };
+            for (url, file_name) in geodata {
+                let path = format!("{}/{}", &self.mihomo_target_config_root, file_name);
+                let created = !Path::new(&path).exists();
+                self.get_verified_file(&client, url, &path).await?;
+                if created {
+                    self.track(&[&path])?;
+                }
             }
 
-            println!("{} Downloaded and updated geodata", self.prefix.green());
+            println!(
+                "{} {}",
+                self.prefix.success(),
+                tr("Downloaded and updated geodata")
+            );
         } else {
             println!(
-                "{} `geox_url` undefined, refer to {}",
-                self.prefix.yellow(),
-                "'https://wiki.metacubex.one/config/general/#geo_3'"
-                    .bold()
-                    .underline()
+                "{} {}",
+                self.prefix.warning(),
+                tr_args(
+                    "`geox_url` undefined, refer to {}",
+                    &[&"'https://wiki.metacubex.one/config/general/#geo_3'"
+                        .bold()
+                        .underline()]
+                )
             );
         }
         Ok(())
     }
 
-    pub async fn apply(&self) -> Result<()> {
+    pub async fn apply(&self, client: Client) -> Result<()> {
         // Apply mihomo config override
-        apply_mihomo_override(&self.mihomo_target_config_path, &self.config.mihomo_config).map(
-            |_| {
-                println!(
-                    "{} Applied mihomo config overrides",
-                    self.prefix.green().bold()
-                );
-            },
-        )?;
+        self.generate_config().map(|_| {
+            println!(
+                "{} {}",
+                self.prefix.success().bold(),
+                tr("Applied mihomo config overrides")
+            );
+        })?;
+
+        // Pick up changes of `watchdog_sec` in mihomo.service
+        let service = self.mihomo_service_unit()?;
+        if fs::read_to_string(&self.mihomo_target_service_path).is_ok_and(|old| old != service) {
+            create_mihomo_service(&service, &self.mihomo_target_service_path, &self.prefix)?;
+            self.systemctl().daemon_reload().execute()?;
+        }
 
         // Restart mihomo systemd service
-        Systemctl::new()
+        self.systemctl()
             .restart("mihomo.service")
             .execute()
             .map(|_| {
-                println!("{} Restarted mihomo.service", self.prefix.green().bold());
+                println!(
+                    "{} {}",
+                    self.prefix.success().bold(),
+                    tr("Restarted mihomo.service")
+                );
             })?;
-        Ok(())
+        self.sync_resolved()?;
+        self.ensure_external_ui(&client).await
     }
 
-    pub fn uninstall(&self) -> Result<()> {
-        Systemctl::new().stop("mihomo.service").execute()?;
-        Systemctl::new().disable("mihomo.service").execute()?;
+    /// Directory of the web UI downloaded from `external_ui_url`: `external_ui_name` under
+    /// `external_ui`, relative to mihomo's config root as resolved by mihomo.
+    fn external_ui_dir(&self) -> Option<PathBuf> {
+        let mihomo_config = &self.config.mihomo_config;
+        let ui_dir =
+            Path::new(&self.mihomo_target_config_root).join(mihomo_config.external_ui.as_ref()?);
+        Some(match &mihomo_config.external_ui_name {
+            Some(name) => ui_dir.join(name),
+            None => ui_dir,
+        })
+    }
 
-        delete_file(&self.mihomo_target_service_path, &self.prefix)?;
-        delete_file(&self.mihomo_target_config_path, &self.prefix)?;
+    /// Check that mihomo, just restarted, downloaded its web UI from `external_ui_url`, and
+    /// install it with mihoro instead if not done within `UI_DOWNLOAD_TIMEOUT`.
+    async fn ensure_external_ui(&self, client: &Client) -> Result<()> {
+        let Some(url) = &self.config.mihomo_config.external_ui_url else {
+            return Ok(());
+        };
+        let Some(ui_dir) = self.external_ui_dir() else {
+            println!(
+                "{} `external_ui_url` is ignored by mihomo without `external_ui`",
+                self.prefix.warning()
+            );
+            return Ok(());
+        };
+        let deadline = tokio::time::Instant::now() + UI_DOWNLOAD_TIMEOUT;
+        while !ui_installed(&ui_dir) {
+            if tokio::time::Instant::now() >= deadline {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+        if ui_installed(&ui_dir) {
+            return Ok(());
+        }
 
-        Systemctl::new().daemon_reload().execute()?;
-        Systemctl::new().reset_failed().execute()?;
         println!(
-            "{} Disabled and reloaded systemd services",
-            self.prefix.green()
+            "{} {}",
+            self.prefix.warning(),
+            tr_args(
+                "mihomo did not download the web UI from {}, installing it with mihoro",
+                &[&url.underline()]
+            )
+        );
+        let archive_path = format!("{}/external-ui.archive", self.mihomo_target_config_root);
+        let mirrors = self.config.mirrors(&RemoteUrl::Single(url.clone()));
+        get_file_from_system_or_remote(client, &mirrors, &archive_path, &self.download_options())
+            .await?;
+        let extracted = extract_ui(Path::new(&archive_path), &ui_dir);
+        fs::remove_file(&archive_path)?;
+        let files: Vec<String> = extracted?
+            .iter()
+            .map(|path| path.to_string_lossy().to_string())
+            .collect();
+        self.track(&files.iter().collect::<Vec<_>>())?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Installed web UI to {}",
+                &[&ui_dir.display().to_string().underline().yellow()]
+            )
+        );
+        Ok(())
+    }
+
+    /// Stop and disable mihomo.service, then remove all files created by mihoro after listing them
+    /// and asking for confirmation (unless `yes`).
+    ///
+    /// Files are taken from the state manifest, along with the default files of installs that
+    /// predate it. The local rules and proxies fragments hold user edits and are only removed with
+    /// `purge`. The config root is removed as well once empty.
+    pub fn uninstall(&self, yes: bool, purge: bool) -> Result<()> {
+        let state = State::load(&self.mihomo_target_state_path)?;
+        let mut files = state.manifest;
+        files.extend(
+            [
+                &self.mihomo_target_binary_path,
+                &self.mihomo_target_service_path,
+                &self.mihomo_target_config_path,
+                &self.mihomo_target_remote_config_path,
+                &self.mihomo_target_local_rules_path,
+                &self.mihomo_target_local_proxies_path,
+                &self.mihomo_target_temporary_rules_path,
+                &self.mihomo_target_usage_path,
+                &self.mihomo_target_log_path,
+                &self.mihoro_usage_service_path,
+                &self.mihoro_usage_timer_path,
+            ]
+            .map(String::from),
         );
+        // Left in the working directory by `mihoro setup` before it was removed after installing
+        files.insert(DOWNLOADED_BINARY_ARCHIVE.to_string());
+        for file_name in ["geoip.dat", "geosite.dat", "country.mmdb", "cache.db"] {
+            files.insert(format!("{}/{}", self.mihomo_target_config_root, file_name));
+        }
+        if let Ok(backups) = fs::read_dir(&self.mihomo_target_backups_root) {
+            for backup in backups {
+                files.insert(backup?.path().to_string_lossy().to_string());
+            }
+        }
+        let local_rules =
+            LocalRules::load_expanded(&self.mihomo_target_local_rules_path, &self.template())?;
+        for cache_path in ruleset_cache_paths(&self.mihomo_target_config_root, &local_rules) {
+            files.insert(cache_path.to_string_lossy().to_string());
+        }
+        files.retain(|file| Path::new(file).is_file());
+        let fragments = [
+            &self.mihomo_target_local_rules_path,
+            &self.mihomo_target_local_proxies_path,
+        ];
+        let kept: Vec<&String> = fragments
+            .into_iter()
+            .filter(|fragment| !purge && files.contains(fragment.as_str()))
+            .collect();
+        files.retain(|file| !kept.contains(&file));
+        files.insert(self.mihomo_target_state_path.clone());
+        let download_cache = DownloadCache::new(cache_root());
+
         println!(
-            "{} You may need to remove mihomo binary and config directory manually",
-            self.prefix.yellow()
+            "{} {}",
+            self.prefix.warning(),
+            tr("Stopping mihomo.service and removing:")
         );
+        for file in &files {
+            println!("  {} {}", "-".dimmed(), file.underline());
+        }
+        if download_cache.root().exists() {
+            let cache_root = download_cache.root().display().to_string();
+            println!("  {} {}/", "-".dimmed(), cache_root.underline());
+        }
+        for fragment in &kept {
+            println!(
+                "{} {}",
+                self.prefix.info(),
+                tr_args(
+                    "Keeping {}, pass `--purge` to remove it",
+                    &[&fragment.underline()]
+                )
+            );
+        }
+        if !yes && !confirm(tr("Uninstall?"))? {
+            println!("{} {}", self.prefix.warning(), tr("Uninstall cancelled"));
+            return Ok(());
+        }
+
+        self.revert_resolved()?;
+        if Path::new(NM_DISPATCHER_PATH).exists() {
+            self.nm_dispatcher_commands(&Some(NmDispatcherCommands::Disable))?;
+        }
+        self.systemctl().stop("mihomo.service").execute()?;
+        self.systemctl().disable("mihomo.service").execute()?;
+        if Path::new(&self.mihoro_usage_timer_path).exists() {
+            self.systemctl().stop("mihoro-usage.timer").execute()?;
+            self.systemctl().disable("mihoro-usage.timer").execute()?;
+        }
+
+        for file in &files {
+            delete_file(file, &self.prefix)?;
+        }
+        download_cache.clear()?;
 
-        let remove_cmd = format!(
-            "rm -R {} {}",
-            self.mihomo_target_binary_path, self.mihomo_target_config_root
+        self.systemctl().daemon_reload().execute()?;
+        self.systemctl().reset_failed().execute()?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr("Disabled and reloaded systemd services")
         );
-        println!("{} `{}`", "->".dimmed(), remove_cmd.underline().bold());
+
+        // Directories such as `ruleset/`, `conf.d/` and `patches/` are kept if not empty
+        let config_root = Path::new(&self.mihomo_target_config_root);
+        remove_empty_dirs(config_root)?;
+        if config_root.exists() {
+            println!(
+                "{} {}",
+                self.prefix.warning(),
+                tr_args(
+                    "Kept {} with files not created by mihoro",
+                    &[&self.mihomo_target_config_root.underline()]
+                )
+            );
+        }
         Ok(())
     }
 
-    pub fn proxy_commands(&self, proxy: &Option<ProxyCommands>) -> Result<()> {
-        // `mixed_port` takes precedence over `port` and `socks_port` for proxy export
-        let port = self
-            .config
-            .mihomo_config
-            .mixed_port
-            .as_ref()
-            .unwrap_or(&self.config.mihomo_config.port);
-        let socks_port = self
-            .config
-            .mihomo_config
-            .mixed_port
-            .as_ref()
-            .unwrap_or(&self.config.mihomo_config.socks_port);
+    /// Remove the download cache, backups beyond `backup_retention`, quarantined downloads and
+    /// leftovers of interrupted downloads, reporting reclaimed disk space.
+    pub fn clean(&self) -> Result<()> {
+        let retention = self.config.backup_retention();
+        let backups_root = Path::new(&self.mihomo_target_backups_root);
+        let mut stale_files = Vec::new();
+        for path in [
+            &self.mihomo_target_config_path,
+            &self.mihomo_target_binary_path,
+        ] {
+            let Some(file_name) = Path::new(path).file_name() else {
+                continue;
+            };
+            let backups = list_backups(backups_root, &file_name.to_string_lossy())?;
+            stale_files.extend(backups.into_iter().skip(retention));
+        }
 
-        match proxy {
-            Some(ProxyCommands::Export) => {
-                println!("{}", proxy_export_cmd("127.0.0.1", port, socks_port))
-            }
-            Some(ProxyCommands::ExportLan) => {
-                if !self.config.mihomo_config.allow_lan.unwrap_or(false) {
-                    println!(
-                        "{} `{}` is false, proxy is not available for LAN",
-                        "warning:".yellow(),
-                        "allow_lan".bold()
-                    );
+        let leftover_suffixes = [
+            ".quarantine.yaml",
+            ".part",
+            ".new",
+            ".unverified",
+            ".minisig",
+        ];
+        if let Ok(entries) = fs::read_dir(&self.mihomo_target_config_root) {
+            for entry in entries {
+                let path = entry?.path();
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                if path.is_file()
+                    && leftover_suffixes
+                        .iter()
+                        .any(|suffix| file_name.ends_with(suffix))
+                {
+                    stale_files.push(path);
                 }
-
-                println!(
-                    "{}",
-                    proxy_export_cmd(&local_ip()?.to_string(), port, socks_port)
-                );
             }
-            Some(ProxyCommands::Unset) => {
-                println!("{}", proxy_unset_cmd())
-            }
-            _ => (),
         }
+        let staged_binary = PathBuf::from(format!("{}.new", self.mihomo_target_binary_path));
+        if staged_binary.is_file() {
+            stale_files.push(staged_binary);
+        }
+
+        let download_cache = DownloadCache::new(cache_root());
+        let mut reclaimed = download_cache.clear()?;
+        if reclaimed > 0 {
+            println!(
+                "{} {}",
+                self.prefix.info(),
+                tr_args(
+                    "Removed download cache {}",
+                    &[&download_cache
+                        .root()
+                        .display()
+                        .to_string()
+                        .underline()
+                        .yellow()]
+                )
+            );
+        }
+        for stale_file in stale_files {
+            reclaimed += fs::metadata(&stale_file)?.len();
+            delete_file(&stale_file.to_string_lossy(), &self.prefix)?;
+        }
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args("Reclaimed {}", &[&HumanBytes(reclaimed).to_string().bold()])
+        );
         Ok(())
     }
-}
 
-/// Create a systemd service file for running mihomo as a service.
-///
-/// By default, user systemd services are created under `~/.config/systemd/user/mihomo.service` and
-/// invoked with `systemctl --user start mihomo.service`. Directory is created if not present.
+    /// Back up `path` before `replacement` takes its place, unless the contents are unchanged.
+    fn backup_replaced(&self, path: &str, replacement: &str) -> Result<()> {
+        if fs::read(path).ok() == fs::read(replacement).ok() {
+            return Ok(());
+        }
+        backup_file(
+            path,
+            Path::new(&self.mihomo_target_backups_root),
+            self.config.backup_retention(),
+        )
+    }
+
+    /// Record files created by mihoro in the state manifest, to be removed on uninstall.
+    ///
+    /// Only called when mihoro creates a file, so that rewriting it (e.g. `config.yaml` on every
+    /// apply) does not read and write the state again.
+    fn track(&self, paths: &[&String]) -> Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let mut state = State::load(&self.mihomo_target_state_path)?;
+        let untracked: Vec<String> = paths
+            .iter()
+            .filter(|path| !state.manifest.contains(path.as_str()))
+            .map(|path| path.to_string())
+            .collect();
+        if untracked.is_empty() {
+            return Ok(());
+        }
+        state.manifest.extend(untracked);
+        state.write(&self.mihomo_target_state_path)
+    }
+
+    /// Http and socks proxy ports, `mixed_port` takes precedence over `port` and `socks_port`.
+    /// With `auto_ports`, ports are read from the generated `config.yaml`, which may differ.
+    fn proxy_ports(&self) -> (u16, u16) {
+        let mihomo_config = &self.config.mihomo_config;
+        let mut ports = BTreeMap::from([
+            ("port", mihomo_config.port),
+            ("socks-port", mihomo_config.socks_port),
+        ]);
+        if let Some(mixed_port) = mihomo_config.mixed_port {
+            ports.insert("mixed-port", mixed_port);
+        }
+        if self.config.auto_ports.unwrap_or(false) {
+            let generated = fs::read_to_string(&self.mihomo_target_config_path)
+                .ok()
+                .and_then(|raw| serde_yaml::from_str::<serde_yaml::Value>(&raw).ok());
+            if let Some(generated) = generated {
+                ports.extend(config_ports(&generated));
+            }
+        }
+        match ports.get("mixed-port") {
+            Some(mixed_port) => (*mixed_port, *mixed_port),
+            None => (ports["port"], ports["socks-port"]),
+        }
+    }
+
+    /// Run `command` with `http_proxy`, `https_proxy`, `all_proxy` and `no_proxy` pointing to
+    /// mihomo, leaving the calling shell untouched.
+    pub fn exec(&self, command: &[String]) -> Result<ExitStatus> {
+        let (port, socks_port) = self.proxy_ports();
+        let bypass = self.config.bypass.as_deref().unwrap_or_default();
+        let (program, args) = command.split_first().context("no command given")?;
+        Command::new(program)
+            .args(args)
+            .envs(proxy_env("127.0.0.1", &port, &socks_port, bypass))
+            .status()
+            .with_context(|| format!("failed to run `{}`", program))
+    }
+
+    pub fn proxy_commands(&self, proxy: &Option<ProxyCommands>) -> Result<()> {
+        let (port, socks_port) = self.proxy_ports();
+        let bypass = self.config.bypass.as_deref().unwrap_or_default();
+
+        match proxy {
+            Some(ProxyCommands::Export) => {
+                println!(
+                    "{}",
+                    proxy_export_cmd("127.0.0.1", &port, &socks_port, bypass)
+                )
+            }
+            Some(ProxyCommands::ExportLan) => {
+                if !self.config.mihomo_config.allow_lan.unwrap_or(false) {
+                    println!(
+                        "{} `{}` is false, proxy is not available for LAN",
+                        "warning:".yellow(),
+                        "allow_lan".bold()
+                    );
+                }
+
+                println!(
+                    "{}",
+                    proxy_export_cmd(&local_ip()?.to_string(), &port, &socks_port, bypass)
+                );
+            }
+            Some(ProxyCommands::Unset) => {
+                println!("{}", proxy_unset_cmd())
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    pub fn browser_policy(
+        &self,
+        browser: Browser,
+        pac: &Option<String>,
+        lan: bool,
+        output: &Option<String>,
+    ) -> Result<()> {
+        let (port, socks_port) = self.proxy_ports();
+        let hostname = if lan {
+            local_ip()?.to_string()
+        } else {
+            String::from("127.0.0.1")
+        };
+        let proxy = match pac {
+            Some(url) => PolicyProxy::Pac(url),
+            None => PolicyProxy::Fixed {
+                hostname: &hostname,
+                http_port: port,
+                socks_port,
+                bypass: self.config.bypass.as_deref().unwrap_or_default(),
+            },
+        };
+        let policy = match browser {
+            Browser::Chrome => chrome_policy(&proxy),
+            Browser::Firefox => firefox_policy(&proxy),
+        };
+        let policy = serde_json::to_string_pretty(&policy)?;
+        match output {
+            Some(output) => {
+                fs::write(output, format!("{}\n", policy))?;
+                println!(
+                    "{} {}",
+                    self.prefix.success(),
+                    tr_args(
+                        "Generated browser policy at {}",
+                        &[&output.underline().yellow()]
+                    )
+                );
+            }
+            None => println!("{}", policy),
+        }
+        Ok(())
+    }
+
+    /// Point the system proxy to mihomo or restore it, through `networksetup` on macOS and the
+    /// desktop's proxy settings on Linux.
+    pub fn sysproxy_commands(&self, sysproxy: &Option<SysproxyCommands>) -> Result<()> {
+        let on = match sysproxy {
+            Some(SysproxyCommands::On) => true,
+            Some(SysproxyCommands::Off) => false,
+            None => return Ok(()),
+        };
+        let mut state = State::load(&self.mihomo_target_state_path)?;
+        if cfg!(target_os = "macos") {
+            self.macos_sysproxy(on, &mut state)
+        } else {
+            self.desktop_sysproxy(on, &mut state)
+        }
+    }
+
+    fn macos_sysproxy(&self, on: bool, state: &mut State) -> Result<()> {
+        if on {
+            let services = active_services()?;
+            if services.is_empty() {
+                bail!("no active network service found");
+            }
+            let (port, socks_port) = self.proxy_ports();
+            let proxies = ServiceProxies {
+                web: ProxySetting::local(port),
+                secure_web: ProxySetting::local(port),
+                socks: ProxySetting::local(socks_port),
+                bypass: self.config.bypass.clone().unwrap_or_default(),
+            };
+            for service in &services {
+                // Keep settings saved by an earlier `on`, which are the ones to restore
+                if !state.sysproxy_restore.contains_key(service) {
+                    let previous = ServiceProxies::read(service)?;
+                    state.sysproxy_restore.insert(service.clone(), previous);
+                    state.write(&self.mihomo_target_state_path)?;
+                }
+                proxies.apply(service)?;
+            }
+            println!(
+                "{} {}",
+                self.prefix.success(),
+                tr_args("Set system proxy of {}", &[&services.join(", ").bold()])
+            );
+            return Ok(());
+        }
+
+        if state.sysproxy_restore.is_empty() {
+            println!(
+                "{} {}",
+                self.prefix.warning(),
+                tr("System proxy is not set by mihoro")
+            );
+            return Ok(());
+        }
+        let services: Vec<String> = state.sysproxy_restore.keys().cloned().collect();
+        for service in &services {
+            state.sysproxy_restore[service].apply(service)?;
+            state.sysproxy_restore.remove(service);
+            state.write(&self.mihomo_target_state_path)?;
+        }
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Restored system proxy of {}",
+                &[&services.join(", ").bold()]
+            )
+        );
+        Ok(())
+    }
+
+    fn desktop_sysproxy(&self, on: bool, state: &mut State) -> Result<()> {
+        if on {
+            let Some(desktop) = Desktop::detect() else {
+                bail!("no supported desktop found, GNOME (gsettings) or KDE is required");
+            };
+            let (port, socks_port) = self.proxy_ports();
+            let bypass = self.config.bypass.as_deref().unwrap_or_default();
+            let settings = desktop.settings("127.0.0.1", port, socks_port, bypass);
+            // Keep settings saved by an earlier `on`, which are the ones to restore
+            if state.desktop_proxy_restore.is_none() {
+                let mut previous = BTreeMap::new();
+                for (key, _) in &settings {
+                    previous.insert(key.to_string(), desktop.read(key)?);
+                }
+                state.desktop_proxy_restore = Some(DesktopProxies {
+                    desktop,
+                    settings: previous,
+                });
+                state.write(&self.mihomo_target_state_path)?;
+            }
+            for (key, value) in &settings {
+                desktop.write(key, value)?;
+            }
+            desktop.reload();
+            println!(
+                "{} {}",
+                self.prefix.success(),
+                tr_args("Set {} proxy settings", &[&desktop.as_str().bold()])
+            );
+            return Ok(());
+        }
+
+        let Some(saved) = &state.desktop_proxy_restore else {
+            println!(
+                "{} {}",
+                self.prefix.warning(),
+                tr("System proxy is not set by mihoro")
+            );
+            return Ok(());
+        };
+        for (key, value) in &saved.settings {
+            saved.desktop.write(key, value)?;
+        }
+        saved.desktop.reload();
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Restored {} proxy settings",
+                &[&saved.desktop.as_str().bold()]
+            )
+        );
+        state.desktop_proxy_restore = None;
+        state.write(&self.mihomo_target_state_path)
+    }
+
+    pub async fn pac_commands(&self, pac: &Option<PacCommands>) -> Result<()> {
+        let (port, socks_port) = self.proxy_ports();
+        let bypass = self.config.bypass.as_deref().unwrap_or_default();
+
+        match pac {
+            Some(PacCommands::Generate { output, lan }) => {
+                let hostname = if *lan {
+                    local_ip()?.to_string()
+                } else {
+                    String::from("127.0.0.1")
+                };
+                let pac = pac_file(&hostname, &port, &socks_port, bypass);
+                match output {
+                    Some(output) => {
+                        fs::write(output, pac)?;
+                        println!(
+                            "{} {}",
+                            self.prefix.success(),
+                            tr_args("Generated PAC file at {}", &[&output.underline().yellow()])
+                        );
+                    }
+                    None => print!("{}", pac),
+                }
+            }
+            Some(PacCommands::Serve {
+                port: pac_port,
+                lan,
+            }) => {
+                let (hostname, listen) = if *lan {
+                    (local_ip()?.to_string(), "0.0.0.0")
+                } else {
+                    (String::from("127.0.0.1"), "127.0.0.1")
+                };
+                let pac = pac_file(&hostname, &port, &socks_port, bypass);
+                println!(
+                    "{} Serving PAC file at {}",
+                    self.prefix.success(),
+                    format!("http://{}:{}/proxy.pac", hostname, pac_port).underline()
+                );
+                serve(&format!("{}:{}", listen, pac_port), move |target, _| {
+                    if target == "/proxy.pac" {
+                        Response::ok(PAC_CONTENT_TYPE, pac.clone())
+                    } else {
+                        Response::not_found()
+                    }
+                })
+                .await?;
+            }
+            None => (),
+        }
+        Ok(())
+    }
+
+    pub async fn share(&self, port: u16, share_config: bool) -> Result<()> {
+        if !self.config.mihomo_config.allow_lan.unwrap_or(false) {
+            println!(
+                "{} `{}` is false, proxy is not available for LAN",
+                "warning:".yellow(),
+                "allow_lan".bold()
+            );
+        }
+
+        let hostname = local_ip()?.to_string();
+        let (content_type, body) = if share_config {
+            (
+                "text/yaml",
+                sanitized_mihomo_config(&self.mihomo_target_config_path)?,
+            )
+        } else {
+            let (http_port, socks_port) = self.proxy_ports();
+            (
+                "text/plain",
+                format!("http://{hostname}:{http_port}\nsocks5://{hostname}:{socks_port}\n"),
+            )
+        };
+
+        // The token is consumed by the first successful request, after which sharing stops
+        let token = random_token(16)?;
+        let path = format!("/{}", token);
+        let (consumed_tx, consumed_rx) = oneshot::channel();
+        let consumed_tx = Arc::new(Mutex::new(Some(consumed_tx)));
+        println!(
+            "{} Sharing {} at {}",
+            self.prefix.success(),
+            if share_config {
+                "config"
+            } else {
+                "proxy details"
+            },
+            format!("http://{}:{}{}", hostname, port, path).underline()
+        );
+
+        let handler = move |target: &str, peer: &str| {
+            if target != path {
+                return Response::error(403, "invalid token");
+            }
+            match consumed_tx.lock().ok().and_then(|mut tx| tx.take()) {
+                Some(tx) => {
+                    let _ = tx.send(peer.to_string());
+                    Response::ok(content_type, body.clone())
+                }
+                None => Response::error(410, "token already used"),
+            }
+        };
+        let listen = format!("0.0.0.0:{}", port);
+        tokio::select! {
+            served = serve(&listen, handler) => served?,
+            Ok(peer) = consumed_rx => {
+                // Give the response a moment to be flushed before exiting
+                tokio::time::sleep(Duration::from_millis(500)).await;
+                println!("{} Shared with {}, token revoked", self.prefix.success(), peer.bold());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn qr_commands(&self, qr: &Option<QrCommands>) -> Result<()> {
+        let content = match qr {
+            Some(QrCommands::Node { name }) => {
+                node_to_uri(&find_node(&self.mihomo_target_config_path, name)?)?
+            }
+            Some(QrCommands::Subscription) => self.config.remote_config_url.primary().to_string(),
+            None => return Ok(()),
+        };
+
+        // Render light modules as dark, which scans better on dark terminal backgrounds
+        let qr_code = QrCode::new(content.as_bytes())?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build();
+        println!("{}", qr_code);
+        println!("{} {}", "->".dimmed(), content.underline());
+        Ok(())
+    }
+
+    /// Look up the GeoSite categories of a domain in the database mihomo loads, and the `GEOSITE`
+    /// rules of the generated config they would match, in order.
+    pub fn geosite_commands(&self, geosite: &Option<GeositeCommands>) -> Result<()> {
+        let Some(GeositeCommands::Lookup { domain }) = geosite else {
+            return Ok(());
+        };
+        let Some(path) = find_geosite(&self.mihomo_target_config_root) else {
+            bail!(
+                "no {} in {}, downloaded by mihomo for `GEOSITE` rules, or by `mihoro \
+                 update-geodata` with `geodata_mode = true`",
+                GEOSITE_FILE,
+                self.mihomo_target_config_root
+            );
+        };
+        let categories = lookup(&path, domain)?;
+        if categories.is_empty() {
+            println!(
+                "{} {} is in no GeoSite category",
+                self.prefix.info(),
+                domain.bold()
+            );
+            return Ok(());
+        }
+        println!(
+            "{} {} is in {} GeoSite categories:",
+            self.prefix.info(),
+            domain.bold(),
+            categories.len()
+        );
+        for category in &categories {
+            let attributes: Vec<String> = category
+                .attributes
+                .iter()
+                .map(|attribute| format!("@{}", attribute))
+                .collect();
+            println!("  {} {}", category.category, attributes.join(" ").dimmed());
+        }
+
+        let raw_mihomo_yaml =
+            fs::read_to_string(&self.mihomo_target_config_path).unwrap_or_default();
+        let mihomo_yaml: serde_yaml::Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+        let rules = mihomo_yaml
+            .get("rules")
+            .and_then(serde_yaml::Value::as_sequence);
+        let matching_rules: Vec<&str> = rules
+            .into_iter()
+            .flatten()
+            .filter_map(serde_yaml::Value::as_str)
+            .filter(|rule| {
+                let mut fields = rule.split(',').map(str::trim);
+                fields
+                    .next()
+                    .is_some_and(|kind| kind.eq_ignore_ascii_case("GEOSITE"))
+                    && fields.next().is_some_and(|value| {
+                        categories
+                            .iter()
+                            .any(|category| category.matches_rule(value))
+                    })
+            })
+            .collect();
+        if !matching_rules.is_empty() {
+            println!(
+                "{} Matching rules of {}, earlier rules of other types may match first:",
+                self.prefix.info(),
+                self.mihomo_target_config_path.underline()
+            );
+            for rule in matching_rules {
+                println!("  {}", rule);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn fake_ip_filter_commands(&self, filter: &Option<FakeIpFilterCommands>) -> Result<()> {
+        let (added, removed) = match filter {
+            Some(FakeIpFilterCommands::Add { patterns }) => (&patterns[..], &[][..]),
+            Some(FakeIpFilterCommands::Remove { patterns }) => (&[][..], &patterns[..]),
+            Some(FakeIpFilterCommands::List) => {
+                let config = Config::setup_from(&self.config_path)?;
+                let dns = config.mihomo_config.dns.unwrap_or_default();
+                for pattern in dns.fake_ip_filter.unwrap_or_default() {
+                    println!("{}", pattern);
+                }
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+
+        edit_config(&self.config_path, |document| {
+            let dns = table_mut(document, &["mihomo_config", "dns"])?;
+            let key = field_key(dns, &["fake-ip-filter", "fake_ip_filter"]);
+            let patterns = dns
+                .entry(key)
+                .or_insert(Array::new().into())
+                .as_array_mut()
+                .context("`fake-ip-filter` of mihoro config is not an array")?;
+            let contains = |patterns: &Array, pattern: &str| {
+                patterns.iter().any(|value| value.as_str() == Some(pattern))
+            };
+
+            for pattern in added {
+                if !contains(patterns, pattern) {
+                    patterns.push(pattern.as_str());
+                }
+            }
+            for pattern in removed
+                .iter()
+                .filter(|pattern| !contains(patterns, pattern))
+            {
+                println!(
+                    "{} `{}` is not in {}, patterns of the remote config are not affected",
+                    self.prefix.warning(),
+                    pattern,
+                    self.config_path.underline()
+                );
+            }
+            patterns.retain(|value| {
+                value
+                    .as_str()
+                    .is_none_or(|pattern| !removed.iter().any(|removed| removed == pattern))
+            });
+            // Respace arrays on one line, those on several lines are kept as laid out
+            let multiline = patterns.iter().any(|value| {
+                value
+                    .decor()
+                    .prefix()
+                    .and_then(|prefix| prefix.as_str())
+                    .is_some_and(|prefix| prefix.contains('\n'))
+            });
+            if !multiline {
+                patterns.fmt();
+            }
+            Ok(())
+        })?;
+        println!(
+            "{} Updated `fake-ip-filter` in {}, run `mihoro apply` to take effect",
+            self.prefix.success(),
+            self.config_path.underline().yellow()
+        );
+        Ok(())
+    }
+
+    pub fn nameserver_policy_commands(
+        &self,
+        policy: &Option<NameserverPolicyCommands>,
+    ) -> Result<()> {
+        match policy {
+            Some(NameserverPolicyCommands::Set {
+                domain,
+                nameservers,
+            }) => {
+                let nameservers: Value = match &nameservers[..] {
+                    [nameserver] => nameserver.as_str().into(),
+                    nameservers => nameservers.iter().collect::<Array>().into(),
+                };
+                edit_config(&self.config_path, |document| {
+                    let dns = table_mut(document, &["mihomo_config", "dns"])?;
+                    let key = field_key(dns, &["nameserver-policy", "nameserver_policy"]);
+                    let domains = table_mut(document, &["mihomo_config", "dns", key])?;
+                    set_value(domains, domain, nameservers);
+                    Ok(())
+                })?;
+            }
+            Some(NameserverPolicyCommands::Remove { domains: removed }) => {
+                edit_config(&self.config_path, |document| {
+                    let dns = table_mut(document, &["mihomo_config", "dns"])?;
+                    let key = field_key(dns, &["nameserver-policy", "nameserver_policy"]);
+                    let Some(domains) = dns.get_mut(key).and_then(Item::as_table_like_mut) else {
+                        return Ok(());
+                    };
+                    for domain in removed {
+                        domains.remove(domain);
+                    }
+                    if domains.is_empty() {
+                        dns.remove(key);
+                    }
+                    Ok(())
+                })?;
+            }
+            Some(NameserverPolicyCommands::List) => {
+                let config = Config::setup_from(&self.config_path)?;
+                let dns = config.mihomo_config.dns.unwrap_or_default();
+                for (domain, nameservers) in dns.nameserver_policy.unwrap_or_default() {
+                    println!(
+                        "{} {} {}",
+                        domain.bold(),
+                        "->".dimmed(),
+                        nameservers.list().join(", ")
+                    );
+                }
+                return Ok(());
+            }
+            None => return Ok(()),
+        }
+
+        println!(
+            "{} Updated `nameserver-policy` in {}, run `mihoro apply` to take effect",
+            self.prefix.success(),
+            self.config_path.underline().yellow()
+        );
+        Ok(())
+    }
+}
+
+impl Mihoro {
+    pub fn node_commands(&self, node: &Option<NodeCommands>) -> Result<()> {
+        let mut local_proxies = LocalProxies::load(&self.mihomo_target_local_proxies_path)?;
+
+        match node {
+            Some(NodeCommands::Import { uris, clipboard }) => {
+                let uris = if *clipboard {
+                    split_uris(&read_clipboard()?)
+                } else if uris.is_empty() {
+                    let mut input = String::new();
+                    io::stdin().read_to_string(&mut input)?;
+                    split_uris(&input)
+                } else {
+                    uris.clone()
+                };
+                if uris.is_empty() {
+                    println!("{} No share links found", self.prefix.warning());
+                    return Ok(());
+                }
+
+                for uri in uris {
+                    match uri_to_node(&uri) {
+                        Ok(node) => {
+                            println!(
+                                "{} Imported node `{}`",
+                                self.prefix.info(),
+                                entry_name(&node).unwrap_or_default()
+                            );
+                            local_proxies.add(node);
+                        }
+                        Err(e) => println!("{} Skipped: {}", self.prefix.warning(), e),
+                    }
+                }
+            }
+            Some(NodeCommands::AddWireguard { config, name }) => {
+                let config_path = tilde(config).to_string();
+                let name = match name {
+                    Some(name) => name.clone(),
+                    None => Path::new(&config_path)
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                        .with_context(|| format!("invalid WireGuard config path {}", config))?,
+                };
+                let wireguard_config = fs::read_to_string(&config_path)
+                    .with_context(|| format!("failed to read {}", config))?;
+                local_proxies.add(wireguard_to_node(&name, &wireguard_config)?);
+                println!("{} Added WireGuard node `{}`", self.prefix.info(), name);
+            }
+            Some(NodeCommands::AddSsh {
+                target,
+                name,
+                identity,
+                password,
+            }) => {
+                let name = name.as_deref().unwrap_or(target);
+                local_proxies.add(ssh_to_node(
+                    name,
+                    target,
+                    identity.as_deref(),
+                    password.as_deref(),
+                )?);
+                println!("{} Added SSH node `{}`", self.prefix.info(), name);
+            }
+            Some(NodeCommands::List) => {
+                for proxy in &local_proxies.proxies {
+                    let name = entry_name(proxy).unwrap_or_default();
+                    let node_type = proxy.get("type").and_then(|t| t.as_str());
+                    println!("{} {}", name.bold(), node_type.unwrap_or_default().dimmed());
+                }
+                return Ok(());
+            }
+            Some(NodeCommands::Remove { names }) => {
+                for name in names {
+                    if !local_proxies.remove(name) {
+                        println!("{} Node `{}` not found", self.prefix.warning(), name);
+                    }
+                }
+            }
+            None => return Ok(()),
+        }
+
+        let created = !Path::new(&self.mihomo_target_local_proxies_path).exists();
+        create_parent_dir(&self.mihomo_target_local_proxies_path)?;
+        local_proxies.write(&self.mihomo_target_local_proxies_path)?;
+        if created {
+            self.track(&[&self.mihomo_target_local_proxies_path])?;
+        }
+        println!(
+            "{} Updated {}, run `mihoro apply` to take effect",
+            self.prefix.success(),
+            self.mihomo_target_local_proxies_path.underline().yellow()
+        );
+        Ok(())
+    }
+
+    pub fn rules_commands(&self, rules: &Option<RulesCommands>) -> Result<()> {
+        let mut local_rules = LocalRules::load(&self.mihomo_target_local_rules_path)?;
+
+        match rules {
+            Some(RulesCommands::Import {
+                source,
+                policy,
+                behavior,
+            }) => {
+                let behavior = behavior.as_ref().map(RulesetBehavior::as_str);
+                for (ruleset, policy) in resolve_rulesets(source, policy, behavior)? {
+                    local_rules.add_ruleset(&ruleset, &policy)?;
+                    println!(
+                        "{} Imported ruleset `{}` with policy {}",
+                        self.prefix.info(),
+                        ruleset.name,
+                        policy.bold()
+                    );
+                }
+            }
+            Some(RulesCommands::Convert {
+                input,
+                name,
+                policy,
+                behavior,
+            }) => {
+                if *behavior == RulesetBehavior::Classical {
+                    bail!(".mrs rulesets only hold domains or ip cidrs");
+                }
+                let name = match name {
+                    Some(name) => name.clone(),
+                    None => Path::new(input)
+                        .file_stem()
+                        .with_context(|| format!("no ruleset name in `{}`", input))?
+                        .to_string_lossy()
+                        .to_lowercase(),
+                };
+                let target = Path::new(&self.mihomo_target_config_root)
+                    .join(converted_ruleset_path(&name).trim_start_matches("./"))
+                    .to_string_lossy()
+                    .to_string();
+                create_parent_dir(&target)?;
+                let status = Command::new(&self.mihomo_target_binary_path)
+                    .args(["convert-ruleset", behavior.as_str(), "text"])
+                    .args([input, &target])
+                    .status()
+                    .with_context(|| format!("failed to run {}", self.mihomo_target_binary_path))?;
+                if !status.success() {
+                    bail!("failed to convert `{}` to .mrs", input);
+                }
+                local_rules.add_converted_ruleset(&name, behavior.as_str(), policy)?;
+                println!(
+                    "{} Converted {} to ruleset `{}` with policy {}",
+                    self.prefix.info(),
+                    input.underline(),
+                    name,
+                    policy.bold()
+                );
+            }
+            Some(RulesCommands::List) => {
+                for (name, provider) in &local_rules.rule_providers {
+                    // Converted rulesets have a local path instead of a url
+                    let source = provider.get("url").or_else(|| provider.get("path"));
+                    let source = source.and_then(|source| source.as_str());
+                    println!("{} {}", name.bold(), source.unwrap_or_default().underline());
+                }
+                return Ok(());
+            }
+            Some(RulesCommands::Remove { names }) => {
+                for name in names {
+                    if !local_rules.remove_ruleset(name) {
+                        println!("{} Ruleset `{}` not found", self.prefix.warning(), name);
+                    }
+                }
+            }
+            None => return Ok(()),
+        }
+
+        self.write_local_rules(&local_rules)
+    }
+
+    pub async fn route_commands(
+        &self,
+        client: Client,
+        route: &Option<RouteCommands>,
+    ) -> Result<()> {
+        let mut local_rules = LocalRules::load(&self.mihomo_target_local_rules_path)?;
+
+        match route {
+            Some(RouteCommands::Now { target, policy }) => {
+                return self.route_now(client, target, policy.as_str()).await;
+            }
+            Some(RouteCommands::Add { target, policy }) => {
+                local_rules.add_route(target, policy.as_str());
+                println!(
+                    "{} Routing {} with {}",
+                    self.prefix.info(),
+                    target.bold(),
+                    policy.as_str().bold()
+                );
+            }
+            Some(RouteCommands::List) => {
+                for rule in local_rules.routes() {
+                    println!("{}", rule);
+                }
+                return Ok(());
+            }
+            Some(RouteCommands::Remove { targets }) => {
+                for target in targets {
+                    if !local_rules.remove_route(target) {
+                        println!("{} No route found for `{}`", self.prefix.warning(), target);
+                    }
+                }
+            }
+            None => return Ok(()),
+        }
+
+        self.write_local_rules(&local_rules)
+    }
+
+    /// Inject a route rule into the running mihomo by reloading it with the generated config plus
+    /// temporary routes, leaving `config.yaml` untouched.
+    async fn route_now(&self, client: Client, target: &str, policy: &str) -> Result<()> {
+        let api = self.api(client)?;
+        let mut temporary_rules = LocalRules::load(&self.mihomo_target_temporary_rules_path)?;
+        temporary_rules.add_route(target, policy);
+        let mihomo_yaml = self.running_config(&temporary_rules)?;
+        api.reload_config(&serde_yaml::to_string(&mihomo_yaml)?)
+            .await?;
+        let created = !Path::new(&self.mihomo_target_temporary_rules_path).exists();
+        temporary_rules.write(&self.mihomo_target_temporary_rules_path)?;
+        if created {
+            self.track(&[&self.mihomo_target_temporary_rules_path])?;
+        }
+
+        println!(
+            "{} Routing {} with {} until the config is applied again",
+            self.prefix.success(),
+            target.bold(),
+            policy.bold()
+        );
+        println!(
+            "{} Run `mihoro route add` to keep the route",
+            self.prefix.warning()
+        );
+        Ok(())
+    }
+
+    /// Test whether nodes actually relay UDP, through temporary SOCKS listeners bound to each node.
+    ///
+    /// mihomo is reloaded with the listeners for the test, and with the running config afterwards.
+    pub async fn test_udp(&self, client: Client, node: &Option<String>) -> Result<()> {
+        let api = self.api(client)?;
+        let temporary_rules = LocalRules::load(&self.mihomo_target_temporary_rules_path)?;
+        let running_config = serde_yaml::to_string(&self.running_config(&temporary_rules)?)?;
+        let mut mihomo_yaml = self.running_config(&temporary_rules)?;
+
+        let nodes: Vec<serde_yaml::Value> = mihomo_yaml
+            .get("proxies")
+            .and_then(|proxies| proxies.as_sequence())
+            .map(|proxies| {
+                proxies
+                    .iter()
+                    .filter(|proxy| node.is_none() || entry_name(proxy) == node.as_deref())
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default();
+        if nodes.is_empty() {
+            match node {
+                Some(node) => bail!(
+                    "node `{}` not found in {}",
+                    node,
+                    self.mihomo_target_config_path
+                ),
+                None => bail!("no nodes found in {}", self.mihomo_target_config_path),
+            }
+        }
+
+        // Reserve a free port for each listener
+        let reserved = nodes
+            .iter()
+            .map(|_| std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let ports = reserved
+            .iter()
+            .map(|listener| listener.local_addr().map(|addr| addr.port()))
+            .collect::<Result<Vec<_>, _>>()?;
+        drop(reserved);
+
+        let listeners = mihomo_yaml
+            .entry(String::from("listeners"))
+            .or_insert_with(|| serde_yaml::Value::Sequence(Vec::new()));
+        if let Some(listeners) = listeners.as_sequence_mut() {
+            for (index, (node, port)) in nodes.iter().zip(&ports).enumerate() {
+                let mut listener = serde_yaml::Mapping::new();
+                listener.insert("name".into(), format!("mihoro-udp-test-{}", index).into());
+                listener.insert("type".into(), "socks".into());
+                listener.insert("listen".into(), "127.0.0.1".into());
+                listener.insert("port".into(), (*port).into());
+                listener.insert("udp".into(), true.into());
+                listener.insert("proxy".into(), entry_name(node).unwrap_or_default().into());
+                listeners.push(serde_yaml::Value::Mapping(listener));
+            }
+        }
+
+        println!(
+            "{} Testing UDP relay of {} node(s), mihomo is reloaded during the test",
+            self.prefix.info(),
+            nodes.len()
+        );
+        let tested = async {
+            api.reload_config(&serde_yaml::to_string(&mihomo_yaml)?)
+                .await?;
+            let results = join_all(ports.iter().map(|port| {
+                test_udp_relay(
+                    SocketAddr::from((Ipv4Addr::LOCALHOST, *port)),
+                    Duration::from_secs(5),
+                )
+            }))
+            .await;
+            anyhow::Ok(results)
+        }
+        .await;
+        // Restore the running config even if the test failed halfway
+        api.reload_config(&running_config)
+            .await
+            .context("failed to restore the running config after the UDP test")?;
+        let results = tested?;
+
+        let mut relayed = 0;
+        for (node, result) in nodes.iter().zip(results) {
+            let name = entry_name(node).unwrap_or_default();
+            let advertised = node.get("udp").and_then(|udp| udp.as_bool()) == Some(true);
+            match result {
+                Ok(elapsed) => {
+                    relayed += 1;
+                    let elapsed = format!("{}ms", elapsed.as_millis());
+                    println!("{} {} {}", "✓".green(), name.bold(), elapsed.dimmed());
+                }
+                Err(e) if advertised => println!(
+                    "{} {} {} {}",
+                    "✗".red(),
+                    name.bold(),
+                    "advertises UDP but failed:".yellow(),
+                    e
+                ),
+                Err(e) => println!("{} {} {}", "✗".red(), name.bold(), e.to_string().dimmed()),
+            }
+        }
+        println!(
+            "{} {} of {} node(s) relay UDP",
+            self.prefix.success(),
+            relayed,
+            nodes.len()
+        );
+        Ok(())
+    }
+
+    /// Download options from `max_download_rate` (KiB/s) and `mirror_timeout` (seconds, defaults
+    /// to 30).
+    fn download_options(&self) -> DownloadOptions {
+        DownloadOptions {
+            max_rate: self.config.max_download_rate.map(|rate| rate * 1024),
+            timeout: Some(Duration::from_secs(
+                self.config.mirror_timeout.unwrap_or(30),
+            )),
+            headers: HeaderMap::new(),
+            cache: Some(DownloadCache::new(cache_root())),
+            response_headers: None,
+        }
+    }
+
+    /// Get a remote artifact (binary or geodata) to path. If `minisign_public_key` is set, the
+    /// artifact is only installed once its signature, fetched from `{url}.minisig`, is verified.
+    async fn get_verified_file(&self, client: &Client, url: &RemoteUrl, path: &str) -> Result<()> {
+        let mirrors = self.config.mirrors(url);
+        let options = self.download_options();
+        let Some(public_key) = &self.config.minisign_public_key else {
+            return get_file_from_system_or_remote(client, &mirrors, path, &options).await;
+        };
+
+        let unverified_path = format!("{}.unverified", path);
+        let signature_path = format!("{}.minisig", path);
+        let signature_mirrors: Vec<String> = mirrors
+            .iter()
+            .map(|mirror| format!("{}.minisig", mirror))
+            .collect();
+        let verified = async {
+            get_file_from_system_or_remote(client, &mirrors, &unverified_path, &options).await?;
+            get_file_from_system_or_remote(client, &signature_mirrors, &signature_path, &options)
+                .await
+                .context("failed to get signature")?;
+            verify_signature(public_key, &unverified_path, &signature_path)
+        }
+        .await;
+
+        let _ = fs::remove_file(&signature_path);
+        if let Err(error) = verified {
+            let _ = fs::remove_file(&unverified_path);
+            return Err(error.context(format!("refusing to install unverified {}", path)));
+        }
+        fs::rename(&unverified_path, path)?;
+        println!(
+            "{} Verified signature of {}",
+            self.prefix.success(),
+            path.underline()
+        );
+        Ok(())
+    }
+
+    /// Http client of the subscription, presenting the client certificate and trusting only the
+    /// CA from `[subscription.tls]` if set.
+    fn subscription_client(&self, client: &Client) -> Result<Client> {
+        let Some(tls) = self
+            .config
+            .subscription
+            .as_ref()
+            .and_then(|subscription| subscription.tls.as_ref())
+        else {
+            return Ok(client.clone());
+        };
+        let read = |path: &String| {
+            fs::read(tilde(path).as_ref()).with_context(|| format!("failed to read {}", path))
+        };
+
+        let mut builder = Client::builder();
+        match (&tls.client_cert, &tls.client_key) {
+            (Some(cert), Some(key)) => {
+                let identity = Identity::from_pkcs8_pem(&read(cert)?, &read(key)?)
+                    .context("invalid subscription client certificate or key")?;
+                builder = builder.identity(identity);
+            }
+            (None, None) => {}
+            _ => bail!("`client_cert` and `client_key` of `subscription.tls` must be set together"),
+        }
+        if let Some(ca_cert) = &tls.ca_cert {
+            let ca_cert = Certificate::from_pem(&read(ca_cert)?)
+                .context("invalid subscription CA certificate")?;
+            builder = builder
+                .tls_built_in_root_certs(false)
+                .add_root_certificate(ca_cert);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Download options of the subscription, sending `user_agent`, `headers` and credentials from
+    /// `[subscription]`.
+    fn subscription_download_options(&self) -> Result<DownloadOptions> {
+        // Subscriptions change with every update and may embed credentials, never cache them
+        let mut options = DownloadOptions {
+            cache: None,
+            ..self.download_options()
+        };
+        let Some(subscription) = &self.config.subscription else {
+            return Ok(options);
+        };
+        for (name, value) in subscription.headers.iter().flatten() {
+            let header =
+                || -> Result<(HeaderName, HeaderValue)> { Ok((name.parse()?, value.parse()?)) };
+            let (name, value) =
+                header().with_context(|| format!("invalid subscription header `{}`", name))?;
+            options.headers.insert(name, value);
+        }
+        if let Some(user_agent) = &subscription.user_agent {
+            let user_agent = user_agent
+                .parse()
+                .with_context(|| format!("invalid subscription user agent `{}`", user_agent))?;
+            options.headers.insert(USER_AGENT, user_agent);
+        }
+        if let Some(auth) = &subscription.auth {
+            let mut authorization: HeaderValue = auth
+                .authorization()
+                .context("invalid `subscription.auth`")?
+                .parse()?;
+            authorization.set_sensitive(true);
+            options.headers.insert(AUTHORIZATION, authorization);
+        }
+        Ok(options)
+    }
+
+    /// Check mihomo.service status, followed by the selected node of each proxy group and its last
+    /// delay, as reported by the running mihomo.
+    pub async fn status(&self, client: Client) -> Result<()> {
+        self.systemctl().status("mihomo.service").execute()?;
+
+        let state = State::load(&self.mihomo_target_state_path)?;
+        if let Some(subscription) = &state.subscription {
+            let expiry = match subscription
+                .expire
+                .and_then(|expire| DateTime::from_timestamp(expire, 0))
+            {
+                Some(expire) => {
+                    let days_left = subscription
+                        .days_left(Utc::now().timestamp())
+                        .unwrap_or_default();
+                    format!(
+                        ", expires {} ({} day(s) left)",
+                        expire.with_timezone(&Local).format("%Y-%m-%d"),
+                        days_left
+                    )
+                }
+                None => String::new(),
+            };
+            println!(
+                "{} Subscription: {} of {} left{}",
+                self.prefix.info(),
+                HumanBytes(subscription.remaining()),
+                HumanBytes(subscription.total),
+                expiry
+            );
+        }
+        let usage = Usage::load(&self.mihomo_target_usage_path)?;
+        if let Some(last_hour) = usage.last_hour(Utc::now().timestamp(), LAST_HOUR_SLOTS) {
+            let values: Vec<Option<u64>> = last_hour.iter().copied().map(Some).collect();
+            println!(
+                "{} Traffic of the last hour: {} {}",
+                self.prefix.info(),
+                sparkline(&values).cyan(),
+                HumanBytes(last_hour.iter().sum())
+            );
+        }
+        if let Some(source) = state
+            .subscription_source
+            .as_ref()
+            .filter(|source| *source != "remote_config_url")
+        {
+            println!(
+                "{} Subscription last downloaded from {}",
+                self.prefix.warning(),
+                source.bold()
+            );
+        }
+        for conflict in self.proxy_conflicts() {
+            println!("{} {}", self.prefix.warning(), conflict);
+        }
+
+        let proxies = match self.api(client)?.proxies().await {
+            Ok(proxies) => proxies,
+            Err(error) => {
+                println!("{} {:#}", self.prefix.warning(), error);
+                return Ok(());
+            }
+        };
+
+        // Groups in config order as listed by `GLOBAL`, followed by any other groups
+        let mut groups: Vec<&Proxy> = proxies
+            .get("GLOBAL")
+            .and_then(|global| global.all.as_ref())
+            .into_iter()
+            .flatten()
+            .filter_map(|name| proxies.get(name))
+            .filter(|proxy| proxy.now.is_some())
+            .collect();
+        let mut unlisted_groups: Vec<&Proxy> = proxies
+            .values()
+            .filter(|proxy| proxy.now.is_some() && proxy.name != "GLOBAL")
+            .filter(|proxy| !groups.iter().any(|group| group.name == proxy.name))
+            .collect();
+        unlisted_groups.sort_by(|a, b| a.name.cmp(&b.name));
+        groups.extend(unlisted_groups);
+
+        println!("{} Selected nodes:", self.prefix.info());
+        let width = groups
+            .iter()
+            .map(|group| group.name.chars().count())
+            .max()
+            .unwrap_or(0);
+        for group in groups {
+            // Follow nested groups down to the node traffic exits through
+            let mut selected = vec![group.now.clone().unwrap_or_default()];
+            while let Some(now) = proxies
+                .get(selected.last().unwrap())
+                .and_then(|p| p.now.clone())
+            {
+                if selected.contains(&now) || selected.len() > 8 {
+                    break;
+                }
+                selected.push(now);
+            }
+            let node = proxies.get(selected.last().unwrap());
+            let delay = match node.and_then(Proxy::last_delay) {
+                Some(0) => "timeout".red(),
+                Some(delay) if delay < 300 => format!("{} ms", delay).green(),
+                Some(delay) => format!("{} ms", delay).yellow(),
+                None => "untested".dimmed(),
+            };
+            // Trend of the latest delay tests, timeouts as dots
+            let history: Vec<Option<u64>> = node
+                .map(|node| &node.history[..])
+                .unwrap_or_default()
+                .iter()
+                .map(|history| (history.delay > 0).then_some(history.delay))
+                .collect();
+            let trend = if history.len() > 1 {
+                format!(" {}", sparkline(&history).dimmed())
+            } else {
+                String::new()
+            };
+            let padding = " ".repeat(width - group.name.chars().count());
+            println!(
+                "  {}{} {} {} ({}){}",
+                group.name.bold(),
+                padding,
+                "->".dimmed(),
+                selected.join(" -> "),
+                delay,
+                trend
+            );
+        }
+        Ok(())
+    }
+
+    /// Test delay of members of a proxy group, or of all nodes if no group is given, with up to
+    /// `concurrency` tests at once. Results are printed fastest first, followed by failures.
+    /// Options left out default to `[latency]` in mihoro config.
+    pub async fn ping(
+        &self,
+        client: Client,
+        group: &Option<String>,
+        concurrency: Option<usize>,
+        url: &Option<String>,
+        timeout: Option<u64>,
+    ) -> Result<()> {
+        let latency = self.latency(url, timeout, concurrency);
+        let api = self.api(client)?;
+        let proxies = api.proxies().await?;
+
+        let is_testable = |name: &&String| is_testable(&proxies, name);
+        let mut names: Vec<&String> = match group {
+            Some(group) => proxies
+                .get(group)
+                .and_then(|proxy| proxy.all.as_ref())
+                .with_context(|| format!("proxy group `{}` not found", group))?
+                .iter()
+                .filter(is_testable)
+                .collect(),
+            None => proxies
+                .values()
+                .filter(|proxy| proxy.all.is_none())
+                .map(|proxy| &proxy.name)
+                .filter(is_testable)
+                .collect(),
+        };
+        names.sort();
+        if names.is_empty() {
+            bail!("no nodes to test");
+        }
+
+        println!(
+            "{} Testing delay of {} node(s) with {}",
+            self.prefix.info(),
+            names.len(),
+            latency.test_url().underline()
+        );
+        let results = test_delays(&api, names, &latency).await;
+
+        let width = results
+            .iter()
+            .map(|(name, _)| name.chars().count())
+            .max()
+            .unwrap_or(0);
+        let mut reachable = 0;
+        for (name, result) in &results {
+            let padding = " ".repeat(width - name.chars().count());
+            match result {
+                Ok(delay) => {
+                    reachable += 1;
+                    let delay = match format!("{} ms", delay) {
+                        formatted if *delay < 300 => formatted.green(),
+                        formatted => formatted.yellow(),
+                    };
+                    println!("{} {}{} {}", "✓".green(), name.bold(), padding, delay);
+                }
+                Err(error) => {
+                    println!(
+                        "{} {}{} {}",
+                        "✗".red(),
+                        name.bold(),
+                        padding,
+                        error.to_string().red()
+                    );
+                }
+            }
+        }
+        println!(
+            "{} {}/{} node(s) reachable",
+            self.prefix.info(),
+            reachable,
+            results.len()
+        );
+        Ok(())
+    }
+
+    /// Switch a selector group to a node, picking those not given from a fuzzy-searchable list in
+    /// interactive mode.
+    pub async fn select(
+        &self,
+        client: Client,
+        group: &Option<String>,
+        node: &Option<String>,
+        interactive: bool,
+    ) -> Result<()> {
+        if interactive && !io::stdin().is_terminal() {
+            bail!("--interactive requires a terminal");
+        }
+        let api = self.api(client)?;
+        let proxies = api.proxies().await?;
+        let group = match group {
+            Some(group) => group.clone(),
+            None => {
+                let mut groups: Vec<&Proxy> = proxies
+                    .values()
+                    .filter(|proxy| proxy.proxy_type == "Selector")
+                    .collect();
+                groups.sort_by(|a, b| a.name.cmp(&b.name));
+                let items: Vec<PickerItem> = groups
+                    .iter()
+                    .map(|group| PickerItem {
+                        name: group.name.clone(),
+                        detail: group.now.clone().unwrap_or_default(),
+                        current: false,
+                    })
+                    .collect();
+                match picker::pick("Proxy group", &items).await? {
+                    Some(index) => items[index].name.clone(),
+                    None => return Ok(()),
+                }
+            }
+        };
+        let group = group.as_str();
+        let selector = selector_group(&proxies, group)?;
+        let node = match node {
+            Some(node) => node.clone(),
+            None => {
+                let items: Vec<PickerItem> = selector
+                    .all
+                    .iter()
+                    .flatten()
+                    .map(|name| PickerItem {
+                        name: name.clone(),
+                        detail: match proxies.get(name).and_then(Proxy::last_delay) {
+                            Some(0) => String::from("timeout"),
+                            Some(delay) => format!("{} ms", delay),
+                            None => String::new(),
+                        },
+                        current: selector.now.as_ref() == Some(name),
+                    })
+                    .collect();
+                match picker::pick(&format!("Node of `{}`", group), &items).await? {
+                    Some(index) => items[index].name.clone(),
+                    None => return Ok(()),
+                }
+            }
+        };
+        let node = node.as_str();
+        if !selector.all.iter().flatten().any(|name| name == node) {
+            bail!("`{}` is not a member of proxy group `{}`", node, group);
+        }
+        if selector.now.as_deref() == Some(node) {
+            println!(
+                "{} {}",
+                self.prefix.info(),
+                tr_args("`{}` already selected {}", &[&group, &node.bold()])
+            );
+            return Ok(());
+        }
+        api.select_proxy(group, node).await?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args("Switched `{}` to {}", &[&group, &node.bold()])
+        );
+        Ok(())
+    }
+
+    /// Switch a selector group to its fastest member after testing the delay of all of them,
+    /// skipping members whose name matches `exclude`.
+    pub async fn autoselect(
+        &self,
+        client: Client,
+        group: &str,
+        exclude: &Option<String>,
+        concurrency: Option<usize>,
+        url: &Option<String>,
+        timeout: Option<u64>,
+    ) -> Result<()> {
+        let latency = self.latency(url, timeout, concurrency);
+        let exclude = exclude
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .context("invalid exclude pattern")?;
+        let api = self.api(client)?;
+        let proxies = api.proxies().await?;
+
+        let selector = selector_group(&proxies, group)?;
+        let names: Vec<&String> = selector
+            .all
+            .iter()
+            .flatten()
+            .filter(|name| is_testable(&proxies, name))
+            .filter(|name| {
+                !exclude
+                    .as_ref()
+                    .is_some_and(|exclude| exclude.is_match(name))
+            })
+            .collect();
+        if names.is_empty() {
+            bail!("no nodes left to test in proxy group `{}`", group);
+        }
+
+        let results = test_delays(&api, names, &latency).await;
+        let Some((fastest, Ok(delay))) = results.first() else {
+            bail!(
+                "none of the {} node(s) in proxy group `{}` is reachable",
+                results.len(),
+                group
+            );
+        };
+        if selector.now.as_ref() == Some(*fastest) {
+            println!(
+                "{} {}",
+                self.prefix.info(),
+                tr_args(
+                    "`{}` already selected {} ({} ms)",
+                    &[&group, &fastest.bold(), &delay]
+                )
+            );
+            return Ok(());
+        }
+        api.select_proxy(group, fastest).await?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Switched `{}` to {} ({} ms)",
+                &[&group, &fastest.bold(), &delay]
+            )
+        );
+        Ok(())
+    }
+
+    /// Print names for shell completions, one per line. Nothing is printed if mihomo is not
+    /// reachable, so that completions fall back to static ones.
+    pub async fn complete(&self, client: Client, complete: &CompleteCommands) {
+        let Ok(proxies) = async { self.api(client)?.proxies().await }.await else {
+            return;
+        };
+        let mut names: Vec<&String> = match complete {
+            CompleteCommands::Groups { selector } => proxies
+                .values()
+                .filter(|proxy| proxy.all.is_some())
+                .filter(|proxy| !selector || proxy.proxy_type == "Selector")
+                .map(|proxy| &proxy.name)
+                .collect(),
+            CompleteCommands::Nodes { group: Some(group) } => proxies
+                .get(group)
+                .and_then(|group| group.all.as_ref())
+                .into_iter()
+                .flatten()
+                .collect(),
+            CompleteCommands::Nodes { group: None } => proxies
+                .values()
+                .filter(|proxy| proxy.all.is_none() && is_testable(&proxies, &proxy.name))
+                .filter(|proxy| proxy.proxy_type != "Direct")
+                .map(|proxy| &proxy.name)
+                .collect(),
+        };
+        // Members keep the group's order, other names are sorted
+        if !matches!(complete, CompleteCommands::Nodes { group: Some(_) }) {
+            names.sort();
+        }
+        for name in names {
+            println!("{}", name);
+        }
+    }
+
+    /// Sample connections every second over `window` seconds, then print the hosts and processes
+    /// with the most traffic during the window.
+    pub async fn top(&self, client: Client, window: u64, limit: usize) -> Result<()> {
+        let api = self.api(client)?;
+
+        // Traffic of each connection since the first sample it showed up in, connections already
+        // open at the first sample only count traffic from then on
+        let mut baselines: HashMap<String, (u64, u64)> = api
+            .connections()
+            .await?
+            .into_iter()
+            .map(|connection| (connection.id, (connection.upload, connection.download)))
+            .collect();
+        let mut latest: HashMap<String, Connection> = HashMap::new();
+        println!(
+            "{} Sampling connections for {}s...",
+            self.prefix.info(),
+            window
+        );
+        for _ in 0..window.max(1) {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            for connection in api.connections().await? {
+                baselines.entry(connection.id.clone()).or_insert((0, 0));
+                latest.insert(connection.id.clone(), connection);
+            }
+        }
+
+        let mut by_host: HashMap<&str, (u64, u64)> = HashMap::new();
+        let mut by_process: HashMap<&str, (u64, u64)> = HashMap::new();
+        for (id, connection) in &latest {
+            let (upload, download) = baselines[id];
+            let upload = connection.upload.saturating_sub(upload);
+            let download = connection.download.saturating_sub(download);
+            let process = match connection.metadata.process.as_str() {
+                "" => "(unknown)",
+                process => process,
+            };
+            for (key, totals) in [
+                (connection.metadata.destination(), &mut by_host),
+                (process, &mut by_process),
+            ] {
+                let total = totals.entry(key).or_default();
+                total.0 += upload;
+                total.1 += download;
+            }
+        }
+
+        for (title, totals) in [("Host", by_host), ("Process", by_process)] {
+            let mut totals: Vec<(&str, (u64, u64))> = totals.into_iter().collect();
+            totals.sort_by_key(|(key, (upload, download))| (Reverse(upload + download), *key));
+            totals.truncate(limit);
+
+            let width = totals
+                .iter()
+                .map(|(key, _)| key.chars().count())
+                .chain([title.len()])
+                .max()
+                .unwrap_or(0);
+            println!(
+                "\n{}{} {:>12} {:>12} {:>12}",
+                title.bold(),
+                " ".repeat(width - title.len()),
+                "Upload".bold(),
+                "Download".bold(),
+                "Rate".bold()
+            );
+            for (key, (upload, download)) in totals {
+                let rate = format!("{}/s", HumanBytes((upload + download) / window.max(1)));
+                println!(
+                    "{}{} {:>12} {:>12} {:>12}",
+                    key,
+                    " ".repeat(width - key.chars().count()),
+                    HumanBytes(upload).to_string(),
+                    HumanBytes(download).to_string(),
+                    rate.dimmed()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Run the plugin `mihoro-<name>` found on PATH for an unknown subcommand `name`, with the
+    /// paths of the setup and access to mihomo's external controller passed in `MIHORO_*`
+    /// environment variables.
+    pub fn run_plugin(&self, args: &[String]) -> Result<ExitStatus> {
+        let (name, args) = args.split_first().context("no plugin given")?;
+        let program = format!("mihoro-{}", name);
+        let mihomo_config = &self.config.mihomo_config;
+        let mut plugin = Command::new(&program);
+        plugin
+            .args(args)
+            .env("MIHORO_CONFIG", &self.config_path)
+            .env("MIHORO_CONFIG_ROOT", &self.mihomo_target_config_root)
+            .env("MIHORO_MIHOMO_CONFIG", &self.mihomo_target_config_path)
+            .env("MIHORO_BINARY", &self.mihomo_target_binary_path);
+        if let Some(external_controller) = &mihomo_config.external_controller {
+            plugin.env("MIHORO_CONTROLLER_URL", controller_url(external_controller));
+        }
+        if let Some(socket_path) = &mihomo_config.external_controller_unix {
+            let socket_path = Path::new(&self.mihomo_target_config_root).join(socket_path);
+            plugin.env("MIHORO_CONTROLLER_SOCKET", socket_path);
+        }
+        if let Some(secret) = &mihomo_config.secret {
+            plugin.env("MIHORO_SECRET", secret);
+        }
+        match plugin.status() {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => bail!(
+                "unrecognized subcommand `{}`, and no `{}` plugin found on PATH",
+                name,
+                program
+            ),
+            result => result.with_context(|| format!("failed to run `{}`", program)),
+        }
+    }
+
+    /// Send a request to mihomo's external controller with the configured secret and print the
+    /// response, pretty-printed if JSON.
+    pub async fn api_request(
+        &self,
+        client: Client,
+        method: &str,
+        path: &str,
+        data: Option<&str>,
+    ) -> Result<()> {
+        let method = Method::from_bytes(method.to_uppercase().as_bytes())
+            .with_context(|| format!("invalid request method `{}`", method))?;
+        let path = if path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        let body = data
+            .map(serde_json::from_str::<serde_json::Value>)
+            .transpose()
+            .context("request body is not valid JSON")?;
+        let response = self.api(client)?.request(method, &path, body).await?;
+        match serde_json::from_str::<serde_json::Value>(&response) {
+            Ok(json) => println!("{}", serde_json::to_string_pretty(&json)?),
+            Err(_) if response.trim().is_empty() => {}
+            Err(_) => println!("{}", response.trim_end()),
+        }
+        Ok(())
+    }
+
+    /// List active connections, or show them in a continuously refreshing table with `live`.
+    pub async fn connections(&self, client: Client, live: bool, refresh: u64) -> Result<()> {
+        let api = self.api(client)?;
+        if live {
+            live_connections(&api, Duration::from_secs(refresh.max(1))).await
+        } else {
+            print_connections(&api).await
+        }
+    }
+
+    /// Print daily traffic through mihomo over the last 7 days, or over the current month with
+    /// `month`, after recording the latest traffic if mihomo is running. With `record`, only the
+    /// latest traffic is recorded, as done periodically by `mihoro-usage.timer`.
+    pub async fn usage(&self, client: Client, month: bool, record: bool) -> Result<()> {
+        let api = self.api(client)?;
+        let recorded = self.record_usage(&api).await;
+        if record {
+            return recorded;
+        }
+        if let Err(error) = recorded {
+            println!("{} {:#}", self.prefix.warning(), error);
+        }
+
+        let usage = Usage::load(&self.mihomo_target_usage_path)?;
+        let today = Local::now().date_naive();
+        let (title, first_day) = if month {
+            (
+                today.format("%B %Y").to_string(),
+                today.with_day(1).unwrap_or(today),
+            )
+        } else {
+            (String::from("last 7 days"), today - chrono::Days::new(6))
+        };
+        let first_day = first_day.format("%Y-%m-%d").to_string();
+        let day_totals = usage.day_totals();
+        let days: Vec<(&String, &Traffic)> = day_totals.range(first_day..).collect();
+        let totals: Vec<Option<u64>> = days
+            .iter()
+            .map(|(_, traffic)| Some(traffic.total()))
+            .collect();
+
+        if usage.machines.is_empty() {
+            println!("{} Traffic of {}:", self.prefix.info(), title);
+        } else {
+            println!(
+                "{} Traffic of {}, including {} other machine(s):",
+                self.prefix.info(),
+                title,
+                usage.machines.len()
+            );
+        }
+        println!(
+            "  {:<10} {:>12} {:>12} {:>12}",
+            "Date".bold(),
+            "Upload".bold(),
+            "Download".bold(),
+            "Total".bold()
+        );
+        let mut sum = Traffic::default();
+        for (day, traffic) in days {
+            sum.upload += traffic.upload;
+            sum.download += traffic.download;
+            println!(
+                "  {:<10} {:>12} {:>12} {:>12}",
+                day,
+                HumanBytes(traffic.upload).to_string(),
+                HumanBytes(traffic.download).to_string(),
+                HumanBytes(traffic.total()).to_string()
+            );
+        }
+        println!(
+            "  {:<10} {:>12} {:>12} {:>12}",
+            "Total".bold(),
+            HumanBytes(sum.upload).to_string(),
+            HumanBytes(sum.download).to_string(),
+            HumanBytes(sum.total()).to_string().bold()
+        );
+        if totals.len() > 1 {
+            println!("  {:<10} {}", "Trend".bold(), sparkline(&totals).cyan());
+        }
+        Ok(())
+    }
+
+    /// Add traffic of the running mihomo since the last record to today's usage.
+    async fn record_usage(&self, api: &MihomoApi) -> Result<()> {
+        let (upload, download) = api.traffic_totals().await?;
+        let mut usage = Usage::load(&self.mihomo_target_usage_path)?;
+        let now = Local::now();
+        let today = now.format("%Y-%m-%d").to_string();
+        usage.record(&today, now.timestamp(), Traffic { upload, download });
+        let created = !Path::new(&self.mihomo_target_usage_path).exists();
+        usage.write(&self.mihomo_target_usage_path)?;
+        if created {
+            self.track(&[&self.mihomo_target_usage_path])?;
+        }
+        Ok(())
+    }
+
+    /// Create `mihoro-usage.timer` and its service, recording traffic for `mihoro usage` every 5
+    /// minutes with this mihoro binary and config.
+    fn create_usage_timer(&self) -> Result<()> {
+        let service = format!(
+            "[Unit]
+Description=Record mihomo traffic for mihoro usage
+After=mihomo.service
+
+[Service]
+Type=oneshot
+ExecStart={} usage --record",
+            self.mihoro_command()?
+        );
+        let timer = "[Unit]
+Description=Record mihomo traffic for mihoro usage periodically
+
+[Timer]
+OnBootSec=5min
+OnUnitActiveSec=5min
+
+[Install]
+WantedBy=timers.target";
+
+        let created = !Path::new(&self.mihoro_usage_timer_path).exists();
+        create_parent_dir(&self.mihoro_usage_service_path)?;
+        fs::write(&self.mihoro_usage_service_path, service)?;
+        fs::write(&self.mihoro_usage_timer_path, timer)?;
+        if created {
+            self.track(&[
+                &self.mihoro_usage_service_path,
+                &self.mihoro_usage_timer_path,
+            ])?;
+        }
+        println!(
+            "{} Created mihoro-usage.timer at {}",
+            self.prefix.success(),
+            self.mihoro_usage_timer_path.underline().yellow()
+        );
+        Ok(())
+    }
+
+    /// Command line running this mihoro binary with the same config and root, e.g. in units.
+    fn mihoro_command(&self) -> Result<String> {
+        let mut command = env::current_exe()?.display().to_string();
+        if let Some(root) = root() {
+            command.push_str(&format!(" --root {}", root.display()));
+        }
+        command.push_str(&format!(" -m {}", self.config_path));
+        Ok(command)
+    }
+
+    /// mihomo.service running mihomo directly, or under `mihoro watchdog` with `watchdog_sec`.
+    fn mihomo_service_unit(&self) -> Result<String> {
+        let exec_start = match self.config.watchdog_sec {
+            Some(_) => format!("{} watchdog", self.mihoro_command()?),
+            None => format!(
+                "{} -d {}",
+                self.mihomo_target_binary_path, self.mihomo_target_config_root
+            ),
+        };
+        Ok(mihomo_service_unit(
+            &exec_start,
+            self.config.watchdog_sec,
+            self.config.install_scope(),
+        ))
+    }
+
+    /// Run mihomo as mihomo.service's main process when `watchdog_sec` is set: systemd is notified
+    /// once the external controller responds, then after every successful health check at half
+    /// the watchdog timeout. A hung mihomo misses them and is restarted by systemd. With
+    /// `[memory_limit]`, mihomo is restarted in place once its memory grows past the ceiling.
+    pub async fn watchdog(&self, client: Client) -> Result<()> {
+        let api = self.api(client.clone())?;
+        let timeout = watchdog_timeout().unwrap_or(Duration::from_secs(
+            self.config.watchdog_sec.unwrap_or(DEFAULT_WATCHDOG_SEC),
+        ));
+        let spawn_mihomo = || {
+            tokio::process::Command::new(&self.mihomo_target_binary_path)
+                .arg("-d")
+                .arg(&self.mihomo_target_config_root)
+                .kill_on_drop(true)
+                .spawn()
+                .context("failed to run mihomo")
+        };
+        let mut mihomo = spawn_mihomo()?;
+        let mut memory_guard = self.memory_guard(client)?;
+        let mut memory_checks =
+            tokio::time::interval(memory_guard.as_ref().map_or(timeout, MemoryGuard::interval));
+        let mut sigterm = signal(SignalKind::terminate())?;
+        let mut sighup = signal(SignalKind::hangup())?;
+        let mut checks = tokio::time::interval(timeout / 4);
+        let mut ready = false;
+        loop {
+            tokio::select! {
+                status = mihomo.wait() => bail!("mihomo exited with {}", status?),
+                _ = sigterm.recv() => {
+                    sd_notify("STOPPING=1")?;
+                    if let Some(pid) = mihomo.id() {
+                        send_signal(pid, "TERM")?;
+                    }
+                    mihomo.wait().await?;
+                    return Ok(());
+                }
+                // Reloads of mihomo.service signal the main process
+                _ = sighup.recv() => {
+                    if let Some(pid) = mihomo.id() {
+                        send_signal(pid, "HUP")?;
+                    }
+                }
+                _ = memory_checks.tick(), if memory_guard.is_some() => {
+                    if let Some(guard) = memory_guard.as_mut() {
+                        if restart_on_memory(guard, &mut mihomo, &self.prefix).await? {
+                            mihomo = spawn_mihomo()?;
+                        }
+                    }
+                }
+                _ = checks.tick() => match tokio::time::timeout(timeout / 2, api.version()).await {
+                    Ok(Ok(_)) => {
+                        if !ready {
+                            sd_notify("READY=1")?;
+                            ready = true;
+                        }
+                        sd_notify("WATCHDOG=1")?;
+                    }
+                    // mihomo is still starting up
+                    _ if !ready => {}
+                    Ok(Err(error)) => eprintln!("{} Health check failed: {:#}", self.prefix.error(), error),
+                    Err(_) => eprintln!(
+                        "{} Health check failed: no response within {}s",
+                        self.prefix.error(),
+                        (timeout / 2).as_secs_f64()
+                    ),
+                },
+            }
+        }
+    }
+
+    /// Watch nodes of the subscription and switch to `backup_profile` of `[failover]` once all of
+    /// them fail `failures` health checks in a row, then back once its servers accept connections
+    /// again. Whether it failed over is kept in state, so that it resumes after a restart.
+    pub async fn failover(&self, client: Client) -> Result<()> {
+        let Some(failover) = &self.config.failover else {
+            bail!("`[failover]` undefined, set its `backup_profile` first");
+        };
+        let backup = Mihoro::new(&failover.backup_profile)?;
+        if backup.mihomo_target_config_root != self.mihomo_target_config_root {
+            bail!(
+                "backup profile {} must share `mihomo_config_root` with {}",
+                backup.config_path,
+                self.config_path
+            );
+        }
+        let interval_sec = failover
+            .interval_sec
+            .unwrap_or(DEFAULT_FAILOVER_INTERVAL_SEC);
+        let threshold = failover.failures.unwrap_or(DEFAULT_FAILOVER_FAILURES);
+        println!(
+            "{} Checking nodes of {} every {}s, failing over to {}",
+            self.prefix.info(),
+            self.profile.bold(),
+            interval_sec,
+            backup.profile.bold()
+        );
+
+        let mut checks = tokio::time::interval(Duration::from_secs(interval_sec.max(1)));
+        let mut failures = 0;
+        loop {
+            checks.tick().await;
+            let failed_over = State::load(&self.mihomo_target_state_path)?
+                .failover_profile
+                .is_some();
+            let (target, message) = if failed_over {
+                if !self.subscription_recovered(&client).await {
+                    continue;
+                }
+                (
+                    self,
+                    format!(
+                        "Servers of {} are reachable again, switching back from {}",
+                        self.profile, backup.profile
+                    ),
+                )
+            } else {
+                match self.subscription_down(&client).await {
+                    Ok(true) => failures += 1,
+                    Ok(false) => failures = 0,
+                    // mihomo itself being down is not the provider's fault
+                    Err(error) => {
+                        eprintln!("{} Health check failed: {:#}", self.prefix.error(), error);
+                        continue;
+                    }
+                }
+                if failures < threshold.max(1) {
+                    continue;
+                }
+                failures = 0;
+                (
+                    &backup,
+                    format!(
+                        "All nodes of {} are down, switching to {}",
+                        self.profile, backup.profile
+                    ),
+                )
+            };
+
+            println!("{} {}", self.prefix.warning(), message);
+            if let Err(error) = target.update(client.clone()).await {
+                eprintln!(
+                    "{} Failed to switch to {}: {:#}",
+                    self.prefix.error(),
+                    target.profile,
+                    error
+                );
+                continue;
+            }
+            let mut state = State::load(&self.mihomo_target_state_path)?;
+            state.failover_profile = (!failed_over).then(|| backup.profile.clone());
+            state.write(&self.mihomo_target_state_path)?;
+            if failover.notify == Some(true) {
+                notify_desktop("mihoro: failover", &message);
+            }
+        }
+    }
+
+    /// Whether every node of the subscription fails its delay test through the running mihomo.
+    /// Local and static nodes and `DIRECT` are left out, as they do not depend on the provider.
+    async fn subscription_down(&self, client: &Client) -> Result<bool> {
+        let api = self.api(client.clone())?;
+        let proxies = api.proxies().await?;
+        let local_proxies =
+            LocalProxies::load_expanded(&self.mihomo_target_local_proxies_path, &self.template())?;
+        let static_proxies = self.config.static_proxies.iter().flatten();
+        let local_names: Vec<&str> = local_proxies
+            .proxies
+            .iter()
+            .filter_map(entry_name)
+            .chain(static_proxies.map(|proxy| proxy.name.as_str()))
+            .collect();
+        let names: Vec<&String> = proxies
+            .values()
+            .filter(|proxy| proxy.all.is_none() && proxy.proxy_type != "Direct")
+            .map(|proxy| &proxy.name)
+            .filter(|name| is_testable(&proxies, name) && !local_names.contains(&name.as_str()))
+            .collect();
+        if names.is_empty() {
+            return Ok(false);
+        }
+        let results = test_delays(&api, names, &self.latency(&None, None, None)).await;
+        Ok(results.iter().all(|(_, result)| result.is_err()))
+    }
+
+    /// Whether servers of the subscription accept connections again, probed from a fresh download
+    /// of the remote config while mihomo runs the backup profile's.
+    async fn subscription_recovered(&self, client: &Client) -> bool {
+        let probe_path = format!("{}/{}", self.mihomo_target_config_root, FAILOVER_PROBE_FILE);
+        let fetched = self.fetch_remote_config(client, &probe_path).await;
+        let raw_config = fetched.and_then(|_| Ok(fs::read_to_string(&probe_path)?));
+        let _ = fs::remove_file(&probe_path);
+        match raw_config {
+            Ok(raw_config) => {
+                servers_reachable(&raw_config, &self.latency(&None, None, None)).await
+            }
+            Err(error) => {
+                eprintln!(
+                    "{} Subscription of {} is still unavailable: {:#}",
+                    self.prefix.warning(),
+                    self.profile,
+                    error
+                );
+                false
+            }
+        }
+    }
+
+    /// Run mihomo in the foreground under mihoro's supervision instead of systemd, logging to
+    /// `mihomo.log` rotated at `max_log_size` MiB (or every `max_log_age` hours) with `log_files`
+    /// rotated logs kept.
+    pub async fn run(
+        &self,
+        client: Client,
+        max_log_size: u64,
+        max_log_age: Option<u64>,
+        log_files: usize,
+    ) -> Result<()> {
+        let log = RotatingLog::open(
+            &self.mihomo_target_log_path,
+            max_log_size * 1024 * 1024,
+            max_log_age.map(|hours| Duration::from_secs(hours * 3600)),
+            log_files,
+        )?;
+        println!(
+            "{} Supervising mihomo, logging to {}",
+            self.prefix.info(),
+            self.mihomo_target_log_path.underline().yellow()
+        );
+        supervise(
+            &self.mihomo_target_binary_path,
+            &self.mihomo_target_config_root,
+            log,
+            self.memory_guard(client)?,
+            &self.prefix,
+        )
+        .await
+    }
+
+    /// Whether mihomo is run by `mihoro run` rather than mihomo.service, i.e. its logs are in
+    /// `mihomo.log` and not the journal.
+    pub fn supervised(&self) -> bool {
+        !Path::new(&self.mihomo_target_service_path).exists()
+            && Path::new(&self.mihomo_target_log_path).exists()
+    }
+
+    /// Print the last lines of `mihomo.log` written by `mihoro run` and follow it.
+    pub async fn follow_log(&self) -> Result<()> {
+        follow_log(&self.mihomo_target_log_path, 10).await
+    }
+
+    /// Guard restarting mihomo past the memory ceiling of `[memory_limit]`, if set.
+    fn memory_guard(&self, client: Client) -> Result<Option<MemoryGuard>> {
+        let Some(memory_limit) = &self.config.memory_limit else {
+            return Ok(None);
+        };
+        println!(
+            "{} Restarting mihomo once it uses more than {} MiB of memory",
+            self.prefix.info(),
+            memory_limit.max_mb
+        );
+        Ok(Some(MemoryGuard::new(self.api(client)?, memory_limit)))
+    }
+
+    /// Warnings of quotas crossed and upcoming subscription expiry, per the thresholds of `quota` in
+    /// mihoro config.
+    pub fn quota_warnings(&self) -> Result<Vec<String>> {
+        let quota = self.config.quota.clone().unwrap_or_default();
+        let mut warnings = Vec::new();
+
+        let state = State::load(&self.mihomo_target_state_path)?;
+        if let (Some(min_remaining_percent), Some(subscription)) =
+            (quota.min_remaining_percent, &state.subscription)
+        {
+            let remaining_percent =
+                subscription.remaining() as f64 / subscription.total as f64 * 100.0;
+            if remaining_percent < min_remaining_percent {
+                warnings.push(tr_args(
+                    "only {} ({}) of subscription traffic left, as of the last update",
+                    &[
+                        &HumanBytes(subscription.remaining()),
+                        &format!("{:.1}%", remaining_percent),
+                    ],
+                ));
+            }
+        }
+
+        let days_left = state
+            .subscription
+            .as_ref()
+            .and_then(|subscription| subscription.days_left(Utc::now().timestamp()));
+        match days_left {
+            Some(days_left) if days_left < 0 => {
+                warnings.push(String::from(tr("subscription has expired")));
+            }
+            Some(days_left) if days_left < quota.expiry_warn_days.unwrap_or(7) => {
+                warnings.push(tr_args("subscription expires in {} day(s)", &[&days_left]));
+            }
+            _ => {}
+        }
+
+        if let Some(monthly_limit_gib) = quota.monthly_limit_gib {
+            let usage = Usage::load(&self.mihomo_target_usage_path)?;
+            let month = Local::now().format("%Y-%m-").to_string();
+            let used: u64 = usage
+                .day_totals()
+                .iter()
+                .filter(|(day, _)| day.starts_with(&month))
+                .map(|(_, traffic)| traffic.total())
+                .sum();
+            let limit = monthly_limit_gib * 1024.0 * 1024.0 * 1024.0;
+            let used_percent = used as f64 / limit * 100.0;
+            if used_percent >= quota.monthly_warn_percent.unwrap_or(80.0) {
+                warnings.push(tr_args(
+                    "{} of {} monthly traffic used ({})",
+                    &[
+                        &HumanBytes(used),
+                        &HumanBytes(limit as u64),
+                        &format!("{:.0}%", used_percent),
+                    ],
+                ));
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Print quota and expiry warnings to stderr, and send them as a desktop notification at most
+    /// once a day if `quota.notify` is set. Failures are ignored so that commands are never
+    /// blocked.
+    pub fn warn_quota(&self) {
+        let Ok(warnings) = self.quota_warnings() else {
+            return;
+        };
+        if warnings.is_empty() {
+            return;
+        }
+        for warning in &warnings {
+            eprintln!("{} {}", tr("warning:").yellow().bold(), warning);
+        }
+
+        let notify = self.config.quota.as_ref().and_then(|quota| quota.notify);
+        let Ok(mut state) = State::load(&self.mihomo_target_state_path) else {
+            return;
+        };
+        let today = Local::now().format("%Y-%m-%d").to_string();
+        if notify != Some(true) || state.quota_notified.as_ref() == Some(&today) {
+            return;
+        }
+        if notify_desktop("mihoro: subscription", &warnings.join("\n")) {
+            state.quota_notified = Some(today);
+            let _ = state.write(&self.mihomo_target_state_path);
+        }
+    }
+
+    /// Template variables of local fragments, as expanded when generating the config.
+    fn template(&self) -> Template {
+        Template::new(
+            &self.config,
+            &self.profile,
+            Path::new(&self.mihomo_target_config_root),
+        )
+    }
+
+    /// systemctl managing services of `install_scope`.
+    pub fn systemctl(&self) -> Systemctl {
+        Systemctl::for_scope(self.config.install_scope())
+    }
+
+    /// Whether a command writes files of a system-wide install, which requires root. Commands
+    /// that only read, such as listing nodes or showing usage, run as the invoking user.
+    pub fn requires_root(&self, command: &Commands) -> bool {
+        if self.config.install_scope() != InstallScope::System || is_root() {
+            return false;
+        }
+        match command {
+            Commands::Setup
+            | Commands::Update
+            | Commands::Fetch
+            | Commands::Build
+            | Commands::Activate
+            | Commands::UpdateGeodata
+            | Commands::Apply
+            | Commands::Clean
+            | Commands::Uninstall { .. }
+            | Commands::Doctor { .. }
+            | Commands::Failover => true,
+            // Showing usage records it on a best-effort basis, mihoro-usage.timer runs as root
+            Commands::Usage { record, .. } => *record,
+            Commands::Node { node } => !matches!(node, None | Some(NodeCommands::List)),
+            Commands::Rules { rules } => !matches!(rules, None | Some(RulesCommands::List)),
+            Commands::Route { route } => !matches!(route, None | Some(RouteCommands::List)),
+            Commands::Adblock { adblock } => adblock.is_some(),
+            Commands::Tun { tun } => tun.is_some(),
+            Commands::Dns { dns } => dns.is_some(),
+            Commands::Secret { secret } => secret.is_some(),
+            _ => false,
+        }
+    }
+
+    pub fn transparent_commands(&self, transparent: &Option<TransparentCommands>) -> Result<()> {
+        let (script, apply) = match transparent {
+            Some(TransparentCommands::Enable { apply, local }) => {
+                let mihomo_config = &self.config.mihomo_config;
+                let Some(tproxy_port) = mihomo_config.tproxy_port else {
+                    bail!("`mihomo_config.tproxy_port` undefined, set it and run `mihoro apply`");
+                };
+                let routing_mark = match (local, mihomo_config.routing_mark) {
+                    (false, _) => None,
+                    (true, Some(routing_mark)) => Some(routing_mark),
+                    (true, None) => bail!(
+                        "`mihomo_config.routing_mark` undefined, required to keep mihomo's own \
+                         traffic out of the proxy, set it and run `mihoro apply`"
+                    ),
+                };
+                let bypass = self.config.bypass.as_deref().unwrap_or_default();
+                (
+                    tproxy_enable_script(tproxy_port, routing_mark, bypass),
+                    *apply,
+                )
+            }
+            Some(TransparentCommands::Disable { apply }) => (tproxy_disable_script(), *apply),
+            None => return Ok(()),
+        };
+        if !apply {
+            print!("{}", script);
+            return Ok(());
+        }
+
+        if !run_root_script(&script)?.success() {
+            bail!("failed to apply transparent proxy rules");
+        }
+        let action = match transparent {
+            Some(TransparentCommands::Enable { .. }) => "Enabled",
+            _ => "Disabled",
+        };
+        println!("{} {} transparent proxying", self.prefix.success(), action);
+        Ok(())
+    }
+
+    /// Run `command` with its traffic routed to `policy` through the listener of its `app_routes`
+    /// entry: this process is moved into the route's cgroup, matched by nftables rules, before
+    /// starting it.
+    pub fn app_route(&self, policy: &str, command: &[String]) -> Result<ExitStatus> {
+        let app_routes = self.config.app_routes.as_deref().unwrap_or_default();
+        let Some(route) = app_routes.iter().find(|route| route.policy == policy) else {
+            bail!(
+                "no `app_routes` entry for `{}`, add one with a free port and run `mihoro apply`",
+                policy
+            );
+        };
+        let listener = app_route_listener(route);
+        let raw_mihomo_yaml = fs::read_to_string(&self.mihomo_target_config_path)?;
+        let mihomo_yaml: serde_yaml::Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+        let listening = mihomo_yaml
+            .get("listeners")
+            .and_then(serde_yaml::Value::as_sequence)
+            .is_some_and(|listeners| {
+                listeners.iter().any(|entry| {
+                    entry.get("name").and_then(serde_yaml::Value::as_str) == Some(&listener)
+                })
+            });
+        if !listening {
+            bail!(
+                "listener `{}` not in mihomo config, run `mihoro apply`",
+                listener
+            );
+        }
+        let (program, args) = command.split_first().context("no command given")?;
+
+        let bypass = self.config.bypass.as_deref().unwrap_or_default();
+        let script = app_route_enable_script(app_routes, route, std::process::id(), bypass);
+        if !run_root_script(&script)?.success() {
+            bail!("failed to apply app routing rules");
+        }
+        println!(
+            "{} Routing `{}` through {}",
+            self.prefix.info(),
+            program,
+            policy.bold()
+        );
+        Command::new(program)
+            .args(args)
+            .status()
+            .with_context(|| format!("failed to run `{}`", program))
+    }
+
+    /// Revert the rules and cgroups of `mihoro app-route`.
+    pub fn clear_app_routes(&self) -> Result<()> {
+        if !run_root_script(&app_route_disable_script())?.success() {
+            bail!("failed to revert app routing rules");
+        }
+        println!("{} Cleared app routing rules", self.prefix.success());
+        Ok(())
+    }
+
+    /// Enable or disable mihomo's TUN mode in `mihoro.toml` and apply it, along with what TUN mode
+    /// depends on outside of mihomo's config: the binary's capabilities for user installs, and the
+    /// system resolver when queries would not pass through the TUN device.
+    pub async fn tun_commands(&self, client: Client, tun: &Option<TunCommands>) -> Result<()> {
+        let enable = match tun {
+            Some(TunCommands::Enable) => true,
+            Some(TunCommands::Disable) => false,
+            None => return Ok(()),
+        };
+
+        edit_config(&self.config_path, |document| {
+            let tun = table_mut(document, &["mihomo_config", "tun"])?;
+            set_value(tun, "enable", enable);
+            if !enable {
+                return Ok(());
+            }
+            set_default(tun, &["stack"], "mixed");
+            set_default(tun, &["auto-route", "auto_route"], true);
+            set_default(
+                tun,
+                &["auto-detect-interface", "auto_detect_interface"],
+                true,
+            );
+            set_default(
+                tun,
+                &["dns-hijack", "dns_hijack"],
+                Array::from_iter(["any:53", "tcp://any:53"]),
+            );
+
+            // Hijacked queries are answered by mihomo's DNS, in fake-ip mode unless set otherwise
+            let dns = table_mut(document, &["mihomo_config", "dns"])?;
+            set_value(dns, "enable", true);
+            set_default(dns, &["enhanced-mode"], "fake-ip");
+            Ok(())
+        })?;
+        println!(
+            "{} {} `tun` in {}",
+            self.prefix.success(),
+            if enable { "Enabled" } else { "Disabled" },
+            self.config_path.underline().yellow()
+        );
+
+        let resolved_configured = Path::new(RESOLVED_DROP_IN).exists();
+        let mut script = String::new();
+        if enable {
+            if self.config.install_scope() == InstallScope::User
+                && !has_tun_capabilities(&self.mihomo_target_binary_path)
+            {
+                script.push_str(&tun_capabilities_script(&self.mihomo_target_binary_path));
+            }
+            match Resolver::detect(&fs::read_to_string(RESOLV_CONF).unwrap_or_default()) {
+                Resolver::SystemdResolved if !resolved_configured => {
+                    script.push_str(&resolved_enable_script())
+                }
+                Resolver::Loopback(nameserver) => println!(
+                    "{} {} uses the local resolver {}, whose queries bypass the TUN device, point \
+                     it to mihomo's `dns.listen` or a remote nameserver",
+                    self.prefix.warning(),
+                    RESOLV_CONF.underline(),
+                    nameserver.bold()
+                ),
+                _ => {}
+            }
+        } else if resolved_configured {
+            script.push_str(&resolved_disable_script());
+        }
+        if !script.is_empty() {
+            print!("{}", script.dimmed());
+            if !run_root_script(&script)?.success() {
+                bail!("failed to set up the system for TUN mode");
+            }
+        }
+
+        // Reload the config via a fresh `Mihoro`, as `self` still holds the previous one
+        Mihoro::new(&self.config_path)?.apply(client).await
+    }
+
+    /// Switch `enhanced-mode` of mihomo's DNS between fake-ip and redir-host in `mihoro.toml`,
+    /// flush the fake-ip cache so that no app keeps stale fake ips, and apply the config.
+    pub async fn dns_commands(&self, client: Client, dns: &Option<DnsCommands>) -> Result<()> {
+        let Some(DnsCommands::FakeIp { state }) = dns else {
+            return Ok(());
+        };
+        let mode = match state {
+            Switch::On => "fake-ip",
+            Switch::Off => "redir-host",
+        };
+
+        edit_config(&self.config_path, |document| {
+            let dns = table_mut(document, &["mihomo_config", "dns"])?;
+            set_value(dns, "enhanced-mode", mode);
+            Ok(())
+        })?;
+        println!(
+            "{} Set `dns.enhanced-mode` to {} in {}",
+            self.prefix.success(),
+            mode.bold(),
+            self.config_path.underline().yellow()
+        );
+
+        // Flushed before the restart, which would otherwise load fake ips stored in `cache.db`
+        match self.api(client.clone())?.flush_fake_ip().await {
+            Ok(()) => println!("{} Flushed the fake-ip cache", self.prefix.success()),
+            Err(error) => println!(
+                "{} Failed to flush the fake-ip cache: {:#}",
+                self.prefix.warning(),
+                error
+            ),
+        }
+
+        // Reload the config via a fresh `Mihoro`, as `self` still holds the previous one
+        Mihoro::new(&self.config_path)?.apply(client).await
+    }
+
+    /// Check the setup for common silent failures, offering fixes of `mihoro.toml` where there
+    /// are, applied without asking with `fix`.
+    pub async fn doctor(&self, client: Client, fix: bool) -> Result<()> {
+        self.check_vpns();
+        self.check_proxy_conflicts();
+        self.check_ipv6(client, fix).await
+    }
+
+    /// Check for VPNs running alongside mihomo whose routes clash with TUN mode, suggesting
+    /// exclusions in `[mihomo_config.tun]` for each.
+    fn check_vpns(&self) {
+        let tun = self.config.mihomo_config.tun.as_ref();
+        let tun_enabled = tun.and_then(|tun| tun.enable).unwrap_or(false);
+        let auto_route = tun.and_then(|tun| tun.auto_route).unwrap_or(false);
+        let tun_list = |field: &str| -> Vec<String> {
+            tun.and_then(|tun| tun.extra.get(field))
+                .and_then(toml::Value::as_array)
+                .map(|items| {
+                    items
+                        .iter()
+                        .filter_map(|item| item.as_str().map(String::from))
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let tun_device = tun
+            .and_then(|tun| tun.extra.get("device"))
+            .and_then(toml::Value::as_str)
+            .unwrap_or(DEFAULT_TUN_DEVICE);
+
+        let vpns = detect_vpns(tun_device);
+        if vpns.is_empty() {
+            println!(
+                "{} No Tailscale, WireGuard or OpenVPN interfaces found",
+                self.prefix.success()
+            );
+            return;
+        }
+        let default_routes = default_route_interfaces();
+        let excluded_interfaces = tun_list("exclude-interface");
+        let excluded_addresses = tun_list("route-exclude-address");
+        for vpn in &vpns {
+            let default_route = default_routes.contains(&vpn.interface);
+            println!(
+                "{} {} interface {}{}",
+                self.prefix.info(),
+                vpn.kind.label(),
+                vpn.interface.bold(),
+                if default_route {
+                    ", holding the default route"
+                } else {
+                    ""
+                }
+            );
+            if !tun_enabled {
+                continue;
+            }
+
+            let missing_interface = !excluded_interfaces.contains(&vpn.interface);
+            let missing_addresses: Vec<&str> = (vpn.kind.networks().iter())
+                .filter(|network| !excluded_addresses.iter().any(|address| address == *network))
+                .copied()
+                .collect();
+            if !missing_interface && missing_addresses.is_empty() {
+                println!(
+                    "{} {} is excluded from TUN mode",
+                    self.prefix.success(),
+                    vpn.interface
+                );
+                continue;
+            }
+            if default_route && auto_route {
+                println!(
+                    "{} {} and mihomo's TUN both take the default route, traffic may loop \
+                     between them or skip the proxy, depending on which routes win",
+                    self.prefix.warning(),
+                    vpn.interface.bold()
+                );
+            } else {
+                println!(
+                    "{} mihomo's TUN may capture traffic meant for {}, e.g. its peers or its \
+                     DNS",
+                    self.prefix.warning(),
+                    vpn.interface.bold()
+                );
+            }
+
+            let mut suggestion = String::from("[mihomo_config.tun]\n");
+            if missing_interface {
+                suggestion.push_str(&format!("exclude-interface = [\"{}\"]\n", vpn.interface));
+            }
+            if !missing_addresses.is_empty() {
+                suggestion.push_str(&format!(
+                    "route-exclude-address = [{}]\n",
+                    missing_addresses
+                        .iter()
+                        .map(|address| format!("\"{}\"", address))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+            if default_route {
+                suggestion.push_str(
+                    "# Or leave the default route to the VPN and turn off `auto-route`\n",
+                );
+            }
+            println!(
+                "{} Exclude it in {}, merging with existing entries:\n{}",
+                self.prefix.info(),
+                self.config_path.underline().yellow(),
+                suggestion.dimmed()
+            );
+        }
+        if !tun_enabled {
+            println!(
+                "{} TUN mode is disabled, VPNs do not clash with mihomo's proxy ports",
+                self.prefix.success()
+            );
+        }
+    }
+
+    /// Check for other proxies set or listening alongside mihomo, see `proxy_conflicts`.
+    fn check_proxy_conflicts(&self) {
+        let conflicts = self.proxy_conflicts();
+        if conflicts.is_empty() {
+            println!(
+                "{} No other proxies set in the environment or the desktop, nor bound to \
+                 mihomo's ports",
+                self.prefix.success()
+            );
+        }
+        for conflict in conflicts {
+            println!("{} {}", self.prefix.warning(), conflict);
+        }
+    }
+
+    /// Settings and processes leading to double proxying or to traffic skipping mihomo, as
+    /// warnings: proxy environment variables and desktop proxies pointing to something other than
+    /// mihomo's ports, and other processes listening on those ports.
+    fn proxy_conflicts(&self) -> Vec<String> {
+        let mihomo_config = &self.config.mihomo_config;
+        let mut ports = vec![mihomo_config.port, mihomo_config.socks_port];
+        ports.extend(mihomo_config.mixed_port);
+        let generated = fs::read_to_string(&self.mihomo_target_config_path)
+            .ok()
+            .and_then(|raw| serde_yaml::from_str::<serde_yaml::Value>(&raw).ok());
+        if let Some(generated) = generated {
+            ports.extend(config_ports(&generated).into_iter().map(|(_, port)| port));
+        }
+        ports.sort();
+        ports.dedup();
+
+        let lan_ip = local_ip().ok();
+        let is_local = |host: &str| {
+            let host = host.trim_matches(['[', ']']);
+            host == "localhost"
+                || host
+                    .parse::<IpAddr>()
+                    .is_ok_and(|ip| ip.is_loopback() || ip.is_unspecified() || Some(ip) == lan_ip)
+        };
+        // Proxies on this machine and on one of mihomo's ports, with any scheme or credentials
+        let points_to_mihomo = |proxy: &str| {
+            let address = proxy.split_once("://").map_or(proxy, |(_, rest)| rest);
+            let address = address.split(['/', '?']).next().unwrap_or_default();
+            let address = address.rsplit('@').next().unwrap_or(address);
+            address.rsplit_once(':').is_some_and(|(host, port)| {
+                is_local(host) && port.parse().is_ok_and(|port| ports.contains(&port))
+            })
+        };
+
+        let mut conflicts = Vec::new();
+        for variable in [
+            "http_proxy",
+            "https_proxy",
+            "all_proxy",
+            "HTTP_PROXY",
+            "HTTPS_PROXY",
+            "ALL_PROXY",
+        ] {
+            let Ok(proxy) = env::var(variable) else {
+                continue;
+            };
+            if !proxy.is_empty() && !points_to_mihomo(&proxy) {
+                conflicts.push(format!(
+                    "`{}` points to {}, not mihomo, apps honoring it go through that proxy as \
+                     well as or instead of mihomo",
+                    variable,
+                    proxy.bold()
+                ));
+            }
+        }
+
+        if !cfg!(target_os = "macos") {
+            if let Some(desktop) = Desktop::detect() {
+                for proxy in desktop.proxies() {
+                    // Only PAC urls keep their scheme, taken as mihoro's if served on this machine
+                    let local_pac = proxy.contains("://")
+                        && Url::parse(&proxy).is_ok_and(|url| url.host_str().is_some_and(is_local));
+                    if !local_pac && !points_to_mihomo(&proxy) {
+                        conflicts.push(format!(
+                            "{} proxy settings point to {}, not mihomo, run `mihoro sysproxy on` \
+                             to use mihomo instead",
+                            desktop.as_str(),
+                            proxy.bold()
+                        ));
+                    }
+                }
+            }
+        }
+
+        // Sockets of a mihomo running as another user are not visible, only count them while
+        // mihomo.service is down
+        let mihomo_active = self
+            .systemctl()
+            .is_active("mihomo.service")
+            .execute()
+            .is_ok_and(|status| status.success());
+        let binary_name = Path::new(&self.mihomo_target_binary_path)
+            .file_name()
+            .map(|name| name.to_string_lossy().chars().take(15).collect::<String>())
+            .unwrap_or_default();
+        for port in &ports {
+            let mut others: Vec<String> = Vec::new();
+            for listener in port_listeners(*port) {
+                let other = match listener {
+                    Some(name) if name == binary_name || name == "mihomo" => continue,
+                    Some(name) => name,
+                    None if mihomo_active => continue,
+                    None => String::from("a process of another user"),
+                };
+                if !others.contains(&other) {
+                    others.push(other);
+                }
+            }
+            if !others.is_empty() {
+                conflicts.push(format!(
+                    "Port {} of mihomo is also listened on by {}",
+                    port,
+                    others.join(", ").bold()
+                ));
+            }
+        }
+        conflicts
+    }
+
+    /// Check that IPv6 destinations are reachable through mihomo while `ipv6` is enabled, and
+    /// offer to disable IPv6 along with AAAA lookups of mihomo's DNS otherwise, as connections to
+    /// IPv6 addresses would fail or time out before falling back to IPv4.
+    async fn check_ipv6(&self, client: Client, fix: bool) -> Result<()> {
+        if !self.config.mihomo_config.ipv6.unwrap_or(true) {
+            println!("{} IPv6 is disabled in mihomo", self.prefix.info());
+            return Ok(());
+        }
+        let (http_port, _) = self.proxy_ports();
+        if !reachable_through(http_port, IPV4_PROBE_URL).await? {
+            println!(
+                "{} {} is unreachable through mihomo on port {}, skipped checking IPv6",
+                self.prefix.warning(),
+                IPV4_PROBE_URL.underline(),
+                http_port
+            );
+            return Ok(());
+        }
+        if reachable_through(http_port, IPV6_PROBE_URL).await? {
+            println!("{} IPv6 works through mihomo", self.prefix.success());
+            return Ok(());
+        }
+
+        println!(
+            "{} IPv4 works through mihomo but IPv6 does not, connections to IPv6 addresses fail \
+             or stall before falling back to IPv4",
+            self.prefix.warning()
+        );
+        if !fix && !confirm("Set `ipv6 = false` for mihomo and its DNS, and apply?")? {
+            return Ok(());
+        }
+        edit_config(&self.config_path, |document| {
+            let mihomo_config = table_mut(document, &["mihomo_config"])?;
+            set_value(mihomo_config, "ipv6", false);
+            if let Some(dns) = mihomo_config
+                .get_mut("dns")
+                .and_then(Item::as_table_like_mut)
+            {
+                set_value(dns, "ipv6", false);
+            }
+            Ok(())
+        })?;
+        println!(
+            "{} Disabled IPv6 in {}",
+            self.prefix.success(),
+            self.config_path.underline().yellow()
+        );
+        Mihoro::new(&self.config_path)?.apply(client).await
+    }
+
+    /// Replace the secret of mihomo's external controller with a random one in `mihoro.toml` and
+    /// `config.yaml`, then reload mihomo with it and print the url of the dashboard.
+    pub async fn secret_commands(
+        &self,
+        client: Client,
+        secret: &Option<SecretCommands>,
+    ) -> Result<()> {
+        let Some(SecretCommands::Rotate) = secret else {
+            return Ok(());
+        };
+        // Authenticated with the previous secret until mihomo is reloaded
+        let api = self.api(client)?;
+        let raw_mihomo_yaml = fs::read_to_string(&self.mihomo_target_config_path)
+            .with_context(|| format!("failed to read {}", self.mihomo_target_config_path))?;
+        let mut mihomo_yaml: serde_yaml::Mapping = serde_yaml::from_str(&raw_mihomo_yaml)?;
+
+        // A secret referenced as `{{env.NAME}}` is rotated in the dotenv file, so that it is never
+        // written to `mihoro.toml`
+        let secret = random_token(16)?;
+        let env_path = Path::new(&self.config_path).with_file_name(ENV_FILE);
+        let config = Config::setup_from(&self.config_path)?;
+        let rotated_path = match config.mihomo_config.secret.as_deref() {
+            Some(raw_secret) if raw_secret.contains("{{env.") => {
+                let Some(name) = env_reference(raw_secret) else {
+                    bail!(
+                        "`secret` in {} is not a single environment variable, set new values \
+                         of its variables to rotate it",
+                        self.config_path
+                    );
+                };
+                if !replace_env_var(&env_path, name, &secret)? {
+                    bail!(
+                        "`secret` in {} is read from `{}`, which {} does not define, set a new \
+                         secret where it is defined",
+                        self.config_path,
+                        name,
+                        env_path.display()
+                    );
+                }
+                env_path.display().to_string()
+            }
+            _ => {
+                edit_config(&self.config_path, |document| {
+                    set_value(table_mut(document, &["mihomo_config"])?, "secret", &*secret);
+                    Ok(())
+                })?;
+                self.config_path.clone()
+            }
+        };
+        // Only the secret changes, other edits of `mihoro.toml` wait for `mihoro apply`
+        backup_file(
+            &self.mihomo_target_config_path,
+            Path::new(&self.mihomo_target_backups_root),
+            self.config.backup_retention(),
+        )?;
+        mihomo_yaml.insert("secret".into(), secret.clone().into());
+        fs::write(
+            &self.mihomo_target_config_path,
+            serde_yaml::to_string(&mihomo_yaml)?,
+        )?;
+        println!(
+            "{} {}",
+            self.prefix.success(),
+            tr_args(
+                "Rotated `secret` in {} and {}",
+                &[
+                    &rotated_path.underline(),
+                    &self.mihomo_target_config_path.underline()
+                ]
+            )
+        );
+
+        let temporary_rules = LocalRules::load(&self.mihomo_target_temporary_rules_path)?;
+        let running_config = serde_yaml::to_string(&self.running_config(&temporary_rules)?)?;
+        match api.reload_config(&running_config).await {
+            Ok(()) => println!(
+                "{} {}",
+                self.prefix.success(),
+                tr("Reloaded mihomo with the new secret")
+            ),
+            Err(error) => {
+                println!(
+                    "{} {}",
+                    self.prefix.warning(),
+                    tr_args(
+                        "Failed to reload mihomo ({}), restarting mihomo.service",
+                        &[&format!("{:#}", error)]
+                    )
+                );
+                self.systemctl().restart("mihomo.service").execute()?;
+            }
+        }
+
+        // Dashboards are only served over TCP
+        let Some(external_controller) = &self.config.mihomo_config.external_controller else {
+            return Ok(());
+        };
+        let controller_url = controller_url(external_controller);
+        let base_url = Url::parse(&controller_url)?;
+        let dashboard_url = match &self.config.mihomo_config.external_ui {
+            // Setup link understood by metacubexd and zashboard
+            Some(_) => format!(
+                "{}/ui/#/setup?hostname={}&port={}&secret={}",
+                controller_url,
+                base_url.host_str().unwrap_or_default(),
+                base_url.port_or_known_default().unwrap_or_default(),
+                secret
+            ),
+            None => controller_url,
+        };
+        println!(
+            "{} {}",
+            self.prefix.info(),
+            tr_args("Dashboard: {}", &[&dashboard_url.underline()])
+        );
+        Ok(())
+    }
+
+    /// Point systemd-resolved to mihomo's DNS listener as configured in `resolved`, once
+    /// mihomo.service runs. Settings are reverted if `resolved` is no longer set.
+    pub fn sync_resolved(&self) -> Result<()> {
+        let Some(resolved) = &self.config.resolved else {
+            return self.revert_resolved();
+        };
+        let listen = (self.config.mihomo_config.dns.as_ref())
+            .and_then(|dns| dns.extra.get("listen"))
+            .and_then(toml::Value::as_str);
+        let Some(listener) = listen.and_then(listener_address) else {
+            bail!("`mihomo_config.dns.listen` undefined or invalid, required by `resolved`");
+        };
+        let domains = resolved.domains.as_deref().unwrap_or_default();
+        if !run_root_script(&resolvectl_script(listener, domains))?.success() {
+            bail!("failed to point systemd-resolved to mihomo with resolvectl");
+        }
+        println!(
+            "{} Resolving {} with mihomo's DNS at {}",
+            self.prefix.success(),
+            match domains {
+                [] => String::from("all domains"),
+                domains => domains.join(", "),
+            },
+            listener.to_string().bold()
+        );
+        Ok(())
+    }
+
+    /// Restore systemd-resolved's settings from before `sync_resolved`, before mihomo.service
+    /// stops.
+    pub fn revert_resolved(&self) -> Result<()> {
+        if !resolved_link_exists() {
+            return Ok(());
+        }
+        if !run_root_script(&resolvectl_revert_script())?.success() {
+            bail!("failed to restore systemd-resolved settings");
+        }
+        println!(
+            "{} Restored systemd-resolved settings",
+            self.prefix.success()
+        );
+        Ok(())
+    }
+
+    /// Delay test settings of `[latency]`, with the ones given on the command line taking
+    /// precedence.
+    fn latency(
+        &self,
+        test_url: &Option<String>,
+        timeout_ms: Option<u64>,
+        concurrency: Option<usize>,
+    ) -> Latency {
+        let latency = self.config.latency.clone().unwrap_or_default();
+        latency.overridden(test_url, timeout_ms, concurrency)
+    }
+
+    /// Client of the running mihomo's API, on the `external_controller_unix` socket if set.
+    fn api(&self, client: Client) -> Result<MihomoApi> {
+        let mihomo_config = &self.config.mihomo_config;
+        let secret = mihomo_config.secret.as_deref();
+        // Relative to mihomo's config root, as resolved by mihomo
+        if let Some(socket_path) = &mihomo_config.external_controller_unix {
+            let socket_path = Path::new(&self.mihomo_target_config_root).join(socket_path);
+            return Ok(MihomoApi::new_unix(&socket_path, secret));
+        }
+        let Some(external_controller) = &mihomo_config.external_controller else {
+            bail!(
+                "`external_controller` and `external_controller_unix` undefined, mihomo API is \
+                 required"
+            );
+        };
+        Ok(MihomoApi::new(client, external_controller, secret))
+    }
+
+    /// Config of the running mihomo, i.e. the generated `config.yaml` with temporary routes.
+    fn running_config(
+        &self,
+        temporary_rules: &LocalRules,
+    ) -> Result<BTreeMap<String, serde_yaml::Value>> {
+        let raw_mihomo_yaml = fs::read_to_string(&self.mihomo_target_config_path)?;
+        let mut mihomo_yaml = serde_yaml::from_str(&raw_mihomo_yaml)?;
+        merge_local_rules(&mut mihomo_yaml, temporary_rules);
+        Ok(mihomo_yaml)
+    }
+
+    pub fn adblock_commands(&self, adblock: &Option<AdblockCommands>) -> Result<()> {
+        let mut local_rules = LocalRules::load(&self.mihomo_target_local_rules_path)?;
+
+        match adblock {
+            Some(AdblockCommands::Enable) => {
+                let url = self
+                    .config
+                    .adblock_url
+                    .as_deref()
+                    .unwrap_or(DEFAULT_ADBLOCK_URL);
+                let ruleset = Ruleset {
+                    name: String::from(ADBLOCK_RULESET),
+                    url: url.to_string(),
+                    behavior: "domain",
+                    format: ruleset_format(url),
+                };
+                local_rules.prepend_ruleset(&ruleset, "REJECT")?;
+                println!(
+                    "{} Enabled ad-blocking with {}",
+                    self.prefix.info(),
+                    url.underline()
+                );
+            }
+            Some(AdblockCommands::Disable) => {
+                if !local_rules.remove_ruleset(ADBLOCK_RULESET) {
+                    println!("{} Ad-blocking is not enabled", self.prefix.warning());
+                    return Ok(());
+                }
+                println!("{} Disabled ad-blocking", self.prefix.info());
+            }
+            None => return Ok(()),
+        }
+
+        self.write_local_rules(&local_rules)
+    }
+
+    pub fn nm_dispatcher_commands(&self, dispatcher: &Option<NmDispatcherCommands>) -> Result<()> {
+        let (script, action) = match dispatcher {
+            Some(NmDispatcherCommands::Enable) => (
+                format!(
+                    "cat > {path} <<'EOF'\n{}EOF\nchmod 755 {path}\n",
+                    self.nm_dispatcher_script()?,
+                    path = NM_DISPATCHER_PATH
+                ),
+                "Installed",
+            ),
+            Some(NmDispatcherCommands::Disable) => {
+                if !Path::new(NM_DISPATCHER_PATH).exists() {
+                    println!(
+                        "{} NetworkManager dispatcher script is not installed",
+                        self.prefix.warning()
+                    );
+                    return Ok(());
+                }
+                (format!("rm -f {}\n", NM_DISPATCHER_PATH), "Removed")
+            }
+            Some(NmDispatcherCommands::Show) => {
+                print!("{}", self.nm_dispatcher_script()?);
+                return Ok(());
+            }
+            None => return Ok(()),
+        };
+        if !run_root_script(&script)?.success() {
+            bail!("failed to update {}", NM_DISPATCHER_PATH);
+        }
+        println!(
+            "{} {} NetworkManager dispatcher script {}",
+            self.prefix.success(),
+            action,
+            NM_DISPATCHER_PATH.underline().yellow()
+        );
+        Ok(())
+    }
+
+    /// Dispatcher script restarting mihomo.service of this install scope, ignoring mihomo's own
+    /// TUN device and systemd-resolved link.
+    fn nm_dispatcher_script(&self) -> Result<String> {
+        let restart_command = match self.config.install_scope() {
+            InstallScope::System => String::from("systemctl --no-block restart mihomo.service"),
+            InstallScope::User => {
+                let user = env::var("USER")
+                    .context("`USER` undefined, required to restart the user's mihomo.service")?;
+                format!(
+                    "systemctl --user --machine={}@ --no-block restart mihomo.service",
+                    user
+                )
+            }
+        };
+        let tun_device = (self.config.mihomo_config.tun.as_ref())
+            .and_then(|tun| tun.extra.get("device"))
+            .and_then(toml::Value::as_str)
+            .unwrap_or(DEFAULT_TUN_DEVICE);
+        Ok(nm_dispatcher_script(
+            &restart_command,
+            &[tun_device, RESOLVED_LINK],
+        ))
+    }
+
+    pub fn sync_commands(&self, sync: &Option<SyncCommands>) -> Result<()> {
+        let repo = Path::new(&self.mihomo_target_sync_root);
+        let config_root = Path::new(&self.mihomo_target_config_root);
+        let initialized = repo.join(".git").exists();
+        match sync {
+            Some(SyncCommands::Init { remote }) => {
+                if initialized {
+                    bail!(
+                        "{} is already a sync repository",
+                        self.mihomo_target_sync_root
+                    );
+                }
+                create_parent_dir(&self.mihomo_target_sync_root)?;
+                let status = Command::new("git")
+                    .arg("clone")
+                    .arg(remote)
+                    .arg(repo)
+                    .status()
+                    .context("failed to run git")?;
+                if !status.success() {
+                    bail!("failed to clone {}", remote);
+                }
+                println!(
+                    "{} {}",
+                    self.prefix.success(),
+                    tr_args(
+                        "Cloned {} into {}, run `mihoro sync push` to upload this machine's \
+                         config or `mihoro sync pull` to use the synced one",
+                        &[
+                            &remote.underline(),
+                            &self.mihomo_target_sync_root.underline().yellow()
+                        ]
+                    )
+                );
+            }
+            Some(SyncCommands::Push { message }) => {
+                if !initialized {
+                    bail!("sync repository not initialized, run `mihoro sync init <remote>`");
+                }
+                let mut document: DocumentMut = fs::read_to_string(&self.config_path)?.parse()?;
+                without_secrets(&mut document);
+                fs::write(repo.join(SYNCED_CONFIG_FILE), document.to_string())?;
+                for file in SYNCED_FILES {
+                    replace_with_copy(&config_root.join(file), &repo.join(file))?;
+                }
+
+                git(repo, &["add", "--all"])?;
+                if git_output(repo, &["status", "--porcelain"])?.is_empty() {
+                    println!(
+                        "{} {}",
+                        self.prefix.warning(),
+                        tr("No local changes to commit")
+                    );
+                } else {
+                    let message = message.as_deref().unwrap_or("Sync mihoro config");
+                    git(repo, &["commit", "--quiet", "--message", message])?;
+                }
+                // Also pushes earlier commits whose push failed
+                git(repo, &["push", "--quiet", "origin", "HEAD"])?;
+                println!(
+                    "{} {}",
+                    self.prefix.success(),
+                    tr_args(
+                        "Pushed config from {}",
+                        &[&self.mihomo_target_sync_root.underline().yellow()]
+                    )
+                );
+            }
+            Some(SyncCommands::Pull) => {
+                if !initialized {
+                    bail!("sync repository not initialized, run `mihoro sync init <remote>`");
+                }
+                git(repo, &["pull", "--quiet", "--ff-only"])?;
+                let synced_config_path = repo.join(SYNCED_CONFIG_FILE);
+                if !synced_config_path.exists() {
+                    bail!("no config synced yet, run `mihoro sync push` on another machine first");
+                }
+                let synced: DocumentMut = fs::read_to_string(&synced_config_path)?
+                    .parse()
+                    .with_context(|| format!("failed to parse {}", synced_config_path.display()))?;
+
+                // Files replaced by synced ones are backed up, directories are replaced as is
+                let backups_root = Path::new(&self.mihomo_target_backups_root);
+                let retention = self.config.backup_retention();
+                backup_file(&self.config_path, backups_root, retention)?;
+                edit_config(&self.config_path, |document| {
+                    let local = std::mem::replace(document, synced);
+                    restore_secrets(document, &local);
+                    Ok(())
+                })?;
+                for file in SYNCED_FILES {
+                    let local_path = config_root.join(file);
+                    backup_file(&local_path.to_string_lossy(), backups_root, retention)?;
+                    replace_with_copy(&repo.join(file), &local_path)?;
+                }
+                println!(
+                    "{} {}",
+                    self.prefix.success(),
+                    tr_args(
+                        "Pulled config into {} and {}, run `mihoro apply` to take effect",
+                        &[
+                            &self.config_path.underline().yellow(),
+                            &self.mihomo_target_config_root.underline().yellow()
+                        ]
+                    )
+                );
+            }
+            None => {}
+        }
+        Ok(())
+    }
+
+    /// Bundle the setup into an archive with `export_setup`.
+    pub fn export(&self, path: &str, geodata: bool) -> Result<()> {
+        let mut files = SYNCED_FILES.to_vec();
+        if geodata {
+            files.extend(GEODATA_FILES);
+        }
+        let exported = export_setup(
+            path,
+            &self.config_path,
+            Path::new(&self.mihomo_target_config_root),
+            &files,
+        )?;
+        println!(
+            "{} Exported to {}:",
+            self.prefix.success(),
+            path.underline()
+        );
+        for file in exported {
+            println!("  {} {}", "-".dimmed(), file.display());
+        }
+        println!(
+            "{} The archive contains secrets of {}, keep it private",
+            self.prefix.warning(),
+            self.config_path.underline()
+        );
+        Ok(())
+    }
+
+    /// Write a tarball for bug reports with versions, `mihoro.toml`, the generated config, recent
+    /// logs of mihomo and the output of `mihoro doctor`, secrets and node credentials redacted.
+    pub fn debug_bundle(&self, path: &str) -> Result<()> {
+        let mut redactor = Redactor::default();
+        let mut config: toml::Value = toml::from_str(&fs::read_to_string(&self.config_path)?)?;
+        redactor.toml(&mut config);
+        let mihomo_config = match fs::read_to_string(&self.mihomo_target_config_path) {
+            Ok(raw_mihomo_yaml) => {
+                let mut mihomo_yaml: serde_yaml::Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+                redactor.yaml(&mut mihomo_yaml);
+                serde_yaml::to_string(&mihomo_yaml)?
+            }
+            Err(err) => format!("# {}: {}\n", self.mihomo_target_config_path, err),
+        };
+
+        let uname = Command::new("uname").arg("-a").output();
+        let versions = format!(
+            "mihoro {}\nmihomo {}\n{}\ninstall scope: {:?}\n",
+            env!("CARGO_PKG_VERSION"),
+            mihomo_version(&self.mihomo_target_binary_path)
+                .unwrap_or_else(|err| format!("unavailable ({:#})", err)),
+            uname.map_or_else(
+                |err| err.to_string(),
+                |output| String::from_utf8_lossy(&output.stdout).trim().to_string()
+            ),
+            self.config.install_scope()
+        );
+
+        // Recent logs, from `mihoro run` or the journal of mihomo.service
+        let logs = if self.supervised() {
+            match fs::read_to_string(&self.mihomo_target_log_path) {
+                Ok(log) => {
+                    let skipped = log.lines().count().saturating_sub(BUNDLE_LOG_LINES);
+                    log.lines().skip(skipped).collect::<Vec<_>>().join("\n")
+                }
+                Err(err) => format!("failed to read {}: {}", self.mihomo_target_log_path, err),
+            }
+        } else {
+            let mut journalctl = Command::new("journalctl");
+            if self.config.install_scope() == InstallScope::User {
+                journalctl.arg("--user");
+            }
+            let output = journalctl
+                .args(["-u", "mihomo.service", "--no-pager", "-n"])
+                .arg(BUNDLE_LOG_LINES.to_string())
+                .output();
+            match output {
+                Ok(output) => String::from_utf8_lossy(&output.stdout).to_string(),
+                Err(err) => format!("failed to run journalctl: {}", err),
+            }
+        };
+
+        // Run as a separate process, without a terminal to answer the offer of fixes
+        let mut doctor = Command::new(env::current_exe()?);
+        doctor.arg("--mihoro-config").arg(&self.config_path);
+        if let Some(root) = root() {
+            doctor.arg("--root").arg(root);
+        }
+        let doctor = doctor
+            .args(["--color", "never", "doctor"])
+            .stdin(Stdio::null())
+            .output();
+        let doctor = match doctor {
+            Ok(output) => format!(
+                "{}{}",
+                String::from_utf8_lossy(&output.stdout),
+                String::from_utf8_lossy(&output.stderr)
+            ),
+            Err(err) => format!("failed to run mihoro doctor: {}", err),
+        };
+
+        let config = toml::to_string_pretty(&config)?;
+        write_bundle(
+            path,
+            &[
+                ("versions.txt", versions),
+                ("mihoro.toml", redactor.text(&config)),
+                ("config.yaml", redactor.text(&mihomo_config)),
+                ("mihomo.log", redactor.text(&logs)),
+                ("doctor.txt", redactor.text(&doctor)),
+            ],
+        )?;
+        println!(
+            "{} Wrote debug bundle to {}, check it for anything private before sharing",
+            self.prefix.success(),
+            path.underline()
+        );
+        Ok(())
+    }
+
+    /// Unpack an archive written by `mihoro export` with `import_setup`, before mihoro is
+    /// configured on a new machine.
+    pub fn import(config_path: &str, path: &str) -> Result<()> {
+        let config_path = expand_path(config_path);
+        let imported = import_setup(path, &config_path)?;
+        let mihoro = Mihoro::new(&config_path)?;
+        println!(
+            "{} Imported from {}:",
+            mihoro.prefix.success(),
+            path.underline()
+        );
+        for file in imported {
+            println!("  {} {}", "-".dimmed(), file.display());
+        }
+        println!(
+            "{} Run `mihoro setup` to install mihomo with the imported config, or `mihoro apply` \
+             if already installed",
+            mihoro.prefix.success()
+        );
+        Ok(())
+    }
+
+    /// Push or pull state shared with other machines at `state_sync`, keyed by profile.
+    pub async fn state_sync_commands(
+        &self,
+        client: Client,
+        state_sync: &Option<StateSyncCommands>,
+    ) -> Result<()> {
+        let Some(command) = state_sync else {
+            return Ok(());
+        };
+        let Some(state_sync) = &self.config.state_sync else {
+            bail!("`state_sync` undefined in {}", self.config_path);
+        };
+        let store = StateStore::new(client.clone(), state_sync, &self.profile)?;
+        let api = self.api(client)?;
+        let machine = machine_name();
+        let mut shared = store.get().await?;
+        let mut usage = Usage::load(&self.mihomo_target_usage_path)?;
+
+        match command {
+            StateSyncCommands::Push => {
+                match api.proxies().await {
+                    Ok(proxies) => {
+                        shared.selections = proxies
+                            .into_values()
+                            .filter(|proxy| proxy.proxy_type == "Selector")
+                            .filter_map(|proxy| Some((proxy.name, proxy.now?)))
+                            .collect();
+                    }
+                    Err(error) => println!(
+                        "{} Kept shared selections, mihomo is not reachable: {:#}",
+                        self.prefix.warning(),
+                        error
+                    ),
+                }
+                shared.usage.insert(machine.clone(), usage.days);
+                store.put(&shared).await?;
+                println!(
+                    "{} Pushed {} selection(s) and usage of {} to {}",
+                    self.prefix.success(),
+                    shared.selections.len(),
+                    machine.bold(),
+                    store.url().as_str().underline()
+                );
+            }
+            StateSyncCommands::Pull => {
+                let mut switched = 0;
+                match api.proxies().await {
+                    Ok(proxies) => {
+                        for (group, node) in &shared.selections {
+                            let Some(selector) = proxies
+                                .get(group)
+                                .filter(|proxy| proxy.proxy_type == "Selector")
+                            else {
+                                continue;
+                            };
+                            let is_member = selector.all.iter().flatten().any(|name| name == node);
+                            if selector.now.as_ref() != Some(node) && is_member {
+                                api.select_proxy(group, node).await?;
+                                switched += 1;
+                            }
+                        }
+                    }
+                    Err(error) => println!(
+                        "{} Skipped shared selections, mihomo is not reachable: {:#}",
+                        self.prefix.warning(),
+                        error
+                    ),
+                }
+                shared.usage.remove(&machine);
+                usage.machines = shared.usage;
+                let created = !Path::new(&self.mihomo_target_usage_path).exists();
+                usage.write(&self.mihomo_target_usage_path)?;
+                if created {
+                    self.track(&[&self.mihomo_target_usage_path])?;
+                }
+                println!(
+                    "{} Switched {} group(s) to shared selections and pulled usage of {} other \
+                     machine(s) from {}",
+                    self.prefix.success(),
+                    switched,
+                    usage.machines.len(),
+                    store.url().as_str().underline()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn write_local_rules(&self, local_rules: &LocalRules) -> Result<()> {
+        let created = !Path::new(&self.mihomo_target_local_rules_path).exists();
+        create_parent_dir(&self.mihomo_target_local_rules_path)?;
+        local_rules.write(&self.mihomo_target_local_rules_path)?;
+        if created {
+            self.track(&[&self.mihomo_target_local_rules_path])?;
+        }
+        println!(
+            "{} Updated {}, run `mihoro apply` to take effect",
+            self.prefix.success(),
+            self.mihomo_target_local_rules_path.underline().yellow()
+        );
+        Ok(())
+    }
+}
+
+/// Remove a directory and its subdirectories, as long as they contain no files.
+fn remove_empty_dirs(dir: &Path) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+        }
+    }
+    if fs::read_dir(dir)?.next().is_none() {
+        fs::remove_dir(dir)?;
+    }
+    Ok(())
+}
+
+/// Name of a mihoro profile, i.e. the file stem of its config, such as `mihoro` for the default
+/// `~/.config/mihoro.toml`.
+fn profile_name(config_path: &str) -> String {
+    Path::new(config_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| String::from("mihoro"))
+}
+
+/// Version reported by `mihomo -v`, e.g. `v1.18.3` or `alpha-7e2a5e6`, failing if the binary does
+/// not run (wrong architecture, truncated download).
+fn mihomo_version(mihomo_binary_path: &str) -> Result<String> {
+    let output = Command::new(mihomo_binary_path)
+        .arg("-v")
+        .output()
+        .context("failed to run mihomo binary")?;
+    if !output.status.success() {
+        bail!("`mihomo -v` exited with {}", output.status);
+    }
+    let version_output = String::from_utf8_lossy(&output.stdout);
+    let version_pattern = Regex::new(r"\s(v\d+\.\d+\.\d+\S*|alpha\S*)")?;
+    version_pattern
+        .captures(&version_output)
+        .map(|captures| captures[1].to_string())
+        .with_context(|| format!("unrecognized `mihomo -v` output: {}", version_output.trim()))
+}
+
+/// Release version in a binary download url, e.g. `v1.18.3` from
+/// `.../releases/download/v1.18.3/mihomo-linux-amd64-v1.18.3.gz`.
+fn release_version(url: &str) -> Option<&str> {
+    url.split(['/', '-'])
+        .map(|part| part.trim_end_matches(".gz"))
+        .find(|part| {
+            part.strip_prefix('v')
+                .is_some_and(|version| version.split('.').count() == 3)
+        })
+}
+
+/// Major, minor and patch numbers of a version such as `v1.18.3` or `v1.18.3-rc1`.
+fn version_numbers(version: &str) -> Option<(u32, u32, u32)> {
+    let numbers = version.strip_prefix('v')?;
+    let end = numbers
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(numbers.len());
+    let mut parts = numbers[..end].split('.').map(str::parse);
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(major)), Some(Ok(minor)), Some(Ok(patch)), None) => Some((major, minor, patch)),
+        _ => None,
+    }
+}
+
+/// Systemd service file for running mihomo as a service with `exec_start`.
+///
+/// By default, user systemd services are created under `~/.config/systemd/user/mihomo.service` and
+/// invoked with `systemctl --user start mihomo.service`. System-wide installs are started at boot
+/// instead of at login. With `watchdog_sec`, the service is restarted unless `exec_start` notifies
+/// systemd within that many seconds, as `mihoro watchdog` does while mihomo is healthy.
 ///
 /// Reference: https://wiki.metacubex.one/startup/service/
-fn create_mihomo_service(
-    mihomo_binary_path: &str,
-    mihomo_config_root: &str,
-    mihomo_service_path: &str,
-    prefix: &str,
-) -> Result<()> {
-    let service = format!(
+fn mihomo_service_unit(
+    exec_start: &str,
+    watchdog_sec: Option<u64>,
+    install_scope: InstallScope,
+) -> String {
+    let wanted_by = match install_scope {
+        InstallScope::User => "default.target",
+        InstallScope::System => "multi-user.target",
+    };
+    let service_type = match watchdog_sec {
+        Some(watchdog_sec) => format!("Type=notify\nWatchdogSec={}s", watchdog_sec),
+        None => String::from("Type=simple"),
+    };
+    format!(
         "[Unit]
 Description=mihomo Daemon, Another Clash Kernel.
 After=network.target NetworkManager.service systemd-networkd.service iwd.service
 
 [Service]
-Type=simple
+{}
 LimitNPROC=500
 LimitNOFILE=1000000
 Restart=always
 ExecStartPre=/usr/bin/sleep 1s
-ExecStart={} -d {}
+ExecStart={}
 ExecReload=/bin/kill -HUP $MAINPID
 
 [Install]
-WantedBy=default.target",
-        mihomo_binary_path, mihomo_config_root
-    );
+WantedBy={}",
+        service_type, exec_start, wanted_by
+    )
+}
 
+/// Write a systemd service file for running mihomo as a service, creating its directory if not
+/// present.
+fn create_mihomo_service(service: &str, mihomo_service_path: &str, prefix: &Prefix) -> Result<()> {
     // Create mihomo service directory if not exists
     create_parent_dir(mihomo_service_path)?;
 
@@ -293,9 +4650,60 @@ WantedBy=default.target",
     fs::write(mihomo_service_path, service)?;
 
     println!(
-        "{} Created mihomo.service at {}",
-        prefix.green(),
-        mihomo_service_path.underline().yellow()
+        "{} {}",
+        prefix.success(),
+        tr_args(
+            "Created mihomo.service at {}",
+            &[&mihomo_service_path.underline().yellow()]
+        )
     );
     Ok(())
 }
+
+/// Proxy group that can be switched, i.e. a selector group.
+fn selector_group<'a>(proxies: &'a HashMap<String, Proxy>, group: &str) -> Result<&'a Proxy> {
+    let Some(selector) = proxies.get(group).filter(|proxy| proxy.all.is_some()) else {
+        bail!("proxy group `{}` not found", group);
+    };
+    if selector.proxy_type != "Selector" {
+        bail!(
+            "proxy group `{}` is of type {}, only Selector groups can be switched",
+            group,
+            selector.proxy_type
+        );
+    }
+    Ok(selector)
+}
+
+/// Whether a proxy can be delay tested, i.e. is not a built-in policy such as `REJECT`. Nested
+/// groups are tested through their selected node.
+fn is_testable(proxies: &HashMap<String, Proxy>, name: &str) -> bool {
+    proxies.get(name).is_none_or(|proxy| {
+        !matches!(
+            proxy.proxy_type.as_str(),
+            "Reject" | "RejectDrop" | "Pass" | "Compatible"
+        )
+    })
+}
+
+/// Test delay of proxies per the `latency` settings, fastest first followed by failures.
+async fn test_delays<'a>(
+    api: &MihomoApi,
+    names: Vec<&'a String>,
+    latency: &Latency,
+) -> Vec<(&'a String, Result<u64>)> {
+    let (url, timeout) = (latency.test_url(), latency.timeout_ms());
+    let mut results: Vec<(&String, Result<u64>)> = stream::iter(names)
+        .map(|name| async move { (name, api.proxy_delay(name, url, timeout).await) })
+        .buffer_unordered(latency.concurrency().max(1))
+        .collect()
+        .await;
+    results.sort_by_key(|(name, result)| (result.as_ref().map_or(u64::MAX, |d| *d), *name));
+    results
+}
+
+/// Traffic and expiry of a subscription from its `subscription-userinfo` response header.
+fn subscription_userinfo(headers: &HeaderMap) -> Option<SubscriptionInfo> {
+    let header = headers.get("subscription-userinfo")?.to_str().ok()?;
+    SubscriptionInfo::parse(header)
+}
@@ -0,0 +1,873 @@
+use crate::config::{AutoGroup, AutoGroupType, Config, StaticProxy};
+use crate::rules::map_policy;
+use crate::template::Template;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+/// File name of the local proxies fragment, kept next to mihomo's `config.yaml`.
+pub const LOCAL_PROXIES_FILE: &str = "proxies.local.yaml";
+
+/// Local proxies fragment, holding nodes imported from share links.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LocalProxies {
+    #[serde(default)]
+    pub proxies: Vec<Value>,
+}
+
+impl LocalProxies {
+    /// Read local proxies fragment from path as written, with template variables unexpanded for
+    /// editing, returning no proxies if the file does not exist.
+    pub fn load(path: &str) -> Result<LocalProxies> {
+        if !Path::new(path).exists() {
+            return Ok(LocalProxies::default());
+        }
+        let raw_proxies = fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&raw_proxies)?)
+    }
+
+    /// Read local proxies fragment from path with template variables expanded, as merged into the
+    /// config.
+    pub fn load_expanded(path: &str, template: &Template) -> Result<LocalProxies> {
+        template.read_yaml(Path::new(path))
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_yaml::to_string(&self)?)?;
+        Ok(())
+    }
+
+    /// Add a node, replacing any local node of the same name.
+    pub fn add(&mut self, node: Value) {
+        match self
+            .proxies
+            .iter_mut()
+            .find(|proxy| entry_name(proxy) == entry_name(&node))
+        {
+            Some(existing) => *existing = node,
+            None => self.proxies.push(node),
+        }
+    }
+
+    /// Remove a node by name, returning whether it existed.
+    pub fn remove(&mut self, name: &str) -> bool {
+        let proxies_count = self.proxies.len();
+        self.proxies.retain(|proxy| entry_name(proxy) != Some(name));
+        self.proxies.len() != proxies_count
+    }
+}
+
+/// Merge local nodes into `proxies`, replacing remote nodes of the same name, and make them
+/// selectable from the first `select` group.
+///
+/// Local nodes are kept as is, i.e. they are not filtered, renamed nor added to generated groups.
+pub fn merge_local_proxies(extra: &mut BTreeMap<String, Value>, local_proxies: &LocalProxies) {
+    merge_nodes(extra, &local_proxies.proxies, None);
+}
+
+/// Merge `static_proxies` of the config into `proxies` like local nodes, making each selectable
+/// from the groups listed in its `add_to` (or the first `select` group).
+pub fn merge_static_proxies(
+    extra: &mut BTreeMap<String, Value>,
+    static_proxies: &[StaticProxy],
+) -> Result<()> {
+    for static_proxy in static_proxies {
+        merge_nodes(
+            extra,
+            &[static_proxy.node()?],
+            static_proxy.add_to.as_deref(),
+        );
+    }
+    Ok(())
+}
+
+/// Add `nodes` to `proxies`, replacing nodes of the same name, and append them to the groups named
+/// in `add_to`, or to the first `select` group if `None`.
+fn merge_nodes(extra: &mut BTreeMap<String, Value>, nodes: &[Value], add_to: Option<&[String]>) {
+    if nodes.is_empty() {
+        return;
+    }
+    let names: Vec<Value> = nodes
+        .iter()
+        .filter_map(|proxy| proxy.get("name").cloned())
+        .collect();
+
+    let proxies = extra
+        .entry(String::from("proxies"))
+        .or_insert_with(|| Value::Sequence(Vec::new()));
+    if let Some(proxies) = proxies.as_sequence_mut() {
+        proxies.retain(|proxy| proxy.get("name").is_none_or(|name| !names.contains(name)));
+        proxies.extend(nodes.iter().cloned());
+    }
+
+    let Some(groups) = extra
+        .get_mut("proxy-groups")
+        .and_then(Value::as_sequence_mut)
+    else {
+        return;
+    };
+    let targets = groups
+        .iter_mut()
+        .filter(|group| match add_to {
+            Some(add_to) => entry_name(group).is_some_and(|name| add_to.iter().any(|n| n == name)),
+            None => group.get("type").and_then(Value::as_str) == Some("select"),
+        })
+        .take(add_to.map_or(1, <[String]>::len));
+    for target in targets {
+        if let Some(proxies) = target.get_mut("proxies").and_then(Value::as_sequence_mut) {
+            proxies.retain(|proxy| !names.contains(proxy));
+            proxies.extend(names.iter().cloned());
+        }
+    }
+}
+
+/// Country metadata used to recognize which region a node is located in from its name.
+pub struct Country {
+    pub code: &'static str,
+    pub flag: &'static str,
+    pub name: &'static str,
+    keywords: &'static [&'static str],
+}
+
+const COUNTRIES: &[Country] = &[
+    Country {
+        code: "HK",
+        flag: "🇭🇰",
+        name: "Hong Kong",
+        keywords: &["香港", "hong kong", "hongkong"],
+    },
+    Country {
+        code: "TW",
+        flag: "🇹🇼",
+        name: "Taiwan",
+        keywords: &["台湾", "台灣", "taiwan"],
+    },
+    Country {
+        code: "JP",
+        flag: "🇯🇵",
+        name: "Japan",
+        keywords: &["日本", "东京", "大阪", "japan", "tokyo", "osaka"],
+    },
+    Country {
+        code: "SG",
+        flag: "🇸🇬",
+        name: "Singapore",
+        keywords: &["新加坡", "狮城", "singapore"],
+    },
+    Country {
+        code: "KR",
+        flag: "🇰🇷",
+        name: "Korea",
+        keywords: &["韩国", "韓國", "首尔", "korea", "seoul"],
+    },
+    Country {
+        code: "US",
+        flag: "🇺🇸",
+        name: "United States",
+        keywords: &["美国", "美國", "united states", "los angeles", "san jose"],
+    },
+    Country {
+        code: "GB",
+        flag: "🇬🇧",
+        name: "United Kingdom",
+        keywords: &["英国", "英國", "united kingdom", "london"],
+    },
+    Country {
+        code: "DE",
+        flag: "🇩🇪",
+        name: "Germany",
+        keywords: &["德国", "德國", "germany", "frankfurt"],
+    },
+    Country {
+        code: "FR",
+        flag: "🇫🇷",
+        name: "France",
+        keywords: &["法国", "法國", "france", "paris"],
+    },
+    Country {
+        code: "NL",
+        flag: "🇳🇱",
+        name: "Netherlands",
+        keywords: &["荷兰", "荷蘭", "netherlands", "amsterdam"],
+    },
+    Country {
+        code: "CA",
+        flag: "🇨🇦",
+        name: "Canada",
+        keywords: &["加拿大", "canada"],
+    },
+    Country {
+        code: "AU",
+        flag: "🇦🇺",
+        name: "Australia",
+        keywords: &["澳大利亚", "澳洲", "australia", "sydney"],
+    },
+    Country {
+        code: "RU",
+        flag: "🇷🇺",
+        name: "Russia",
+        keywords: &["俄罗斯", "俄羅斯", "russia", "moscow"],
+    },
+    Country {
+        code: "IN",
+        flag: "🇮🇳",
+        name: "India",
+        keywords: &["印度", "india", "mumbai"],
+    },
+    Country {
+        code: "TR",
+        flag: "🇹🇷",
+        name: "Turkey",
+        keywords: &["土耳其", "turkey", "istanbul"],
+    },
+    Country {
+        code: "MY",
+        flag: "🇲🇾",
+        name: "Malaysia",
+        keywords: &["马来西亚", "馬來西亞", "malaysia"],
+    },
+    Country {
+        code: "TH",
+        flag: "🇹🇭",
+        name: "Thailand",
+        keywords: &["泰国", "泰國", "thailand", "bangkok"],
+    },
+    Country {
+        code: "VN",
+        flag: "🇻🇳",
+        name: "Vietnam",
+        keywords: &["越南", "vietnam"],
+    },
+    Country {
+        code: "PH",
+        flag: "🇵🇭",
+        name: "Philippines",
+        keywords: &["菲律宾", "菲律賓", "philippines"],
+    },
+    Country {
+        code: "AR",
+        flag: "🇦🇷",
+        name: "Argentina",
+        keywords: &["阿根廷", "argentina"],
+    },
+    Country {
+        code: "BR",
+        flag: "🇧🇷",
+        name: "Brazil",
+        keywords: &["巴西", "brazil"],
+    },
+];
+
+/// Detect the country of a node from its name, by flag emoji, keyword, or an uppercase ISO
+/// country code appearing as a standalone word (e.g. `HK 01`, `US-West`), so that words such as
+/// `us` or `in` are not mistaken for codes.
+pub fn detect_country(name: &str) -> Option<&'static Country> {
+    let lowercase_name = name.to_lowercase();
+    COUNTRIES
+        .iter()
+        .find(|country| {
+            name.contains(country.flag)
+                || country
+                    .keywords
+                    .iter()
+                    .any(|keyword| lowercase_name.contains(keyword))
+        })
+        .or_else(|| {
+            name.split(|c: char| !c.is_ascii_alphabetic())
+                .find_map(|word| COUNTRIES.iter().find(|country| word == country.code))
+        })
+}
+
+/// Name of a proxy or proxy group entry in `config.yaml`.
+pub fn entry_name(entry: &Value) -> Option<&str> {
+    entry.get("name").and_then(Value::as_str)
+}
+
+/// Find a node by name in the `proxies` of mihomo's `config.yaml`.
+pub fn find_node(config_path: &str, name: &str) -> Result<Value> {
+    let raw_mihomo_yaml = fs::read_to_string(config_path)?;
+    let mihomo_yaml: Value = serde_yaml::from_str(&raw_mihomo_yaml)?;
+    let node = mihomo_yaml
+        .get("proxies")
+        .and_then(Value::as_sequence)
+        .and_then(|proxies| proxies.iter().find(|proxy| entry_name(proxy) == Some(name)));
+    match node {
+        Some(node) => Ok(node.clone()),
+        None => bail!("node `{}` not found in {}", name, config_path),
+    }
+}
+
+/// Remove names from the `proxies` list of every proxy group.
+///
+/// Groups left without any proxies or providers fall back to `DIRECT`, as mihomo refuses to load
+/// empty groups.
+pub fn remove_from_groups(extra: &mut BTreeMap<String, Value>, removed: &HashSet<String>) {
+    let Some(groups) = extra
+        .get_mut("proxy-groups")
+        .and_then(Value::as_sequence_mut)
+    else {
+        return;
+    };
+
+    for group in groups.iter_mut() {
+        let uses_providers = group
+            .get("use")
+            .and_then(Value::as_sequence)
+            .is_some_and(|providers| !providers.is_empty());
+        if let Some(proxies) = group.get_mut("proxies").and_then(Value::as_sequence_mut) {
+            proxies.retain(|proxy| proxy.as_str().is_none_or(|name| !removed.contains(name)));
+            if proxies.is_empty() && !uses_providers {
+                proxies.push(Value::from("DIRECT"));
+            }
+        }
+    }
+}
+
+/// Post-process nodes of a downloaded config, in order: filtering, deduplication, renaming, then
+/// generating automatic groups.
+pub fn process_nodes(extra: &mut BTreeMap<String, Value>, config: &Config) -> Result<()> {
+    filter_nodes(extra, config)?;
+    dedup_nodes(extra);
+    if let Some(template) = &config.node_rename {
+        rename_nodes(
+            extra,
+            template,
+            &provider_name(config.remote_config_url.primary()),
+        );
+    }
+    if let Some(auto_groups) = &config.auto_groups {
+        generate_groups(extra, auto_groups)?;
+    }
+    Ok(())
+}
+
+/// Default url used by generated groups to test node delay.
+const DEFAULT_TEST_URL: &str = "https://www.gstatic.com/generate_204";
+
+/// Default seconds between delay tests of generated groups.
+const DEFAULT_TEST_INTERVAL: u32 = 300;
+
+/// Whether a node belongs to one of the given ISO country codes.
+fn in_countries(name: &str, countries: &[String]) -> bool {
+    detect_country(name).is_some_and(|country| {
+        countries
+            .iter()
+            .any(|code| code.eq_ignore_ascii_case(country.code))
+    })
+}
+
+/// Build a mihomo proxy group entry.
+fn build_group(auto_group: &AutoGroup, name: &str, mut proxies: Vec<String>) -> Value {
+    if auto_group.include_direct.unwrap_or(false) {
+        proxies.push(String::from("DIRECT"));
+    }
+    let group_type = serde_yaml::to_value(&auto_group.group_type).unwrap_or(Value::Null);
+
+    let mut group = serde_yaml::Mapping::new();
+    group.insert(Value::from("name"), Value::from(name));
+    group.insert(Value::from("type"), group_type);
+    group.insert(Value::from("proxies"), Value::from(proxies));
+    group.insert(
+        Value::from("url"),
+        Value::from(auto_group.url.as_deref().unwrap_or(DEFAULT_TEST_URL)),
+    );
+    group.insert(
+        Value::from("interval"),
+        Value::from(auto_group.interval.unwrap_or(DEFAULT_TEST_INTERVAL)),
+    );
+    if let Some(tolerance) = auto_group.tolerance {
+        group.insert(Value::from("tolerance"), Value::from(tolerance));
+    }
+    if let Some(lazy) = auto_group.lazy {
+        group.insert(Value::from("lazy"), Value::from(lazy));
+    }
+    Value::Mapping(group)
+}
+
+/// Synthesize `url-test`, `fallback` and `load-balance` groups from nodes in `proxies`.
+///
+/// Groups replace existing groups of the same name, and are prepended to the groups listed in
+/// `add_to` (or the first `select` group) so they are selectable. Groups without any matching
+/// node are skipped.
+fn generate_groups(extra: &mut BTreeMap<String, Value>, auto_groups: &[AutoGroup]) -> Result<()> {
+    let nodes: Vec<String> = extra
+        .get("proxies")
+        .and_then(Value::as_sequence)
+        .map(|proxies| {
+            proxies
+                .iter()
+                .filter_map(entry_name)
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let groups = extra
+        .entry(String::from("proxy-groups"))
+        .or_insert_with(|| Value::Sequence(Vec::new()));
+    let Some(groups) = groups.as_sequence_mut() else {
+        return Ok(());
+    };
+
+    for auto_group in auto_groups {
+        if auto_group.tolerance.is_some()
+            && !matches!(auto_group.group_type, AutoGroupType::UrlTest)
+        {
+            bail!(
+                "`tolerance` of group `{}` only applies to `url-test` groups",
+                auto_group.name
+            );
+        }
+        let filter = auto_group
+            .filter
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .with_context(|| format!("invalid `filter` regex of group `{}`", auto_group.name))?;
+        let members: Vec<&String> = nodes
+            .iter()
+            .filter(|node| filter.as_ref().is_none_or(|re| re.is_match(node)))
+            .filter(|node| {
+                auto_group
+                    .countries
+                    .as_ref()
+                    .is_none_or(|countries| in_countries(node, countries))
+            })
+            .collect();
+
+        let mut generated = Vec::new();
+        if auto_group.by_country.unwrap_or(false) {
+            let mut by_country: Vec<(&Country, Vec<String>)> = Vec::new();
+            for node in members {
+                let Some(country) = detect_country(node) else {
+                    continue;
+                };
+                match by_country.iter_mut().find(|(c, _)| c.code == country.code) {
+                    Some((_, country_nodes)) => country_nodes.push(node.clone()),
+                    None => by_country.push((country, vec![node.clone()])),
+                }
+            }
+            for (country, country_nodes) in by_country {
+                let name = format!("{} | {} {}", auto_group.name, country.flag, country.name);
+                generated.push(build_group(auto_group, &name, country_nodes));
+            }
+        } else if !members.is_empty() {
+            let members = members.into_iter().cloned().collect();
+            generated.push(build_group(auto_group, &auto_group.name, members));
+        }
+
+        let generated_names: Vec<Value> = generated
+            .iter()
+            .filter_map(|group| group.get("name").cloned())
+            .collect();
+        for group in generated {
+            match groups
+                .iter_mut()
+                .find(|existing| existing.get("name") == group.get("name"))
+            {
+                Some(existing) => *existing = group,
+                None => groups.push(group),
+            }
+        }
+
+        // Make generated groups selectable from the target groups
+        let targets = groups
+            .iter_mut()
+            .filter(|group| match &auto_group.add_to {
+                Some(add_to) => {
+                    entry_name(group).is_some_and(|name| add_to.iter().any(|n| n == name))
+                }
+                None => group.get("type").and_then(Value::as_str) == Some("select"),
+            })
+            .take(auto_group.add_to.as_ref().map_or(1, Vec::len));
+        for target in targets {
+            if let Some(proxies) = target.get_mut("proxies").and_then(Value::as_sequence_mut) {
+                proxies.retain(|proxy| !generated_names.contains(proxy));
+                for (index, name) in generated_names.iter().enumerate() {
+                    proxies.insert(index, name.clone());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Make `name` unique among `names` by appending ` (2)`, ` (3)`, etc.
+fn unique_name(names: &HashSet<String>, name: &str) -> String {
+    let mut unique_name = name.to_string();
+    let mut index = 2;
+    while names.contains(&unique_name) {
+        unique_name = format!("{} ({})", name, index);
+        index += 1;
+    }
+    unique_name
+}
+
+/// Short provider name used in rename templates, derived from the subscription url's host.
+fn provider_name(url: &str) -> String {
+    reqwest::Url::parse(url)
+        .ok()
+        .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| String::from("local"))
+}
+
+/// Rename nodes with a template, supporting placeholders:
+///
+/// * `{name}` - original node name
+/// * `{flag}`, `{country}`, `{code}` - flag emoji, name and ISO code of the detected country
+/// * `{index}` - 1-based index of the node within its country, zero-padded to two digits
+/// * `{provider}` - host of the subscription url
+///
+/// Group and rule references are updated to the new names.
+fn rename_nodes(extra: &mut BTreeMap<String, Value>, template: &str, provider: &str) {
+    let mut renamed = HashMap::new();
+    let mut names = HashSet::new();
+    let mut country_indexes: HashMap<&str, usize> = HashMap::new();
+
+    if let Some(proxies) = extra.get_mut("proxies").and_then(Value::as_sequence_mut) {
+        for proxy in proxies.iter_mut() {
+            let Some(name) = entry_name(proxy).map(str::to_string) else {
+                continue;
+            };
+            let country = detect_country(&name);
+            let index = country_indexes
+                .entry(country.map_or("", |country| country.code))
+                .or_default();
+            *index += 1;
+
+            let new_name = template
+                .replace("{name}", &name)
+                .replace("{flag}", country.map_or("", |country| country.flag))
+                .replace("{country}", country.map_or("Other", |country| country.name))
+                .replace("{code}", country.map_or("", |country| country.code))
+                .replace("{index}", &format!("{:02}", index))
+                .replace("{provider}", provider);
+            let new_name = unique_name(&names, new_name.trim());
+            proxy["name"] = Value::from(new_name.as_str());
+            names.insert(new_name.clone());
+            renamed.insert(name, new_name);
+        }
+    }
+    rename_in_rules(extra, &renamed);
+
+    if let Some(groups) = extra
+        .get_mut("proxy-groups")
+        .and_then(Value::as_sequence_mut)
+    {
+        for group in groups.iter_mut() {
+            if let Some(proxies) = group.get_mut("proxies").and_then(Value::as_sequence_mut) {
+                for proxy in proxies.iter_mut() {
+                    if let Some(new_name) = proxy.as_str().and_then(|name| renamed.get(name)) {
+                        *proxy = Value::from(new_name.as_str());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Point policies of `rules` and `sub-rules` at nodes to other policies, e.g. renamed nodes to
+/// their new names.
+fn rename_in_rules(extra: &mut BTreeMap<String, Value>, renamed: &HashMap<String, String>) {
+    let rename = |rule: &mut Value| {
+        if let Some(text) = rule.as_str() {
+            *rule = Value::from(map_policy(text, |policy| renamed.get(policy).cloned()));
+        }
+    };
+    if let Some(rules) = extra.get_mut("rules").and_then(Value::as_sequence_mut) {
+        rules.iter_mut().for_each(rename);
+    }
+    if let Some(sub_rules) = extra.get_mut("sub-rules").and_then(Value::as_mapping_mut) {
+        for rules in sub_rules.values_mut().filter_map(Value::as_sequence_mut) {
+            rules.iter_mut().for_each(rename);
+        }
+    }
+}
+
+/// Identity of a node, nodes sharing the same identity connect to the same endpoint.
+fn node_identity(node: &Value) -> String {
+    ["type", "server", "port", "uuid", "password", "username"]
+        .iter()
+        .map(|key| match node.get(key) {
+            Some(Value::String(value)) => value.clone(),
+            Some(Value::Number(value)) => value.to_string(),
+            _ => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+/// Remove duplicate nodes (same type, server, port and credentials) from `proxies`, keeping the
+/// first one. Group and rule references to removed duplicates are pointed to the kept node.
+///
+/// Distinct nodes sharing a name are renamed deterministically in order of appearance, e.g.
+/// `HK 01`, `HK 01 (2)`, `HK 01 (3)`, since mihomo rejects duplicated proxy names. Repeated
+/// references to that name within a group are mapped to the renamed nodes in the same order.
+fn dedup_nodes(extra: &mut BTreeMap<String, Value>) {
+    let mut canonical = HashMap::new();
+    let mut replaced = HashMap::new();
+    let mut variants: HashMap<String, Vec<String>> = HashMap::new();
+    let mut names = HashSet::new();
+
+    if let Some(proxies) = extra.get_mut("proxies").and_then(Value::as_sequence_mut) {
+        proxies.retain_mut(|proxy| {
+            let Some(name) = entry_name(proxy).map(str::to_string) else {
+                return true;
+            };
+            let identity = node_identity(proxy);
+            if let Some(kept) = canonical.get(&identity) {
+                replaced.insert(name, String::clone(kept));
+                return false;
+            }
+
+            let unique_name = unique_name(&names, &name);
+            if unique_name != name {
+                proxy["name"] = Value::from(unique_name.as_str());
+            }
+            names.insert(unique_name.clone());
+            variants.entry(name).or_default().push(unique_name.clone());
+            canonical.insert(identity, unique_name);
+            true
+        });
+    }
+    // Names that still belong to a kept node are never redirected
+    replaced.retain(|name, _| !names.contains(name));
+    rename_in_rules(extra, &replaced);
+
+    let Some(groups) = extra
+        .get_mut("proxy-groups")
+        .and_then(Value::as_sequence_mut)
+    else {
+        return;
+    };
+    for group in groups.iter_mut() {
+        let Some(proxies) = group.get_mut("proxies").and_then(Value::as_sequence_mut) else {
+            continue;
+        };
+        let mut occurrences: HashMap<String, usize> = HashMap::new();
+        let mut seen = HashSet::new();
+        proxies.retain_mut(|proxy| {
+            if let Some(name) = proxy.as_str() {
+                let name = replaced.get(name).map_or(name, String::as_str).to_string();
+                let occurrence = occurrences.entry(name.clone()).or_default();
+                let resolved = variants
+                    .get(&name)
+                    .and_then(|renamed| renamed.get(*occurrence))
+                    .cloned()
+                    .unwrap_or(name);
+                *occurrence += 1;
+                *proxy = Value::from(resolved);
+            }
+            seen.insert(proxy.clone())
+        });
+    }
+}
+
+/// Filter nodes in `proxies` by `node_filter`, `node_exclude` and `node_countries`, dropping
+/// removed nodes from proxy groups as well and pointing rules at them to `DIRECT`.
+///
+/// `node_filter` and `node_exclude` are also set as `filter` and `exclude-filter` of each
+/// `proxy-providers` entry that does not define its own, so provider nodes are filtered by mihomo.
+fn filter_nodes(extra: &mut BTreeMap<String, Value>, config: &Config) -> Result<()> {
+    let node_filter = config
+        .node_filter
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("invalid `node_filter` regex")?;
+    let node_exclude = config
+        .node_exclude
+        .as_deref()
+        .map(Regex::new)
+        .transpose()
+        .context("invalid `node_exclude` regex")?;
+    let node_countries = config.node_countries.as_ref();
+
+    if node_filter.is_none() && node_exclude.is_none() && node_countries.is_none() {
+        return Ok(());
+    }
+
+    let keep = |name: &str| {
+        node_filter.as_ref().is_none_or(|re| re.is_match(name))
+            && node_exclude.as_ref().is_none_or(|re| !re.is_match(name))
+            && node_countries.is_none_or(|countries| in_countries(name, countries))
+    };
+
+    let mut removed = HashSet::new();
+    if let Some(proxies) = extra.get_mut("proxies").and_then(Value::as_sequence_mut) {
+        proxies.retain(|proxy| match entry_name(proxy) {
+            Some(name) if !keep(name) => {
+                removed.insert(name.to_string());
+                false
+            }
+            _ => true,
+        });
+    }
+    remove_from_groups(extra, &removed);
+    // mihomo refuses rules whose policy is a missing proxy
+    let direct = removed
+        .into_iter()
+        .map(|name| (name, String::from("DIRECT")))
+        .collect();
+    rename_in_rules(extra, &direct);
+
+    if let Some(providers) = extra
+        .get_mut("proxy-providers")
+        .and_then(Value::as_mapping_mut)
+    {
+        for (_, provider) in providers.iter_mut() {
+            let Some(provider) = provider.as_mapping_mut() else {
+                continue;
+            };
+            if let Some(filter) = &config.node_filter {
+                if !provider.contains_key("filter") {
+                    provider.insert(Value::from("filter"), Value::from(filter.as_str()));
+                }
+            }
+            if let Some(exclude) = &config.node_exclude {
+                if !provider.contains_key("exclude-filter") {
+                    provider.insert(Value::from("exclude-filter"), Value::from(exclude.as_str()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> BTreeMap<String, Value> {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn filtered_nodes_leave_groups_and_rules_to_direct() {
+        let mut config = Config::new();
+        config.node_exclude = Some(String::from("expire"));
+        let mut extra = parse(
+            "
+proxies:
+  - {name: HK 01, type: ss}
+  - {name: expire soon, type: ss}
+proxy-groups:
+  - {name: Proxy, type: select, proxies: [HK 01, expire soon]}
+  - {name: Notice, type: select, proxies: [expire soon]}
+rules:
+  - DOMAIN,expire soon,expire soon
+  - DOMAIN-SUFFIX,example.com,expire soon,no-resolve
+sub-rules:
+  inner:
+    - MATCH,expire soon
+",
+        );
+
+        filter_nodes(&mut extra, &config).unwrap();
+
+        assert_eq!(
+            extra,
+            parse(
+                "
+proxies:
+  - {name: HK 01, type: ss}
+proxy-groups:
+  - {name: Proxy, type: select, proxies: [HK 01]}
+  - {name: Notice, type: select, proxies: [DIRECT]}
+rules:
+  - DOMAIN,expire soon,DIRECT
+  - DOMAIN-SUFFIX,example.com,DIRECT,no-resolve
+sub-rules:
+  inner:
+    - MATCH,DIRECT
+",
+            )
+        );
+    }
+
+    #[test]
+    fn duplicates_are_dropped_and_rules_follow_the_kept_node() {
+        let mut extra = parse(
+            "
+proxies:
+  - {name: HK 01, type: ss, server: a.example.com, port: 8388}
+  - {name: HK 01 copy, type: ss, server: a.example.com, port: 8388}
+  - {name: HK 01, type: ss, server: b.example.com, port: 8388}
+proxy-groups:
+  - {name: Proxy, type: select, proxies: [HK 01, HK 01]}
+  - {name: Copy, type: select, proxies: [HK 01 copy]}
+rules:
+  - DOMAIN,a.example.com,HK 01 copy
+  - DOMAIN,HK 01 copy,DIRECT
+",
+        );
+
+        dedup_nodes(&mut extra);
+
+        assert_eq!(
+            extra,
+            parse(
+                "
+proxies:
+  - {name: HK 01, type: ss, server: a.example.com, port: 8388}
+  - {name: HK 01 (2), type: ss, server: b.example.com, port: 8388}
+proxy-groups:
+  - {name: Proxy, type: select, proxies: [HK 01, HK 01 (2)]}
+  - {name: Copy, type: select, proxies: [HK 01]}
+rules:
+  - DOMAIN,a.example.com,HK 01
+  - DOMAIN,HK 01 copy,DIRECT
+",
+            )
+        );
+    }
+
+    #[test]
+    fn renamed_nodes_are_renamed_in_groups_and_rule_policies() {
+        let mut extra = parse(
+            "
+proxies:
+  - {name: HK Premium, type: ss}
+  - {name: JP Basic, type: ss}
+proxy-groups:
+  - {name: Proxy, type: select, proxies: [HK Premium, JP Basic]}
+rules:
+  - DOMAIN,jp.example.com,JP Basic
+  - DOMAIN-KEYWORD,HK Premium,DIRECT
+sub-rules:
+  inner:
+    - MATCH,HK Premium
+",
+        );
+
+        rename_nodes(&mut extra, "{flag} {code} {index} | {provider}", "Sub");
+
+        assert_eq!(
+            extra,
+            parse(
+                "
+proxies:
+  - {name: 🇭🇰 HK 01 | Sub, type: ss}
+  - {name: 🇯🇵 JP 01 | Sub, type: ss}
+proxy-groups:
+  - {name: Proxy, type: select, proxies: [🇭🇰 HK 01 | Sub, 🇯🇵 JP 01 | Sub]}
+rules:
+  - DOMAIN,jp.example.com,🇯🇵 JP 01 | Sub
+  - DOMAIN-KEYWORD,HK Premium,DIRECT
+sub-rules:
+  inner:
+    - MATCH,🇭🇰 HK 01 | Sub
+",
+            )
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use serde_yaml::{Mapping, Value};
+use shellexpand::tilde;
+
+/// Private keys tried in order when no identity is given to an SSH node, as `ssh` does.
+const DEFAULT_IDENTITIES: [&str; 3] = ["~/.ssh/id_ed25519", "~/.ssh/id_ecdsa", "~/.ssh/id_rsa"];
+
+fn insert(node: &mut Mapping, key: &str, value: impl Into<Value>) {
+    node.insert(Value::from(key), value.into());
+}
+
+/// Comma separated values of a WireGuard config entry.
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Split `host:port`, with IPv6 hosts in brackets, into host and port.
+fn split_endpoint(endpoint: &str) -> Result<(String, u16)> {
+    let (host, port) = endpoint
+        .rsplit_once(':')
+        .with_context(|| format!("endpoint `{}` has no port", endpoint))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = port
+        .parse()
+        .with_context(|| format!("invalid port in endpoint `{}`", endpoint))?;
+    Ok((host.to_string(), port))
+}
+
+/// Value of a key in a section of a WireGuard config.
+fn get(entries: &[(String, String)], key: &str) -> Option<String> {
+    entries
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.clone())
+}
+
+/// Convert a `wg-quick` config (`[Interface]` and `[Peer]` sections) into a mihomo `wireguard`
+/// proxy entry, using the first peer with an `Endpoint`.
+pub fn wireguard_to_node(name: &str, config: &str) -> Result<Value> {
+    let mut section = String::new();
+    let mut interface: Vec<(String, String)> = Vec::new();
+    let mut peers: Vec<Vec<(String, String)>> = Vec::new();
+    for line in config.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim().to_lowercase();
+            if section == "peer" {
+                peers.push(Vec::new());
+            }
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("invalid line `{}` in WireGuard config", line);
+        };
+        let entry = (key.trim().to_lowercase(), value.trim().to_string());
+        match (section.as_str(), peers.last_mut()) {
+            ("interface", _) => interface.push(entry),
+            ("peer", Some(peer)) => peer.push(entry),
+            _ => {}
+        }
+    }
+
+    let peer = peers
+        .iter()
+        .find(|peer| get(peer, "endpoint").is_some())
+        .context("WireGuard config has no [Peer] with an `Endpoint`")?;
+    let (server, port) = split_endpoint(&get(peer, "endpoint").unwrap_or_default())?;
+
+    let mut node = Mapping::new();
+    insert(&mut node, "name", name);
+    insert(&mut node, "type", "wireguard");
+    insert(&mut node, "server", server);
+    insert(&mut node, "port", port);
+    for address in list(&get(&interface, "address").unwrap_or_default()) {
+        let ip = address.split('/').next().unwrap_or_default().to_string();
+        let key = if ip.contains(':') { "ipv6" } else { "ip" };
+        if !node.contains_key(key) {
+            insert(&mut node, key, ip);
+        }
+    }
+    if !node.contains_key("ip") && !node.contains_key("ipv6") {
+        bail!("WireGuard config has no `Address` in [Interface]");
+    }
+    insert(
+        &mut node,
+        "private-key",
+        get(&interface, "privatekey").context("WireGuard config has no `PrivateKey`")?,
+    );
+    insert(
+        &mut node,
+        "public-key",
+        get(peer, "publickey").context("WireGuard peer has no `PublicKey`")?,
+    );
+    if let Some(pre_shared_key) = get(peer, "presharedkey") {
+        insert(&mut node, "pre-shared-key", pre_shared_key);
+    }
+    if let Some(allowed_ips) = get(peer, "allowedips") {
+        insert(&mut node, "allowed-ips", list(&allowed_ips));
+    }
+    if let Some(mtu) = get(&interface, "mtu").and_then(|mtu| mtu.parse::<u64>().ok()) {
+        insert(&mut node, "mtu", mtu);
+    }
+    if let Some(keepalive) = get(peer, "persistentkeepalive").and_then(|k| k.parse::<u64>().ok()) {
+        insert(&mut node, "persistent-keepalive", keepalive);
+    }
+    if let Some(dns) = get(&interface, "dns") {
+        insert(&mut node, "remote-dns-resolve", true);
+        insert(&mut node, "dns", list(&dns));
+    }
+    insert(&mut node, "udp", true);
+    Ok(Value::Mapping(node))
+}
+
+/// Convert an SSH endpoint (`user@host[:port]`) into a mihomo `ssh` proxy entry, authenticated
+/// by `password`, by the `identity` private key or else by the first default key found.
+pub fn ssh_to_node(
+    name: &str,
+    target: &str,
+    identity: Option<&str>,
+    password: Option<&str>,
+) -> Result<Value> {
+    let (username, address) = target
+        .split_once('@')
+        .with_context(|| format!("SSH target `{}` is not `user@host[:port]`", target))?;
+    let (server, port) = if address.starts_with('[') || address.matches(':').count() == 1 {
+        split_endpoint(address)?
+    } else {
+        (address.to_string(), 22)
+    };
+    if username.is_empty() || server.is_empty() {
+        bail!("SSH target `{}` is not `user@host[:port]`", target);
+    }
+
+    let mut node = Mapping::new();
+    insert(&mut node, "name", name);
+    insert(&mut node, "type", "ssh");
+    insert(&mut node, "server", server);
+    insert(&mut node, "port", port);
+    insert(&mut node, "username", username);
+    if let Some(password) = password {
+        insert(&mut node, "password", password);
+    }
+    let identity = match identity {
+        Some(identity) => Some(tilde(identity).to_string()),
+        None if password.is_none() => DEFAULT_IDENTITIES
+            .iter()
+            .map(|identity| tilde(identity).to_string())
+            .find(|identity| Path::new(identity).exists()),
+        None => None,
+    };
+    match identity {
+        Some(identity) => {
+            if !Path::new(&identity).exists() {
+                bail!("identity file `{}` does not exist", identity);
+            }
+            insert(&mut node, "private-key", identity);
+        }
+        None if password.is_none() => {
+            bail!("no SSH key found in ~/.ssh, pass `--identity` or `--password`")
+        }
+        None => {}
+    }
+    Ok(Value::Mapping(node))
+}
@@ -0,0 +1,46 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Content type of proxy auto-config files.
+pub const PAC_CONTENT_TYPE: &str = "application/x-ns-proxy-autoconfig";
+
+/// Condition matching a `bypass` entry in `FindProxyForURL`, or `None` if it cannot be expressed
+/// in a PAC file (e.g. IPv6 cidr).
+fn bypass_condition(target: &str) -> Option<String> {
+    if let Some((ip, prefix)) = target.split_once('/') {
+        let ip: Ipv4Addr = ip.parse().ok()?;
+        let prefix: u32 = prefix.parse().ok().filter(|prefix| *prefix <= 32)?;
+        let mask = Ipv4Addr::from(u32::MAX.checked_shl(32 - prefix).unwrap_or(0));
+        return Some(format!("isInNet(host, \"{}\", \"{}\")", ip, mask));
+    }
+    if target.parse::<IpAddr>().is_ok() {
+        return Some(format!("host === \"{}\"", target));
+    }
+
+    let domain = target.trim_start_matches("*.").trim_start_matches('.');
+    if domain.is_empty() || domain == "*" {
+        return None;
+    }
+    Some(format!(
+        "host === \"{domain}\" || dnsDomainIs(host, \".{domain}\")"
+    ))
+}
+
+/// Generate a proxy auto-config file sending everything through mihomo, except plain host names
+/// and hosts in `bypass`.
+///
+/// Reference: https://developer.mozilla.org/en-US/docs/Web/HTTP/Proxy_servers_and_tunneling/Proxy_Auto-Configuration_PAC_file
+pub fn pac_file(hostname: &str, http_port: &u16, socks_port: &u16, bypass: &[String]) -> String {
+    let mut conditions = vec![String::from("isPlainHostName(host)")];
+    conditions.extend(bypass.iter().filter_map(|target| bypass_condition(target)));
+
+    let bypass_checks: String = conditions
+        .iter()
+        .map(|condition| format!("  if ({}) return \"DIRECT\";\n", condition))
+        .collect();
+    format!(
+        "function FindProxyForURL(url, host) {{\n\
+         {bypass_checks}  \
+         return \"PROXY {hostname}:{http_port}; SOCKS5 {hostname}:{socks_port}; DIRECT\";\n\
+         }}\n"
+    )
+}
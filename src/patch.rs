@@ -0,0 +1,176 @@
+use crate::template::Template;
+use crate::utils::list_yaml_files;
+
+use std::{fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_yaml::{Mapping, Value};
+
+/// Directory of patch files, kept next to mihomo's `config.yaml`.
+pub const PATCHES_DIR: &str = "patches";
+
+/// JSON Patch operation, with paths as JSON Pointers (e.g. `/dns/nameserver/-`).
+///
+/// Reference: https://datatracker.ietf.org/doc/html/rfc6902
+#[derive(Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Operation {
+    Add { path: String, value: Value },
+    Remove { path: String },
+    Replace { path: String, value: Value },
+    Move { from: String, path: String },
+    Copy { from: String, path: String },
+    Test { path: String, value: Value },
+}
+
+/// Apply `*.yaml` and `*.yml` patch files in `dir` to the config, in lexical order of file names.
+///
+/// Each YAML document of a patch file is either:
+/// * a mapping, applied as a JSON Merge Patch (RFC 7386): mappings are merged recursively, `null`
+///   removes a key, and any other value (including lists) replaces the existing one.
+/// * a list of JSON Patch (RFC 6902) operations, e.g. `{op: add, path: /rules/0, value: ...}`.
+pub fn apply_patches(dir: &Path, template: &Template, config: &mut Value) -> Result<()> {
+    for patch_file in list_yaml_files(dir)? {
+        apply_patch_file(&patch_file, template, config)
+            .with_context(|| format!("failed to apply patch {}", patch_file.display()))?;
+    }
+    Ok(())
+}
+
+fn apply_patch_file(path: &Path, template: &Template, config: &mut Value) -> Result<()> {
+    let raw_patch = fs::read_to_string(path)?;
+    for document in serde_yaml::Deserializer::from_str(&raw_patch) {
+        let mut document = Value::deserialize(document)?;
+        template.expand_yaml(&mut document)?;
+        match document {
+            Value::Mapping(patch) => merge_patch(config, Value::Mapping(patch)),
+            Value::Sequence(operations) => {
+                for operation in operations {
+                    apply_operation(config, serde_yaml::from_value(operation)?)?;
+                }
+            }
+            Value::Null => {}
+            _ => bail!("patch must be a mapping or a list of JSON Patch operations"),
+        }
+    }
+    Ok(())
+}
+
+fn merge_patch(target: &mut Value, patch: Value) {
+    let Value::Mapping(patch) = patch else {
+        *target = patch;
+        return;
+    };
+    if !target.is_mapping() {
+        *target = Value::Mapping(Mapping::new());
+    }
+    let Some(target) = target.as_mapping_mut() else {
+        return;
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(&key);
+        } else {
+            merge_patch(target.entry(key).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn apply_operation(config: &mut Value, operation: Operation) -> Result<()> {
+    match operation {
+        Operation::Add { path, value } => add(config, &path, value),
+        Operation::Remove { path } => remove(config, &path).map(|_| ()),
+        Operation::Replace { path, value } => {
+            *resolve(config, &parse_pointer(&path)?, &path)? = value;
+            Ok(())
+        }
+        Operation::Move { from, path } => {
+            let value = remove(config, &from)?;
+            add(config, &path, value)
+        }
+        Operation::Copy { from, path } => {
+            let value = resolve(config, &parse_pointer(&from)?, &from)?.clone();
+            add(config, &path, value)
+        }
+        Operation::Test { path, value } => {
+            if *resolve(config, &parse_pointer(&path)?, &path)? != value {
+                bail!("test failed, `{}` does not match the expected value", path);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Split a JSON Pointer into unescaped reference tokens.
+fn parse_pointer(pointer: &str) -> Result<Vec<String>> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(pointer) = pointer.strip_prefix('/') else {
+        bail!("invalid path `{}`, must start with `/`", pointer);
+    };
+    Ok(pointer
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn resolve<'a>(value: &'a mut Value, tokens: &[String], pointer: &str) -> Result<&'a mut Value> {
+    let mut current = value;
+    for token in tokens {
+        current = match current {
+            Value::Mapping(mapping) => mapping.get_mut(token.as_str()),
+            Value::Sequence(sequence) => token
+                .parse::<usize>()
+                .ok()
+                .and_then(|index| sequence.get_mut(index)),
+            _ => None,
+        }
+        .with_context(|| format!("path `{}` not found", pointer))?;
+    }
+    Ok(current)
+}
+
+fn add(config: &mut Value, pointer: &str, value: Value) -> Result<()> {
+    let tokens = parse_pointer(pointer)?;
+    let Some((last, parent)) = tokens.split_last() else {
+        *config = value;
+        return Ok(());
+    };
+    match resolve(config, parent, pointer)? {
+        Value::Mapping(mapping) => {
+            mapping.insert(Value::from(last.as_str()), value);
+        }
+        Value::Sequence(sequence) => {
+            let index = match last.as_str() {
+                "-" => sequence.len(),
+                index => index
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|index| *index <= sequence.len())
+                    .with_context(|| format!("index of path `{}` out of bounds", pointer))?,
+            };
+            sequence.insert(index, value);
+        }
+        _ => bail!("path `{}` not found", pointer),
+    }
+    Ok(())
+}
+
+fn remove(config: &mut Value, pointer: &str) -> Result<Value> {
+    let tokens = parse_pointer(pointer)?;
+    let Some((last, parent)) = tokens.split_last() else {
+        bail!("cannot remove the whole config");
+    };
+    let removed = match resolve(config, parent, pointer)? {
+        Value::Mapping(mapping) => mapping.remove(last.as_str()),
+        Value::Sequence(sequence) => last
+            .parse::<usize>()
+            .ok()
+            .filter(|index| *index < sequence.len())
+            .map(|index| sequence.remove(index)),
+        _ => None,
+    };
+    removed.with_context(|| format!("path `{}` not found", pointer))
+}
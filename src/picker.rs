@@ -0,0 +1,186 @@
+use std::io::{self, Write};
+
+use anyhow::Result;
+use crossterm::{
+    cursor,
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute, queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+use futures_util::StreamExt;
+
+/// Entry of the picker, e.g. a node with its latest delay as `detail`.
+pub struct PickerItem {
+    pub name: String,
+    pub detail: String,
+    /// Marked as the current choice, and highlighted when the picker opens
+    pub current: bool,
+}
+
+/// State of the picker between key presses.
+struct Picker<'a> {
+    title: &'a str,
+    items: &'a [PickerItem],
+    query: String,
+    /// Indices of items matching the query, best match first
+    matches: Vec<usize>,
+    /// Index of the highlighted entry among `matches`
+    selected: usize,
+}
+
+impl Picker<'_> {
+    fn filter(&mut self) {
+        let mut scored: Vec<(i64, usize)> = self
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, item)| Some((fuzzy_score(&self.query, &item.name)?, index)))
+            .collect();
+        // Stable, so that equally good matches keep their order
+        scored.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+        self.matches = scored.into_iter().map(|(_, index)| index).collect();
+        self.selected = 0;
+    }
+
+    fn render(&self, out: &mut impl Write) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let width = width as usize;
+        let fit = |line: &str| line.chars().take(width).collect::<String>();
+        let name_width = self
+            .items
+            .iter()
+            .map(|item| item.name.chars().count())
+            .max()
+            .unwrap_or_default();
+
+        queue!(
+            out,
+            cursor::MoveTo(0, 0),
+            terminal::Clear(ClearType::All),
+            Print(fit(&format!(
+                "{} ({}/{}) > {}_",
+                self.title,
+                self.matches.len(),
+                self.items.len(),
+                self.query
+            ))),
+            cursor::MoveToNextLine(1),
+            SetAttribute(Attribute::Dim),
+            Print(fit(
+                "type to search | up/down select | enter confirm | esc cancel"
+            )),
+            SetAttribute(Attribute::Reset),
+        )?;
+        let capacity = (height as usize).saturating_sub(2);
+        let offset = self.selected.saturating_sub(capacity.saturating_sub(1));
+        for (position, index) in self.matches.iter().enumerate().skip(offset).take(capacity) {
+            let item = &self.items[*index];
+            let marker = if item.current { "*" } else { " " };
+            let line = format!(
+                "{} {:<name_width$}  {}",
+                marker,
+                item.name,
+                item.detail,
+                name_width = name_width
+            );
+            queue!(out, cursor::MoveToNextLine(1))?;
+            if position == self.selected {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            queue!(out, Print(fit(&line)), SetAttribute(Attribute::Reset))?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Handle a key press, returning the picked item index, or `Some(None)` if cancelled.
+    fn handle_key(&mut self, key: KeyEvent) -> Option<Option<usize>> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+        match key.code {
+            KeyCode::Char('c') if control => return Some(None),
+            KeyCode::Esc => return Some(None),
+            KeyCode::Enter => return Some(self.matches.get(self.selected).copied()),
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Char('p') if control => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down => self.selected += 1,
+            KeyCode::Char('n') if control => self.selected += 1,
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.filter();
+            }
+            KeyCode::Char(c) if !control => {
+                self.query.push(c);
+                self.filter();
+            }
+            _ => {}
+        }
+        self.selected = self.selected.min(self.matches.len().saturating_sub(1));
+        None
+    }
+}
+
+/// Let the user pick one of `items` with a fuzzy search in the terminal, returning its index or
+/// `None` if cancelled.
+pub async fn pick(title: &str, items: &[PickerItem]) -> Result<Option<usize>> {
+    let mut picker = Picker {
+        title,
+        items,
+        query: String::new(),
+        matches: (0..items.len()).collect(),
+        selected: items.iter().position(|item| item.current).unwrap_or(0),
+    };
+
+    let mut out = io::stdout();
+    terminal::enable_raw_mode()?;
+    execute!(out, terminal::EnterAlternateScreen, cursor::Hide)?;
+    let result = run_picker(&mut picker, &mut out).await;
+    execute!(out, cursor::Show, terminal::LeaveAlternateScreen)?;
+    terminal::disable_raw_mode()?;
+    result
+}
+
+async fn run_picker(picker: &mut Picker<'_>, out: &mut impl Write) -> Result<Option<usize>> {
+    let mut events = EventStream::new();
+    loop {
+        picker.render(out)?;
+        match events.next().await {
+            Some(Ok(Event::Key(key))) if key.kind == KeyEventKind::Press => {
+                if let Some(picked) = picker.handle_key(key) {
+                    return Ok(picked);
+                }
+            }
+            Some(Ok(_)) => {}
+            Some(Err(error)) => return Err(error.into()),
+            None => return Ok(None),
+        }
+    }
+}
+
+/// Score of `name` matching all characters of `query` in order, ignoring case, or `None` if it
+/// does not match. Consecutive matches and matches at word starts score higher, so that `hk1`
+/// ranks `HK 01` above `Hong Kong 11`.
+pub fn fuzzy_score(query: &str, name: &str) -> Option<i64> {
+    let name: Vec<char> = name.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut position = 0;
+    let mut previous_match: Option<usize> = None;
+    for query_char in query.chars().flat_map(char::to_lowercase) {
+        let offset = name[position..]
+            .iter()
+            .position(|name_char| *name_char == query_char)?;
+        let index = position + offset;
+        score += 1;
+        if previous_match.is_some_and(|previous| previous + 1 == index) {
+            score += 4;
+        }
+        if index == 0 || !name[index - 1].is_alphanumeric() {
+            score += 2;
+        }
+        // Prefer matches early in the name
+        score -= offset.min(4) as i64;
+        previous_match = Some(index);
+        position = index + 1;
+    }
+    Some(score)
+}
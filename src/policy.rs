@@ -0,0 +1,66 @@
+use serde_json::{json, Value};
+
+/// Where the policy is read from, either mihomo's proxy ports on `hostname` or a PAC file url.
+pub enum PolicyProxy<'a> {
+    Fixed {
+        hostname: &'a str,
+        http_port: u16,
+        socks_port: u16,
+        bypass: &'a [String],
+    },
+    Pac(&'a str),
+}
+
+/// Managed policy of Chrome and Chromium configuring its proxy, installed as a JSON file under
+/// `/etc/opt/chrome/policies/managed/` (or `/etc/chromium/policies/managed/`).
+///
+/// Reference: https://chromeenterprise.google/policies/#ProxySettings
+pub fn chrome_policy(proxy: &PolicyProxy) -> Value {
+    let proxy_settings = match proxy {
+        PolicyProxy::Fixed {
+            hostname,
+            http_port,
+            bypass,
+            ..
+        } => json!({
+            "ProxyMode": "fixed_servers",
+            "ProxyServer": format!("{}:{}", hostname, http_port),
+            "ProxyBypassList": bypass.join(","),
+        }),
+        PolicyProxy::Pac(url) => json!({
+            "ProxyMode": "pac_script",
+            "ProxyPacUrl": url,
+        }),
+    };
+    json!({ "ProxySettings": proxy_settings })
+}
+
+/// Enterprise policy of Firefox configuring and locking its proxy, installed as `policies.json`
+/// under `/etc/firefox/policies/`.
+///
+/// Reference: https://mozilla.github.io/policy-templates/#proxy
+pub fn firefox_policy(proxy: &PolicyProxy) -> Value {
+    let proxy = match proxy {
+        PolicyProxy::Fixed {
+            hostname,
+            http_port,
+            socks_port,
+            bypass,
+        } => json!({
+            "Mode": "manual",
+            "Locked": true,
+            "HTTPProxy": format!("{}:{}", hostname, http_port),
+            "UseHTTPProxyForAllProtocols": true,
+            "SOCKSProxy": format!("{}:{}", hostname, socks_port),
+            "SOCKSVersion": 5,
+            "Passthrough": bypass.join(", "),
+            "UseProxyForDNS": true,
+        }),
+        PolicyProxy::Pac(url) => json!({
+            "Mode": "autoConfig",
+            "Locked": true,
+            "AutoConfigURL": url,
+        }),
+    };
+    json!({ "policies": { "Proxy": proxy } })
+}
@@ -0,0 +1,119 @@
+use std::fs;
+use std::net::{Ipv4Addr, TcpListener};
+
+use anyhow::Result;
+use serde_yaml::Value;
+
+/// Listening port fields of mihomo's `config.yaml`.
+pub const PORT_FIELDS: [&str; 4] = ["port", "socks-port", "mixed-port", "tproxy-port"];
+
+/// Ports tried above a busy one before falling back to any free port picked by the system.
+const PORT_SEARCH_RANGE: u16 = 100;
+
+/// Whether nothing listens on `port`, on any address.
+pub fn port_available(port: u16) -> bool {
+    TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok()
+}
+
+/// Processes listening on TCP `port` by name, from `/proc/net/tcp` and `/proc/net/tcp6`, with
+/// `None` for sockets of processes that are not visible, e.g. of other users without root.
+pub fn port_listeners(port: u16) -> Vec<Option<String>> {
+    let mut sockets = Vec::new();
+    for table in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let Ok(content) = fs::read_to_string(table) else {
+            continue;
+        };
+        for line in content.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            // Local address as `address:port` in hex, state `0A` for listening sockets
+            let local_port = fields
+                .get(1)
+                .and_then(|address| address.rsplit_once(':'))
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if local_port == Some(port) && fields.get(3) == Some(&"0A") {
+                if let Some(inode) = fields.get(9) {
+                    sockets.push(format!("socket:[{}]", inode));
+                }
+            }
+        }
+    }
+    if sockets.is_empty() {
+        return Vec::new();
+    }
+
+    let mut found = Vec::new();
+    let mut listeners = Vec::new();
+    for process in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            let target = target.to_string_lossy().to_string();
+            // Sockets inherited by child processes are counted once
+            if sockets.contains(&target) && !found.contains(&target) {
+                let comm = fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+                listeners.push(Some(comm.trim().to_string()));
+                found.push(target);
+            }
+        }
+    }
+    listeners.extend(
+        sockets
+            .iter()
+            .filter(|socket| !found.contains(socket))
+            .map(|_| None),
+    );
+    listeners
+}
+
+/// Ports of `PORT_FIELDS` set in a mihomo config.
+pub fn config_ports(mihomo_yaml: &Value) -> Vec<(&'static str, u16)> {
+    PORT_FIELDS
+        .iter()
+        .filter_map(|field| {
+            let port = mihomo_yaml.get(*field)?.as_u64()?;
+            Some((*field, u16::try_from(port).ok()?))
+        })
+        .collect()
+}
+
+/// Move listening ports of `mihomo_yaml` that are busy to free ones, preferring the closest
+/// ports above. Ports in `held`, i.e. listened on by the running mihomo itself, count as free, so
+/// that ports reassigned by an earlier run are kept.
+///
+/// Returns the reassigned fields with their previous and new ports.
+pub fn reassign_busy_ports(
+    mihomo_yaml: &mut Value,
+    held: &[u16],
+) -> Result<Vec<(&'static str, u16, u16)>> {
+    let ports = config_ports(mihomo_yaml);
+    let mut taken: Vec<u16> = ports.iter().map(|(_, port)| *port).collect();
+    let mut reassigned = Vec::new();
+    for (field, port) in ports {
+        if held.contains(&port) || port_available(port) {
+            continue;
+        }
+        let free_port = (1..=PORT_SEARCH_RANGE)
+            .filter_map(|offset| port.checked_add(offset))
+            .chain(held.iter().copied())
+            .find(|candidate| {
+                !taken.contains(candidate)
+                    && (held.contains(candidate) || port_available(*candidate))
+            });
+        let free_port = match free_port {
+            Some(free_port) => free_port,
+            None => TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0))?
+                .local_addr()?
+                .port(),
+        };
+        taken.push(free_port);
+        if let Some(mapping) = mihomo_yaml.as_mapping_mut() {
+            mapping.insert(Value::from(field), Value::from(free_port));
+        }
+        reassigned.push((field, port, free_port));
+    }
+    Ok(reassigned)
+}
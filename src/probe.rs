@@ -0,0 +1,24 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use reqwest::{Client, Proxy};
+
+/// Urls only reachable over IPv4 and IPv6 respectively, telling a broken proxy from broken IPv6.
+pub const IPV4_PROBE_URL: &str = "http://ipv4.msftconnecttest.com/connecttest.txt";
+pub const IPV6_PROBE_URL: &str = "http://ipv6.msftconnecttest.com/connecttest.txt";
+
+/// Time allowed for a probe request through mihomo.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether `url` responds successfully through mihomo's http proxy on `http_port`.
+pub async fn reachable_through(http_port: u16, url: &str) -> Result<bool> {
+    let client = Client::builder()
+        .proxy(Proxy::all(format!("http://127.0.0.1:{}", http_port))?)
+        .timeout(PROBE_TIMEOUT)
+        .build()?;
+    Ok(client
+        .get(url)
+        .send()
+        .await
+        .is_ok_and(|response| response.status().is_success()))
+}
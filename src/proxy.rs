@@ -1,39 +1,80 @@
 use clap_complete::shells::Shell;
 
-pub fn proxy_export_cmd(hostname: &str, http_port: &u16, socks_port: &u16) -> String {
+pub fn proxy_export_cmd(
+    hostname: &str,
+    http_port: &u16,
+    socks_port: &u16,
+    no_proxy: &[String],
+) -> String {
+    let no_proxy = no_proxy.join(",");
+
     // Check current shell
     let shell = Shell::from_env().unwrap_or(Shell::Bash);
     match shell {
         Shell::Fish => {
             // For fish, use `set -gx $ENV_VAR value` to set environment variables
-            format!(
-                "set -gx https_proxy http://{hostname}:{http_port} \
-                set -gx http_proxy http://{hostname}:{http_port} \
+            let mut cmd = format!(
+                "set -gx https_proxy http://{hostname}:{http_port}; \
+                set -gx http_proxy http://{hostname}:{http_port}; \
                 set -gx all_proxy socks5://{hostname}:{socks_port}"
-            )
+            );
+            if !no_proxy.is_empty() {
+                cmd.push_str(&format!("; set -gx no_proxy {no_proxy}"));
+            }
+            cmd
         }
         _ => {
             // For all other shells (bash/zsh), use `export $ENV_VAR=value`
-            format!(
+            let mut cmd = format!(
                 "export https_proxy=http://{hostname}:{http_port} \
                 http_proxy=http://{hostname}:{http_port} \
                 all_proxy=socks5://{hostname}:{socks_port}"
-            )
+            );
+            if !no_proxy.is_empty() {
+                cmd.push_str(&format!(" no_proxy={no_proxy}"));
+            }
+            cmd
         }
     }
 }
 
+/// Proxy environment variables of a child process, in both lower and upper case as tools differ
+/// in which they read.
+pub fn proxy_env(
+    hostname: &str,
+    http_port: &u16,
+    socks_port: &u16,
+    no_proxy: &[String],
+) -> Vec<(String, String)> {
+    let mut vars = vec![
+        ("http_proxy", format!("http://{hostname}:{http_port}")),
+        ("https_proxy", format!("http://{hostname}:{http_port}")),
+        ("all_proxy", format!("socks5://{hostname}:{socks_port}")),
+    ];
+    if !no_proxy.is_empty() {
+        vars.push(("no_proxy", no_proxy.join(",")));
+    }
+    vars.into_iter()
+        .flat_map(|(name, value)| {
+            [
+                (name.to_string(), value.clone()),
+                (name.to_uppercase(), value),
+            ]
+        })
+        .collect()
+}
+
 pub fn proxy_unset_cmd() -> String {
     // Check current shell
     let shell = Shell::from_env().unwrap_or(Shell::Bash);
     match shell {
         Shell::Fish => {
             // For fish, use `set -e $ENV_VAR` to unset environment variables
-            "set -e https_proxy http_proxy all_proxy".to_owned()
+            "set -e https_proxy http_proxy all_proxy no_proxy".to_owned()
         }
         _ => {
             // For all other shells (bash/zsh), use `unset $ENV_VAR`
-            "unset https_proxy http_proxy all_proxy".to_owned()
+            "unset https_proxy http_proxy all_proxy no_proxy".to_owned()
         }
     }
 }
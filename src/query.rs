@@ -0,0 +1,242 @@
+use anyhow::{bail, Context, Result};
+use serde_yaml::Value;
+
+/// Step of a query path.
+#[derive(Debug)]
+enum Step {
+    /// `.name` or `."name"`, `null` if missing.
+    Key(String),
+    /// `[n]`, counting from the end if negative, `null` if out of range.
+    Index(i64),
+    /// `[]`, every item of a list or value of a mapping.
+    Iterate,
+}
+
+/// Stage of a query, separated by `|`.
+#[derive(Debug)]
+enum Stage {
+    Path(Vec<Step>),
+    /// Number of items of a list or mapping, or characters of a string.
+    Length,
+    /// Keys of a mapping, or indices of a list.
+    Keys,
+}
+
+/// Query over mihomo's config in a small subset of jq: paths such as `.dns.nameserver[0]` or
+/// `.proxy-groups[].name`, piped with `|` into further paths, `length` or `keys`.
+#[derive(Debug)]
+pub struct Query {
+    stages: Vec<Stage>,
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Result<Query> {
+        let stages = query
+            .split('|')
+            .map(|stage| match stage.trim() {
+                "length" => Ok(Stage::Length),
+                "keys" => Ok(Stage::Keys),
+                path => parse_path(path).map(Stage::Path),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Query { stages })
+    }
+
+    /// Run the query on `value`, returning every result in order.
+    pub fn run(&self, value: &Value) -> Result<Vec<Value>> {
+        let mut values = vec![value.clone()];
+        for stage in &self.stages {
+            let mut next = Vec::new();
+            for value in values {
+                match stage {
+                    Stage::Path(steps) => next.extend(walk(value, steps)?),
+                    Stage::Length => next.push(Value::from(length(&value)?)),
+                    Stage::Keys => next.push(keys(&value)?),
+                }
+            }
+            values = next;
+        }
+        Ok(values)
+    }
+}
+
+fn parse_path(path: &str) -> Result<Vec<Step>> {
+    let Some(mut rest) = path.strip_prefix('.') else {
+        bail!("invalid query `{}`, paths start with `.`", path);
+    };
+    let mut steps = Vec::new();
+    loop {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted
+                .find('"')
+                .with_context(|| format!("unclosed quote in `{}`", path))?;
+            steps.push(Step::Key(quoted[..end].to_string()));
+            rest = &quoted[end + 1..];
+        } else {
+            let end = rest.find(['.', '[']).unwrap_or(rest.len());
+            if end > 0 {
+                steps.push(Step::Key(rest[..end].to_string()));
+            }
+            rest = &rest[end..];
+        }
+        while let Some(bracketed) = rest.strip_prefix('[') {
+            let end = bracketed
+                .find(']')
+                .with_context(|| format!("unclosed `[` in `{}`", path))?;
+            let index = bracketed[..end].trim();
+            steps.push(match index {
+                "" => Step::Iterate,
+                index => Step::Index(
+                    index
+                        .parse()
+                        .with_context(|| format!("invalid index `{}` in `{}`", index, path))?,
+                ),
+            });
+            rest = &bracketed[end + 1..];
+        }
+        match rest.strip_prefix('.') {
+            Some(next) => rest = next,
+            None if rest.is_empty() => return Ok(steps),
+            None => bail!("invalid query `{}` at `{}`", path, rest),
+        }
+    }
+}
+
+fn walk(value: Value, steps: &[Step]) -> Result<Vec<Value>> {
+    let Some((step, steps)) = steps.split_first() else {
+        return Ok(vec![value]);
+    };
+    match (step, value) {
+        (Step::Key(_) | Step::Index(_), Value::Null) => walk(Value::Null, steps),
+        (Step::Key(key), Value::Mapping(mut mapping)) => {
+            walk(mapping.remove(key.as_str()).unwrap_or(Value::Null), steps)
+        }
+        (Step::Index(index), Value::Sequence(mut items)) => {
+            let position = if *index < 0 {
+                items.len().checked_sub(index.unsigned_abs() as usize)
+            } else {
+                Some(*index as usize)
+            };
+            let item = position
+                .filter(|position| *position < items.len())
+                .map(|position| items.swap_remove(position));
+            walk(item.unwrap_or(Value::Null), steps)
+        }
+        (Step::Iterate, Value::Sequence(items)) => iterate(items, steps),
+        (Step::Iterate, Value::Mapping(mapping)) => iterate(mapping.into_values(), steps),
+        (Step::Key(key), value) => bail!("cannot get `{}` of {}", key, kind(&value)),
+        (Step::Index(index), value) => bail!("cannot get [{}] of {}", index, kind(&value)),
+        (Step::Iterate, value) => bail!("cannot iterate over {}", kind(&value)),
+    }
+}
+
+fn iterate(items: impl IntoIterator<Item = Value>, steps: &[Step]) -> Result<Vec<Value>> {
+    let mut results = Vec::new();
+    for item in items {
+        results.extend(walk(item, steps)?);
+    }
+    Ok(results)
+}
+
+fn length(value: &Value) -> Result<usize> {
+    match value {
+        Value::Null => Ok(0),
+        Value::String(text) => Ok(text.chars().count()),
+        Value::Sequence(items) => Ok(items.len()),
+        Value::Mapping(mapping) => Ok(mapping.len()),
+        value => bail!("{} has no length", kind(value)),
+    }
+}
+
+fn keys(value: &Value) -> Result<Value> {
+    match value {
+        Value::Mapping(mapping) => Ok(Value::Sequence(mapping.keys().cloned().collect())),
+        Value::Sequence(items) => Ok(Value::Sequence((0..items.len()).map(Value::from).collect())),
+        value => bail!("{} has no keys", kind(value)),
+    }
+}
+
+fn kind(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Sequence(_) => "a list",
+        Value::Mapping(_) => "a mapping",
+        Value::Tagged(_) => "a tagged value",
+    }
+}
+
+/// A query result as printed: strings raw and numbers as is, like `jq -r`, lists and mappings as
+/// YAML.
+pub fn format_result(value: &Value) -> Result<String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Null => Ok(String::from("null")),
+        Value::Bool(value) => Ok(value.to_string()),
+        Value::Number(value) => Ok(value.to_string()),
+        value => Ok(serde_yaml::to_string(value)?.trim_end().to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(query: &str, yaml: &str) -> Result<Vec<String>> {
+        let value = serde_yaml::from_str(yaml)?;
+        Query::parse(query)?
+            .run(&value)?
+            .iter()
+            .map(format_result)
+            .collect()
+    }
+
+    const CONFIG: &str = r#"
+dns:
+  nameserver: [1.1.1.1, 8.8.8.8]
+proxy-groups:
+  - name: Nodes
+    proxies: [a, b]
+  - name: Auto
+"a.b": quoted
+"#;
+
+    #[test]
+    fn paths_walk_keys_indices_and_iterations() {
+        assert_eq!(query(".dns.nameserver[0]", CONFIG).unwrap(), ["1.1.1.1"]);
+        assert_eq!(query(".dns.nameserver[-1]", CONFIG).unwrap(), ["8.8.8.8"]);
+        assert_eq!(query(".dns.nameserver[2]", CONFIG).unwrap(), ["null"]);
+        assert_eq!(
+            query(".proxy-groups[].name", CONFIG).unwrap(),
+            ["Nodes", "Auto"]
+        );
+        assert_eq!(query(".\"a.b\"", CONFIG).unwrap(), ["quoted"]);
+        assert_eq!(query(".missing.key", CONFIG).unwrap(), ["null"]);
+        assert_eq!(query(".", "port: 7890").unwrap(), ["port: 7890"]);
+    }
+
+    #[test]
+    fn stages_pipe_into_length_and_keys() {
+        assert_eq!(
+            query(".proxy-groups[] | .proxies | length", CONFIG).unwrap(),
+            ["2", "0"]
+        );
+        assert_eq!(query(".dns | keys", CONFIG).unwrap(), ["- nameserver"]);
+        assert_eq!(
+            query(".dns.nameserver | keys", CONFIG).unwrap(),
+            ["- 0\n- 1"]
+        );
+    }
+
+    #[test]
+    fn invalid_queries_and_steps_are_errors() {
+        for invalid in ["dns", ".\"dns", ".dns[0", ".dns[x]", ".dns[0]x"] {
+            assert!(Query::parse(invalid).is_err(), "{}", invalid);
+        }
+        assert!(query(".dns.nameserver.first", CONFIG).is_err());
+        assert!(query(".\"a.b\"[]", CONFIG).is_err());
+        assert!(query(".dns | length | length", CONFIG).is_err());
+    }
+}
@@ -0,0 +1,55 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+
+/// Dummy network link carrying the systemd-resolved DNS settings pointing to mihomo, so that the
+/// settings of real links are left untouched and are restored by deleting it.
+pub const RESOLVED_LINK: &str = "mihoro0";
+
+/// Address systemd-resolved sends queries to for mihomo's DNS `listen` address, i.e. loopback
+/// if mihomo listens on all addresses.
+pub fn listener_address(listen: &str) -> Option<SocketAddr> {
+    let listen = match listen.strip_prefix(':') {
+        Some(port) => format!("0.0.0.0:{}", port),
+        None => listen.to_string(),
+    };
+    let mut address: SocketAddr = listen.parse().ok()?;
+    match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => address.set_ip(Ipv4Addr::LOCALHOST.into()),
+        IpAddr::V6(ip) if ip.is_unspecified() => address.set_ip(Ipv6Addr::LOCALHOST.into()),
+        _ => {}
+    }
+    Some(address)
+}
+
+/// Whether `RESOLVED_LINK` exists, i.e. systemd-resolved may still point to mihomo.
+pub fn resolved_link_exists() -> bool {
+    Path::new("/sys/class/net").join(RESOLVED_LINK).exists()
+}
+
+/// Shell commands creating `RESOLVED_LINK` and pointing systemd-resolved to `listener` through
+/// it, for `domains` (as routing-only domains `~example.com`) or all domains if empty.
+pub fn resolvectl_script(listener: SocketAddr, domains: &[String]) -> String {
+    let routing_domains = match domains {
+        [] => String::from("'~.'"),
+        domains => domains
+            .iter()
+            .map(|domain| format!("'~{}'", domain.trim_start_matches('.')))
+            .collect::<Vec<_>>()
+            .join(" "),
+    };
+    let default_route = if domains.is_empty() { "yes" } else { "no" };
+    format!(
+        "ip link show {RESOLVED_LINK} >/dev/null 2>&1 || ip link add {RESOLVED_LINK} type dummy
+ip link set {RESOLVED_LINK} up
+resolvectl dns {RESOLVED_LINK} {listener}
+resolvectl domain {RESOLVED_LINK} {routing_domains}
+resolvectl default-route {RESOLVED_LINK} {default_route}
+"
+    )
+}
+
+/// Shell commands reverting `resolvectl_script`, which restores systemd-resolved's previous
+/// settings.
+pub fn resolvectl_revert_script() -> String {
+    format!("ip link del {RESOLVED_LINK}\n")
+}
@@ -0,0 +1,591 @@
+use crate::nodes::entry_name;
+use crate::template::Template;
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
+
+/// File name of the local rules fragment, kept next to mihomo's `config.yaml`.
+pub const LOCAL_RULES_FILE: &str = "rules.local.yaml";
+
+/// File name of temporary routes injected into the running mihomo by `mihoro route now`, until
+/// the config is generated again.
+pub const TEMPORARY_RULES_FILE: &str = "rules.temporary.yaml";
+
+/// Refresh interval of imported rulesets in seconds.
+const RULESET_INTERVAL: u32 = 86400;
+
+/// Name of the ad-blocking ruleset managed by `mihoro adblock`.
+pub const ADBLOCK_RULESET: &str = "adblock";
+
+/// Default ad-blocking domain list, from https://github.com/privacy-protection-tools/anti-AD
+pub const DEFAULT_ADBLOCK_URL: &str =
+    "https://cdn.jsdelivr.net/gh/privacy-protection-tools/anti-AD@master/anti-ad-clash.yaml";
+
+/// Built-in policies that are never resolved to a proxy group.
+const BUILTIN_POLICIES: &[&str] = &["DIRECT", "REJECT", "REJECT-DROP", "PASS", "COMPATIBLE"];
+
+/// Local rules fragment, merged ahead of the remote config's rules so that local rules always take
+/// precedence over provider rules. Rule trees in `sub-rules` are entered with `SUB-RULE` rules, e.g.
+/// `SUB-RULE,(SRC-IP-CIDR,192.168.0.0/16),lan` for different behavior of LAN sources.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct LocalRules {
+    #[serde(
+        rename = "rule-providers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub rule_providers: BTreeMap<String, Value>,
+    #[serde(
+        rename = "sub-rules",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    pub sub_rules: BTreeMap<String, Vec<String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rules: Vec<String>,
+}
+
+impl LocalRules {
+    /// Read local rules fragment from path as written, with template variables unexpanded for
+    /// editing, returning empty rules if the file does not exist.
+    pub fn load(path: &str) -> Result<LocalRules> {
+        if !Path::new(path).exists() {
+            return Ok(LocalRules::default());
+        }
+        let raw_rules = fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&raw_rules)?)
+    }
+
+    /// Read local rules fragment from path with template variables expanded, as merged into the
+    /// config.
+    pub fn load_expanded(path: &str, template: &Template) -> Result<LocalRules> {
+        template.read_yaml(Path::new(path))
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        fs::write(path, serde_yaml::to_string(&self)?)?;
+        Ok(())
+    }
+
+    /// Add a ruleset as a rule-provider with its `RULE-SET` rule after existing local rules,
+    /// replacing any ruleset of the same name.
+    pub fn add_ruleset(&mut self, ruleset: &Ruleset, policy: &str) -> Result<()> {
+        self.remove_ruleset(&ruleset.name);
+        self.insert_ruleset(self.rules.len(), ruleset, policy)
+    }
+
+    /// Same as `add_ruleset`, but the `RULE-SET` rule goes ahead of all other rulesets.
+    pub fn prepend_ruleset(&mut self, ruleset: &Ruleset, policy: &str) -> Result<()> {
+        self.remove_ruleset(&ruleset.name);
+        self.insert_ruleset(self.first_ruleset_index(), ruleset, policy)
+    }
+
+    /// Add a ruleset converted to .mrs by mihomo's `convert-ruleset` as a file rule-provider, with
+    /// its `RULE-SET` rule after existing local rules. Unlike http rule-providers, it is never
+    /// removed to be refreshed.
+    pub fn add_converted_ruleset(
+        &mut self,
+        name: &str,
+        behavior: &str,
+        policy: &str,
+    ) -> Result<()> {
+        self.remove_ruleset(name);
+        let provider = RuleProvider {
+            provider_type: String::from("file"),
+            behavior: behavior.to_string(),
+            format: String::from("mrs"),
+            url: None,
+            path: converted_ruleset_path(name),
+            interval: None,
+        };
+        self.insert_provider(self.rules.len(), name, provider, policy)
+    }
+
+    fn insert_ruleset(&mut self, index: usize, ruleset: &Ruleset, policy: &str) -> Result<()> {
+        self.insert_provider(index, &ruleset.name, RuleProvider::from(ruleset), policy)
+    }
+
+    fn insert_provider(
+        &mut self,
+        index: usize,
+        name: &str,
+        provider: RuleProvider,
+        policy: &str,
+    ) -> Result<()> {
+        self.rule_providers
+            .insert(name.to_string(), serde_yaml::to_value(provider)?);
+        let rule = format!("RULE-SET,{},{}", name, policy);
+        self.rules.insert(index, rule);
+        Ok(())
+    }
+
+    /// Index of the first `RULE-SET` rule, route rules are kept ahead of it.
+    fn first_ruleset_index(&self) -> usize {
+        self.rules
+            .iter()
+            .position(|rule| rule.starts_with("RULE-SET,"))
+            .unwrap_or(self.rules.len())
+    }
+
+    /// Route rules, i.e. local rules other than imported rulesets.
+    pub fn routes(&self) -> impl Iterator<Item = &String> {
+        self.rules
+            .iter()
+            .filter(|rule| !rule.starts_with("RULE-SET,"))
+    }
+
+    /// Add a route rule for a domain or ip (cidr) ahead of all rulesets, replacing existing route
+    /// rules of the same target.
+    pub fn add_route(&mut self, target: &str, policy: &str) {
+        self.remove_route(target);
+        let index = self.first_ruleset_index();
+        self.rules.insert(index, route_rule(target, policy));
+    }
+
+    /// Route a bypassed domain or ip (cidr) with `DIRECT` after all route rules, unless a route
+    /// rule already exists for it. Leading `.` and `*.` of domains are ignored.
+    pub fn add_bypass(&mut self, target: &str) {
+        let target = target.trim_start_matches("*.").trim_start_matches('.');
+        if target.is_empty() || target == "*" {
+            return;
+        }
+        let route = route_rule(target, "DIRECT");
+        let route_target = route.split(',').nth(1);
+        if self
+            .routes()
+            .any(|rule| rule.split(',').nth(1) == route_target)
+        {
+            return;
+        }
+        let index = self.first_ruleset_index();
+        self.rules.insert(index, route);
+    }
+
+    /// Remove route rules of a domain or ip (cidr), returning whether any existed.
+    pub fn remove_route(&mut self, target: &str) -> bool {
+        let route = route_rule(target, "");
+        let route_target = route.split(',').nth(1);
+        let rules_count = self.rules.len();
+        self.rules
+            .retain(|rule| rule.starts_with("RULE-SET,") || rule.split(',').nth(1) != route_target);
+        self.rules.len() != rules_count
+    }
+
+    /// Remove a ruleset and the rules referencing it, returning whether it existed.
+    pub fn remove_ruleset(&mut self, name: &str) -> bool {
+        let rule_prefix = format!("RULE-SET,{},", name);
+        self.rules.retain(|rule| !rule.starts_with(&rule_prefix));
+        self.rule_providers.remove(name).is_some()
+    }
+}
+
+/// A remote ruleset imported as an http rule-provider.
+pub struct Ruleset {
+    pub name: String,
+    pub url: String,
+    pub behavior: &'static str,
+    pub format: &'static str,
+}
+
+/// `mihomo` rule-provider entry.
+///
+/// Reference: https://wiki.metacubex.one/config/rule-providers
+#[derive(Serialize, Deserialize, Debug)]
+struct RuleProvider {
+    #[serde(rename = "type")]
+    provider_type: String,
+    behavior: String,
+    format: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interval: Option<u32>,
+}
+
+impl From<&Ruleset> for RuleProvider {
+    fn from(ruleset: &Ruleset) -> Self {
+        let extension = match ruleset.format {
+            "yaml" => "yaml",
+            "mrs" => "mrs",
+            _ => "list",
+        };
+        RuleProvider {
+            provider_type: String::from("http"),
+            behavior: ruleset.behavior.to_string(),
+            format: ruleset.format.to_string(),
+            url: Some(ruleset.url.clone()),
+            path: format!("./ruleset/{}.{}", ruleset.name, extension),
+            interval: Some(RULESET_INTERVAL),
+        }
+    }
+}
+
+/// Path of a ruleset converted to .mrs, relative to mihomo's config root.
+pub fn converted_ruleset_path(name: &str) -> String {
+    format!("./ruleset/{}.mrs", name)
+}
+
+/// Ruleset file of a community preset.
+struct PresetRuleset {
+    name: &'static str,
+    file: &'static str,
+    behavior: &'static str,
+    format: &'static str,
+    policy: &'static str,
+}
+
+/// Community ruleset preset, importable by name.
+struct Preset {
+    name: &'static str,
+    base_url: &'static str,
+    rulesets: &'static [PresetRuleset],
+}
+
+/// Community ruleset presets.
+///
+/// * `acl4ssr` - https://github.com/ACL4SSR/ACL4SSR
+/// * `loyalsoldier` - https://github.com/Loyalsoldier/clash-rules
+const PRESETS: &[Preset] = &[
+    Preset {
+        name: "acl4ssr",
+        base_url: "https://cdn.jsdelivr.net/gh/ACL4SSR/ACL4SSR@master/Clash",
+        rulesets: &[
+            PresetRuleset {
+                name: "acl4ssr-lan",
+                file: "LocalAreaNetwork.list",
+                behavior: "classical",
+                format: "text",
+                policy: "DIRECT",
+            },
+            PresetRuleset {
+                name: "acl4ssr-banad",
+                file: "BanAD.list",
+                behavior: "classical",
+                format: "text",
+                policy: "REJECT",
+            },
+            PresetRuleset {
+                name: "acl4ssr-banprogramad",
+                file: "BanProgramAD.list",
+                behavior: "classical",
+                format: "text",
+                policy: "REJECT",
+            },
+            PresetRuleset {
+                name: "acl4ssr-proxy",
+                file: "ProxyGFWlist.list",
+                behavior: "classical",
+                format: "text",
+                policy: "PROXY",
+            },
+            PresetRuleset {
+                name: "acl4ssr-china-domain",
+                file: "ChinaDomain.list",
+                behavior: "classical",
+                format: "text",
+                policy: "DIRECT",
+            },
+            PresetRuleset {
+                name: "acl4ssr-china-ip",
+                file: "ChinaCompanyIp.list",
+                behavior: "classical",
+                format: "text",
+                policy: "DIRECT",
+            },
+        ],
+    },
+    Preset {
+        name: "loyalsoldier",
+        base_url: "https://cdn.jsdelivr.net/gh/Loyalsoldier/clash-rules@release",
+        rulesets: &[
+            PresetRuleset {
+                name: "loyalsoldier-private",
+                file: "private.txt",
+                behavior: "domain",
+                format: "yaml",
+                policy: "DIRECT",
+            },
+            PresetRuleset {
+                name: "loyalsoldier-reject",
+                file: "reject.txt",
+                behavior: "domain",
+                format: "yaml",
+                policy: "REJECT",
+            },
+            PresetRuleset {
+                name: "loyalsoldier-proxy",
+                file: "proxy.txt",
+                behavior: "domain",
+                format: "yaml",
+                policy: "PROXY",
+            },
+            PresetRuleset {
+                name: "loyalsoldier-direct",
+                file: "direct.txt",
+                behavior: "domain",
+                format: "yaml",
+                policy: "DIRECT",
+            },
+            PresetRuleset {
+                name: "loyalsoldier-lancidr",
+                file: "lancidr.txt",
+                behavior: "ipcidr",
+                format: "yaml",
+                policy: "DIRECT",
+            },
+            PresetRuleset {
+                name: "loyalsoldier-cncidr",
+                file: "cncidr.txt",
+                behavior: "ipcidr",
+                format: "yaml",
+                policy: "DIRECT",
+            },
+        ],
+    },
+];
+
+/// Build a route rule, matching ips and cidrs with `IP-CIDR`/`IP-CIDR6` and anything else as a
+/// domain suffix.
+fn route_rule(target: &str, policy: &str) -> String {
+    let ip = target.split('/').next().unwrap_or(target);
+    match ip.parse::<IpAddr>() {
+        Ok(IpAddr::V4(_)) if target.contains('/') => {
+            format!("IP-CIDR,{},{},no-resolve", target, policy)
+        }
+        Ok(IpAddr::V4(_)) => format!("IP-CIDR,{}/32,{},no-resolve", target, policy),
+        Ok(IpAddr::V6(_)) if target.contains('/') => {
+            format!("IP-CIDR6,{},{},no-resolve", target, policy)
+        }
+        Ok(IpAddr::V6(_)) => format!("IP-CIDR6,{}/128,{},no-resolve", target, policy),
+        Err(_) => format!("DOMAIN-SUFFIX,{},{}", target, policy),
+    }
+}
+
+/// Rule-provider format of a ruleset url, detected from its file extension.
+pub fn ruleset_format(url: &str) -> &'static str {
+    match url.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("yaml" | "yml") => "yaml",
+        Some("mrs") => "mrs",
+        _ => "text",
+    }
+}
+
+/// Resolve a ruleset import source, either a preset name or a ruleset url, into rulesets paired
+/// with their policies. `behavior` applies to rulesets imported from url, defaulting to `domain`
+/// for .mrs rulesets, which only hold domains or ip cidrs, and to `classical` otherwise.
+pub fn resolve_rulesets(
+    source: &str,
+    policy: &str,
+    behavior: Option<&'static str>,
+) -> Result<Vec<(Ruleset, String)>> {
+    if let Some(preset) = PRESETS.iter().find(|preset| preset.name == source) {
+        return Ok(preset
+            .rulesets
+            .iter()
+            .map(|preset_ruleset| {
+                let ruleset = Ruleset {
+                    name: preset_ruleset.name.to_string(),
+                    url: format!("{}/{}", preset.base_url, preset_ruleset.file),
+                    behavior: preset_ruleset.behavior,
+                    format: preset_ruleset.format,
+                };
+                (ruleset, preset_ruleset.policy.to_string())
+            })
+            .collect());
+    }
+
+    let Ok(url) = reqwest::Url::parse(source) else {
+        let presets: Vec<&str> = PRESETS.iter().map(|preset| preset.name).collect();
+        bail!(
+            "`{}` is neither a ruleset url nor a preset ({})",
+            source,
+            presets.join(", ")
+        );
+    };
+    let file_name = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default();
+    let stem = file_name.split('.').next().unwrap_or(file_name);
+    let format = ruleset_format(source);
+    let behavior = match (format, behavior) {
+        ("mrs", Some("classical")) => bail!(".mrs rulesets only hold domains or ip cidrs"),
+        (_, Some(behavior)) => behavior,
+        ("mrs", None) => "domain",
+        (_, None) => "classical",
+    };
+    let ruleset = Ruleset {
+        name: stem.to_lowercase(),
+        url: source.to_string(),
+        behavior,
+        format,
+    };
+    Ok(vec![(ruleset, policy.to_string())])
+}
+
+/// A rule with its policy replaced by `map`, if it returns one. The policy is the last field, or
+/// the one before a trailing `no-resolve` or `src` parameter. `SUB-RULE` rules end with the name
+/// of a sub-rule rather than a policy and are kept as is.
+pub fn map_policy(rule: &str, map: impl FnOnce(&str) -> Option<String>) -> String {
+    let mut fields: Vec<&str> = rule.split(',').collect();
+    let policy_index = match fields.last() {
+        Some(&("no-resolve" | "src")) => fields.len().saturating_sub(2),
+        _ => fields.len().saturating_sub(1),
+    };
+    if policy_index == 0 || fields[0] == "SUB-RULE" {
+        return rule.to_string();
+    }
+    let Some(policy) = map(fields[policy_index]) else {
+        return rule.to_string();
+    };
+    fields[policy_index] = &policy;
+    fields.join(",")
+}
+
+/// Resolve the `PROXY` policy of a rule to the first `select` group when the config has no group
+/// named `PROXY`, as subscriptions name their main group arbitrarily.
+fn resolve_policy(rule: &str, proxy_group: Option<&str>) -> String {
+    let Some(proxy_group) = proxy_group else {
+        return rule.to_string();
+    };
+    map_policy(rule, |policy| {
+        (policy == "PROXY").then(|| proxy_group.to_string())
+    })
+}
+
+/// Merge local rules into the remote config: rule-providers and sub-rules are added (replacing
+/// same-named remote ones), and rules are prepended ahead of the remote rules.
+pub fn merge_local_rules(extra: &mut BTreeMap<String, Value>, local_rules: &LocalRules) {
+    if local_rules.rule_providers.is_empty()
+        && local_rules.sub_rules.is_empty()
+        && local_rules.rules.is_empty()
+    {
+        return;
+    }
+
+    let groups = extra.get("proxy-groups").and_then(Value::as_sequence);
+    let has_proxy_group = groups.is_some_and(|groups| {
+        groups
+            .iter()
+            .any(|group| entry_name(group) == Some("PROXY"))
+    });
+    let proxy_group = if has_proxy_group {
+        None
+    } else {
+        groups
+            .and_then(|groups| {
+                groups
+                    .iter()
+                    .find(|group| group.get("type").and_then(Value::as_str) == Some("select"))
+            })
+            .and_then(entry_name)
+            .filter(|name| !BUILTIN_POLICIES.contains(name))
+            .map(str::to_string)
+    };
+
+    let providers = extra
+        .entry(String::from("rule-providers"))
+        .or_insert_with(|| Value::Mapping(serde_yaml::Mapping::new()));
+    if let Some(providers) = providers.as_mapping_mut() {
+        for (name, provider) in &local_rules.rule_providers {
+            providers.insert(Value::from(name.as_str()), provider.clone());
+        }
+    }
+
+    if !local_rules.sub_rules.is_empty() {
+        let sub_rules = extra
+            .entry(String::from("sub-rules"))
+            .or_insert_with(|| Value::Mapping(serde_yaml::Mapping::new()));
+        if let Some(sub_rules) = sub_rules.as_mapping_mut() {
+            for (name, rules) in &local_rules.sub_rules {
+                let rules = rules
+                    .iter()
+                    .map(|rule| Value::from(resolve_policy(rule, proxy_group.as_deref())))
+                    .collect();
+                sub_rules.insert(Value::from(name.as_str()), Value::Sequence(rules));
+            }
+        }
+    }
+
+    let rules = extra
+        .entry(String::from("rules"))
+        .or_insert_with(|| Value::Sequence(Vec::new()));
+    if let Some(rules) = rules.as_sequence_mut() {
+        let local_rules = local_rules
+            .rules
+            .iter()
+            .map(|rule| Value::from(resolve_policy(rule, proxy_group.as_deref())));
+        rules.splice(0..0, local_rules);
+    }
+}
+
+/// Remove cached files of local rule-providers under `config_root`, so that mihomo downloads them
+/// again on restart.
+pub fn clear_ruleset_cache(config_root: &str, local_rules: &LocalRules) -> Result<()> {
+    for cache_path in ruleset_cache_paths(config_root, local_rules) {
+        if cache_path.exists() {
+            fs::remove_file(cache_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Paths where mihomo caches the imported rulesets, i.e. http rule-providers.
+pub fn ruleset_cache_paths(config_root: &str, local_rules: &LocalRules) -> Vec<PathBuf> {
+    local_rules
+        .rule_providers
+        .values()
+        .filter(|provider| provider.get("type").and_then(Value::as_str) == Some("http"))
+        .filter_map(|provider| provider.get("path").and_then(Value::as_str))
+        .map(|path| Path::new(config_root).join(path))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proxy_is_only_replaced_in_the_policy_field() {
+        let resolve = |rule| resolve_policy(rule, Some("Nodes"));
+        assert_eq!(
+            resolve("DOMAIN-KEYWORD,PROXY,PROXY"),
+            "DOMAIN-KEYWORD,PROXY,Nodes"
+        );
+        assert_eq!(
+            resolve("IP-CIDR,10.0.0.0/8,PROXY,no-resolve"),
+            "IP-CIDR,10.0.0.0/8,Nodes,no-resolve"
+        );
+        assert_eq!(resolve("MATCH,PROXY"), "MATCH,Nodes");
+        assert_eq!(
+            resolve("DOMAIN,proxy.example.com,DIRECT"),
+            "DOMAIN,proxy.example.com,DIRECT"
+        );
+        assert_eq!(
+            resolve("SUB-RULE,(NETWORK,udp),PROXY"),
+            "SUB-RULE,(NETWORK,udp),PROXY"
+        );
+        assert_eq!(resolve_policy("MATCH,PROXY", None), "MATCH,PROXY");
+    }
+
+    #[test]
+    fn policy_is_kept_when_not_mapped() {
+        assert_eq!(
+            map_policy("MATCH", |_| Some(String::from("DIRECT"))),
+            "MATCH"
+        );
+        assert_eq!(
+            map_policy("DOMAIN,example.com,HK", |_| None),
+            "DOMAIN,example.com,HK"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::{fs, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use colored::Colorize;
+use rhai::{module_resolvers::DummyModuleResolver, Dynamic, Engine, Scope};
+use serde_yaml::Value;
+
+/// File name of the config transformation script, kept next to mihomo's `config.yaml`.
+pub const TRANSFORM_SCRIPT: &str = "transform.rhai";
+
+/// Rhai engine for user scripts, without access to the file system or other modules and with
+/// limits on operations and sizes, so a faulty script cannot hang or exhaust config generation.
+fn sandboxed_engine(script_name: String) -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .set_max_operations(10_000_000)
+        .set_max_call_levels(64)
+        .set_max_expr_depths(128, 64)
+        .set_max_string_size(1 << 20)
+        .set_max_array_size(100_000)
+        .set_max_map_size(100_000)
+        .disable_symbol("eval");
+    engine.on_print(move |text| println!("{} {}", script_name.dimmed(), text));
+    engine
+}
+
+/// Run `fn transform(config)` of the Rhai script at `path` on the config, replacing the config
+/// with the returned value. Nothing is done if the script does not exist.
+///
+/// Reference: https://rhai.rs/book
+pub fn run_transform_script(path: &Path, config: &mut Value) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let script_name = format!("{}:", path.display());
+    let script = fs::read_to_string(path)?;
+    let engine = sandboxed_engine(script_name.clone());
+    let ast = engine
+        .compile(&script)
+        .map_err(|e| anyhow!("{} {}", script_name, e))?;
+    if !ast
+        .iter_functions()
+        .any(|function| function.name == "transform")
+    {
+        bail!("{} script must define `fn transform(config)`", script_name);
+    }
+
+    let input = rhai::serde::to_dynamic(&*config).map_err(|e| anyhow!("{} {}", script_name, e))?;
+    let output: Dynamic = engine
+        .call_fn(&mut Scope::new(), &ast, "transform", (input,))
+        .map_err(|e| anyhow!("{} {}", script_name, e))?;
+    let output: Value =
+        rhai::serde::from_dynamic(&output).map_err(|e| anyhow!("{} {}", script_name, e))?;
+    if !output.is_mapping() {
+        bail!(
+            "{} `transform` must return the config map, got {}",
+            script_name,
+            output_type(&output)
+        );
+    }
+    *config = output;
+    Ok(())
+}
+
+fn output_type(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "()",
+        Value::Bool(_) => "a bool",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Sequence(_) => "an array",
+        _ => "an unsupported value",
+    }
+}
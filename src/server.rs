@@ -0,0 +1,94 @@
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Response of the built-in HTTP server.
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn ok(content_type: &'static str, body: String) -> Self {
+        Response {
+            status: 200,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn error(status: u16, body: &str) -> Self {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: body.to_string(),
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Response {
+            status: 404,
+            content_type: "text/plain",
+            body: String::from("not found"),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            403 => "Forbidden",
+            410 => "Gone",
+            _ => "Not Found",
+        }
+    }
+}
+
+/// Serve GET requests on `addr` with a minimal HTTP/1.1 server until interrupted.
+///
+/// The handler receives the request target (path and query) and the peer address. Only the
+/// request line is parsed, which is all that is needed to serve small generated files.
+pub async fn serve<F>(addr: &str, handler: F) -> Result<()>
+where
+    F: Fn(&str, &str) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen on {}", addr))?;
+    let handler = Arc::new(handler);
+
+    loop {
+        let (mut stream, peer) = listener.accept().await?;
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            let mut buf = vec![0; 4096];
+            let Ok(len) = stream.read(&mut buf).await else {
+                return;
+            };
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let target = request
+                .lines()
+                .next()
+                .and_then(|line| line.strip_prefix("GET "))
+                .and_then(|line| line.split_whitespace().next());
+
+            let response = match target {
+                Some(target) => handler(target, &peer.ip().to_string()),
+                None => Response::not_found(),
+            };
+            let head = format!(
+                "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                response.status,
+                response.reason(),
+                response.content_type,
+                response.body.len()
+            );
+            let _ = stream.write_all(head.as_bytes()).await;
+            let _ = stream.write_all(response.body.as_bytes()).await;
+        });
+    }
+}
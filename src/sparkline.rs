@@ -0,0 +1,18 @@
+/// Bars of a sparkline, lowest to highest.
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Placeholder of missing values, e.g. timed out delay tests.
+const MISSING: char = '·';
+
+/// Render values as a one-line graph of bars scaled to the largest value, missing values as dots.
+pub fn sparkline(values: &[Option<u64>]) -> String {
+    let max = values.iter().flatten().copied().max().unwrap_or(0);
+    values
+        .iter()
+        .map(|value| match value {
+            None => MISSING,
+            Some(_) if max == 0 => BARS[0],
+            Some(value) => BARS[(value * (BARS.len() as u64 - 1) / max) as usize],
+        })
+        .collect()
+}
@@ -0,0 +1,47 @@
+use crate::cache::sha256_file;
+
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+
+/// Directory of an update staged by `mihoro fetch` and `mihoro build`, kept next to mihomo's
+/// `config.yaml` until `mihoro activate` swaps it in.
+pub const STAGING_DIR: &str = "staging";
+
+/// Interval of health checks of mihomo after activating a config with `rollback_sec` set.
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Consecutive health checks mihomo has to pass to keep an activated config.
+pub const HEALTHY_CHECKS: u32 = 3;
+
+/// Test a config with `mihomo -t`, resolving geodata and providers under mihomo's config root as
+/// the running mihomo would.
+pub fn test_mihomo_config(
+    mihomo_binary_path: &str,
+    config_root: &str,
+    config_path: &str,
+) -> Result<()> {
+    let output = Command::new(mihomo_binary_path)
+        .args(["-d", config_root, "-f", config_path, "-t"])
+        .output()
+        .context("failed to run mihomo binary")?;
+    if !output.status.success() {
+        bail!(
+            "`mihomo -t` rejected {}: {}",
+            config_path,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Whether a staged file has the same SHA-256 digest as the file it would replace.
+pub fn unchanged(staged_path: &str, path: &str) -> Result<bool> {
+    let (staged_path, path) = (Path::new(staged_path), Path::new(path));
+    if !staged_path.is_file() || !path.is_file() {
+        return Ok(false);
+    }
+    Ok(sha256_file(staged_path)? == sha256_file(path)?)
+}
@@ -0,0 +1,100 @@
+use crate::sysproxy::{DesktopProxies, ServiceProxies};
+use crate::utils::create_parent_dir;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// State kept by mihoro across runs, next to mihomo's `config.yaml`.
+pub const STATE_FILE: &str = "mihoro-state.toml";
+
+/// State recorded by mihoro, as opposed to `mihoro.toml` which is only written by users.
+///
+/// * `mihomo_version` - version reported by the installed mihomo binary.
+/// * `manifest` - files created by mihoro, removed by `mihoro uninstall`.
+/// * `subscription` - traffic and expiry reported by the provider at the last update.
+/// * `subscription_source` - source the subscription was last downloaded from, `remote_config_url`
+///   or an entry of `subscription.backup_urls` such as `backup_urls[0]`.
+/// * `quota_notified` - day (`YYYY-MM-DD`) a quota desktop notification was last sent.
+/// * `sysproxy_restore` - proxies of each macOS network service before `mihoro sysproxy on`,
+///   restored by `mihoro sysproxy off`.
+/// * `desktop_proxy_restore` - Linux desktop proxy settings before `mihoro sysproxy on`, restored
+///   likewise.
+/// * `failover_profile` - backup profile switched to by `mihoro failover` while the subscription
+///   is down.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct State {
+    pub mihomo_version: Option<String>,
+    pub quota_notified: Option<String>,
+    pub subscription: Option<SubscriptionInfo>,
+    pub subscription_source: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub manifest: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub sysproxy_restore: BTreeMap<String, ServiceProxies>,
+    pub desktop_proxy_restore: Option<DesktopProxies>,
+    pub failover_profile: Option<String>,
+}
+
+/// Traffic and expiry of a subscription, from its `subscription-userinfo` response header such as
+/// `upload=1234; download=5678; total=10737418240; expire=1735660800`.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SubscriptionInfo {
+    pub upload: u64,
+    pub download: u64,
+    pub total: u64,
+    /// Unix timestamp, absent for subscriptions that do not expire
+    pub expire: Option<i64>,
+}
+
+impl SubscriptionInfo {
+    pub fn parse(header: &str) -> Option<SubscriptionInfo> {
+        let mut info = SubscriptionInfo::default();
+        for field in header.split(';') {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "upload" => info.upload = value.parse().ok()?,
+                "download" => info.download = value.parse().ok()?,
+                "total" => info.total = value.parse().ok()?,
+                "expire" => info.expire = value.parse().ok().filter(|expire| *expire > 0),
+                _ => {}
+            }
+        }
+        (info.total > 0).then_some(info)
+    }
+
+    /// Whole days until the subscription expires at `now` (unix timestamp), negative once expired.
+    pub fn days_left(&self, now: i64) -> Option<i64> {
+        self.expire.map(|expire| (expire - now).div_euclid(86400))
+    }
+
+    /// Traffic left on the subscription in bytes.
+    pub fn remaining(&self) -> u64 {
+        self.total.saturating_sub(self.upload + self.download)
+    }
+}
+
+impl State {
+    /// Read state from path, returning empty state if the file does not exist.
+    pub fn load(path: &str) -> Result<State> {
+        if !Path::new(path).exists() {
+            return Ok(State::default());
+        }
+        let raw_state = fs::read_to_string(path)?;
+        Ok(toml::from_str(&raw_state)?)
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        create_parent_dir(path)?;
+        fs::write(path, toml::to_string(&self)?)?;
+        Ok(())
+    }
+}
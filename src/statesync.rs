@@ -0,0 +1,184 @@
+use crate::config::{StateSync, StateSyncBackend};
+use crate::usage::Traffic;
+
+use std::collections::BTreeMap;
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, Method, RequestBuilder, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+/// State shared between machines through `state_sync`, stored as `{profile}.toml` under its url so
+/// that only machines using the same profile share it.
+///
+/// * `selections` - selected node of each selector group.
+/// * `usage` - traffic of each day recorded by `mihoro usage --record`, by machine.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SharedState {
+    #[serde(default)]
+    pub selections: BTreeMap<String, String>,
+    #[serde(default)]
+    pub usage: BTreeMap<String, BTreeMap<String, Traffic>>,
+}
+
+/// Shared state file on a WebDAV server or S3 bucket.
+pub struct StateStore {
+    client: Client,
+    url: Url,
+    backend: StateSyncBackend,
+    username: Option<String>,
+    password: Option<String>,
+    region: String,
+}
+
+impl StateStore {
+    pub fn new(client: Client, state_sync: &StateSync, profile: &str) -> Result<StateStore> {
+        let mut base_url = Url::parse(&state_sync.url)?;
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        Ok(StateStore {
+            client,
+            url: base_url.join(&format!("{}.toml", profile))?,
+            backend: state_sync.backend.unwrap_or_default(),
+            username: state_sync.username.clone(),
+            password: state_sync.password()?,
+            region: state_sync
+                .region
+                .clone()
+                .unwrap_or_else(|| String::from("us-east-1")),
+        })
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Fetch the shared state, empty if nothing was pushed yet.
+    pub async fn get(&self) -> Result<SharedState> {
+        let response = self.request(Method::GET, Vec::new())?.send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(SharedState::default());
+        }
+        if !response.status().is_success() {
+            bail!("GET {} failed with {}", self.url, response.status());
+        }
+        Ok(toml::from_str(&response.text().await?)?)
+    }
+
+    pub async fn put(&self, state: &SharedState) -> Result<()> {
+        let body = toml::to_string(state)?.into_bytes();
+        let response = self.request(Method::PUT, body)?.send().await?;
+        if !response.status().is_success() {
+            bail!("PUT {} failed with {}", self.url, response.status());
+        }
+        Ok(())
+    }
+
+    fn request(&self, method: Method, body: Vec<u8>) -> Result<RequestBuilder> {
+        let mut request = self.client.request(method.clone(), self.url.clone());
+        match self.backend {
+            StateSyncBackend::Webdav => {
+                if let Some(username) = &self.username {
+                    request = request.basic_auth(username, self.password.as_ref());
+                }
+            }
+            StateSyncBackend::S3 => {
+                let (Some(access_key_id), Some(secret_access_key)) =
+                    (&self.username, &self.password)
+                else {
+                    bail!("`username` and `password` of `state_sync` are required for S3");
+                };
+                let signature = S3Signature {
+                    access_key_id,
+                    secret_access_key,
+                    region: &self.region,
+                };
+                for (name, value) in signature.headers(method.as_str(), &self.url, &body) {
+                    request = request.header(name, value);
+                }
+            }
+        }
+        Ok(request.body(body))
+    }
+}
+
+/// AWS Signature Version 4 of requests to S3 without query parameters.
+///
+/// Reference: https://docs.aws.amazon.com/AmazonS3/latest/API/sig-v4-header-based-auth.html
+struct S3Signature<'a> {
+    access_key_id: &'a str,
+    secret_access_key: &'a str,
+    region: &'a str,
+}
+
+impl S3Signature<'_> {
+    /// `Authorization` and the signed `x-amz-*` headers of a request.
+    fn headers(&self, method: &str, url: &Url, body: &[u8]) -> Vec<(&'static str, String)> {
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let payload_hash = hex(&Sha256::digest(body));
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "{method}\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\n\
+             x-amz-date:{amz_date}\n\n{signed_headers}\n{payload_hash}",
+            path = url.path(),
+        );
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let mut key = format!("AWS4{}", self.secret_access_key).into_bytes();
+        for part in [date.as_str(), self.region, "s3", "aws4_request"] {
+            key = hmac_sha256(&key, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&key, string_to_sign.as_bytes()));
+
+        vec![
+            (
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key_id, scope, signed_headers, signature
+                ),
+            ),
+            ("x-amz-content-sha256", payload_hash),
+            ("x-amz-date", amz_date),
+        ]
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Name of this machine, identifying its traffic in the shared state.
+pub fn machine_name() -> String {
+    Command::new("uname")
+        .arg("-n")
+        .output()
+        .ok()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| String::from("localhost"))
+}
@@ -0,0 +1,283 @@
+use crate::memory::{MemoryCheck, MemoryGuard};
+use crate::theme::Prefix;
+use crate::utils::{create_parent_dir, send_signal};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{sleep, Instant};
+
+/// mihomo's output captured by `mihoro run`, next to mihomo's `config.yaml`.
+pub const LOG_FILE: &str = "mihomo.log";
+
+/// Delay before restarting a crashed mihomo, doubled on each crash up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Time after which a running mihomo is considered recovered, resetting the backoff.
+const STABLE_RUN: Duration = Duration::from_secs(60);
+
+/// Interval of checks for lines appended to a followed log.
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Log file rotated once it would exceed `max_size` bytes, or has been written to for `max_age`
+/// if set, keeping `keep` previous files from `<path>.1` (the most recent) to `<path>.<keep>`.
+pub struct RotatingLog {
+    path: String,
+    max_size: u64,
+    max_age: Option<Duration>,
+    keep: usize,
+    file: File,
+    size: u64,
+    started: SystemTime,
+}
+
+impl RotatingLog {
+    pub fn open(
+        path: &str,
+        max_size: u64,
+        max_age: Option<Duration>,
+        keep: usize,
+    ) -> Result<RotatingLog> {
+        create_parent_dir(path)?;
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        Ok(RotatingLog {
+            path: path.to_string(),
+            max_size,
+            max_age,
+            keep,
+            file,
+            size: metadata.len(),
+            started: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let length = line.len() as u64 + 1;
+        let expired = self.max_age.is_some_and(|max_age| {
+            self.started
+                .elapsed()
+                .is_ok_and(|elapsed| elapsed >= max_age)
+        });
+        if self.size > 0 && (self.size + length > self.max_size || expired) {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += length;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        for index in (1..self.keep).rev() {
+            let rotated = format!("{}.{}", self.path, index);
+            if Path::new(&rotated).exists() {
+                fs::rename(&rotated, format!("{}.{}", self.path, index + 1))?;
+            }
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        self.started = SystemTime::now();
+        Ok(())
+    }
+}
+
+/// Run mihomo with `mihomo_config_root` until SIGTERM or SIGINT, restarting it with increasing
+/// delays whenever it exits. Its output is printed and written to `log`, and SIGHUP is forwarded
+/// to it for reloads. With a `memory_guard`, mihomo is also restarted right away once its memory
+/// grows past the ceiling.
+pub async fn supervise(
+    mihomo_binary_path: &str,
+    mihomo_config_root: &str,
+    log: RotatingLog,
+    mut memory_guard: Option<MemoryGuard>,
+    prefix: &Prefix,
+) -> Result<()> {
+    let log = Arc::new(Mutex::new(log));
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut memory_checks = tokio::time::interval(
+        memory_guard
+            .as_ref()
+            .map_or(MAX_BACKOFF, MemoryGuard::interval),
+    );
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let started = Instant::now();
+        let mut mihomo = Command::new(mihomo_binary_path)
+            .arg("-d")
+            .arg(mihomo_config_root)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("failed to run mihomo")?;
+        if let Some(stdout) = mihomo.stdout.take() {
+            forward_lines(stdout, false, log.clone());
+        }
+        if let Some(stderr) = mihomo.stderr.take() {
+            forward_lines(stderr, true, log.clone());
+        }
+        println!(
+            "{} Started mihomo with pid {}",
+            prefix.success(),
+            mihomo.id().unwrap_or_default()
+        );
+
+        let status = loop {
+            tokio::select! {
+                status = mihomo.wait() => break Some(status?),
+                _ = sigterm.recv() => return stop(&mut mihomo, prefix).await,
+                _ = sigint.recv() => return stop(&mut mihomo, prefix).await,
+                _ = sighup.recv() => {
+                    if let Some(pid) = mihomo.id() {
+                        send_signal(pid, "HUP")?;
+                    }
+                }
+                _ = memory_checks.tick(), if memory_guard.is_some() => {
+                    if let Some(guard) = memory_guard.as_mut() {
+                        if restart_on_memory(guard, &mut mihomo, prefix).await? {
+                            break None;
+                        }
+                    }
+                }
+            }
+        };
+        let Some(status) = status else {
+            continue;
+        };
+
+        if started.elapsed() >= STABLE_RUN {
+            backoff = INITIAL_BACKOFF;
+        }
+        eprintln!(
+            "{} mihomo exited with {}, restarting in {}s",
+            prefix.error(),
+            status,
+            backoff.as_secs()
+        );
+        tokio::select! {
+            _ = sleep(backoff) => {}
+            _ = sigterm.recv() => return Ok(()),
+            _ = sigint.recv() => return Ok(()),
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Check mihomo's memory with `guard`, stopping mihomo if it is to be restarted. Returns whether
+/// it was stopped.
+pub async fn restart_on_memory(
+    guard: &mut MemoryGuard,
+    mihomo: &mut Child,
+    prefix: &Prefix,
+) -> Result<bool> {
+    match guard.check().await {
+        MemoryCheck::Normal => Ok(false),
+        MemoryCheck::Cooldown(inuse) => {
+            eprintln!(
+                "{} mihomo uses {} of memory, over the {} ceiling, but was restarted recently",
+                prefix.warning(),
+                HumanBytes(inuse),
+                HumanBytes(guard.max_bytes())
+            );
+            Ok(false)
+        }
+        MemoryCheck::Restart(inuse) => {
+            eprintln!(
+                "{} mihomo uses {} of memory, over the {} ceiling, restarting it",
+                prefix.warning(),
+                HumanBytes(inuse),
+                HumanBytes(guard.max_bytes())
+            );
+            if let Some(pid) = mihomo.id() {
+                send_signal(pid, "TERM")?;
+            }
+            mihomo.wait().await?;
+            Ok(true)
+        }
+    }
+}
+
+/// Forward SIGTERM to mihomo and wait for it to exit.
+async fn stop(mihomo: &mut Child, prefix: &Prefix) -> Result<()> {
+    if let Some(pid) = mihomo.id() {
+        send_signal(pid, "TERM")?;
+    }
+    mihomo.wait().await?;
+    println!("{} Stopped mihomo", prefix.success());
+    Ok(())
+}
+
+/// Print lines of mihomo's stdout (or stderr) to the same stream and write them to the log, until
+/// mihomo closes it.
+fn forward_lines(
+    output: impl AsyncRead + Unpin + Send + 'static,
+    stderr: bool,
+    log: Arc<Mutex<RotatingLog>>,
+) {
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(output).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if stderr {
+                eprintln!("{}", line);
+            } else {
+                println!("{}", line);
+            }
+            if let Err(error) = log.lock().unwrap().write_line(&line) {
+                eprintln!("failed to write mihomo log: {:#}", error);
+            }
+        }
+    });
+}
+
+/// Print the last `lines` lines of the log at `path`, then follow lines appended to it like
+/// `tail -F`, reopening it once rotated.
+pub async fn follow_log(path: &str, lines: usize) -> Result<()> {
+    let mut file = File::open(path).with_context(|| format!("failed to open {}", path))?;
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    let skipped = content.lines().count().saturating_sub(lines);
+    for line in content.lines().skip(skipped) {
+        println!("{}", line);
+    }
+    let mut position = content.len() as u64;
+    let mut inode = file.metadata()?.ino();
+
+    loop {
+        sleep(FOLLOW_INTERVAL).await;
+        let Ok(metadata) = fs::metadata(path) else {
+            continue;
+        };
+        // Rotated away or truncated, start over with the new file
+        if metadata.ino() != inode || metadata.len() < position {
+            file = File::open(path)?;
+            inode = metadata.ino();
+            position = 0;
+        }
+        if metadata.len() == position {
+            continue;
+        }
+        file.seek(SeekFrom::Start(position))?;
+        let mut appended = Vec::new();
+        file.read_to_end(&mut appended)?;
+        position += appended.len() as u64;
+        io::stdout().write_all(&appended)?;
+        io::stdout().flush()?;
+    }
+}
@@ -0,0 +1,129 @@
+use crate::confd::CONF_D_DIR;
+use crate::nodes::LOCAL_PROXIES_FILE;
+use crate::patch::PATCHES_DIR;
+use crate::rules::LOCAL_RULES_FILE;
+use crate::script::TRANSFORM_SCRIPT;
+use crate::utils::create_parent_dir;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use toml_edit::{DocumentMut, TableLike};
+
+/// Git repository of `mihoro sync`, kept next to mihomo's `config.yaml`.
+pub const SYNC_DIR: &str = "sync";
+
+/// Name of mihoro's config in the sync repository, whatever its local file name.
+pub const SYNCED_CONFIG_FILE: &str = "mihoro.toml";
+
+/// Local fragments, scripts and patches synced as is, relative to mihomo's config root and the
+/// sync repository alike.
+pub const SYNCED_FILES: &[&str] = &[
+    LOCAL_RULES_FILE,
+    LOCAL_PROXIES_FILE,
+    TRANSFORM_SCRIPT,
+    CONF_D_DIR,
+    PATCHES_DIR,
+];
+
+/// Inline secrets of the config, as keys in tables: mihomo's controller `secret`, the `password`
+/// and `token` of the subscription's auth and the `password` of `state_sync`. Secrets in the system
+/// keyring are referenced by name and kept.
+const SECRET_KEYS: &[(&[&str], &str)] = &[
+    (&["mihomo_config"], "secret"),
+    (&["subscription", "auth"], "password"),
+    (&["subscription", "auth"], "token"),
+    (&["state_sync"], "password"),
+];
+
+/// The table holding a secret of a config document, if present.
+fn secret_table<'a>(document: &'a mut DocumentMut, path: &[&str]) -> Option<&'a mut dyn TableLike> {
+    path.iter().try_fold(
+        document.as_table_mut() as &mut dyn TableLike,
+        |table, key| table.get_mut(key)?.as_table_like_mut(),
+    )
+}
+
+/// Remove inline secrets from a config document, keeping the rest as written.
+pub fn without_secrets(document: &mut DocumentMut) {
+    for (tables, key) in SECRET_KEYS {
+        if let Some(table) = secret_table(document, tables) {
+            table.remove(key);
+        }
+    }
+}
+
+/// Fill inline secrets missing in a pulled config document with those of the local one, reverting
+/// `without_secrets`.
+pub fn restore_secrets(document: &mut DocumentMut, local: &DocumentMut) {
+    for (tables, key) in SECRET_KEYS {
+        let local_secret = tables
+            .iter()
+            .try_fold(local.as_table() as &dyn TableLike, |table, key| {
+                table.get(key)?.as_table_like()
+            })
+            .and_then(|table| table.get(key));
+        let (Some(table), Some(local_secret)) = (secret_table(document, tables), local_secret)
+        else {
+            continue;
+        };
+        if !table.contains_key(key) {
+            table.insert(key, local_secret.clone());
+        }
+    }
+}
+
+/// Replace the file or directory at `to` with a copy of `from`, removing it if `from` does not
+/// exist.
+pub fn replace_with_copy(from: &Path, to: &Path) -> Result<()> {
+    if to.is_dir() {
+        fs::remove_dir_all(to)?;
+    } else if to.exists() {
+        fs::remove_file(to)?;
+    }
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            replace_with_copy(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else if from.is_file() {
+        create_parent_dir(&to.to_string_lossy())?;
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+/// Run git in the repository, failing if it exits unsuccessfully.
+pub fn git(repo: &Path, args: &[&str]) -> Result<()> {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .status()
+        .context("failed to run git")?;
+    if !status.success() {
+        bail!("`git {}` exited with {}", args.join(" "), status);
+    }
+    Ok(())
+}
+
+/// Output of git in the repository.
+pub fn git_output(repo: &Path, args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(repo)
+        .args(args)
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Proxy of one kind set on a macOS network service, as reported by `networksetup -getwebproxy`
+/// and the like.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProxySetting {
+    pub enabled: bool,
+    pub server: String,
+    pub port: u16,
+}
+
+impl ProxySetting {
+    pub fn local(port: u16) -> ProxySetting {
+        ProxySetting {
+            enabled: true,
+            server: String::from("127.0.0.1"),
+            port,
+        }
+    }
+}
+
+/// HTTP, HTTPS and SOCKS proxies and bypassed hosts of a macOS network service.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ServiceProxies {
+    pub web: ProxySetting,
+    pub secure_web: ProxySetting,
+    pub socks: ProxySetting,
+    pub bypass: Vec<String>,
+}
+
+impl ServiceProxies {
+    /// Read the proxies currently set on a network service.
+    pub fn read(service: &str) -> Result<ServiceProxies> {
+        let bypass = networksetup(&["-getproxybypassdomains", service])?;
+        Ok(ServiceProxies {
+            web: get_proxy(service, "webproxy")?,
+            secure_web: get_proxy(service, "securewebproxy")?,
+            socks: get_proxy(service, "socksfirewallproxy")?,
+            // Reported as a sentence if there are none
+            bypass: bypass
+                .lines()
+                .map(str::trim)
+                .filter(|host| !host.is_empty() && !host.contains(' '))
+                .map(String::from)
+                .collect(),
+        })
+    }
+
+    /// Set the proxies on a network service.
+    pub fn apply(&self, service: &str) -> Result<()> {
+        set_proxy(service, "webproxy", &self.web)?;
+        set_proxy(service, "securewebproxy", &self.secure_web)?;
+        set_proxy(service, "socksfirewallproxy", &self.socks)?;
+        let mut args = vec!["-setproxybypassdomains", service];
+        if self.bypass.is_empty() {
+            args.push("Empty");
+        } else {
+            args.extend(self.bypass.iter().map(String::as_str));
+        }
+        networksetup(&args)?;
+        Ok(())
+    }
+}
+
+/// Enabled macOS network services with an ip address, e.g. `Wi-Fi`.
+pub fn active_services() -> Result<Vec<String>> {
+    let services = networksetup(&["-listallnetworkservices"])?;
+    let mut active = Vec::new();
+    // The first line notes that disabled services are marked with `*`
+    for service in services
+        .lines()
+        .skip(1)
+        .filter(|line| !line.starts_with('*'))
+    {
+        let info = networksetup(&["-getinfo", service])?;
+        let has_address = info.lines().any(|line| {
+            line.strip_prefix("IP address:")
+                .is_some_and(|address| !matches!(address.trim(), "" | "none"))
+        });
+        if has_address {
+            active.push(service.to_string());
+        }
+    }
+    Ok(active)
+}
+
+fn get_proxy(service: &str, kind: &str) -> Result<ProxySetting> {
+    let output = networksetup(&[&format!("-get{}", kind), service])?;
+    let mut setting = ProxySetting::default();
+    for line in output.lines() {
+        match line.split_once(':') {
+            Some(("Enabled", value)) => setting.enabled = value.trim() == "Yes",
+            Some(("Server", value)) => setting.server = value.trim().to_string(),
+            Some(("Port", value)) => setting.port = value.trim().parse().unwrap_or_default(),
+            _ => {}
+        }
+    }
+    Ok(setting)
+}
+
+fn set_proxy(service: &str, kind: &str, setting: &ProxySetting) -> Result<()> {
+    if !setting.server.is_empty() {
+        networksetup(&[
+            &format!("-set{}", kind),
+            service,
+            &setting.server,
+            &setting.port.to_string(),
+        ])?;
+    }
+    let state = if setting.enabled { "on" } else { "off" };
+    networksetup(&[&format!("-set{}state", kind), service, state])?;
+    Ok(())
+}
+
+fn networksetup(args: &[&str]) -> Result<String> {
+    let output = Command::new("networksetup")
+        .args(args)
+        .output()
+        .context("failed to run networksetup")?;
+    if !output.status.success() {
+        bail!(
+            "`networksetup {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Linux desktop whose proxy settings GUI apps follow.
+///
+/// * `gnome` - `org.gnome.system.proxy` settings with `gsettings`, also used by other GTK based
+///   desktops.
+/// * `kde` - `Proxy Settings` in `kioslaverc` with `kwriteconfig6` (or `kwriteconfig5`).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Desktop {
+    Gnome,
+    Kde,
+}
+
+/// Desktop proxy settings saved by `mihoro sysproxy on`, by key as in `Desktop::settings`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DesktopProxies {
+    pub desktop: Desktop,
+    pub settings: BTreeMap<String, String>,
+}
+
+impl Desktop {
+    /// Desktop of the current session by `XDG_CURRENT_DESKTOP`, falling back to GNOME's settings
+    /// if `gsettings` is available.
+    pub fn detect() -> Option<Desktop> {
+        let current_desktop = env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
+        if current_desktop.split(':').any(|desktop| desktop == "KDE") {
+            return Some(Desktop::Kde);
+        }
+        Command::new("gsettings")
+            .arg("--version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+            .then_some(Desktop::Gnome)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Desktop::Gnome => "GNOME",
+            Desktop::Kde => "KDE",
+        }
+    }
+
+    /// Settings pointing the desktop's manual proxy configuration to `host`, as keys and values
+    /// in the form `read` and `write` take.
+    pub fn settings(
+        &self,
+        host: &str,
+        http_port: u16,
+        socks_port: u16,
+        bypass: &[String],
+    ) -> Vec<(&'static str, String)> {
+        match self {
+            Desktop::Gnome => {
+                let ignore_hosts = bypass
+                    .iter()
+                    .map(|host| format!("'{}'", host))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                vec![
+                    ("org.gnome.system.proxy mode", String::from("'manual'")),
+                    ("org.gnome.system.proxy.http host", format!("'{}'", host)),
+                    ("org.gnome.system.proxy.http port", http_port.to_string()),
+                    ("org.gnome.system.proxy.https host", format!("'{}'", host)),
+                    ("org.gnome.system.proxy.https port", http_port.to_string()),
+                    ("org.gnome.system.proxy.socks host", format!("'{}'", host)),
+                    ("org.gnome.system.proxy.socks port", socks_port.to_string()),
+                    (
+                        "org.gnome.system.proxy ignore-hosts",
+                        format!("[{}]", ignore_hosts),
+                    ),
+                ]
+            }
+            Desktop::Kde => vec![
+                // Manually specified proxies
+                ("ProxyType", String::from("1")),
+                ("httpProxy", format!("http://{} {}", host, http_port)),
+                ("httpsProxy", format!("http://{} {}", host, http_port)),
+                ("socksProxy", format!("socks://{} {}", host, socks_port)),
+                ("NoProxyFor", bypass.join(",")),
+            ],
+        }
+    }
+
+    /// Proxies of the desktop's manual proxy configuration as `host:port`, or its PAC url in
+    /// automatic mode, none if it connects directly or cannot be read.
+    pub fn proxies(&self) -> Vec<String> {
+        let read = |key: &str| self.read(key).unwrap_or_default();
+        let proxies = match self {
+            Desktop::Gnome => match read("org.gnome.system.proxy mode").trim_matches('\'') {
+                "manual" => ["http", "https", "socks"]
+                    .iter()
+                    .filter_map(|kind| {
+                        let schema = format!("org.gnome.system.proxy.{}", kind);
+                        let host = read(&format!("{} host", schema));
+                        let port = read(&format!("{} port", schema));
+                        let host = host.trim_matches('\'');
+                        (!host.is_empty() && port != "0").then(|| format!("{}:{}", host, port))
+                    })
+                    .collect(),
+                "auto" => vec![read("org.gnome.system.proxy autoconfig-url")
+                    .trim_matches('\'')
+                    .to_string()],
+                _ => Vec::new(),
+            },
+            // Proxies are written as `http://host port`
+            Desktop::Kde => match read("ProxyType").as_str() {
+                "1" => ["httpProxy", "httpsProxy", "socksProxy"]
+                    .iter()
+                    .map(|key| {
+                        let proxy = read(key);
+                        let proxy = proxy
+                            .split_once("://")
+                            .map_or(proxy.as_str(), |(_, rest)| rest);
+                        proxy.replace(' ', ":")
+                    })
+                    .collect(),
+                "2" => vec![read("Proxy Config Script")],
+                _ => Vec::new(),
+            },
+        };
+        proxies
+            .into_iter()
+            .filter(|proxy| !proxy.is_empty())
+            .collect()
+    }
+
+    /// Current value of a setting, empty if unset.
+    pub fn read(&self, key: &str) -> Result<String> {
+        let output = match self {
+            Desktop::Gnome => {
+                let (schema, key) = key.split_once(' ').unwrap_or_default();
+                Command::new("gsettings")
+                    .args(["get", schema, key])
+                    .output()
+                    .context("failed to run gsettings")?
+            }
+            Desktop::Kde => Command::new(kde_tool("kreadconfig"))
+                .args([
+                    "--file",
+                    "kioslaverc",
+                    "--group",
+                    "Proxy Settings",
+                    "--key",
+                    key,
+                ])
+                .output()
+                .context("failed to run kreadconfig")?,
+        };
+        if !output.status.success() {
+            bail!(
+                "failed to read {} proxy setting `{}`: {}",
+                self.as_str(),
+                key,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Set a setting, removing it if `value` is empty.
+    pub fn write(&self, key: &str, value: &str) -> Result<()> {
+        let status = match self {
+            Desktop::Gnome => {
+                let (schema, key) = key.split_once(' ').unwrap_or_default();
+                Command::new("gsettings")
+                    .args(["set", schema, key, value])
+                    .status()
+                    .context("failed to run gsettings")?
+            }
+            Desktop::Kde => {
+                let mut kwriteconfig = Command::new(kde_tool("kwriteconfig"));
+                kwriteconfig.args([
+                    "--file",
+                    "kioslaverc",
+                    "--group",
+                    "Proxy Settings",
+                    "--key",
+                    key,
+                ]);
+                if value.is_empty() {
+                    kwriteconfig.arg("--delete");
+                } else {
+                    kwriteconfig.arg(value);
+                }
+                kwriteconfig
+                    .status()
+                    .context("failed to run kwriteconfig")?
+            }
+        };
+        if !status.success() {
+            bail!("failed to set {} proxy setting `{}`", self.as_str(), key);
+        }
+        Ok(())
+    }
+
+    /// Make running apps pick up changed settings, which GNOME apps do on their own.
+    pub fn reload(&self) {
+        if let Desktop::Kde = self {
+            let _ = Command::new("dbus-send")
+                .args([
+                    "--type=signal",
+                    "/KIO/Scheduler",
+                    "org.kde.KIO.Scheduler.reparseSlaveConfiguration",
+                    "string:",
+                ])
+                .stderr(Stdio::null())
+                .status();
+        }
+    }
+}
+
+/// `kreadconfig` or `kwriteconfig` of Plasma 6, or of Plasma 5 if not installed.
+fn kde_tool(tool: &str) -> String {
+    let plasma6 = format!("{}6", tool);
+    let installed = Command::new(&plasma6)
+        .arg("--help")
+        .output()
+        .is_ok_and(|output| output.status.success());
+    if installed {
+        plasma6
+    } else {
+        format!("{}5", tool)
+    }
+}
@@ -1,55 +1,129 @@
+use crate::config::InstallScope;
+use crate::utils::is_root;
+
 use std::process::{Command, ExitStatus};
 
 use anyhow::{Context, Result};
 
 pub struct Systemctl {
     systemctl: Command,
+    scope_args: Vec<&'static str>,
+    sudo: bool,
+}
+
+impl Default for Systemctl {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Systemctl {
     pub fn new() -> Self {
         Self {
             systemctl: Command::new("systemctl"),
+            scope_args: vec!["--user"],
+            sudo: false,
+        }
+    }
+
+    /// Manage system services instead of user services, through sudo unless running as root.
+    pub fn new_system() -> Self {
+        let sudo = !is_root();
+        let systemctl = if sudo {
+            let mut sudo = Command::new("sudo");
+            sudo.arg("systemctl");
+            sudo
+        } else {
+            Command::new("systemctl")
+        };
+        Self {
+            systemctl,
+            scope_args: Vec::new(),
+            sudo,
+        }
+    }
+
+    /// Query services without sudo, as reading their status does not require root.
+    fn read_only(&mut self) {
+        if self.sudo {
+            self.systemctl = Command::new("systemctl");
+            self.sudo = false;
+        }
+    }
+
+    pub fn for_scope(scope: InstallScope) -> Self {
+        match scope {
+            InstallScope::User => Self::new(),
+            InstallScope::System => Self::new_system(),
         }
     }
 
     pub fn enable(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("enable").arg(service);
+        self.systemctl
+            .args(&self.scope_args)
+            .arg("enable")
+            .arg(service);
         self
     }
 
     pub fn start(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("start").arg(service);
+        self.systemctl
+            .args(&self.scope_args)
+            .arg("start")
+            .arg(service);
         self
     }
 
     pub fn stop(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("stop").arg(service);
+        self.systemctl
+            .args(&self.scope_args)
+            .arg("stop")
+            .arg(service);
         self
     }
 
     pub fn restart(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("restart").arg(service);
+        self.systemctl
+            .args(&self.scope_args)
+            .arg("restart")
+            .arg(service);
         self
     }
 
     pub fn status(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("status").arg(service);
+        self.read_only();
+        self.systemctl
+            .args(&self.scope_args)
+            .arg("status")
+            .arg(service);
+        self
+    }
+
+    /// Check whether a service is active, by the exit status alone.
+    pub fn is_active(&mut self, service: &str) -> &mut Self {
+        self.read_only();
+        self.systemctl
+            .args(&self.scope_args)
+            .args(["is-active", "--quiet"])
+            .arg(service);
         self
     }
 
     pub fn disable(&mut self, service: &str) -> &mut Self {
-        self.systemctl.arg("--user").arg("disable").arg(service);
+        self.systemctl
+            .args(&self.scope_args)
+            .arg("disable")
+            .arg(service);
         self
     }
 
     pub fn daemon_reload(&mut self) -> &mut Self {
-        self.systemctl.arg("--user").arg("daemon-reload");
+        self.systemctl.args(&self.scope_args).arg("daemon-reload");
         self
     }
 
     pub fn reset_failed(&mut self) -> &mut Self {
-        self.systemctl.arg("--user").arg("reset-failed");
+        self.systemctl.args(&self.scope_args).arg("reset-failed");
         self
     }
 
@@ -0,0 +1,254 @@
+use crate::config::Config;
+
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+
+/// Variables of local config fragments, written as `{{name}}` and expanded during config
+/// generation:
+///
+/// * `{{port}}`, `{{socks_port}}`, `{{mixed_port}}` - proxy ports from `mihoro.toml`.
+/// * `{{profile}}` - name of the active mihoro profile, i.e. the file stem of its config.
+/// * `{{config_root}}` - expanded `mihomo_config_root`.
+/// * `{{env.NAME}}` - environment variable `NAME`.
+///
+/// Variables are expanded in string values after parsing, so that their values cannot change the
+/// structure of a fragment. A value that is a single variable, e.g. `"{{mixed_port}}"`, becomes a
+/// number or boolean if the variable's value is one.
+pub struct Template {
+    vars: BTreeMap<&'static str, String>,
+}
+
+impl Template {
+    pub fn new(config: &Config, profile: &str, config_root: &Path) -> Template {
+        let mihomo_config = &config.mihomo_config;
+        let mut vars = BTreeMap::new();
+        vars.insert("port", mihomo_config.port.to_string());
+        vars.insert("socks_port", mihomo_config.socks_port.to_string());
+        if let Some(mixed_port) = mihomo_config.mixed_port {
+            vars.insert("mixed_port", mixed_port.to_string());
+        }
+        vars.insert("profile", profile.to_string());
+        vars.insert("config_root", config_root.to_string_lossy().to_string());
+        Template { vars }
+    }
+
+    /// Expand `{{name}}` variables in text, failing on undefined variables or unclosed braces.
+    fn expand(&self, text: &str) -> Result<String> {
+        let mut expanded = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(start) = rest.find("{{") {
+            expanded.push_str(&rest[..start]);
+            let Some(end) = rest[start..].find("}}") else {
+                bail!("unclosed `{{{{` in template");
+            };
+            let name = rest[start + 2..start + end].trim();
+            let value = match name.strip_prefix("env.") {
+                Some(env_name) => env_var(env_name)?,
+                None => self
+                    .vars
+                    .get(name)
+                    .cloned()
+                    .with_context(|| format!("template variable `{}` undefined", name))?,
+            };
+            expanded.push_str(&value);
+            rest = &rest[start + end + 2..];
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    /// Expand variables in the string values of parsed YAML.
+    pub fn expand_yaml(&self, value: &mut Value) -> Result<()> {
+        match value {
+            Value::String(text) => {
+                let expanded = self.expand(text)?;
+                let whole_variable = text.starts_with("{{")
+                    && text.ends_with("}}")
+                    && text.matches("{{").count() == 1;
+                *value = match serde_yaml::from_str(&expanded) {
+                    Ok(scalar @ (Value::Number(_) | Value::Bool(_))) if whole_variable => scalar,
+                    _ => Value::String(expanded),
+                };
+            }
+            Value::Sequence(items) => {
+                for item in items {
+                    self.expand_yaml(item)?;
+                }
+            }
+            Value::Mapping(mapping) => {
+                for item in mapping.values_mut() {
+                    self.expand_yaml(item)?;
+                }
+            }
+            Value::Tagged(tagged) => self.expand_yaml(&mut tagged.value)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Read a YAML fragment with variables expanded, defaulting if the file does not exist.
+    pub fn read_yaml<T: DeserializeOwned + Default>(&self, path: &Path) -> Result<T> {
+        if !path.exists() {
+            return Ok(T::default());
+        }
+        let read = || -> Result<T> {
+            let mut fragment: Value = serde_yaml::from_str(&fs::read_to_string(path)?)?;
+            if fragment.is_null() {
+                return Ok(T::default());
+            }
+            self.expand_yaml(&mut fragment)?;
+            Ok(serde_yaml::from_value(fragment)?)
+        };
+        read().with_context(|| format!("failed to read {}", path.display()))
+    }
+}
+
+/// File of environment variables next to `mihoro.toml`, loaded before reading the config so that
+/// secrets can be referenced as `{{env.NAME}}` instead of written in it.
+pub const ENV_FILE: &str = ".env";
+
+fn env_var(name: &str) -> Result<String> {
+    env::var(name)
+        .ok()
+        .with_context(|| format!("environment variable `{}` undefined", name))
+}
+
+/// Set variables of a dotenv file, `NAME=value` lines optionally prefixed with `export` and with
+/// quoted values, unless already set in the environment. A missing file is ignored.
+pub fn load_env_file(path: &Path) -> Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let raw_env = fs::read_to_string(path)?;
+    for (index, line) in raw_env.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((name, value)) = line
+            .split_once('=')
+            .filter(|(name, _)| !name.trim().is_empty())
+        else {
+            bail!("invalid line {} in {}", index + 1, path.display());
+        };
+        let value = value.trim();
+        let value = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => value
+                .strip_prefix(quote)
+                .and_then(|value| value.strip_suffix(quote))
+                .with_context(|| {
+                    format!("unclosed quote on line {} in {}", index + 1, path.display())
+                })?,
+            _ => value.split(" #").next().unwrap_or_default().trim_end(),
+        };
+        let name = name.trim();
+        if env::var_os(name).is_none() {
+            env::set_var(name, value);
+        }
+    }
+    Ok(())
+}
+
+/// Set a variable defined in a dotenv file to a new value, replacing the line defining it and
+/// keeping the others. Returns false, leaving the file as is, if the file does not define it.
+pub fn replace_env_var(path: &Path, name: &str, value: &str) -> Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    let raw_env = fs::read_to_string(path)?;
+    let mut replaced = false;
+    let lines: Vec<String> = raw_env
+        .lines()
+        .map(|line| {
+            let definition = line.trim_start();
+            let definition = definition.strip_prefix("export ").unwrap_or(definition);
+            match definition.split_once('=') {
+                Some((defined, _)) if defined.trim() == name => {
+                    replaced = true;
+                    let prefix = &line[..line.len() - definition.len()];
+                    format!("{}{}={}", prefix, name, value)
+                }
+                _ => line.to_string(),
+            }
+        })
+        .collect();
+    if replaced {
+        fs::write(path, lines.join("\n") + "\n")?;
+    }
+    Ok(replaced)
+}
+
+/// Name of the environment variable that a value consists of, as in `{{env.NAME}}`.
+pub fn env_reference(text: &str) -> Option<&str> {
+    let name = text.trim().strip_prefix("{{")?.strip_suffix("}}")?.trim();
+    name.strip_prefix("env.").map(str::trim)
+}
+
+/// Expand `{{env.NAME}}` environment variables in text, leaving anything else as is.
+pub fn expand_env(text: &str) -> Result<String> {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        expanded.push_str(&rest[..start]);
+        match name.strip_prefix("env.") {
+            Some(env_name) => expanded.push_str(&env_var(env_name)?),
+            None => expanded.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &rest[start + end + 2..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expanded(yaml: &str, profile: &str) -> Value {
+        let template = Template::new(&Config::new(), profile, Path::new("/etc/mihomo"));
+        let mut value = serde_yaml::from_str(yaml).unwrap();
+        template.expand_yaml(&mut value).unwrap();
+        value
+    }
+
+    #[test]
+    fn whole_variables_keep_the_type_of_their_value() {
+        let value = expanded(
+            "
+port: '{{mixed_port}}'
+server: '127.0.0.1:{{mixed_port}}'
+path: '{{config_root}}/rules'
+name: '{{profile}}'
+literal: '8080'
+",
+            "home",
+        );
+        let expected: Value = serde_yaml::from_str(
+            "
+port: 7890
+server: '127.0.0.1:7890'
+path: /etc/mihomo/rules
+name: home
+literal: '8080'
+",
+        )
+        .unwrap();
+        assert_eq!(value, expected);
+    }
+
+    #[test]
+    fn values_cannot_change_the_structure() {
+        assert_eq!(
+            expanded("name: '{{profile}}'", "a, b: [c]"),
+            serde_yaml::from_str::<Value>("name: 'a, b: [c]'").unwrap()
+        );
+    }
+}
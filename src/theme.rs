@@ -0,0 +1,81 @@
+use crate::cmd::ColorMode;
+
+use std::env;
+
+use anyhow::{anyhow, Result};
+use colored::{Color, ColoredString, Colorize};
+use serde::{Deserialize, Serialize};
+
+/// Colors of the `mihoro:` prefix of messages by their kind, named as `cyan` or `bright red`.
+///
+/// * `info` - progress and information, `cyan` by default.
+/// * `success` - completed actions, `green` by default.
+/// * `warning` - skipped or cancelled actions and hints, `yellow` by default.
+/// * `error` - failures that do not stop the command, `red` by default.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Theme {
+    pub info: Option<String>,
+    pub success: Option<String>,
+    pub warning: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Prefix of mihoro's messages, colored by the kind of message according to the theme.
+#[derive(Debug, Clone)]
+pub struct Prefix {
+    text: String,
+    info: Color,
+    success: Color,
+    warning: Color,
+    error: Color,
+}
+
+impl Prefix {
+    pub fn new(text: &str, theme: &Theme) -> Result<Prefix> {
+        let color = |name: &Option<String>, default: Color| match name {
+            Some(name) => name
+                .parse()
+                .map_err(|_| anyhow!("invalid theme color `{}`", name)),
+            None => Ok(default),
+        };
+        Ok(Prefix {
+            text: String::from(text),
+            info: color(&theme.info, Color::Cyan)?,
+            success: color(&theme.success, Color::Green)?,
+            warning: color(&theme.warning, Color::Yellow)?,
+            error: color(&theme.error, Color::Red)?,
+        })
+    }
+
+    pub fn info(&self) -> ColoredString {
+        self.text.color(self.info)
+    }
+
+    pub fn success(&self) -> ColoredString {
+        self.text.color(self.success)
+    }
+
+    pub fn warning(&self) -> ColoredString {
+        self.text.color(self.warning)
+    }
+
+    pub fn error(&self) -> ColoredString {
+        self.text.color(self.error)
+    }
+}
+
+/// Enable or disable colored output. By default, colors are disabled if `NO_COLOR` is set,
+/// `TERM` is `dumb` or stdout is not a terminal, unless forced with `CLICOLOR_FORCE`.
+pub fn set_color_mode(mode: ColorMode) {
+    match mode {
+        ColorMode::Always => colored::control::set_override(true),
+        ColorMode::Never => colored::control::set_override(false),
+        // `colored` checks `NO_COLOR`, `CLICOLOR(_FORCE)` and the terminal itself
+        ColorMode::Auto => {
+            let forced = env::var("CLICOLOR_FORCE").is_ok_and(|force| force != "0");
+            if !forced && env::var("TERM").is_ok_and(|term| term == "dumb") {
+                colored::control::set_override(false);
+            }
+        }
+    }
+}
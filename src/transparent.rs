@@ -0,0 +1,289 @@
+use crate::config::AppRoute;
+
+use std::net::IpAddr;
+
+use serde_yaml::Value;
+
+/// nftables table holding mihoro's transparent proxy rules, in the `inet` family.
+pub const NFT_TABLE: &str = "mihoro";
+
+/// Firewall mark of packets routed to the local TPROXY listener, and the routing table doing so.
+const TPROXY_MARK: u32 = 1;
+const TPROXY_ROUTE_TABLE: u32 = 100;
+
+/// Destinations never proxied: local, private, link-local and multicast networks.
+const RESERVED_IPV4: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+];
+const RESERVED_IPV6: &[&str] = &["::1/128", "fc00::/7", "fe80::/10", "ff00::/8"];
+
+/// nftables table holding mihoro's per-application routing rules, in the `inet` family.
+pub const APP_ROUTE_NFT_TABLE: &str = "mihoro_apps";
+
+/// Firewall mark of the first app route, following routes counting up, and the routing table
+/// delivering marked packets to their listeners.
+const APP_ROUTE_MARK_BASE: u32 = 0x100;
+const APP_ROUTE_TABLE: u32 = 101;
+
+/// cgroup v2 mount point, and the cgroup under it holding processes run by `mihoro app-route`,
+/// with a child cgroup per route.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const APP_ROUTE_CGROUP: &str = "mihoro";
+
+/// Prefix of mihomo listeners generated for `app_routes`.
+const APP_ROUTE_LISTENER_PREFIX: &str = "mihoro-app-";
+
+/// Destinations never proxied, the reserved networks and the ip addresses and networks in
+/// `bypass`, split by ip version.
+fn bypass_networks(bypass: &[String]) -> (Vec<&str>, Vec<&str>) {
+    let (mut bypass_ipv4, mut bypass_ipv6): (Vec<&str>, Vec<&str>) =
+        (RESERVED_IPV4.to_vec(), RESERVED_IPV6.to_vec());
+    for target in bypass {
+        let address = target.split('/').next().unwrap_or_default();
+        let set = match address.parse::<IpAddr>() {
+            Ok(IpAddr::V4(_)) => &mut bypass_ipv4,
+            Ok(IpAddr::V6(_)) => &mut bypass_ipv6,
+            Err(_) => continue,
+        };
+        if !set.contains(&target.as_str()) {
+            set.push(target);
+        }
+    }
+    (bypass_ipv4, bypass_ipv6)
+}
+
+/// Shell script setting up TPROXY transparent proxying to mihomo's `tproxy-port`: nftables rules
+/// marking forwarded tcp and udp traffic and diverting it to the port, and policy routing of marked
+/// packets to the loopback interface.
+///
+/// With `local_routing_mark`, traffic of this machine is proxied as well, except for mihomo's own
+/// outbound traffic carrying its `routing-mark`. Ip addresses and networks in `bypass` are never
+/// proxied. The script is idempotent, it replaces rules applied before.
+///
+/// Reference: https://wiki.metacubex.one/example/conf/#tproxy
+pub fn tproxy_enable_script(
+    tproxy_port: u16,
+    local_routing_mark: Option<u32>,
+    bypass: &[String],
+) -> String {
+    let (bypass_ipv4, bypass_ipv6) = bypass_networks(bypass);
+
+    let output_chain = match local_routing_mark {
+        Some(routing_mark) => format!(
+            "
+    chain output {{
+        type route hook output priority mangle; policy accept;
+        meta mark {routing_mark} return
+        fib daddr type local return
+        ip daddr @bypass_ipv4 return
+        ip6 daddr @bypass_ipv6 return
+        meta l4proto {{ tcp, udp }} meta mark set {TPROXY_MARK}
+    }}
+"
+        ),
+        None => String::new(),
+    };
+
+    format!(
+        "#!/bin/sh
+# TPROXY transparent proxying to mihomo, generated by mihoro
+set -e
+
+nft -f - <<'EOF'
+table inet {NFT_TABLE}
+delete table inet {NFT_TABLE}
+table inet {NFT_TABLE} {{
+    set bypass_ipv4 {{
+        type ipv4_addr; flags interval; auto-merge;
+        elements = {{ {bypass_ipv4} }}
+    }}
+    set bypass_ipv6 {{
+        type ipv6_addr; flags interval; auto-merge;
+        elements = {{ {bypass_ipv6} }}
+    }}
+
+    chain prerouting {{
+        type filter hook prerouting priority mangle; policy accept;
+        fib daddr type local return
+        ip daddr @bypass_ipv4 return
+        ip6 daddr @bypass_ipv6 return
+        meta nfproto ipv4 meta l4proto {{ tcp, udp }} tproxy ip to :{tproxy_port} meta mark set {TPROXY_MARK} accept
+        meta nfproto ipv6 meta l4proto {{ tcp, udp }} tproxy ip6 to :{tproxy_port} meta mark set {TPROXY_MARK} accept
+    }}
+{output_chain}}}
+EOF
+
+ip rule del fwmark {TPROXY_MARK} table {TPROXY_ROUTE_TABLE} 2>/dev/null || true
+ip rule add fwmark {TPROXY_MARK} table {TPROXY_ROUTE_TABLE}
+ip route replace local 0.0.0.0/0 dev lo table {TPROXY_ROUTE_TABLE}
+ip -6 rule del fwmark {TPROXY_MARK} table {TPROXY_ROUTE_TABLE} 2>/dev/null || true
+ip -6 rule add fwmark {TPROXY_MARK} table {TPROXY_ROUTE_TABLE}
+ip -6 route replace local ::/0 dev lo table {TPROXY_ROUTE_TABLE}
+",
+        bypass_ipv4 = bypass_ipv4.join(", "),
+        bypass_ipv6 = bypass_ipv6.join(", "),
+    )
+}
+
+/// Shell script reverting `tproxy_enable_script`, ignoring rules that are already gone.
+pub fn tproxy_disable_script() -> String {
+    format!(
+        "#!/bin/sh
+# Revert TPROXY transparent proxying to mihomo, generated by mihoro
+
+nft delete table inet {NFT_TABLE} 2>/dev/null
+ip rule del fwmark {TPROXY_MARK} table {TPROXY_ROUTE_TABLE} 2>/dev/null
+ip route flush table {TPROXY_ROUTE_TABLE} 2>/dev/null
+ip -6 rule del fwmark {TPROXY_MARK} table {TPROXY_ROUTE_TABLE} 2>/dev/null
+ip -6 route flush table {TPROXY_ROUTE_TABLE} 2>/dev/null
+true
+"
+    )
+}
+
+/// Name of the mihomo listener of an app route.
+pub fn app_route_listener(route: &AppRoute) -> String {
+    format!("{}{}", APP_ROUTE_LISTENER_PREFIX, route.port)
+}
+
+/// Add a TPROXY listener to mihomo's `listeners` for each of `app_routes`, sending everything it
+/// receives to the route's policy. Listeners generated before are replaced.
+pub fn merge_app_route_listeners(listeners: &mut Option<Vec<Value>>, app_routes: &[AppRoute]) {
+    if app_routes.is_empty() {
+        return;
+    }
+    let listeners = listeners.get_or_insert_with(Vec::new);
+    listeners.retain(|listener| {
+        listener
+            .get("name")
+            .and_then(Value::as_str)
+            .is_none_or(|name| !name.starts_with(APP_ROUTE_LISTENER_PREFIX))
+    });
+    for route in app_routes {
+        let mut listener = serde_yaml::Mapping::new();
+        listener.insert("name".into(), app_route_listener(route).into());
+        listener.insert("type".into(), "tproxy".into());
+        listener.insert("port".into(), route.port.into());
+        listener.insert("udp".into(), true.into());
+        listener.insert("proxy".into(), route.policy.clone().into());
+        listeners.push(Value::Mapping(listener));
+    }
+}
+
+/// cgroup v2 of processes routed by an app route, relative to the cgroup root as nftables expects.
+fn app_route_cgroup(route: &AppRoute) -> String {
+    format!("{}/app-{}", APP_ROUTE_CGROUP, route.port)
+}
+
+/// Shell script routing traffic of processes in a cgroup per app route to the route's listener:
+/// nftables rules marking tcp and udp traffic of the cgroup, and policy routing of marked packets
+/// to the loopback interface, where they are diverted to the listener.
+///
+/// The process `pid` is moved into the cgroup of `route`, so that programs it starts afterwards
+/// are routed. The script is idempotent, it replaces rules applied before.
+pub fn app_route_enable_script(
+    app_routes: &[AppRoute],
+    route: &AppRoute,
+    pid: u32,
+    bypass: &[String],
+) -> String {
+    let (bypass_ipv4, bypass_ipv6) = bypass_networks(bypass);
+    let mut cgroups = Vec::new();
+    let mut output_rules = Vec::new();
+    let mut prerouting_rules = Vec::new();
+    let mut ip_rules = Vec::new();
+    for (index, app_route) in app_routes.iter().enumerate() {
+        let mark = APP_ROUTE_MARK_BASE + index as u32;
+        let cgroup = app_route_cgroup(app_route);
+        let port = app_route.port;
+        cgroups.push(format!("{CGROUP_ROOT}/{cgroup}"));
+        output_rules.push(format!(
+            "        socket cgroupv2 level 2 \"{cgroup}\" meta l4proto {{ tcp, udp }} meta mark set {mark}"
+        ));
+        prerouting_rules.push(format!(
+            "        meta mark {mark} meta nfproto ipv4 meta l4proto {{ tcp, udp }} tproxy ip to :{port} accept\n        \
+             meta mark {mark} meta nfproto ipv6 meta l4proto {{ tcp, udp }} tproxy ip6 to :{port} accept"
+        ));
+        ip_rules.push(format!(
+            "ip rule add fwmark {mark} table {APP_ROUTE_TABLE}\nip -6 rule add fwmark {mark} table {APP_ROUTE_TABLE}"
+        ));
+    }
+
+    format!(
+        "#!/bin/sh
+# Per-application routing to mihomo listeners, generated by mihoro
+set -e
+
+mkdir -p {cgroups}
+
+nft -f - <<'EOF'
+table inet {APP_ROUTE_NFT_TABLE}
+delete table inet {APP_ROUTE_NFT_TABLE}
+table inet {APP_ROUTE_NFT_TABLE} {{
+    set bypass_ipv4 {{
+        type ipv4_addr; flags interval; auto-merge;
+        elements = {{ {bypass_ipv4} }}
+    }}
+    set bypass_ipv6 {{
+        type ipv6_addr; flags interval; auto-merge;
+        elements = {{ {bypass_ipv6} }}
+    }}
+
+    chain output {{
+        type route hook output priority mangle; policy accept;
+        fib daddr type local return
+        ip daddr @bypass_ipv4 return
+        ip6 daddr @bypass_ipv6 return
+{output_rules}
+    }}
+
+    chain prerouting {{
+        type filter hook prerouting priority mangle - 1; policy accept;
+{prerouting_rules}
+    }}
+}}
+EOF
+
+while ip rule del table {APP_ROUTE_TABLE} 2>/dev/null; do :; done
+while ip -6 rule del table {APP_ROUTE_TABLE} 2>/dev/null; do :; done
+{ip_rules}
+ip route replace local 0.0.0.0/0 dev lo table {APP_ROUTE_TABLE}
+ip -6 route replace local ::/0 dev lo table {APP_ROUTE_TABLE}
+
+echo {pid} > {CGROUP_ROOT}/{route_cgroup}/cgroup.procs
+",
+        cgroups = cgroups.join(" "),
+        bypass_ipv4 = bypass_ipv4.join(", "),
+        bypass_ipv6 = bypass_ipv6.join(", "),
+        output_rules = output_rules.join("\n"),
+        prerouting_rules = prerouting_rules.join("\n"),
+        ip_rules = ip_rules.join("\n"),
+        route_cgroup = app_route_cgroup(route),
+    )
+}
+
+/// Shell script reverting `app_route_enable_script`, ignoring rules that are already gone. The
+/// cgroups are removed once no process is left in them.
+pub fn app_route_disable_script() -> String {
+    format!(
+        "#!/bin/sh
+# Revert per-application routing to mihomo listeners, generated by mihoro
+
+nft delete table inet {APP_ROUTE_NFT_TABLE} 2>/dev/null
+while ip rule del table {APP_ROUTE_TABLE} 2>/dev/null; do :; done
+while ip -6 rule del table {APP_ROUTE_TABLE} 2>/dev/null; do :; done
+ip route flush table {APP_ROUTE_TABLE} 2>/dev/null
+ip -6 route flush table {APP_ROUTE_TABLE} 2>/dev/null
+rmdir {CGROUP_ROOT}/{APP_ROUTE_CGROUP}/app-* {CGROUP_ROOT}/{APP_ROUTE_CGROUP} 2>/dev/null
+true
+"
+    )
+}
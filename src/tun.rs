@@ -0,0 +1,91 @@
+use std::process::Command;
+
+/// System resolver configuration, read from `/etc/resolv.conf`.
+pub const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Name of mihomo's TUN device unless `tun.device` is set.
+pub const DEFAULT_TUN_DEVICE: &str = "Meta";
+
+/// systemd-resolved drop-in forwarding all queries into mihomo's TUN.
+pub const RESOLVED_DROP_IN: &str = "/etc/systemd/resolved.conf.d/mihoro-tun.conf";
+
+/// Address inside mihomo's default TUN network `198.18.0.1/30`, queries sent to it are hijacked
+/// by mihomo's `dns-hijack`.
+const TUN_DNS_ADDRESS: &str = "198.18.0.2";
+
+/// How the system resolves domains, deciding whether queries reach mihomo's TUN.
+pub enum Resolver {
+    /// systemd-resolved's stub listener on `127.0.0.53`, which bypasses the TUN unless pointed
+    /// into it.
+    SystemdResolved,
+    /// Another local resolver (e.g. dnsmasq), whose loopback traffic bypasses the TUN.
+    Loopback(String),
+    /// Remote nameservers, hijacked by the TUN as is.
+    Remote,
+}
+
+impl Resolver {
+    /// Detect the resolver from the contents of `/etc/resolv.conf`.
+    pub fn detect(resolv_conf: &str) -> Resolver {
+        let nameservers: Vec<&str> = resolv_conf
+            .lines()
+            .filter_map(|line| line.trim().strip_prefix("nameserver"))
+            .map(str::trim)
+            .collect();
+        if nameservers.contains(&"127.0.0.53") {
+            return Resolver::SystemdResolved;
+        }
+        match nameservers
+            .iter()
+            .find(|nameserver| nameserver.starts_with("127.") || **nameserver == "::1")
+        {
+            Some(nameserver) => Resolver::Loopback(nameserver.to_string()),
+            None => Resolver::Remote,
+        }
+    }
+}
+
+/// Whether the binary at path has the file capabilities mihomo needs to create a TUN device
+/// without running as root, according to `getcap`.
+pub fn has_tun_capabilities(binary_path: &str) -> bool {
+    Command::new("getcap")
+        .arg(binary_path)
+        .output()
+        .is_ok_and(|output| {
+            let capabilities = String::from_utf8_lossy(&output.stdout);
+            capabilities.contains("cap_net_admin") && capabilities.contains("cap_net_bind_service")
+        })
+}
+
+/// Shell commands granting mihomo's binary the capabilities to create a TUN device and listen on
+/// port 53.
+pub fn tun_capabilities_script(binary_path: &str) -> String {
+    format!(
+        "setcap cap_net_admin,cap_net_bind_service=+ep '{}'\n",
+        binary_path.replace('\'', r"'\''")
+    )
+}
+
+/// Shell commands pointing systemd-resolved into mihomo's TUN for all domains.
+pub fn resolved_enable_script() -> String {
+    format!(
+        "mkdir -p \"$(dirname {RESOLVED_DROP_IN})\"
+cat > {RESOLVED_DROP_IN} <<'EOF'
+# Resolve through mihomo's TUN, generated by mihoro
+[Resolve]
+DNS={TUN_DNS_ADDRESS}
+Domains=~.
+EOF
+systemctl restart systemd-resolved
+"
+    )
+}
+
+/// Shell commands reverting `resolved_enable_script`.
+pub fn resolved_disable_script() -> String {
+    format!(
+        "rm -f {RESOLVED_DROP_IN}
+systemctl restart systemd-resolved
+"
+    )
+}
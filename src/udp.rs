@@ -0,0 +1,100 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+/// DNS server queried through the tested node.
+const DNS_SERVER: (Ipv4Addr, u16) = (Ipv4Addr::new(1, 1, 1, 1), 53);
+
+/// Query `example.com A` with the given transaction id.
+fn dns_query(id: u16) -> Vec<u8> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    // Recursion desired, one question
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    for label in ["example", "com"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // Root label, type A, class IN
+    query.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x01]);
+    query
+}
+
+/// Establish a SOCKS5 `UDP ASSOCIATE` with the SOCKS server at `proxy`, returning the control
+/// connection (which must be kept open) and the UDP relay address.
+///
+/// Reference: https://datatracker.ietf.org/doc/html/rfc1928
+async fn udp_associate(proxy: SocketAddr) -> Result<(TcpStream, SocketAddr)> {
+    let mut control = TcpStream::connect(proxy).await?;
+
+    // No authentication
+    control.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut reply = [0; 2];
+    control.read_exact(&mut reply).await?;
+    if reply != [0x05, 0x00] {
+        bail!("SOCKS5 handshake rejected");
+    }
+
+    control
+        .write_all(&[0x05, 0x03, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await?;
+    let mut reply = [0; 4];
+    control.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        bail!("UDP ASSOCIATE rejected with code {}", reply[1]);
+    }
+    let relay_ip = match reply[3] {
+        0x01 => {
+            let mut ip = [0; 4];
+            control.read_exact(&mut ip).await?;
+            Ipv4Addr::from(ip)
+        }
+        atyp => bail!("unsupported relay address type {}", atyp),
+    };
+    let relay_port = control.read_u16().await?;
+
+    // Relays bound to all interfaces are reached through the proxy's address
+    let relay_ip = if relay_ip.is_unspecified() {
+        proxy.ip()
+    } else {
+        relay_ip.into()
+    };
+    Ok((control, SocketAddr::new(relay_ip, relay_port)))
+}
+
+/// Check whether UDP is relayed through the SOCKS5 proxy at `proxy` by resolving a domain with a
+/// public DNS server, returning the round trip time.
+pub async fn test_udp_relay(proxy: SocketAddr, wait: Duration) -> Result<Duration> {
+    let (_control, relay) = timeout(wait, udp_associate(proxy))
+        .await
+        .context("timed out negotiating UDP ASSOCIATE")??;
+
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let id = SystemTime::now().duration_since(UNIX_EPOCH)?.subsec_nanos() as u16;
+    let (dns_ip, dns_port) = DNS_SERVER;
+    let mut datagram = vec![0x00, 0x00, 0x00, 0x01];
+    datagram.extend_from_slice(&dns_ip.octets());
+    datagram.extend_from_slice(&dns_port.to_be_bytes());
+    datagram.extend_from_slice(&dns_query(id));
+
+    let started = Instant::now();
+    socket.send_to(&datagram, relay).await?;
+    let mut buf = [0; 1500];
+    let len = timeout(wait, socket.recv(&mut buf))
+        .await
+        .context("timed out waiting for DNS response")??;
+
+    // Skip the SOCKS5 UDP header of an IPv4 address, then match the DNS transaction id
+    if len < 12 || buf[10..12] != id.to_be_bytes() {
+        bail!("unexpected response from UDP relay");
+    }
+    Ok(started.elapsed())
+}
@@ -0,0 +1,121 @@
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use flate2::read::GzDecoder;
+
+/// Time mihomo is given to download its web UI from `external-ui-url` after a restart, before
+/// mihoro installs it instead.
+pub const UI_DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Whether a web UI is installed in `ui_dir`.
+pub fn ui_installed(ui_dir: &Path) -> bool {
+    ui_dir.join("index.html").is_file()
+}
+
+/// Extract a web UI archive, zip or gzipped tar as accepted by mihomo's `external-ui-url`, to
+/// `ui_dir`, replacing its contents. As with mihomo, a single top level directory of the archive
+/// (e.g. `metacubexd-gh-pages/`) is stripped. Returns the extracted files.
+pub fn extract_ui(archive_path: &Path, ui_dir: &Path) -> Result<Vec<PathBuf>> {
+    let archive = fs::read(archive_path)?;
+    let mut entries = if archive.starts_with(b"PK\x03\x04") {
+        zip_entries(&archive)?
+    } else if archive.starts_with(&[0x1f, 0x8b]) {
+        tar_gz_entries(&archive)?
+    } else {
+        bail!("web UI archive is neither a zip nor a gzipped tar");
+    };
+
+    let root = entries.first().and_then(|(path, _)| path.iter().next());
+    if let Some(root) = root.map(PathBuf::from) {
+        if entries
+            .iter()
+            .all(|(path, _)| path.starts_with(&root) && path != &root)
+        {
+            for (path, _) in &mut entries {
+                *path = path.strip_prefix(&root)?.to_path_buf();
+            }
+        }
+    }
+    if !entries
+        .iter()
+        .any(|(path, _)| path == Path::new("index.html"))
+    {
+        bail!("web UI archive has no index.html");
+    }
+
+    // Extract next to the UI first, so that a failure leaves the previous one in place
+    let mut staged_dir = ui_dir.as_os_str().to_owned();
+    staged_dir.push(".new");
+    let staged_dir = PathBuf::from(staged_dir);
+    if staged_dir.exists() {
+        fs::remove_dir_all(&staged_dir)?;
+    }
+    for (path, content) in &entries {
+        let staged_path = staged_dir.join(path);
+        if let Some(parent) = staged_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(staged_path, content)?;
+    }
+    if ui_dir.exists() {
+        fs::remove_dir_all(ui_dir)?;
+    }
+    fs::rename(&staged_dir, ui_dir)?;
+    Ok(entries
+        .into_iter()
+        .map(|(path, _)| ui_dir.join(path))
+        .collect())
+}
+
+/// Relative path of an archive entry, `None` for entries escaping the extraction directory.
+fn entry_path(path: &Path) -> Option<PathBuf> {
+    let mut entry_path = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => entry_path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!entry_path.as_os_str().is_empty()).then_some(entry_path)
+}
+
+fn zip_entries(archive: &[u8]) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(archive))?;
+    let mut entries = Vec::new();
+    for index in 0..zip.len() {
+        let mut file = zip.by_index(index)?;
+        if !file.is_file() {
+            continue;
+        }
+        let Some(path) = file.enclosed_name().as_deref().and_then(entry_path) else {
+            bail!("web UI archive has an unsafe path {}", file.name());
+        };
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        entries.push((path, content));
+    }
+    Ok(entries)
+}
+
+fn tar_gz_entries(archive: &[u8]) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let mut tar = tar::Archive::new(GzDecoder::new(archive));
+    let mut entries = Vec::new();
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let raw_path = entry.path()?.to_path_buf();
+        let Some(path) = entry_path(&raw_path) else {
+            bail!("web UI archive has an unsafe path {}", raw_path.display());
+        };
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        entries.push((path, content));
+    }
+    Ok(entries)
+}
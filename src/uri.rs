@@ -0,0 +1,561 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use base64::{
+    prelude::{BASE64_STANDARD, BASE64_STANDARD_NO_PAD, BASE64_URL_SAFE_NO_PAD},
+    Engine,
+};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde_yaml::{Mapping, Value};
+use url::Url;
+
+/// String field of a proxy entry.
+fn field<'a>(node: &'a Value, key: &str) -> Option<&'a str> {
+    node.get(key).and_then(Value::as_str)
+}
+
+/// String or number field of a proxy entry, as a string.
+fn field_string(node: &Value, key: &str) -> Option<String> {
+    match node.get(key) {
+        Some(Value::String(value)) => Some(value.clone()),
+        Some(Value::Number(value)) => Some(value.to_string()),
+        Some(Value::Bool(value)) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+fn encode(value: &str) -> String {
+    utf8_percent_encode(value, NON_ALPHANUMERIC).to_string()
+}
+
+/// Build `key=value` query parameters from present values.
+fn query(params: &[(&str, Option<String>)]) -> String {
+    let params: Vec<String> = params
+        .iter()
+        .filter_map(|(key, value)| {
+            value
+                .as_ref()
+                .map(|value| format!("{}={}", key, encode(value)))
+        })
+        .collect();
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    }
+}
+
+/// Transport options (`ws`, `grpc`, ...) of a proxy entry as share link parameters.
+fn transport_params(node: &Value) -> Vec<(&'static str, Option<String>)> {
+    let network = field(node, "network").unwrap_or("tcp");
+    let ws_opts = node.get("ws-opts");
+    let grpc_opts = node.get("grpc-opts");
+    vec![
+        ("type", Some(network.to_string())),
+        ("path", ws_opts.and_then(|opts| field_string(opts, "path"))),
+        (
+            "host",
+            ws_opts
+                .and_then(|opts| opts.get("headers"))
+                .and_then(|headers| field_string(headers, "Host")),
+        ),
+        (
+            "serviceName",
+            grpc_opts.and_then(|opts| field_string(opts, "grpc-service-name")),
+        ),
+    ]
+}
+
+/// Convert a mihomo proxy entry into a share link (`ss://`, `vmess://`, `vless://`, `trojan://`,
+/// `hysteria2://`) understood by most mobile clients.
+pub fn node_to_uri(node: &Value) -> Result<String> {
+    let name = field(node, "name").unwrap_or_default();
+    let server = field(node, "server").context("node has no `server`")?;
+    let port = field_string(node, "port").context("node has no `port`")?;
+    let server = if server.contains(':') {
+        format!("[{}]", server)
+    } else {
+        server.to_string()
+    };
+    let tls = node.get("tls").and_then(Value::as_bool).unwrap_or(false);
+    let insecure = node
+        .get("skip-cert-verify")
+        .and_then(Value::as_bool)
+        .unwrap_or(false);
+
+    let uri = match field(node, "type") {
+        Some("ss") => {
+            let cipher = field(node, "cipher").context("node has no `cipher`")?;
+            let password = field_string(node, "password").context("node has no `password`")?;
+            let user_info = BASE64_URL_SAFE_NO_PAD.encode(format!("{}:{}", cipher, password));
+            format!("ss://{}@{}:{}#{}", user_info, server, port, encode(name))
+        }
+        Some("trojan") => {
+            let password = field_string(node, "password").context("node has no `password`")?;
+            let mut params = vec![
+                ("sni", field_string(node, "sni")),
+                ("allowInsecure", insecure.then(|| String::from("1"))),
+            ];
+            params.extend(transport_params(node));
+            format!(
+                "trojan://{}@{}:{}{}#{}",
+                encode(&password),
+                server,
+                port,
+                query(&params),
+                encode(name)
+            )
+        }
+        Some("vless") => {
+            let uuid = field(node, "uuid").context("node has no `uuid`")?;
+            let reality_opts = node.get("reality-opts");
+            let security = if reality_opts.is_some() {
+                "reality"
+            } else if tls {
+                "tls"
+            } else {
+                "none"
+            };
+            let mut params = vec![
+                ("encryption", Some(String::from("none"))),
+                ("security", Some(security.to_string())),
+                ("sni", field_string(node, "servername")),
+                ("flow", field_string(node, "flow")),
+                ("fp", field_string(node, "client-fingerprint")),
+                (
+                    "pbk",
+                    reality_opts.and_then(|opts| field_string(opts, "public-key")),
+                ),
+                (
+                    "sid",
+                    reality_opts.and_then(|opts| field_string(opts, "short-id")),
+                ),
+                ("allowInsecure", insecure.then(|| String::from("1"))),
+            ];
+            params.extend(transport_params(node));
+            format!(
+                "vless://{}@{}:{}{}#{}",
+                uuid,
+                server,
+                port,
+                query(&params),
+                encode(name)
+            )
+        }
+        Some("vmess") => {
+            let uuid = field(node, "uuid").context("node has no `uuid`")?;
+            let ws_opts = node.get("ws-opts");
+            let vmess = serde_json::json!({
+                "v": "2",
+                "ps": name,
+                "add": field(node, "server"),
+                "port": port,
+                "id": uuid,
+                "aid": field_string(node, "alterId").unwrap_or_else(|| String::from("0")),
+                "scy": field(node, "cipher").unwrap_or("auto"),
+                "net": field(node, "network").unwrap_or("tcp"),
+                "type": "none",
+                "host": ws_opts
+                    .and_then(|opts| opts.get("headers"))
+                    .and_then(|headers| field(headers, "Host"))
+                    .unwrap_or_default(),
+                "path": ws_opts.and_then(|opts| field(opts, "path")).unwrap_or_default(),
+                "tls": if tls { "tls" } else { "" },
+                "sni": field(node, "servername").unwrap_or_default(),
+            });
+            format!("vmess://{}", BASE64_STANDARD.encode(vmess.to_string()))
+        }
+        Some("hysteria2") => {
+            let password = field_string(node, "password").context("node has no `password`")?;
+            let params = [
+                ("sni", field_string(node, "sni")),
+                ("insecure", insecure.then(|| String::from("1"))),
+                ("obfs", field_string(node, "obfs")),
+                ("obfs-password", field_string(node, "obfs-password")),
+            ];
+            format!(
+                "hysteria2://{}@{}:{}{}#{}",
+                encode(&password),
+                server,
+                port,
+                query(&params),
+                encode(name)
+            )
+        }
+        Some(node_type) => bail!("share links of `{}` nodes are not supported", node_type),
+        None => bail!("node has no `type`"),
+    };
+    Ok(uri)
+}
+
+/// Decode base64 in any of the standard or url-safe alphabets, padded or not, as share links
+/// in the wild use all of them.
+fn decode_base64(value: &str) -> Option<String> {
+    let value = value.trim().trim_end_matches('=');
+    let decoded = BASE64_URL_SAFE_NO_PAD
+        .decode(value)
+        .or_else(|_| BASE64_STANDARD_NO_PAD.decode(value))
+        .ok()?;
+    String::from_utf8(decoded).ok()
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().to_string()
+}
+
+fn insert(node: &mut Mapping, key: &str, value: impl Into<Value>) {
+    node.insert(Value::from(key), value.into());
+}
+
+/// Insert transport options of a share link into a proxy entry, `tcp` needs no options.
+fn insert_transport(
+    node: &mut Mapping,
+    network: &str,
+    path: Option<&str>,
+    host: Option<&str>,
+    service_name: Option<&str>,
+) {
+    match network {
+        "ws" | "httpupgrade" => {
+            insert(node, "network", network);
+            let mut ws_opts = Mapping::new();
+            if let Some(path) = path.filter(|path| !path.is_empty()) {
+                insert(&mut ws_opts, "path", path);
+            }
+            if let Some(host) = host.filter(|host| !host.is_empty()) {
+                let mut headers = Mapping::new();
+                insert(&mut headers, "Host", host);
+                insert(&mut ws_opts, "headers", headers);
+            }
+            insert(node, "ws-opts", ws_opts);
+        }
+        "grpc" => {
+            insert(node, "network", network);
+            let mut grpc_opts = Mapping::new();
+            if let Some(service_name) = service_name.filter(|name| !name.is_empty()) {
+                insert(&mut grpc_opts, "grpc-service-name", service_name);
+            }
+            insert(node, "grpc-opts", grpc_opts);
+        }
+        _ => {}
+    }
+}
+
+/// Parts of a `scheme://user@host:port?query#name` share link.
+struct ShareLink {
+    user: String,
+    password: Option<String>,
+    server: String,
+    port: u16,
+    params: HashMap<String, String>,
+    name: String,
+}
+
+impl ShareLink {
+    fn parse(uri: &str) -> Result<ShareLink> {
+        let url = Url::parse(uri).with_context(|| format!("invalid share link `{}`", uri))?;
+        let server = url
+            .host_str()
+            .context("share link has no server")?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = url.port().context("share link has no port")?;
+        Ok(ShareLink {
+            user: decode(url.username()),
+            password: url.password().map(decode),
+            name: url
+                .fragment()
+                .map(decode)
+                .unwrap_or_else(|| format!("{}:{}", server, port)),
+            params: url.query_pairs().into_owned().collect(),
+            server,
+            port,
+        })
+    }
+
+    fn param(&self, key: &str) -> Option<&str> {
+        self.params.get(key).map(String::as_str)
+    }
+
+    /// Proxy entry with the fields common to all node types.
+    fn node(&self, node_type: &str) -> Mapping {
+        let mut node = Mapping::new();
+        insert(&mut node, "name", self.name.as_str());
+        insert(&mut node, "type", node_type);
+        insert(&mut node, "server", self.server.as_str());
+        insert(&mut node, "port", self.port);
+        insert(&mut node, "udp", true);
+        node
+    }
+
+    fn insecure(&self) -> bool {
+        matches!(
+            self.param("allowInsecure").or(self.param("insecure")),
+            Some("1" | "true")
+        )
+    }
+
+    fn insert_transport(&self, node: &mut Mapping) {
+        insert_transport(
+            node,
+            self.param("type").unwrap_or("tcp"),
+            self.param("path"),
+            self.param("host"),
+            self.param("serviceName"),
+        );
+    }
+}
+
+/// `ss://` link, either SIP002 (`ss://userinfo@host:port#name`, userinfo in base64 or plain) or
+/// legacy (`ss://base64(method:password@host:port)#name`).
+fn ss_to_node(uri: &str) -> Result<Mapping> {
+    let (body, fragment) = match uri.split_once('#') {
+        Some((body, fragment)) => (body, Some(fragment)),
+        None => (uri, None),
+    };
+    let body = body.trim_start_matches("ss://");
+    let link = if body.contains('@') {
+        ShareLink::parse(uri)?
+    } else {
+        let decoded = decode_base64(body).context("invalid base64 in ss link")?;
+        let uri = match fragment {
+            Some(fragment) => format!("ss://{}#{}", decoded, fragment),
+            None => format!("ss://{}", decoded),
+        };
+        ShareLink::parse(&uri)?
+    };
+
+    let user_info = match &link.password {
+        Some(password) => format!("{}:{}", link.user, password),
+        None => decode_base64(&link.user).unwrap_or_else(|| link.user.clone()),
+    };
+    let (cipher, password) = user_info
+        .split_once(':')
+        .context("ss link has no cipher and password")?;
+
+    let mut node = link.node("ss");
+    insert(&mut node, "cipher", cipher);
+    insert(&mut node, "password", password);
+    Ok(node)
+}
+
+fn vmess_to_node(uri: &str) -> Result<Mapping> {
+    let body = uri.trim_start_matches("vmess://");
+    let decoded = decode_base64(body).context("invalid base64 in vmess link")?;
+    let vmess: serde_json::Value = serde_json::from_str(&decoded)?;
+    let field = |key: &str| match vmess.get(key) {
+        Some(serde_json::Value::String(value)) => Some(value.clone()),
+        Some(serde_json::Value::Number(value)) => Some(value.to_string()),
+        _ => None,
+    };
+
+    let server = field("add").context("vmess link has no server")?;
+    let port: u16 = field("port")
+        .context("vmess link has no port")?
+        .parse()
+        .context("invalid port in vmess link")?;
+    let mut node = Mapping::new();
+    insert(
+        &mut node,
+        "name",
+        field("ps").unwrap_or_else(|| format!("{}:{}", server, port)),
+    );
+    insert(&mut node, "type", "vmess");
+    insert(&mut node, "server", server);
+    insert(&mut node, "port", port);
+    insert(&mut node, "udp", true);
+    insert(
+        &mut node,
+        "uuid",
+        field("id").context("vmess link has no id")?,
+    );
+    insert(
+        &mut node,
+        "alterId",
+        field("aid")
+            .and_then(|aid| aid.parse::<u32>().ok())
+            .unwrap_or(0),
+    );
+    insert(
+        &mut node,
+        "cipher",
+        field("scy")
+            .filter(|cipher| !cipher.is_empty())
+            .unwrap_or_else(|| String::from("auto")),
+    );
+    if field("tls").as_deref() == Some("tls") {
+        insert(&mut node, "tls", true);
+        if let Some(sni) = field("sni").filter(|sni| !sni.is_empty()) {
+            insert(&mut node, "servername", sni);
+        }
+    }
+    let path = field("path");
+    let host = field("host");
+    insert_transport(
+        &mut node,
+        field("net").as_deref().unwrap_or("tcp"),
+        path.as_deref(),
+        host.as_deref(),
+        path.as_deref(),
+    );
+    Ok(node)
+}
+
+fn trojan_to_node(uri: &str) -> Result<Mapping> {
+    let link = ShareLink::parse(uri)?;
+    let mut node = link.node("trojan");
+    insert(&mut node, "password", link.user.as_str());
+    if let Some(sni) = link.param("sni").or(link.param("peer")) {
+        insert(&mut node, "sni", sni);
+    }
+    if link.insecure() {
+        insert(&mut node, "skip-cert-verify", true);
+    }
+    link.insert_transport(&mut node);
+    Ok(node)
+}
+
+fn vless_to_node(uri: &str) -> Result<Mapping> {
+    let link = ShareLink::parse(uri)?;
+    let mut node = link.node("vless");
+    insert(&mut node, "uuid", link.user.as_str());
+    let security = link.param("security").unwrap_or("none");
+    if security == "tls" || security == "reality" {
+        insert(&mut node, "tls", true);
+    }
+    if let Some(sni) = link.param("sni") {
+        insert(&mut node, "servername", sni);
+    }
+    if let Some(flow) = link.param("flow").filter(|flow| !flow.is_empty()) {
+        insert(&mut node, "flow", flow);
+    }
+    if let Some(fingerprint) = link.param("fp") {
+        insert(&mut node, "client-fingerprint", fingerprint);
+    }
+    if security == "reality" {
+        let mut reality_opts = Mapping::new();
+        if let Some(public_key) = link.param("pbk") {
+            insert(&mut reality_opts, "public-key", public_key);
+        }
+        if let Some(short_id) = link.param("sid") {
+            insert(&mut reality_opts, "short-id", short_id);
+        }
+        insert(&mut node, "reality-opts", reality_opts);
+    }
+    if link.insecure() {
+        insert(&mut node, "skip-cert-verify", true);
+    }
+    link.insert_transport(&mut node);
+    Ok(node)
+}
+
+fn hysteria2_to_node(uri: &str) -> Result<Mapping> {
+    let link = ShareLink::parse(uri)?;
+    let mut node = link.node("hysteria2");
+    insert(&mut node, "password", link.user.as_str());
+    if let Some(sni) = link.param("sni") {
+        insert(&mut node, "sni", sni);
+    }
+    if let Some(obfs) = link.param("obfs") {
+        insert(&mut node, "obfs", obfs);
+    }
+    if let Some(obfs_password) = link.param("obfs-password") {
+        insert(&mut node, "obfs-password", obfs_password);
+    }
+    if link.insecure() {
+        insert(&mut node, "skip-cert-verify", true);
+    }
+    Ok(node)
+}
+
+/// Convert a share link (`ss://`, `vmess://`, `vless://`, `trojan://`, `hysteria2://`) into a
+/// mihomo proxy entry.
+pub fn uri_to_node(uri: &str) -> Result<Value> {
+    let uri = uri.trim();
+    let node = match uri.split_once("://").map(|(scheme, _)| scheme) {
+        Some("ss") => ss_to_node(uri)?,
+        Some("vmess") => vmess_to_node(uri)?,
+        Some("trojan") => trojan_to_node(uri)?,
+        Some("vless") => vless_to_node(uri)?,
+        Some("hysteria2" | "hy2") => hysteria2_to_node(uri)?,
+        Some(scheme) => bail!("share links of `{}` scheme are not supported", scheme),
+        None => bail!("invalid share link `{}`", uri),
+    };
+    Ok(Value::Mapping(node))
+}
+
+/// Split text into share links, one per line or separated by whitespace. Text that is a single
+/// base64 blob, as served by most subscriptions, is decoded first.
+pub fn split_uris(text: &str) -> Vec<String> {
+    let text = if text.contains("://") {
+        text.to_string()
+    } else {
+        let blob: String = text.split_whitespace().collect();
+        decode_base64(&blob).unwrap_or_default()
+    };
+    text.split_whitespace()
+        .filter(|uri| uri.contains("://"))
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(yaml: &str) -> Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn ss_links_decode_in_sip002_and_legacy_forms() {
+        let expected = node(
+            "{name: HK 01, type: ss, server: hk.example.com, port: 8388, udp: true, \
+             cipher: aes-128-gcm, password: 'pa:ss'}",
+        );
+        let user_info = BASE64_URL_SAFE_NO_PAD.encode("aes-128-gcm:pa:ss");
+        let sip002 = format!("ss://{}@hk.example.com:8388#HK%2001", user_info);
+        assert_eq!(uri_to_node(&sip002).unwrap(), expected);
+        let legacy = BASE64_STANDARD.encode("aes-128-gcm:pa:ss@hk.example.com:8388");
+        assert_eq!(
+            uri_to_node(&format!("ss://{}#HK 01", legacy)).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn nodes_survive_a_round_trip_through_share_links() {
+        for yaml in [
+            "{name: TW 01, type: trojan, server: tw.example.com, port: 443, udp: true, \
+             password: p@ss#1, sni: cdn.example.com, skip-cert-verify: true, network: ws, \
+             ws-opts: {path: /ws, headers: {Host: cdn.example.com}}}",
+            "{name: JP 01, type: vmess, server: jp.example.com, port: 443, udp: true, \
+             uuid: 0d3b3a4e-5e0a-4a3c-9d5e-2c8f3b1a6e7d, alterId: 0, cipher: auto, tls: true, \
+             servername: jp.example.com}",
+            "{name: US 01, type: vless, server: us.example.com, port: 443, udp: true, \
+             uuid: 0d3b3a4e-5e0a-4a3c-9d5e-2c8f3b1a6e7d, tls: true, servername: www.example.com, \
+             flow: xtls-rprx-vision, client-fingerprint: chrome, \
+             reality-opts: {public-key: key, short-id: ab12}}",
+            "{name: SG 01, type: hysteria2, server: '2001:db8::1', port: 8443, udp: true, \
+             password: secret, sni: sg.example.com, obfs: salamander, obfs-password: obfs}",
+        ] {
+            let node = node(yaml);
+            let uri = node_to_uri(&node).unwrap();
+            assert_eq!(uri_to_node(&uri).unwrap(), node, "{}", uri);
+        }
+    }
+
+    #[test]
+    fn base64_subscriptions_split_into_links() {
+        let blob =
+            BASE64_STANDARD.encode("trojan://a@a.example.com:443#A\r\nhy2://b@b.example.com:443\n");
+        assert_eq!(
+            split_uris(&blob),
+            [
+                "trojan://a@a.example.com:443#A",
+                "hy2://b@b.example.com:443"
+            ]
+        );
+    }
+}
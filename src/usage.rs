@@ -0,0 +1,126 @@
+use crate::utils::create_parent_dir;
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Traffic accounting kept next to mihomo's `config.yaml`.
+pub const USAGE_FILE: &str = "usage.toml";
+
+/// Seconds of recent records kept, for the trend of the last hour.
+const RECENT_SECS: i64 = 3600;
+
+/// Slots of the trend of the last hour, one per record of `mihoro-usage.timer`.
+pub const LAST_HOUR_SLOTS: usize = 12;
+
+/// Traffic through mihomo in bytes.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+pub struct Traffic {
+    pub upload: u64,
+    pub download: u64,
+}
+
+impl Traffic {
+    pub fn total(&self) -> u64 {
+        self.upload + self.download
+    }
+}
+
+/// Traffic since the previous record, recorded at `time` (a unix timestamp).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Sample {
+    pub time: i64,
+    pub upload: u64,
+    pub download: u64,
+}
+
+/// Traffic recorded by `mihoro usage --record`:
+///
+/// * `counters` - mihomo's traffic counters at the last record, which reset when mihomo restarts.
+/// * `days` - traffic of each day (`YYYY-MM-DD` in local time).
+/// * `recent` - traffic of each record of the last hour.
+/// * `machines` - traffic of each day recorded by other machines, by machine, as of the last
+///   `mihoro state-sync pull`.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Usage {
+    #[serde(default)]
+    pub counters: Traffic,
+    #[serde(default)]
+    pub days: BTreeMap<String, Traffic>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recent: Vec<Sample>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub machines: BTreeMap<String, BTreeMap<String, Traffic>>,
+}
+
+impl Usage {
+    /// Read usage from path, returning empty usage if the file does not exist.
+    pub fn load(path: &str) -> Result<Usage> {
+        if !Path::new(path).exists() {
+            return Ok(Usage::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn write(&self, path: &str) -> Result<()> {
+        create_parent_dir(path)?;
+        fs::write(path, toml::to_string(&self)?)?;
+        Ok(())
+    }
+
+    /// Traffic of each day on this and other machines.
+    pub fn day_totals(&self) -> BTreeMap<String, Traffic> {
+        let mut totals = self.days.clone();
+        for (day, traffic) in self.machines.values().flatten() {
+            let total = totals.entry(day.clone()).or_default();
+            total.upload += traffic.upload;
+            total.download += traffic.download;
+        }
+        totals
+    }
+
+    /// Add traffic since the last record to `day` and the recent records at `time`, given mihomo's
+    /// current counters. Counters lower than the last record mean mihomo restarted in between, so
+    /// they are all new traffic.
+    pub fn record(&mut self, day: &str, time: i64, counters: Traffic) {
+        let since = |current: u64, last: u64| {
+            if current >= last {
+                current - last
+            } else {
+                current
+            }
+        };
+        let upload = since(counters.upload, self.counters.upload);
+        let download = since(counters.download, self.counters.download);
+        let traffic = self.days.entry(day.to_string()).or_default();
+        traffic.upload += upload;
+        traffic.download += download;
+        self.counters = counters;
+
+        self.recent
+            .retain(|sample| time - sample.time < RECENT_SECS);
+        self.recent.push(Sample {
+            time,
+            upload,
+            download,
+        });
+    }
+
+    /// Total traffic of the last hour before `now` in `slots` of equal length, oldest first, or
+    /// `None` if nothing was recorded then.
+    pub fn last_hour(&self, now: i64, slots: usize) -> Option<Vec<u64>> {
+        let mut totals = vec![0; slots];
+        let mut recorded = false;
+        for sample in &self.recent {
+            let age = now - sample.time;
+            if !(0..RECENT_SECS).contains(&age) {
+                continue;
+            }
+            let slot = ((RECENT_SECS - 1 - age) * slots as i64 / RECENT_SECS) as usize;
+            totals[slot] += sample.upload + sample.download;
+            recorded = true;
+        }
+        recorded.then_some(totals)
+    }
+}
@@ -1,17 +1,78 @@
-use std::{cmp::min, fs::{self, File}, io::{self, Write}, path::Path};
+use crate::cache::DownloadCache;
+use crate::i18n::tr_args;
+use crate::theme::Prefix;
+
+use std::{cmp::min, fs::{self, File}, io::{self, Write}, path::{Path, PathBuf}};
 use std::fs::OpenOptions;
 use std::io::{Read, Seek, SeekFrom};
-use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::os::unix::fs::MetadataExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use std::future::Future;
+use anyhow::{anyhow, bail, Context, Result};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use colored::Colorize;
 use flate2::read::GzDecoder;
 use futures_util::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::Client;
+use minisign_verify::{PublicKey, Signature};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode, Url};
 use shellexpand::tilde;
+use tokio::io::{AsyncWriteExt, BufWriter};
 use truncatable::Truncatable;
 
+static ROOT: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Redirect paths managed by mihoro under `root`, set once by `--root` (or `MIHORO_ROOT`).
+pub fn set_root(root: Option<&str>) {
+    ROOT.get_or_init(|| root.map(|root| PathBuf::from(tilde(root).as_ref())));
+}
+
+/// Marker file next to the mihoro executable that turns on portable mode.
+pub const PORTABLE_MARKER: &str = "mihoro-portable";
+
+/// Directory of the mihoro executable, the root of portable mode if `portable` is set or the
+/// executable is next to a `PORTABLE_MARKER`.
+pub fn portable_root(portable: bool) -> Result<Option<PathBuf>> {
+    let executable = env::current_exe()?.canonicalize()?;
+    let Some(executable_dir) = executable.parent() else {
+        return Ok(None);
+    };
+    if portable || executable_dir.join(PORTABLE_MARKER).exists() {
+        return Ok(Some(executable_dir.to_path_buf()));
+    }
+    Ok(None)
+}
+
+pub fn root() -> Option<&'static Path> {
+    ROOT.get().and_then(Option::as_deref)
+}
+
+/// Expand `~` in a path managed by mihoro. Under a root directory, `~` is the root itself and
+/// absolute paths (e.g. `/etc/mihomo`) are moved below it, unless already there.
+pub fn expand_path(path: &str) -> String {
+    let Some(root) = root() else {
+        return tilde(path).to_string();
+    };
+    let expanded = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+            format!("{}{}", root.display(), rest)
+        }
+        _ => path.to_string(),
+    };
+    if Path::new(&expanded).starts_with(root) {
+        return expanded;
+    }
+    match expanded.strip_prefix('/') {
+        Some(absolute) => root.join(absolute).to_string_lossy().to_string(),
+        None => expanded,
+    }
+}
+
 /// Creates the parent directory for a given path if it does not exist.
 ///
 /// # Arguments
@@ -27,28 +88,130 @@ pub fn create_parent_dir(path: &str) -> Result<()> {
     Ok(())
 }
 
+/// Size of the write buffer of downloads, chunks are flushed to disk whenever it fills up.
+const DOWNLOAD_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Throttles a transfer to a maximum rate by pausing whenever it gets ahead of schedule.
+struct RateLimiter {
+    bytes_per_sec: u64,
+    started: Instant,
+    transferred: u64,
+}
+
+impl RateLimiter {
+    fn new(bytes_per_sec: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_sec: bytes_per_sec.max(1),
+            started: Instant::now(),
+            transferred: 0,
+        }
+    }
+
+    /// Account for transferred bytes, sleeping until the average rate is back under the limit.
+    async fn consume(&mut self, bytes: usize) {
+        self.transferred += bytes as u64;
+        let scheduled =
+            Duration::from_secs_f64(self.transferred as f64 / self.bytes_per_sec as f64);
+        let elapsed = self.started.elapsed();
+        if scheduled > elapsed {
+            tokio::time::sleep(scheduled - elapsed).await;
+        }
+    }
+}
+
+/// Options shared by downloads.
+///
+/// * `max_rate` - throttle downloads to this many bytes per second.
+/// * `timeout` - fail a download if the server takes longer than this to respond or stalls
+///   mid-transfer, so that the next mirror can be tried.
+/// * `headers` - extra request headers, e.g. `User-Agent` of subscriptions.
+/// * `cache` - reuse unchanged downloads from the download cache.
+/// * `response_headers` - receives headers of the response a file was downloaded from, e.g.
+///   `subscription-userinfo` of subscriptions.
+#[derive(Debug, Clone, Default)]
+pub struct DownloadOptions {
+    pub max_rate: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub headers: HeaderMap,
+    pub cache: Option<DownloadCache>,
+    pub response_headers: Option<Arc<Mutex<HeaderMap>>>,
+}
+
+/// Await a future, failing if it does not complete within `timeout`.
+async fn within<F: Future>(timeout: Option<Duration>, future: F) -> Result<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| anyhow!("timed out after {}s", timeout.as_secs_f32())),
+        None => Ok(future.await),
+    }
+}
+
 /// Download file from url to path with a reusable http client.
 ///
+/// Chunks are streamed to `{path}.part` through a fixed-size buffer, keeping memory flat for large
+/// files, then the file is synced to disk and renamed to `path`. An interrupted download never
+/// leaves a truncated file at `path`. Downloads are throttled and timed out as per `options`.
+///
+/// With a download cache, cached urls are revalidated with `If-None-Match` and `If-Modified-Since`,
+/// copying the cached file if unchanged.
+///
 /// Renders a progress bar if content-length is available from the url headers provided. If not,
 /// renders a spinner to indicate that something is downloading.
 ///
 /// With reference from:
 /// * https://github.com/mihaigalos/tutorials/blob/800d5acbc333fd4068622e9b3d870cb5b7d34e12/rust/download_with_progressbar/src/main.rs
 /// * https://github.com/console-rs/indicatif/blob/2954b1a24ac5f1900a7861992e4825bff643c9e2/examples/yarnish.rs
-///
-/// Note: Allow `clippy::unused_io_amount` because we are writing downloaded chunks on the fly.
-#[allow(clippy::unused_io_amount)]
-pub async fn download_file(client: &Client, url: &str, path: &str) -> Result<()> {
+pub async fn download_file(
+    client: &Client,
+    url: &str,
+    path: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
     // Create parent directory for download destination if not exists
     create_parent_dir(path)?;
 
     // Create shared http client for multiple downloads when possible
-    let res = client
-        .get(url)
-        .send()
+    let mut request = client.get(url).headers(options.headers.clone());
+    let cached = options
+        .cache
+        .as_ref()
+        .and_then(|cache| Some((cache, cache.lookup(url)?)));
+    if let Some((_, entry)) = &cached {
+        if let Some(etag) = &entry.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &entry.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+    }
+    let res = within(options.timeout, request.send())
         .await
+        .and_then(|res| Ok(res?.error_for_status()?))
         .with_context(|| format!("failed to GET from '{}'", &url))?;
 
+    if let (StatusCode::NOT_MODIFIED, Some((cache, entry))) = (res.status(), &cached) {
+        cache.restore(entry, path)?;
+        println!(
+            "{} Unchanged, copied {} from cache",
+            "download:".blue(),
+            path.underline()
+        );
+        return Ok(());
+    }
+    let validator = |name| {
+        res.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+    };
+    let (etag, last_modified) = (validator(ETAG), validator(LAST_MODIFIED));
+    if let Some(response_headers) = &options.response_headers {
+        if let Ok(mut response_headers) = response_headers.lock() {
+            *response_headers = res.headers().clone();
+        }
+    }
+
     // If content length is not available or 0, use a spinner instead of a progress bar
     let total_size = res.content_length().unwrap_or(0);
     let pb = ProgressBar::new(total_size);
@@ -73,14 +236,27 @@ pub async fn download_file(client: &Client, url: &str, path: &str) -> Result<()>
     pb.set_message(format!("Downloading {truncated_url}"));
 
     // Start file download and update progress bar when new data chunk is received
-    let mut file = File::create(path)?;
+    let part_path = format!("{}.part", path);
+    let mut file = BufWriter::with_capacity(
+        DOWNLOAD_BUFFER_SIZE,
+        tokio::fs::File::create(&part_path).await?,
+    );
     let mut downloaded: u64 = 0;
     let mut stream = res.bytes_stream();
+    let mut rate_limiter = options.max_rate.map(RateLimiter::new);
 
-    while let Some(item) = stream.next().await {
+    while let Some(item) = within(options.timeout, stream.next())
+        .await
+        .with_context(|| format!("download from '{}' stalled", &url))?
+    {
         let chunk = item.with_context(|| "error while downloading file")?;
 
-        file.write(&chunk).with_context(|| "error while writing to file")?;
+        file.write_all(&chunk)
+            .await
+            .with_context(|| "error while writing to file")?;
+        if let Some(rate_limiter) = rate_limiter.as_mut() {
+            rate_limiter.consume(chunk.len()).await;
+        }
         if total_size != 0 {
             let new = min(downloaded + (chunk.len() as u64), total_size);
             downloaded = new;
@@ -90,21 +266,34 @@ pub async fn download_file(client: &Client, url: &str, path: &str) -> Result<()>
         }
     }
 
+    // Make sure the download is on disk before replacing the destination
+    file.flush().await?;
+    file.get_ref().sync_all().await?;
+    drop(file);
+    tokio::fs::rename(&part_path, path).await?;
+    if let Some(cache) = &options.cache {
+        cache.store(url, path, etag, last_modified)?;
+    }
+
     pb.finish_with_message(format!("Downloaded to {}", path.underline()));
     Ok(())
 }
 
-pub fn delete_file(path: &str, prefix: &str) -> Result<()> {
+pub fn delete_file(path: &str, prefix: &Prefix) -> Result<()> {
     // Delete file if exists
     if Path::new(path).exists() {
         fs::remove_file(path).map(|_| {
-            println!("{} Removed {}", prefix.cyan(), path.underline().yellow());
+            println!(
+                "{} {}",
+                prefix.info(),
+                tr_args("Removed {}", &[&path.underline().yellow()])
+            );
         })?;
     }
     Ok(())
 }
 
-pub fn extract_gzip(gzip_path: &str, filename: &str, prefix: &str) -> Result<()> {
+pub fn extract_gzip(gzip_path: &str, filename: &str, prefix: &Prefix) -> Result<()> {
     // Create parent directory for extraction dest if not exists
     create_parent_dir(filename)?;
 
@@ -114,9 +303,9 @@ pub fn extract_gzip(gzip_path: &str, filename: &str, prefix: &str) -> Result<()>
     io::copy(&mut archive, &mut file)?;
     fs::remove_file(gzip_path)?;
     println!(
-        "{} Extracted to {}",
-        prefix.green(),
-        filename.underline().yellow()
+        "{} {}",
+        prefix.success(),
+        tr_args("Extracted to {}", &[&filename.underline().yellow()])
     );
     Ok(())
 }
@@ -129,7 +318,7 @@ pub fn try_decode_base64_and_overwrite_file(filename: &str) -> Result<()> {
     //try decode
     let decoded = BASE64_STANDARD.decode(base64_buf.as_slice());
     // the file is not base64 encoded .It is ok .Do Nothing
-    if let Err(_) = decoded {
+    if decoded.is_err() {
         return Ok(());
     }
     //try to clear file
@@ -144,16 +333,252 @@ pub fn try_decode_base64_and_overwrite_file(filename: &str) -> Result<()> {
     file.write_all(&decoded_bytes)?;
     Ok(())
 }
-//get file from remote or local file system
-pub async fn get_file_from_system_or_remote(client: &Client, url: &str, path: &str) -> Result<()> {
-    let suffix_url = "file://";
-    //if url is remote url ,download file
-    if !url.starts_with(suffix_url) {
-        download_file(client, url, path).await?;
-        return Ok(());
+/// Local file system path of a `file://` url or a plain path (e.g. `~/Sync/config.yaml`), `None`
+/// for remote urls.
+fn local_path(url: &str) -> Option<String> {
+    let Some(path) = url.strip_prefix("file://") else {
+        return (!url.contains("://")).then(|| tilde(url).to_string());
+    };
+    // Fall back to the raw path for urls like `file://~/config.yaml`
+    let path = Url::parse(url)
+        .ok()
+        .and_then(|url| url.to_file_path().ok())
+        .map(|path| path.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+    Some(tilde(&path).to_string())
+}
+
+/// Get file from remote or local file system, trying each mirror in order until one succeeds. Local
+/// files are given as `file://` urls or plain paths, and `exec://{command}` takes the output of a
+/// shell command.
+pub async fn get_file_from_system_or_remote(
+    client: &Client,
+    mirrors: &[String],
+    path: &str,
+    options: &DownloadOptions,
+) -> Result<()> {
+    for (index, url) in mirrors.iter().enumerate() {
+        let result = if let Some(command) = url.strip_prefix("exec://") {
+            //write output of command to path
+            write_command_output(command, path)
+                .with_context(|| format!("failed to get output of `{}`", command))
+        } else if let Some(local_path) = local_path(url) {
+            //copy file system file to path
+            copy_local_file(&local_path, path)
+                .with_context(|| format!("failed to copy from '{}'", url))
+        } else {
+            //if url is remote url ,download file
+            download_file(client, url, path, options).await
+        };
+        match result {
+            Err(error) if index + 1 < mirrors.len() => {
+                let reason = match error.chain().len() {
+                    1 => error.to_string(),
+                    _ => format!("{}: {}", error, error.root_cause()),
+                };
+                println!("{} {}, trying next mirror", "download:".yellow(), reason);
+            }
+            result => return result,
+        }
+    }
+    bail!("no url to get `{}` from", path)
+}
+
+/// Copy a local file to path, unless both are the same file.
+fn copy_local_file(local_path: &str, path: &str) -> Result<()> {
+    create_parent_dir(path)?;
+    let same_file = fs::canonicalize(path)
+        .is_ok_and(|path| fs::canonicalize(local_path).is_ok_and(|local_path| local_path == path));
+    if !same_file {
+        fs::copy(local_path, path)?;
     }
-    //copy file system file to path
+    Ok(())
+}
+
+/// Run a shell command and write its standard output to path, replacing the file only if the
+/// command succeeds. Standard error is passed through, e.g. for progress or prompts.
+fn write_command_output(command: &str, path: &str) -> Result<()> {
     create_parent_dir(path)?;
-    fs::copy(tilde(&path.replace(suffix_url, "")).to_string(), path)?;
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!("command exited with {}", output.status));
+    }
+    if output.stdout.is_empty() {
+        return Err(anyhow!("command printed nothing"));
+    }
+    let part_path = format!("{}.part", path);
+    fs::write(&part_path, &output.stdout)?;
+    fs::rename(&part_path, path)?;
+    Ok(())
+}
+
+/// Verify the minisign signature of a file against a base64-encoded public key.
+pub fn verify_signature(public_key: &str, path: &str, signature_path: &str) -> Result<()> {
+    let public_key = PublicKey::from_base64(public_key).context("invalid public key")?;
+    let signature = Signature::from_file(signature_path)
+        .with_context(|| format!("invalid signature file '{}'", signature_path))?;
+    let content = fs::read(path)?;
+    public_key
+        .verify(&content, &signature, false)
+        .context("signature does not match")?;
+    Ok(())
+}
+
+/// List `*.yaml` and `*.yml` files in a directory, sorted by file name. A missing directory has no
+/// files.
+pub fn list_yaml_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut yaml_files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "yaml" || extension == "yml")
+        })
+        .collect();
+    yaml_files.sort();
+    Ok(yaml_files)
+}
+
+/// Generate a random hex token from `/dev/urandom`, e.g. for one-time urls.
+pub fn random_token(bytes: usize) -> Result<String> {
+    let mut buf = vec![0; bytes];
+    File::open("/dev/urandom")?.read_exact(&mut buf)?;
+    Ok(buf.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Send a desktop notification with `notify-send`, returning whether it was sent.
+pub fn notify_desktop(summary: &str, body: &str) -> bool {
+    Command::new("notify-send")
+        .args(["--app-name=mihoro", summary, body])
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no.
+pub fn confirm(prompt: &str) -> Result<bool> {
+    print!("{} [y/N] ", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Read text from the system clipboard with the first available clipboard tool.
+pub fn read_clipboard() -> Result<String> {
+    let tools: &[(&str, &[&str])] = &[
+        ("wl-paste", &["--no-newline"]),
+        ("xclip", &["-selection", "clipboard", "-o"]),
+        ("xsel", &["--clipboard", "--output"]),
+        ("pbpaste", &[]),
+    ];
+    for (tool, args) in tools {
+        if let Ok(output) = Command::new(tool).args(*args).output() {
+            if output.status.success() {
+                return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+            }
+        }
+    }
+    Err(anyhow!(
+        "failed to read clipboard, install one of wl-paste, xclip or xsel"
+    ))
+}
+
+/// Look up a secret stored in the system keyring with `secret-tool`, under attributes
+/// `service mihoro account {account}`.
+pub fn read_keyring(account: &str) -> Result<String> {
+    let output = Command::new("secret-tool")
+        .args(["lookup", "service", "mihoro", "account", account])
+        .output()
+        .with_context(|| "failed to run secret-tool, install libsecret to use the keyring")?;
+    let secret = String::from_utf8(output.stdout)?;
+    if !output.status.success() || secret.is_empty() {
+        return Err(anyhow!("secret `{}` not found in keyring", account));
+    }
+    Ok(secret.trim_end_matches('\n').to_string())
+}
+
+/// Whether mihoro runs as root, i.e. owns `/proc/self`.
+pub fn is_root() -> bool {
+    fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0)
+}
+
+/// Run mihoro again as root with sudo and the same arguments, pointing it to the invoking user's
+/// mihoro config, as `~` would expand to root's home otherwise.
+pub fn rerun_as_root(config_path: &str) -> Result<ExitStatus> {
+    let mut args = env::args().skip(1);
+    let mut rest = Vec::new();
+    let mut subcommand = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            // Global options, replaced by the resolved root below
+            "--root" => {
+                args.next();
+            }
+            "--portable" => {}
+            arg if arg.starts_with("--root=") => {}
+            _ if subcommand => rest.push(arg),
+            // Options of mihoro itself, before the subcommand
+            "-m" | "--mihoro-config" => {
+                args.next();
+            }
+            arg if arg.starts_with("--mihoro-config=") || arg.starts_with("-m") => {}
+            "--color" => {
+                rest.push(arg);
+                rest.extend(args.next());
+            }
+            _ => {
+                subcommand = !arg.starts_with('-');
+                rest.push(arg);
+            }
+        }
+    }
+    // Pass the root on explicitly, as sudo resets `MIHORO_ROOT`
+    let root_args = root()
+        .map(|root| vec![String::from("--root"), root.display().to_string()])
+        .unwrap_or_default();
+    Command::new("sudo")
+        .arg(env::current_exe()?)
+        .arg("--mihoro-config")
+        .arg(config_path)
+        .args(root_args)
+        .args(rest)
+        .status()
+        .context("failed to run sudo")
+}
+
+/// Run a shell script with `sh`, through sudo unless running as root.
+pub fn run_root_script(script: &str) -> Result<ExitStatus> {
+    let mut shell = if is_root() {
+        Command::new("sh")
+    } else {
+        let mut sudo = Command::new("sudo");
+        sudo.arg("sh");
+        sudo
+    };
+    let mut child = shell
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("failed to run sh")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(script.as_bytes())?;
+    }
+    Ok(child.wait()?)
+}
+
+/// Send a signal such as `TERM` or `HUP` to a process.
+pub fn send_signal(pid: u32, signal: &str) -> Result<()> {
+    let status = Command::new("kill")
+        .arg(format!("-{}", signal))
+        .arg(pid.to_string())
+        .status()
+        .context("failed to run kill")?;
+    if !status.success() {
+        bail!("failed to send SIG{} to process {}", signal, pid);
+    }
     Ok(())
 }
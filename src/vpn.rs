@@ -0,0 +1,107 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+/// Network interfaces of the system.
+const NET_CLASS_ROOT: &str = "/sys/class/net";
+
+/// VPN client running alongside mihomo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VpnKind {
+    Tailscale,
+    WireGuard,
+    OpenVpn,
+}
+
+impl VpnKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            VpnKind::Tailscale => "Tailscale",
+            VpnKind::WireGuard => "WireGuard",
+            VpnKind::OpenVpn => "OpenVPN",
+        }
+    }
+
+    /// Networks reached over the VPN regardless of its routes, to keep out of mihomo's TUN.
+    pub fn networks(&self) -> &'static [&'static str] {
+        match self {
+            VpnKind::Tailscale => &["100.64.0.0/10", "fd7a:115c:a1e0::/48"],
+            VpnKind::WireGuard | VpnKind::OpenVpn => &[],
+        }
+    }
+}
+
+/// Network interface of a VPN.
+#[derive(Debug, Clone)]
+pub struct Vpn {
+    pub kind: VpnKind,
+    pub interface: String,
+}
+
+/// VPN interfaces of the system, except mihomo's own TUN device `tun_device`: Tailscale by its
+/// interface name, WireGuard by its device type, and tun or tap devices while OpenVPN runs.
+pub fn detect_vpns(tun_device: &str) -> Vec<Vpn> {
+    let Ok(entries) = fs::read_dir(NET_CLASS_ROOT) else {
+        return Vec::new();
+    };
+    let mut interfaces: Vec<String> = entries
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|interface| interface != tun_device)
+        .collect();
+    interfaces.sort();
+
+    let openvpn_running = openvpn_running();
+    interfaces
+        .into_iter()
+        .filter_map(|interface| {
+            let device = Path::new(NET_CLASS_ROOT).join(&interface);
+            let uevent = fs::read_to_string(device.join("uevent")).unwrap_or_default();
+            let kind = if interface.starts_with("tailscale") {
+                VpnKind::Tailscale
+            } else if uevent.lines().any(|line| line == "DEVTYPE=wireguard") {
+                VpnKind::WireGuard
+            } else if openvpn_running && device.join("tun_flags").exists() {
+                VpnKind::OpenVpn
+            } else {
+                return None;
+            };
+            Some(Vpn { kind, interface })
+        })
+        .collect()
+}
+
+/// Whether an `openvpn` process runs, according to `/proc`.
+fn openvpn_running() -> bool {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        fs::read_to_string(entry.path().join("comm")).is_ok_and(|comm| comm.trim() == "openvpn")
+    })
+}
+
+/// Interfaces taking IPv4 traffic by default, in any routing table: those of `default` routes and
+/// of the `0.0.0.0/1` and `128.0.0.0/1` pair VPNs use to override the default route.
+pub fn default_route_interfaces() -> Vec<String> {
+    let Ok(output) = Command::new("ip")
+        .args(["route", "show", "table", "all"])
+        .output()
+    else {
+        return Vec::new();
+    };
+    let mut interfaces = Vec::new();
+    for route in String::from_utf8_lossy(&output.stdout).lines() {
+        let mut fields = route.split_whitespace();
+        if !matches!(fields.next(), Some("default" | "0.0.0.0/1" | "128.0.0.0/1")) {
+            continue;
+        }
+        let interface = fields.skip_while(|field| *field != "dev").nth(1);
+        if let Some(interface) = interface {
+            if !interfaces.iter().any(|known| known == interface) {
+                interfaces.push(interface.to_string());
+            }
+        }
+    }
+    interfaces
+}
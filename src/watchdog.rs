@@ -0,0 +1,37 @@
+use std::env;
+use std::os::unix::net::UnixDatagram;
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// Watchdog timeout assumed when run outside of a service with `WatchdogSec` and `watchdog_sec`
+/// is unset.
+pub const DEFAULT_WATCHDOG_SEC: u64 = 30;
+
+/// Send a state such as `READY=1` or `WATCHDOG=1` to systemd, if running as a service with a
+/// notification socket.
+///
+/// Reference: https://www.freedesktop.org/software/systemd/man/latest/sd_notify.html
+pub fn sd_notify(state: &str) -> Result<()> {
+    let Ok(notify_socket) = env::var("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let socket = UnixDatagram::unbound()?;
+    // Sockets in the abstract namespace are specific to Linux
+    #[cfg(target_os = "linux")]
+    if let Some(name) = notify_socket.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::net::SocketAddr;
+
+        socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        return Ok(());
+    }
+    socket.send_to(state.as_bytes(), &notify_socket)?;
+    Ok(())
+}
+
+/// Watchdog timeout systemd expects notifications within, from `WatchdogSec` of the service.
+pub fn watchdog_timeout() -> Option<Duration> {
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec))
+}
@@ -0,0 +1,431 @@
+//! End-to-end tests of the `mihoro` binary, sandboxed under `--root` with a stub `systemctl` and
+//! a local HTTP server standing in for the subscription.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::thread;
+
+const REMOTE_CONFIG: &str = "\
+proxies:
+  - name: HK 01
+    type: ss
+    server: hk.example.com
+    port: 8388
+    cipher: aes-128-gcm
+    password: secret
+proxy-groups:
+  - name: Proxy
+    type: select
+    proxies:
+      - HK 01
+rules:
+  - MATCH,Proxy
+";
+
+/// Temporary mihoro root with a stub `systemctl` that logs its arguments, removed on drop.
+struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    fn new(name: &str) -> Sandbox {
+        let root =
+            std::env::temp_dir().join(format!("mihoro-test-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&root);
+        let bin = root.join("stub-bin");
+        fs::create_dir_all(&bin).unwrap();
+        let systemctl = bin.join("systemctl");
+        fs::write(
+            &systemctl,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {}\n",
+                root.join("systemctl.log").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&systemctl, fs::Permissions::from_mode(0o755)).unwrap();
+        Sandbox { root }
+    }
+
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.join(path)
+    }
+
+    fn mihoro(&self, args: &[&str]) -> Output {
+        let path = format!(
+            "{}:{}",
+            self.path("stub-bin").display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        Command::new(env!("CARGO_BIN_EXE_mihoro"))
+            .arg("--root")
+            .arg(&self.root)
+            .args(args)
+            .env("PATH", path)
+            .env("LANG", "C")
+            .env("NO_COLOR", "1")
+            .env_remove("LC_ALL")
+            .env_remove("LC_MESSAGES")
+            .env_remove("MIHORO_ROOT")
+            .env_remove("XDG_CACHE_HOME")
+            .env_remove("http_proxy")
+            .env_remove("https_proxy")
+            .env_remove("all_proxy")
+            .env_remove("HTTP_PROXY")
+            .env_remove("HTTPS_PROXY")
+            .env_remove("ALL_PROXY")
+            .output()
+            .unwrap()
+    }
+
+    /// Create the default config with a first run, then point it at `remote_config_url`.
+    fn configure(&self, remote_config_url: &str) {
+        let output = self.mihoro(&["status"]);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("created default config"));
+
+        let config_path = self.path(".config/mihoro.toml");
+        let config = fs::read_to_string(&config_path).unwrap().replace(
+            "remote_config_url = \"\"",
+            &format!("remote_config_url = \"{}\"", remote_config_url),
+        );
+        fs::write(&config_path, config).unwrap();
+    }
+
+    fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    fn systemctl_log(&self) -> String {
+        fs::read_to_string(self.path("systemctl.log")).unwrap_or_default()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+/// Serve `body` with `headers` to every request on a local port, returning its url.
+fn serve(body: &'static str, headers: &'static str) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/config.yaml", listener.local_addr().unwrap());
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+                body.len(),
+                headers,
+                body
+            );
+        }
+    });
+    url
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "mihoro failed:\n{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[test]
+fn root_keeps_default_config_under_root() {
+    let sandbox = Sandbox::new("default-config");
+    sandbox.configure("");
+    let config = sandbox.read(".config/mihoro.toml");
+    assert!(config.contains("config_version"));
+    assert!(config.contains("mihomo_config_root = \"~/.config/mihomo\""));
+}
+
+#[test]
+fn update_fetches_remote_config_and_applies_overrides() {
+    let sandbox = Sandbox::new("update");
+    let url = serve(
+        REMOTE_CONFIG,
+        "subscription-userinfo: upload=1024; download=2048; total=1073741824; expire=4102444800\r\n",
+    );
+    sandbox.configure(&url);
+
+    let output = sandbox.mihoro(&["update"]);
+    assert_success(&output);
+
+    assert_eq!(
+        sandbox.read(".config/mihomo/remote-config.yaml"),
+        REMOTE_CONFIG
+    );
+    let config = sandbox.read(".config/mihomo/config.yaml");
+    assert!(config.contains("mixed-port: 7890"));
+    assert!(config.contains("hk.example.com"));
+    assert!(sandbox
+        .read(".config/mihomo/mihoro-state.toml")
+        .contains("total = 1073741824"));
+    assert!(sandbox
+        .systemctl_log()
+        .contains("--user restart mihomo.service"));
+}
+
+#[test]
+fn update_skips_restart_when_nothing_changed() {
+    let sandbox = Sandbox::new("no-change");
+    let url = serve(REMOTE_CONFIG, "");
+    sandbox.configure(&url);
+
+    assert_success(&sandbox.mihoro(&["update"]));
+    let output = sandbox.mihoro(&["update"]);
+    assert_success(&output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("unchanged"));
+
+    assert!(!sandbox.path(".config/mihomo/staging/config.yaml").exists());
+    assert!(!sandbox
+        .path(".config/mihomo/staging/remote-config.yaml")
+        .exists());
+    assert_eq!(
+        sandbox
+            .systemctl_log()
+            .matches("--user restart mihomo.service")
+            .count(),
+        1
+    );
+}
+
+#[test]
+fn update_keeps_current_config_on_invalid_download() {
+    let sandbox = Sandbox::new("quarantine");
+    let url = serve("<html>login required</html>", "");
+    sandbox.configure(&url);
+    fs::create_dir_all(sandbox.path(".config/mihomo")).unwrap();
+    fs::write(
+        sandbox.path(".config/mihomo/remote-config.yaml"),
+        REMOTE_CONFIG,
+    )
+    .unwrap();
+
+    let output = sandbox.mihoro(&["update"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("downloaded config is invalid"));
+
+    assert_eq!(
+        sandbox.read(".config/mihomo/remote-config.yaml"),
+        REMOTE_CONFIG
+    );
+    assert!(sandbox
+        .path(".config/mihomo/remote-config.quarantine.yaml")
+        .is_file());
+    assert!(sandbox.systemctl_log().is_empty());
+}
+
+#[test]
+fn update_falls_back_to_backup_subscription_url() {
+    let sandbox = Sandbox::new("backup-url");
+    let unused_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    sandbox.configure(&format!("http://127.0.0.1:{}/config.yaml", unused_port));
+    let backup_url = serve(REMOTE_CONFIG, "");
+    let config_path = sandbox.path(".config/mihoro.toml");
+    let mut config = fs::read_to_string(&config_path).unwrap();
+    config.push_str(&format!(
+        "\n[subscription]\nbackup_urls = [\"{}\"]\nretries = 2\n",
+        backup_url
+    ));
+    fs::write(&config_path, config).unwrap();
+
+    let output = sandbox.mihoro(&["update"]);
+    assert_success(&output);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("retrying (1/2)"));
+    assert!(stdout.contains("Downloaded the subscription from backup_urls[0]"));
+
+    assert_eq!(
+        sandbox.read(".config/mihomo/remote-config.yaml"),
+        REMOTE_CONFIG
+    );
+    assert!(sandbox
+        .read(".config/mihomo/mihoro-state.toml")
+        .contains("subscription_source = \"backup_urls[0]\""));
+}
+
+#[test]
+fn apply_regenerates_config_after_override_changes() {
+    let sandbox = Sandbox::new("apply");
+    sandbox.configure("https://example.com/unused.yaml");
+    fs::create_dir_all(sandbox.path(".config/mihomo")).unwrap();
+    fs::write(
+        sandbox.path(".config/mihomo/remote-config.yaml"),
+        REMOTE_CONFIG,
+    )
+    .unwrap();
+
+    assert_success(&sandbox.mihoro(&["apply"]));
+    assert!(sandbox
+        .read(".config/mihomo/config.yaml")
+        .contains("mixed-port: 7890"));
+
+    let config_path = sandbox.path(".config/mihoro.toml");
+    let config = fs::read_to_string(&config_path)
+        .unwrap()
+        .replace("mixed_port = 7890", "mixed_port = 7990");
+    fs::write(&config_path, config).unwrap();
+
+    assert_success(&sandbox.mihoro(&["apply"]));
+    assert!(sandbox
+        .read(".config/mihomo/config.yaml")
+        .contains("mixed-port: 7990"));
+    assert!(Path::new(&sandbox.path(".config/mihomo/backups")).is_dir());
+    assert_eq!(
+        sandbox
+            .systemctl_log()
+            .matches("--user restart mihomo.service")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn staged_update_changes_running_config_only_on_activate() {
+    let sandbox = Sandbox::new("stages");
+    let url = serve(REMOTE_CONFIG, "");
+    sandbox.configure(&url);
+
+    assert_success(&sandbox.mihoro(&["fetch"]));
+    assert_eq!(
+        sandbox.read(".config/mihomo/staging/remote-config.yaml"),
+        REMOTE_CONFIG
+    );
+    assert_success(&sandbox.mihoro(&["build"]));
+    assert!(sandbox
+        .read(".config/mihomo/staging/config.yaml")
+        .contains("hk.example.com"));
+    assert!(!sandbox.path(".config/mihomo/config.yaml").exists());
+    assert!(sandbox.systemctl_log().is_empty());
+
+    assert_success(&sandbox.mihoro(&["activate"]));
+    assert!(sandbox
+        .read(".config/mihomo/config.yaml")
+        .contains("hk.example.com"));
+    assert_eq!(
+        sandbox.read(".config/mihomo/remote-config.yaml"),
+        REMOTE_CONFIG
+    );
+    assert!(!sandbox.path(".config/mihomo/staging/config.yaml").exists());
+    assert!(sandbox
+        .systemctl_log()
+        .contains("--user restart mihomo.service"));
+}
+
+#[test]
+fn activate_refuses_config_rejected_by_mihomo() {
+    let sandbox = Sandbox::new("rejected");
+    let url = serve(REMOTE_CONFIG, "");
+    sandbox.configure(&url);
+    let mihomo = sandbox.path(".local/bin/mihomo");
+    fs::create_dir_all(mihomo.parent().unwrap()).unwrap();
+    fs::write(
+        &mihomo,
+        "#!/bin/sh\necho 'unsupported proxy type'\nexit 1\n",
+    )
+    .unwrap();
+    fs::set_permissions(&mihomo, fs::Permissions::from_mode(0o755)).unwrap();
+
+    assert_success(&sandbox.mihoro(&["fetch"]));
+    assert_success(&sandbox.mihoro(&["build"]));
+    let output = sandbox.mihoro(&["activate"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unsupported proxy type"));
+
+    assert!(!sandbox.path(".config/mihomo/config.yaml").exists());
+    assert!(sandbox.path(".config/mihomo/staging/config.yaml").is_file());
+    assert!(sandbox.systemctl_log().is_empty());
+}
+
+#[test]
+fn activate_rolls_back_when_mihomo_does_not_come_up() {
+    let sandbox = Sandbox::new("rollback");
+    let url = serve(REMOTE_CONFIG, "");
+    sandbox.configure(&url);
+    // Nothing listens on the external controller once the listener is dropped
+    let unused_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let config_path = sandbox.path(".config/mihoro.toml");
+    let config = fs::read_to_string(&config_path)
+        .unwrap()
+        .replacen(
+            "config_version = 2",
+            "config_version = 2\nrollback_sec = 1",
+            1,
+        )
+        .replace(
+            "external_controller = \"0.0.0.0:9090\"",
+            &format!("external_controller = \"127.0.0.1:{}\"", unused_port),
+        );
+    fs::write(&config_path, config).unwrap();
+    fs::create_dir_all(sandbox.path(".config/mihomo")).unwrap();
+    fs::write(
+        sandbox.path(".config/mihomo/config.yaml"),
+        "mixed-port: 7890\n",
+    )
+    .unwrap();
+
+    let output = sandbox.mihoro(&["update"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("did not come up with the activated config"));
+
+    assert_eq!(
+        sandbox.read(".config/mihomo/config.yaml"),
+        "mixed-port: 7890\n"
+    );
+    assert!(sandbox
+        .read(".config/mihomo/staging/config.yaml")
+        .contains("hk.example.com"));
+    assert!(sandbox
+        .path(".config/mihomo/staging/remote-config.yaml")
+        .is_file());
+    assert_eq!(
+        sandbox
+            .systemctl_log()
+            .matches("--user restart mihomo.service")
+            .count(),
+        2
+    );
+}
+
+#[test]
+fn fake_ip_filter_edits_only_its_key_of_the_config() {
+    let sandbox = Sandbox::new("fake-ip-filter");
+    sandbox.configure("http://127.0.0.1:1/config.yaml");
+    let config_path = sandbox.path(".config/mihoro.toml");
+    let config = format!(
+        "# Subscription of the home network\n{}",
+        fs::read_to_string(&config_path).unwrap()
+    );
+    fs::write(&config_path, &config).unwrap();
+
+    assert_success(&sandbox.mihoro(&["fake-ip-filter", "add", "+.lan", "time.*.com"]));
+    assert_success(&sandbox.mihoro(&["fake-ip-filter", "remove", "+.lan"]));
+
+    let edited = sandbox.read(".config/mihoro.toml");
+    assert!(edited.starts_with(&config));
+    assert!(edited.ends_with("[mihomo_config.dns]\nfake-ip-filter = [\"time.*.com\"]\n"));
+}